@@ -0,0 +1,396 @@
+// Access control of visitors, which can be changed at runtime through the admin API.
+// A group of services has rules of its own, checked along with those of each service in it.
+// Rules are persisted to `server.acl_file`, so they survive restarts without touching the config
+use crate::error::Failure;
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::fs;
+use tracing::{info, warn};
+
+// The maximum number of (service or group, IP) pairs tracked by the rate limit
+const ACL_RATE_LIMIT_MAX_ENTRIES: usize = 65536;
+const ACL_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceAcl {
+    // If not empty, only visitors from these networks are accepted
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    pub allow: Vec<IpNet>,
+    // Visitors from these networks are rejected. Takes precedence over `allow`
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    pub deny: Vec<IpNet>,
+    // The maximum number of new connections per visitor IP per minute
+    pub rate_limit: Option<u32>,
+}
+
+// Accept bare IP addresses as well as networks
+fn deserialize_nets<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<IpNet>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| {
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| serde::de::Error::custom(format!("Invalid IP or network {}", s)))
+        })
+        .collect()
+}
+
+impl ServiceAcl {
+    fn validate(&self) -> Result<()> {
+        if self.rate_limit == Some(0) {
+            bail!("`rate_limit` must be positive");
+        }
+        Ok(())
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|n| n.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|n| n.contains(&ip))
+    }
+}
+
+#[derive(Default)]
+pub struct Acl {
+    state: Mutex<AclState>,
+    // Serializes writes of the file
+    persist_lock: tokio::sync::Mutex<()>,
+}
+
+// What the rules apply to. The rate limit of a group is shared by the services in it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Scope {
+    Service(String),
+    Group(String),
+}
+
+#[derive(Default)]
+struct AclState {
+    path: Option<PathBuf>,
+    // The rules, indexed by the service or the group
+    services: BTreeMap<String, ServiceAcl>,
+    groups: BTreeMap<String, ServiceAcl>,
+    // The start of the current window and the number of connections in it
+    connections: HashMap<(Scope, IpAddr), (Instant, u32)>,
+}
+
+// The ACL file. Older versions stored only the rules of services, as a bare map
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclFile {
+    #[serde(default)]
+    services: BTreeMap<String, ServiceAcl>,
+    #[serde(default)]
+    groups: BTreeMap<String, ServiceAcl>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredAcl {
+    File(AclFile),
+    Services(BTreeMap<String, ServiceAcl>),
+}
+
+lazy_static! {
+    pub static ref ACL: Acl = Acl::default();
+}
+
+impl Acl {
+    // Load rules from `path`, replacing the current ones. A missing file means no rules
+    pub async fn load(&self, path: Option<&str>) -> Result<()> {
+        let file = match path {
+            Some(path) => match fs::read(path).await {
+                Ok(v) => match serde_json::from_slice::<StoredAcl>(&v)
+                    .with_context(|| format!("Failed to parse the ACL file {}", path))
+                    .context(Failure::Config)?
+                {
+                    StoredAcl::File(v) => v,
+                    StoredAcl::Services(services) => AclFile {
+                        services,
+                        ..Default::default()
+                    },
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => AclFile::default(),
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to read the ACL file {}", path))
+                        .context(Failure::Config)
+                }
+            },
+            None => AclFile::default(),
+        };
+        for (name, acl) in &file.services {
+            acl.validate()
+                .with_context(|| format!("Invalid ACL of service {}", name))
+                .context(Failure::Config)?;
+        }
+        for (name, acl) in &file.groups {
+            acl.validate()
+                .with_context(|| format!("Invalid ACL of group {}", name))
+                .context(Failure::Config)?;
+        }
+        if !file.services.is_empty() || !file.groups.is_empty() {
+            info!(
+                "Loaded the ACL of {} services and {} groups",
+                file.services.len(),
+                file.groups.len()
+            );
+        }
+
+        let mut s = self.state.lock().unwrap();
+        s.path = path.map(PathBuf::from);
+        s.services = file.services;
+        s.groups = file.groups;
+        s.connections.clear();
+        Ok(())
+    }
+
+    pub fn get(&self) -> BTreeMap<String, ServiceAcl> {
+        self.state.lock().unwrap().services.clone()
+    }
+
+    pub fn get_groups(&self) -> BTreeMap<String, ServiceAcl> {
+        self.state.lock().unwrap().groups.clone()
+    }
+
+    // Set the rules of `service`, or remove them if `acl` is `None`, and persist all rules
+    pub async fn set(&self, service: &str, acl: Option<ServiceAcl>) -> Result<()> {
+        self.update(Scope::Service(service.to_string()), acl).await
+    }
+
+    // Set the rules shared by the services of `group`, like `set`
+    pub async fn set_group(&self, group: &str, acl: Option<ServiceAcl>) -> Result<()> {
+        self.update(Scope::Group(group.to_string()), acl).await
+    }
+
+    async fn update(&self, scope: Scope, acl: Option<ServiceAcl>) -> Result<()> {
+        if let Some(acl) = &acl {
+            acl.validate()?;
+        }
+
+        let _persist = self.persist_lock.lock().await;
+        let (path, content) = {
+            let mut s = self.state.lock().unwrap();
+            let (rules, name) = match &scope {
+                Scope::Service(name) => (&mut s.services, name),
+                Scope::Group(name) => (&mut s.groups, name),
+            };
+            match acl {
+                Some(acl) => {
+                    rules.insert(name.clone(), acl);
+                }
+                None => {
+                    rules.remove(name);
+                }
+            }
+            s.connections.retain(|(v, _), _| *v != scope);
+            let file = AclFile {
+                services: s.services.clone(),
+                groups: s.groups.clone(),
+            };
+            (s.path.clone(), serde_json::to_vec_pretty(&file)?)
+        };
+        match &scope {
+            Scope::Service(name) => info!(service = %name, "ACL updated"),
+            Scope::Group(name) => info!(group = %name, "ACL updated"),
+        }
+
+        match path {
+            Some(path) => persist(&path, &content).await,
+            None => {
+                warn!("`server.acl_file` is not set. The ACL change will be lost on restart");
+                Ok(())
+            }
+        }
+    }
+
+    // Whether a visitor from `ip` is allowed by the lists of `service`, and of its `group`
+    pub fn allows(&self, service: &str, group: Option<&str>, ip: IpAddr) -> bool {
+        let s = self.state.lock().unwrap();
+        s.services.get(service).is_none_or(|acl| acl.allows(ip))
+            && group
+                .and_then(|g| s.groups.get(g))
+                .is_none_or(|acl| acl.allows(ip))
+    }
+
+    // Whether a new connection from `ip` is allowed by the lists and the rate limits of `service`,
+    // and of its `group`
+    pub fn admit(&self, service: &str, group: Option<&str>, ip: IpAddr) -> bool {
+        let mut s = self.state.lock().unwrap();
+        let mut limits = Vec::new();
+        if let Some(acl) = s.services.get(service) {
+            if !acl.allows(ip) {
+                return false;
+            }
+            limits.extend(
+                acl.rate_limit
+                    .map(|v| (Scope::Service(service.to_string()), v)),
+            );
+        }
+        if let Some((group, acl)) = group.and_then(|g| s.groups.get_key_value(g)) {
+            if !acl.allows(ip) {
+                return false;
+            }
+            limits.extend(acl.rate_limit.map(|v| (Scope::Group(group.clone()), v)));
+        }
+        if limits.is_empty() {
+            return true;
+        }
+
+        let now = Instant::now();
+        if s.connections.len() >= ACL_RATE_LIMIT_MAX_ENTRIES {
+            s.connections
+                .retain(|_, (start, _)| now.duration_since(*start) < ACL_RATE_LIMIT_WINDOW);
+        }
+        // Every limit counts the connection, even if another one rejects it
+        let mut admitted = true;
+        for (scope, rate_limit) in limits {
+            let (start, count) = s.connections.entry((scope, ip)).or_insert((now, 0));
+            if now.duration_since(*start) >= ACL_RATE_LIMIT_WINDOW {
+                *start = now;
+                *count = 0;
+            }
+            *count += 1;
+            admitted &= *count <= rate_limit;
+        }
+        admitted
+    }
+}
+
+// Write to a temporary file first, so a crash doesn't leave a truncated file
+async fn persist(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, content)
+        .await
+        .with_context(|| format!("Failed to write {:?}", tmp))?;
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to write the ACL file {:?}", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acl() {
+        let acl = Acl::default();
+        let ip1: IpAddr = "10.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "10.0.1.1".parse().unwrap();
+        let ip3: IpAddr = "192.168.0.1".parse().unwrap();
+
+        assert!(acl.admit("foo", None, ip1));
+
+        let rules: ServiceAcl = serde_json::from_str(
+            r#"{"allow": ["10.0.0.0/16"], "deny": ["10.0.1.1"], "rate_limit": 2}"#,
+        )
+        .unwrap();
+        acl.set("foo", Some(rules)).await.unwrap();
+        assert!(acl.allows("foo", None, ip1));
+        assert!(!acl.allows("foo", None, ip2));
+        assert!(!acl.allows("foo", None, ip3));
+        assert!(acl.allows("bar", None, ip3));
+
+        assert!(acl.admit("foo", None, ip1));
+        assert!(acl.admit("foo", None, ip1));
+        assert!(!acl.admit("foo", None, ip1));
+
+        acl.set("foo", None).await.unwrap();
+        assert!(acl.admit("foo", None, ip1));
+        assert!(acl.admit("foo", None, ip3));
+
+        assert!(serde_json::from_str::<ServiceAcl>(r#"{"allow": ["foo"]}"#).is_err());
+        assert!(acl
+            .set(
+                "foo",
+                Some(ServiceAcl {
+                    rate_limit: Some(0),
+                    ..Default::default()
+                })
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_group_acl() {
+        let acl = Acl::default();
+        let ip1: IpAddr = "10.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "10.0.1.1".parse().unwrap();
+        let g = Some("g");
+
+        acl.set_group(
+            "g",
+            Some(ServiceAcl {
+                deny: vec!["10.0.1.0/24".parse().unwrap()],
+                rate_limit: Some(2),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(!acl.allows("foo", g, ip2));
+        assert!(acl.allows("foo", None, ip2));
+
+        // The rate limit is shared by the services of the group
+        assert!(acl.admit("foo", g, ip1));
+        assert!(acl.admit("bar", g, ip1));
+        assert!(!acl.admit("foo", g, ip1));
+        assert!(acl.admit("foo", None, ip1));
+
+        acl.set_group("g", None).await.unwrap();
+        assert!(acl.admit("foo", g, ip1));
+        assert!(acl.get_groups().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_acl_persist() {
+        let path = std::env::temp_dir().join(format!("rathole-acl-{}.json", rand::random::<u32>()));
+        let path_str = path.to_str().unwrap();
+
+        let acl = Acl::default();
+        acl.load(Some(path_str)).await.unwrap();
+        acl.set(
+            "foo",
+            Some(ServiceAcl {
+                deny: vec!["1.1.1.0/24".parse().unwrap()],
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+        acl.set_group(
+            "g",
+            Some(ServiceAcl {
+                rate_limit: Some(1),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+
+        let loaded = Acl::default();
+        loaded.load(Some(path_str)).await.unwrap();
+        assert_eq!(loaded.get(), acl.get());
+        assert_eq!(loaded.get_groups(), acl.get_groups());
+        assert!(!loaded.allows("foo", None, "1.1.1.1".parse().unwrap()));
+
+        // The format of older versions
+        std::fs::write(&path, r#"{"foo": {"deny": ["1.1.1.1"]}}"#).unwrap();
+        loaded.load(Some(path_str)).await.unwrap();
+        assert!(!loaded.allows("foo", None, "1.1.1.1".parse().unwrap()));
+        assert!(loaded.get_groups().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,677 @@
+// Obtains and renews the certificate of the TLS transport of the server with ACME (RFC 8555),
+// answering HTTP-01 challenges. The certificate and its key are written to `cache_dir`, which the
+// transport reloads when they change
+use crate::config::AcmeConfig;
+use crate::constants::{
+    ACME_CHECK_INTERVAL, ACME_POLL_ATTEMPTS, ACME_POLL_INTERVAL, ACME_RENEW_BEFORE_DAYS,
+};
+use crate::http;
+use anyhow::{anyhow, bail, Context, Result};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let b64 = base64::encode(der);
+    let mut s = format!("-----BEGIN {}-----\n", label);
+    for line in b64.as_bytes().chunks(64) {
+        s.push_str(std::str::from_utf8(line).unwrap());
+        s.push('\n');
+    }
+    s.push_str(&format!("-----END {}-----\n", label));
+    s
+}
+
+fn unpem(pem: &str) -> Result<Vec<u8>> {
+    let b64: String = pem
+        .lines()
+        .filter(|l| !l.starts_with("-----"))
+        .collect::<Vec<_>>()
+        .concat();
+    base64::decode(b64).with_context(|| "Malformed PEM")
+}
+
+// Make sure a certificate that isn't about to expire is in `cache_dir`, obtaining one if not
+pub async fn ensure(config: &AcmeConfig) -> Result<()> {
+    match expires_in(config).await {
+        Some(v) if v > Duration::from_secs(ACME_RENEW_BEFORE_DAYS * 86400) => return Ok(()),
+        Some(_) => info!(
+            "The certificate of {} expires soon. Renewing",
+            config.domain
+        ),
+        None => info!("Obtaining a certificate for {}", config.domain),
+    }
+    match issue(config).await {
+        Ok(()) => Ok(()),
+        // Still usable for a while, and retried later
+        Err(e) if expires_in(config).await.is_some_and(|v| !v.is_zero()) => {
+            error!("Failed to renew the certificate: {:#}", e);
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| "Failed to obtain the certificate with ACME"),
+    }
+}
+
+// Renew the certificate when it's about to expire. Stops once `alive` is dropped
+pub async fn renew_on_expiry<T: Send + Sync>(config: AcmeConfig, alive: Weak<T>) {
+    loop {
+        time::sleep(Duration::from_secs(ACME_CHECK_INTERVAL)).await;
+        if alive.upgrade().is_none() {
+            return;
+        }
+        if let Err(e) = ensure(&config).await {
+            error!("{:#}", e);
+        }
+    }
+}
+
+// The time left before the certificate in `cache_dir` expires. `None` if there's none
+async fn expires_in(config: &AcmeConfig) -> Option<Duration> {
+    let cert = fs::read_to_string(config.cert_path()).await.ok()?;
+    let not_after = der::not_after(&unpem(&cert).ok()?)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(Duration::from_secs(not_after.saturating_sub(now)))
+}
+
+// Write through a temporary file, so the transport never reads a partial file
+async fn write_file(path: &str, content: &[u8]) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, content)
+        .await
+        .with_context(|| format!("Failed to write {}", tmp))?;
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to write {}", path))
+}
+
+// The account key, created on the first run
+async fn account_key(config: &AcmeConfig, rng: &SystemRandom) -> Result<EcdsaKeyPair> {
+    let path = Path::new(&config.cache_dir).join("account.pem");
+    let pkcs8 = match fs::read_to_string(&path).await {
+        Ok(v) => unpem(&v)?,
+        Err(_) => {
+            fs::create_dir_all(&config.cache_dir)
+                .await
+                .with_context(|| format!("Failed to create {}", config.cache_dir))?;
+            let v = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+                .map_err(|_| anyhow!("Failed to generate the account key"))?;
+            write_file(
+                &path.to_string_lossy(),
+                pem("PRIVATE KEY", v.as_ref()).as_bytes(),
+            )
+            .await?;
+            v.as_ref().to_vec()
+        }
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, rng)
+        .map_err(|e| anyhow!("Invalid account key in {}: {}", path.display(), e))
+}
+
+fn jwk(key: &EcdsaKeyPair) -> Value {
+    // An uncompressed point, 0x04 followed by the coordinates
+    let point = key.public_key().as_ref();
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": b64(&point[1..33]),
+        "y": b64(&point[33..65]),
+    })
+}
+
+// The thumbprint of RFC 7638, over the members in lexicographic order without whitespace
+fn thumbprint(key: &EcdsaKeyPair) -> String {
+    let jwk = jwk(key);
+    let s = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        jwk["x"].as_str().unwrap(),
+        jwk["y"].as_str().unwrap()
+    );
+    b64(Sha256::digest(s.as_bytes()))
+}
+
+struct Client {
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    directory: Value,
+    nonce: Option<String>,
+    // The account URL, once registered
+    kid: Option<String>,
+}
+
+impl Client {
+    async fn new(config: &AcmeConfig) -> Result<Client> {
+        let rng = SystemRandom::new();
+        let key = account_key(config, &rng).await?;
+        let resp = http::request("GET", &config.directory, &[], &[]).await?;
+        let directory: Value = serde_json::from_slice(&resp.body)
+            .with_context(|| "Invalid directory of the ACME server")?;
+        let mut client = Client {
+            key,
+            rng,
+            directory,
+            nonce: None,
+            kid: None,
+        };
+        let resp = client
+            .post(
+                &client.url("newAccount")?,
+                Some(json!({
+                    "termsOfServiceAgreed": true,
+                    "contact": [format!("mailto:{}", config.email)],
+                })),
+            )
+            .await?;
+        client.kid = Some(
+            resp.header("Location")
+                .ok_or_else(|| anyhow!("The ACME server returned no account URL"))?
+                .to_string(),
+        );
+        Ok(client)
+    }
+
+    fn url(&self, name: &str) -> Result<String> {
+        self.directory[name]
+            .as_str()
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("No `{}` in the directory of the ACME server", name))
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(v) = self.nonce.take() {
+            return Ok(v);
+        }
+        let resp = http::request("GET", &self.url("newNonce")?, &[], &[]).await?;
+        resp.header("Replay-Nonce")
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("The ACME server returned no nonce"))
+    }
+
+    fn sign(&self, url: &str, nonce: String, payload: Option<&Value>) -> Result<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = jwk(&self.key),
+        }
+        let protected = b64(serde_json::to_vec(&protected)?);
+        // An empty payload makes a POST-as-GET
+        let payload = match payload {
+            Some(v) => b64(serde_json::to_vec(v)?),
+            None => String::new(),
+        };
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow!("Failed to sign the ACME request"))?;
+        Ok(serde_json::to_vec(&json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signature.as_ref()),
+        }))?)
+    }
+
+    // POST a signed request, retrying once on a stale nonce, and fail if the status is not 2xx
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<http::Response> {
+        for retry in [true, false] {
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, nonce, payload.as_ref())?;
+            let resp = http::request(
+                "POST",
+                url,
+                &[("Content-Type", "application/jose+json")],
+                &body,
+            )
+            .await?;
+            self.nonce = resp.header("Replay-Nonce").map(|v| v.to_string());
+            if (200..300).contains(&resp.status) {
+                return Ok(resp);
+            }
+            let problem: Value = serde_json::from_slice(&resp.body).unwrap_or_default();
+            if retry && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+            bail!(
+                "The ACME server responded to {} with status {}: {}",
+                url,
+                resp.status,
+                String::from_utf8_lossy(&resp.body).trim()
+            );
+        }
+        unreachable!()
+    }
+
+    async fn get(&mut self, url: &str) -> Result<Value> {
+        let resp = self.post(url, None).await?;
+        serde_json::from_slice(&resp.body).with_context(|| "Invalid response of the ACME server")
+    }
+
+    // Poll `url` until its status is no longer one of `pending`
+    async fn poll(&mut self, url: &str, pending: &[&str]) -> Result<Value> {
+        for _ in 0..ACME_POLL_ATTEMPTS {
+            let v = self.get(url).await?;
+            let status = v["status"].as_str().unwrap_or_default();
+            if !pending.contains(&status) {
+                return Ok(v);
+            }
+            time::sleep(Duration::from_secs(ACME_POLL_INTERVAL)).await;
+        }
+        bail!("Timeout waiting for {}", url)
+    }
+}
+
+// Answer HTTP-01 challenges with the key authorizations, indexed by the tokens
+async fn serve_challenges(l: TcpListener, tokens: Arc<Mutex<HashMap<String, String>>>) {
+    loop {
+        let (mut conn, addr) = match l.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Failed to accept a connection for the ACME challenges: {}",
+                    e
+                );
+                return;
+            }
+        };
+        let tokens = tokens.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 4096];
+            let mut n = 0;
+            let read = async {
+                while !buf[..n].windows(4).any(|w| w == b"\r\n\r\n") && n < buf.len() {
+                    match conn.read(&mut buf[n..]).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(v) => n += v,
+                    }
+                }
+            };
+            if time::timeout(Duration::from_secs(10), read).await.is_err() {
+                return;
+            }
+            let req = String::from_utf8_lossy(&buf[..n]);
+            let key_auth = req
+                .split(' ')
+                .nth(1)
+                .and_then(|path| path.strip_prefix(CHALLENGE_PREFIX))
+                .and_then(|token| tokens.lock().unwrap().get(token).cloned());
+            debug!("ACME challenge request from {}", addr);
+            let resp = match key_auth {
+                Some(v) => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    v.len(),
+                    v
+                ),
+                None => String::from(
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                ),
+            };
+            let _ = conn.write_all(resp.as_bytes()).await;
+        });
+    }
+}
+
+// Obtain a certificate for `domain`, and write it along with its key to `cache_dir`
+async fn issue(config: &AcmeConfig) -> Result<()> {
+    let mut client = Client::new(config).await?;
+
+    let resp = client
+        .post(
+            &client.url("newOrder")?,
+            Some(json!({ "identifiers": [{ "type": "dns", "value": config.domain }] })),
+        )
+        .await?;
+    let order_url = resp
+        .header("Location")
+        .ok_or_else(|| anyhow!("The ACME server returned no order URL"))?
+        .to_string();
+    let order: Value = serde_json::from_slice(&resp.body)?;
+
+    let tokens = Arc::new(Mutex::new(HashMap::new()));
+    let l = TcpListener::bind(&config.http_addr)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to listen at {} for the ACME challenges",
+                config.http_addr
+            )
+        })?;
+    let challenges =
+        tokio_util::task::AbortOnDropHandle::new(tokio::spawn(serve_challenges(l, tokens.clone())));
+
+    let thumbprint = thumbprint(&client.key);
+    for authz_url in order["authorizations"].as_array().into_iter().flatten() {
+        let authz_url = authz_url.as_str().unwrap_or_default();
+        let authz = client.get(authz_url).await?;
+        if authz["status"] == "valid" {
+            continue;
+        }
+        let challenge = authz["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|c| c["type"] == "http-01")
+            .ok_or_else(|| anyhow!("The ACME server offered no HTTP-01 challenge"))?;
+        let token = challenge["token"].as_str().unwrap_or_default();
+        tokens
+            .lock()
+            .unwrap()
+            .insert(token.to_string(), format!("{}.{}", token, thumbprint));
+        client
+            .post(
+                challenge["url"].as_str().unwrap_or_default(),
+                Some(json!({})),
+            )
+            .await?;
+        let authz = client.poll(authz_url, &["pending", "processing"]).await?;
+        if authz["status"] != "valid" {
+            bail!(
+                "The ACME server failed to validate {}: {}",
+                config.domain,
+                authz["challenges"]
+            );
+        }
+    }
+    drop(challenges);
+
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map_err(|_| anyhow!("Failed to generate the key of the certificate"))?;
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+        .map_err(|e| anyhow!("Invalid key of the certificate: {}", e))?;
+    let csr = der::csr(&config.domain, &key, &rng)?;
+    client
+        .post(
+            order["finalize"].as_str().unwrap_or_default(),
+            Some(json!({ "csr": b64(csr) })),
+        )
+        .await?;
+    let order = client
+        .poll(&order_url, &["pending", "ready", "processing"])
+        .await?;
+    let cert_url = match (order["status"].as_str(), order["certificate"].as_str()) {
+        (Some("valid"), Some(v)) => v.to_string(),
+        _ => bail!("The ACME server failed to issue the certificate: {}", order),
+    };
+    let cert = client.post(&cert_url, None).await?.body;
+
+    write_file(
+        &config.key_path(),
+        pem("PRIVATE KEY", pkcs8.as_ref()).as_bytes(),
+    )
+    .await?;
+    write_file(&config.cert_path(), &cert).await?;
+    info!("Obtained a certificate for {}", config.domain);
+    if expires_in(config).await.is_none() {
+        warn!("Failed to read the expiry of the certificate. It's renewed at every check");
+    }
+    Ok(())
+}
+
+// Just enough DER for certificate signing requests, and the expiry of certificates
+mod der {
+    use super::*;
+
+    const SEQUENCE: u8 = 0x30;
+    const SET: u8 = 0x31;
+    const INTEGER: u8 = 0x02;
+    const BIT_STRING: u8 = 0x03;
+    const OCTET_STRING: u8 = 0x04;
+    const OID: u8 = 0x06;
+    const UTF8_STRING: u8 = 0x0c;
+    const UTC_TIME: u8 = 0x17;
+    const GENERALIZED_TIME: u8 = 0x18;
+
+    const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+    const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+    const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+    const OID_EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+    const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+    const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut v = vec![tag];
+        let len = content.len();
+        if len < 0x80 {
+            v.push(len as u8);
+        } else {
+            let bytes: Vec<u8> = len
+                .to_be_bytes()
+                .into_iter()
+                .skip_while(|&b| b == 0)
+                .collect();
+            v.push(0x80 | bytes.len() as u8);
+            v.extend(bytes);
+        }
+        v.extend_from_slice(content);
+        v
+    }
+
+    fn seq(items: &[Vec<u8>]) -> Vec<u8> {
+        tlv(SEQUENCE, &items.concat())
+    }
+
+    fn bits(v: &[u8]) -> Vec<u8> {
+        tlv(BIT_STRING, &[&[0], v].concat())
+    }
+
+    // A PKCS#10 request for `domain`, as both the common name and the subject alternative name
+    pub fn csr(domain: &str, key: &EcdsaKeyPair, rng: &SystemRandom) -> Result<Vec<u8>> {
+        let subject = seq(&[tlv(
+            SET,
+            &seq(&[
+                tlv(OID, OID_COMMON_NAME),
+                tlv(UTF8_STRING, domain.as_bytes()),
+            ]),
+        )]);
+        let spki = seq(&[
+            seq(&[tlv(OID, OID_EC_PUBLIC_KEY), tlv(OID, OID_P256)]),
+            bits(key.public_key().as_ref()),
+        ]);
+        // `[2] IMPLICIT` for a dNSName, and `[0] IMPLICIT` for the attributes
+        let san = seq(&[tlv(0x82, domain.as_bytes())]);
+        let extensions = seq(&[seq(&[
+            tlv(OID, OID_SUBJECT_ALT_NAME),
+            tlv(OCTET_STRING, &san),
+        ])]);
+        let attributes = tlv(
+            0xa0,
+            &seq(&[tlv(OID, OID_EXTENSION_REQUEST), tlv(SET, &extensions)]),
+        );
+        let info = seq(&[tlv(INTEGER, &[0]), subject, spki, attributes]);
+        let signature = key
+            .sign(rng, &info)
+            .map_err(|_| anyhow!("Failed to sign the certificate request"))?;
+        Ok(seq(&[
+            info,
+            seq(&[tlv(OID, OID_ECDSA_SHA256)]),
+            bits(signature.as_ref()),
+        ]))
+    }
+
+    // Split the first element off `data`, returning its tag and content
+    fn read<'a>(data: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = data.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return None;
+            }
+            let len = rest[..n].iter().fold(0, |acc, &b| acc << 8 | b as usize);
+            rest = &rest[n..];
+            len
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let (content, rest) = rest.split_at(len);
+        *data = rest;
+        Some((tag, content))
+    }
+
+    fn digits(s: &[u8]) -> Option<u64> {
+        let s = std::str::from_utf8(s).ok()?;
+        s.bytes()
+            .all(|c| c.is_ascii_digit())
+            .then(|| s.parse().ok())?
+    }
+
+    // Seconds since the epoch of `YYMMDDHHMMSSZ` or `YYYYMMDDHHMMSSZ`
+    fn time(tag: u8, v: &[u8]) -> Option<u64> {
+        let (year, v) = match tag {
+            UTC_TIME if v.len() == 13 => {
+                let y = digits(&v[..2])?;
+                (if y < 50 { 2000 + y } else { 1900 + y }, &v[2..])
+            }
+            GENERALIZED_TIME if v.len() == 15 => (digits(&v[..4])?, &v[4..]),
+            _ => return None,
+        };
+        let [month, day, hour, min, sec] = [0, 2, 4, 6, 8].map(|i| digits(&v[i..i + 2]));
+        let (month, day) = (month?, day?);
+        if v[10] != b'Z' || !(1..=12).contains(&month) || year < 1970 {
+            return None;
+        }
+        // Days since the epoch of the civil date, from Howard Hinnant's algorithm
+        let (y, m) = if month <= 2 {
+            (year - 1, month + 9)
+        } else {
+            (year, month - 3)
+        };
+        let era = y / 400;
+        let yoe = y % 400;
+        let doy = (153 * m + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        Some(days * 86400 + hour? * 3600 + min? * 60 + sec?)
+    }
+
+    // The `notAfter` of a certificate, in seconds since the epoch
+    pub fn not_after(cert: &[u8]) -> Option<u64> {
+        let mut cert = cert;
+        let (_, mut cert) = read(&mut cert)?;
+        let (_, mut tbs) = read(&mut cert)?;
+        // The version, which is explicitly tagged and optional
+        if tbs.first() == Some(&0xa0) {
+            read(&mut tbs)?;
+        }
+        // The serial number, the signature algorithm and the issuer
+        for _ in 0..3 {
+            read(&mut tbs)?;
+        }
+        let (_, mut validity) = read(&mut tbs)?;
+        read(&mut validity)?;
+        let (tag, v) = read(&mut validity)?;
+        time(tag, v)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_not_after() {
+            let pem = std::fs::read_to_string("tests/tls_client_auth/server.pem").unwrap();
+            // notAfter=Sep 22 14:09:28 2126 GMT
+            assert_eq!(not_after(&unpem(&pem).unwrap()), Some(4945759768));
+            assert_eq!(time(UTC_TIME, b"700101000000Z"), Some(0));
+            assert_eq!(time(UTC_TIME, b"240229120000Z"), Some(1709208000));
+            assert_eq!(not_after(b"\x30\x05\x30"), None);
+        }
+
+        #[test]
+        fn test_csr() {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+            let key =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                    .unwrap();
+            let csr = csr("example.com", &key, &rng).unwrap();
+
+            let mut data = &csr[..];
+            let (tag, mut csr) = read(&mut data).unwrap();
+            assert_eq!((tag, data.len()), (SEQUENCE, 0));
+            let info = csr;
+            let (_, _) = read(&mut csr).unwrap();
+            let info = &info[..info.len() - csr.len()];
+            read(&mut csr).unwrap();
+            let (tag, signature) = read(&mut csr).unwrap();
+            assert_eq!(tag, BIT_STRING);
+            ring::signature::UnparsedPublicKey::new(
+                &ring::signature::ECDSA_P256_SHA256_ASN1,
+                key.public_key().as_ref(),
+            )
+            .verify(info, &signature[1..])
+            .unwrap();
+            assert!(info.windows(11).any(|w| w == b"example.com"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let client = Client {
+            key,
+            rng,
+            directory: Value::Null,
+            nonce: None,
+            kid: None,
+        };
+        let body = client
+            .sign(
+                "https://example.com/acme/new-account",
+                "n".into(),
+                Some(&json!({})),
+            )
+            .unwrap();
+        let jws: Value = serde_json::from_slice(&body).unwrap();
+        let protected =
+            base64::decode_config(jws["protected"].as_str().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        let protected: Value = serde_json::from_slice(&protected).unwrap();
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["jwk"], jwk(&client.key));
+
+        let signature =
+            base64::decode_config(jws["signature"].as_str().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        let input = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        ring::signature::UnparsedPublicKey::new(
+            &ring::signature::ECDSA_P256_SHA256_FIXED,
+            client.key.public_key().as_ref(),
+        )
+        .verify(input.as_bytes(), &signature)
+        .unwrap();
+        assert_eq!(thumbprint(&client.key).len(), 43);
+    }
+}
@@ -0,0 +1,483 @@
+// The admin API, a small HTTP/1.1 server answering JSON
+use crate::acl::{ServiceAcl, ACL};
+use crate::build_info;
+use crate::config::AdminConfig;
+use crate::constants::{ADMIN_MAX_REQUEST_SIZE, ADMIN_REQUEST_TIMEOUT};
+use crate::error::{Failure, Hint, WithHint};
+use crate::groups::{GroupState, GROUPS};
+use crate::health;
+use crate::log_filter;
+use crate::maintenance;
+use crate::pairing::{self, PAIRING};
+use crate::protocol;
+use crate::sampling;
+use crate::state_dump;
+use crate::supervisor;
+use crate::task_group::TaskGroup;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String, // Without the query string
+    pub(crate) query: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Response {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, msg: &str) -> Response {
+        Response {
+            status,
+            body: json!({ "error": msg }),
+        }
+    }
+}
+
+// `snapshot` is the config of the instance, without secrets, for state dumps
+pub async fn run_admin(config: AdminConfig, snapshot: Value) -> Result<()> {
+    let l = TcpListener::bind(&config.bind_addr)
+        .await
+        .with_context(|| format!("Failed to listen for the admin API at {}", config.bind_addr))
+        .hint(Hint::Bind("admin.bind_addr"))
+        .context(Failure::Bind)?;
+    info!("Admin API listening at {}", config.bind_addr);
+    let _listening = health::ListeningGuard::new("admin", l.local_addr().ok(), &config.bind_addr);
+    if config.token.is_none() {
+        warn!("`admin.token` is not set. Anyone who can reach the admin API can use it");
+    }
+
+    // Requests in flight are dropped along with the admin API
+    let tasks = TaskGroup::new();
+    let _guard = tasks.cancel_on_drop();
+
+    let config = Arc::new(config);
+    let snapshot = Arc::new(snapshot);
+    loop {
+        let (conn, addr) = match l.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept an admin connection: {}", e);
+                continue;
+            }
+        };
+        let (config, snapshot) = (config.clone(), snapshot.clone());
+        tasks.spawn(async move {
+            if let Err(e) = time::timeout(
+                Duration::from_secs(ADMIN_REQUEST_TIMEOUT),
+                handle_connection(conn, addr, &config, &snapshot),
+            )
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Timeout")))
+            {
+                debug!("Admin request from {} failed: {:#}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut conn: TcpStream,
+    addr: SocketAddr,
+    config: &AdminConfig,
+    snapshot: &Value,
+) -> Result<()> {
+    let resp = match read_request(&mut conn).await {
+        Ok(req) => {
+            debug!("Admin request {} {} from {}", req.method, req.path, addr);
+            // Probes of orchestrators and load balancers don't carry the token
+            if let Some(resp) = probe(&req) {
+                resp
+            } else if authorized(&req, config) {
+                route(&req, config, snapshot).await
+            } else {
+                Response::error(401, "Unauthorized")
+            }
+        }
+        Err(e) => Response::error(400, &format!("{:#}", e)),
+    };
+
+    let body = serde_json::to_vec_pretty(&resp.body)?;
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        resp.status,
+        reason(resp.status),
+        body.len()
+    );
+    conn.write_all(head.as_bytes()).await?;
+    conn.write_all(&body).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+fn authorized(req: &Request, config: &AdminConfig) -> bool {
+    match &config.token {
+        Some(token) => req
+            .header("Authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|v| protocol::ct_eq(v.trim().as_bytes(), token.as_bytes()))
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+// `/healthz` answers as long as the process is serving, and `/readyz` once every configured
+// service has its control channel established
+fn probe(req: &Request) -> Option<Response> {
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/healthz") => Some(Response::ok(json!({ "status": "ok" }))),
+        ("GET", "/readyz") => {
+            let not_ready = health::not_ready();
+            Some(Response {
+                status: if not_ready.is_empty() { 200 } else { 503 },
+                body: json!({ "ready": not_ready.is_empty(), "not_ready": not_ready }),
+            })
+        }
+        (_, "/healthz" | "/readyz") => Some(Response::error(405, "Method not allowed")),
+        _ => None,
+    }
+}
+
+async fn route(req: &Request, config: &AdminConfig, snapshot: &Value) -> Response {
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/build-info") => Response::ok(build_info::to_json()),
+        ("GET", "/panics") => Response::ok(panics()),
+        ("GET", "/acl") => {
+            Response::ok(json!({ "services": ACL.get(), "groups": ACL.get_groups() }))
+        }
+        ("GET", "/groups") => Response::ok(GROUPS.to_json()),
+        ("GET", "/maintenance") => Response::ok(json!({ "services": maintenance::etas() })),
+        ("GET", "/public-addrs") => Response::ok(health::public_addrs()),
+        ("GET", "/clients") => Response::ok(state_dump::clients()),
+        ("GET", "/traffic") => Response::ok(sampling::to_json()),
+        ("DELETE", "/traffic") => {
+            sampling::reset();
+            Response::ok(sampling::to_json())
+        }
+        ("POST", "/pairing") => pairing_code().await,
+        ("DELETE", "/pairing") => {
+            if !PAIRING.is_enabled() {
+                return Response::error(404, "Pairing is not configured");
+            }
+            Response::ok(json!({ "revoked": PAIRING.revoke() }))
+        }
+        ("GET", "/state") => Response::ok(state_dump::dump(snapshot)),
+        ("POST", "/state") => {
+            let dir = match &config.dump_dir {
+                Some(v) => PathBuf::from(v),
+                None => std::env::temp_dir(),
+            };
+            match state_dump::write(&dir, snapshot).await {
+                Ok(path) => {
+                    info!("State dumped to {:?}", path);
+                    Response::ok(json!({ "path": path }))
+                }
+                Err(e) => Response::error(500, &format!("{:#}", e)),
+            }
+        }
+        (method, "/log-filter") => log_filter(method, &req.body),
+        (
+            _,
+            "/build-info" | "/panics" | "/acl" | "/groups" | "/maintenance" | "/public-addrs"
+            | "/clients" | "/traffic" | "/pairing" | "/state",
+        ) => Response::error(405, "Method not allowed"),
+        (method, path) => {
+            if let Some(service) = path.strip_prefix("/acl/").filter(|s| !s.is_empty()) {
+                service_acl(method, service, &req.body).await
+            } else if let Some(path) = path.strip_prefix("/groups/") {
+                group(method, path, &req.body).await
+            } else if let Some(service) =
+                path.strip_prefix("/maintenance/").filter(|s| !s.is_empty())
+            {
+                service_maintenance(method, service, &req.body)
+            } else {
+                Response::error(404, "Not found")
+            }
+        }
+    }
+}
+
+async fn pairing_code() -> Response {
+    if !PAIRING.is_enabled() {
+        return Response::error(404, "Pairing is not configured");
+    }
+    match PAIRING.create().await {
+        Ok(v) => {
+            info!("A pairing code is made, expiring in {}s", v.expires_in);
+            Response::ok(json!({
+                "code": v.code,
+                "expires_in": v.expires_in,
+                "qr": pairing::qr(&v.code),
+            }))
+        }
+        Err(e) => Response::error(500, &format!("{:#}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+struct LogFilter {
+    filter: String,
+}
+
+fn log_filter(method: &str, body: &[u8]) -> Response {
+    let filter = match method {
+        "GET" => None,
+        "PUT" => match serde_json::from_slice::<LogFilter>(body) {
+            Ok(v) => Some(log_filter::set(Some(&v.filter))),
+            Err(e) => return Response::error(400, &format!("Invalid log filter: {}", e)),
+        },
+        "DELETE" => Some(log_filter::set(None)),
+        _ => return Response::error(405, "Method not allowed"),
+    };
+    match filter {
+        Some(Ok(v)) => Response::ok(json!({ "filter": v })),
+        Some(Err(e)) => Response::error(400, &format!("{:#}", e)),
+        None => match log_filter::get() {
+            Some(v) => Response::ok(json!({ "filter": v })),
+            None => Response::error(404, "The log filter can't be changed in this build"),
+        },
+    }
+}
+
+#[derive(Deserialize)]
+struct Maintenance {
+    eta: String,
+}
+
+fn service_maintenance(method: &str, service: &str, body: &[u8]) -> Response {
+    match method {
+        "GET" => {}
+        "PUT" => match serde_json::from_slice::<Maintenance>(body) {
+            Ok(v) => maintenance::set_eta(service, Some(v.eta)),
+            Err(e) => return Response::error(400, &format!("Invalid maintenance: {}", e)),
+        },
+        "DELETE" => maintenance::set_eta(service, None),
+        _ => return Response::error(405, "Method not allowed"),
+    }
+    Response::ok(json!({ "eta": maintenance::etas().remove(service) }))
+}
+
+async fn service_acl(method: &str, service: &str, body: &[u8]) -> Response {
+    let acl = match method {
+        "GET" => return Response::ok(json!(ACL.get().remove(service).unwrap_or_default())),
+        "PUT" => match serde_json::from_slice::<ServiceAcl>(body) {
+            Ok(v) => Some(v),
+            Err(e) => return Response::error(400, &format!("Invalid ACL: {}", e)),
+        },
+        "DELETE" => None,
+        _ => return Response::error(405, "Method not allowed"),
+    };
+    match ACL.set(service, acl).await {
+        Ok(_) => Response::ok(json!(ACL.get().remove(service).unwrap_or_default())),
+        Err(e) => Response::error(400, &format!("{:#}", e)),
+    }
+}
+
+// `/groups/<name>`, and the operations on the group under it
+async fn group(method: &str, path: &str, body: &[u8]) -> Response {
+    let (name, op) = path.split_once('/').unwrap_or((path, ""));
+    if !GROUPS.exists(name) {
+        return Response::error(404, "No such group");
+    }
+    let state = match (method, op) {
+        ("GET", "") => None,
+        (method, "acl") => return group_acl(method, name, body).await,
+        ("POST", "enable") => Some(GroupState::Enabled),
+        ("POST", "drain") => Some(GroupState::Draining),
+        ("POST", "disable") => Some(GroupState::Disabled),
+        (_, "" | "enable" | "drain" | "disable") => {
+            return Response::error(405, "Method not allowed")
+        }
+        _ => return Response::error(404, "Not found"),
+    };
+    if let Some(Err(e)) = state.map(|v| GROUPS.set_state(name, v)) {
+        return Response::error(404, &format!("{:#}", e));
+    }
+    match GROUPS.get_json(name) {
+        Some(v) => Response::ok(v),
+        None => Response::error(404, "No such group"),
+    }
+}
+
+async fn group_acl(method: &str, group: &str, body: &[u8]) -> Response {
+    let acl = match method {
+        "GET" => return Response::ok(json!(ACL.get_groups().remove(group).unwrap_or_default())),
+        "PUT" => match serde_json::from_slice::<ServiceAcl>(body) {
+            Ok(v) => Some(v),
+            Err(e) => return Response::error(400, &format!("Invalid ACL: {}", e)),
+        },
+        "DELETE" => None,
+        _ => return Response::error(405, "Method not allowed"),
+    };
+    match ACL.set_group(group, acl).await {
+        Ok(_) => Response::ok(json!(ACL.get_groups().remove(group).unwrap_or_default())),
+        Err(e) => Response::error(400, &format!("{:#}", e)),
+    }
+}
+
+pub(crate) fn panics() -> Value {
+    let services: serde_json::Map<String, Value> = supervisor::panics()
+        .into_iter()
+        .map(|(name, r)| {
+            (
+                name,
+                json!({
+                    "count": r.count,
+                    "last_message": r.last_message,
+                    "last_time": r.last_time,
+                }),
+            )
+        })
+        .collect();
+    json!({ "services": services })
+}
+
+pub(crate) fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+pub(crate) async fn read_request<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Request> {
+    let mut buf = Vec::new();
+    let header_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() >= ADMIN_MAX_REQUEST_SIZE {
+            bail!("The request is too large");
+        }
+        let mut chunk = [0u8; 4096];
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("Connection closed before the request ends");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let mut req = parse_request_head(&String::from_utf8_lossy(&buf[..header_end]))?;
+
+    let len = match req.header("Content-Length") {
+        Some(v) => v
+            .parse::<usize>()
+            .with_context(|| "Invalid Content-Length")?,
+        None => 0,
+    };
+    if header_end + 4 + len > ADMIN_MAX_REQUEST_SIZE {
+        bail!("The request is too large");
+    }
+    let mut body = buf.split_off(header_end + 4);
+    if body.len() < len {
+        let n = body.len();
+        body.resize(len, 0);
+        conn.read_exact(&mut body[n..]).await?;
+    }
+    body.truncate(len);
+    req.body = body;
+
+    Ok(req)
+}
+
+pub(crate) fn parse_request_head(head: &str) -> Result<Request> {
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(m), Some(t)) => (m, t),
+        _ => bail!("Malformed request line"),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        a.write_all(b"POST /foo?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+        let req = read_request(&mut b).await.unwrap();
+        assert_eq!(
+            req,
+            Request {
+                method: "POST".to_string(),
+                path: "/foo".to_string(),
+                query: "x=1".to_string(),
+                headers: vec![
+                    ("Host".to_string(), "a".to_string()),
+                    ("Content-Length".to_string(), "5".to_string())
+                ],
+                body: b"hello".to_vec(),
+            }
+        );
+
+        assert!(parse_request_head("garbage").is_err());
+    }
+
+    #[test]
+    fn test_authorized() {
+        let mut req = parse_request_head("GET / HTTP/1.1\r\nAuthorization: Bearer t").unwrap();
+        let mut config = AdminConfig {
+            bind_addr: String::new(),
+            token: None,
+            dump_dir: None,
+        };
+        assert!(authorized(&req, &config));
+        config.token = Some("t".to_string());
+        assert!(authorized(&req, &config));
+        config.token = Some("x".to_string());
+        assert!(!authorized(&req, &config));
+        req.headers.clear();
+        assert!(!authorized(&req, &config));
+    }
+}
@@ -0,0 +1,195 @@
+use crate::config::VisitorAlertConfig;
+use crate::privacy;
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+// The maximum number of (service, IP) pairs remembered. Beyond it, the ones seen least recently
+// are forgotten, so a scanner sweeping from lots of addresses can't grow the memory
+const VISITOR_ALERT_MAX_ENTRIES: usize = 65536;
+
+// Alerts on visitors whose IP hasn't been seen by a service within a time window.
+// Alerts are rate limited globally, so a scan doesn't flood the logs or the webhook.
+pub struct VisitorAlert {
+    window: Duration,
+    rate_limit: u32,
+    webhook: Option<String>,
+    state: Mutex<AlertState>,
+}
+
+struct AlertState {
+    // When each IP last visited each service. Looked up by the name of the service without
+    // allocating, since it's done for every datagram of UDP services
+    last_seen: HashMap<Arc<str>, HashMap<IpAddr, Instant>>,
+    // The same pairs, ordered by when they were last seen
+    by_time: BTreeSet<(Instant, IpAddr, Arc<str>)>,
+    // Token bucket of alerts, refilled at `rate_limit` per minute
+    tokens: f64,
+    last_refill: Instant,
+    suppressed: u64,
+}
+
+impl VisitorAlert {
+    pub fn new(config: &VisitorAlertConfig) -> VisitorAlert {
+        VisitorAlert {
+            window: Duration::from_secs(config.window),
+            rate_limit: config.rate_limit,
+            webhook: config.webhook.clone(),
+            state: Mutex::new(AlertState {
+                last_seen: HashMap::new(),
+                by_time: BTreeSet::new(),
+                tokens: config.rate_limit as f64,
+                last_refill: Instant::now(),
+                suppressed: 0,
+            }),
+        }
+    }
+
+    // Record a visitor of `service`, and alert if it's new
+    pub fn visit(&self, service: &str, ip: IpAddr) {
+        let now = Instant::now();
+        let suppressed = {
+            let mut s = self.state.lock().unwrap();
+
+            // Datagrams of UDP services come in bursts. Refreshing the time of a pair every
+            // second is as good as for each of them
+            let last = s.last_seen.get(service).and_then(|ips| ips.get(&ip));
+            if last.is_some_and(|t| now.duration_since(*t) < Duration::from_secs(1)) {
+                return;
+            }
+
+            let name = match s.last_seen.get_key_value(service) {
+                Some((k, _)) => k.clone(),
+                None => Arc::from(service),
+            };
+            let last = s.last_seen.entry(name.clone()).or_default().insert(ip, now);
+            if let Some(t) = last {
+                s.by_time.remove(&(t, ip, name.clone()));
+            }
+            s.by_time.insert((now, ip, name));
+            s.forget(now.checked_sub(self.window));
+
+            let is_new = match last {
+                Some(t) => now.duration_since(t) > self.window,
+                None => true,
+            };
+            if !is_new {
+                return;
+            }
+
+            let elapsed = now.duration_since(s.last_refill).as_secs_f64();
+            s.last_refill = now;
+            s.tokens =
+                (s.tokens + elapsed * self.rate_limit as f64 / 60.0).min(self.rate_limit as f64);
+            if s.tokens < 1.0 {
+                s.suppressed += 1;
+                return;
+            }
+            s.tokens -= 1.0;
+
+            std::mem::take(&mut s.suppressed)
+        };
+
+        if suppressed > 0 {
+            warn!(
+                "{} new visitor alerts were suppressed by the rate limit",
+                suppressed
+            );
+        }
+        info!(service = %service, visitor = %privacy::ip(ip), "New visitor");
+
+        if let Some(url) = &self.webhook {
+            let url = url.clone();
+            let body = serde_json::json!({
+                "event": "new_visitor",
+                "service": service,
+                "visitor": privacy::ip(ip).to_string(),
+                "suppressed": suppressed,
+                "timestamp": SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+            });
+            tokio::spawn(async move {
+                if let Err(e) = crate::http::post_json(&url, &body).await {
+                    warn!("Failed to send the new visitor alert: {:#}", e);
+                }
+            });
+        }
+    }
+}
+
+impl AlertState {
+    // Forget the pairs last seen before `before`, and the oldest ones beyond the maximum
+    fn forget(&mut self, before: Option<Instant>) {
+        while let Some((t, ip, name)) = self.by_time.first().cloned() {
+            if self.by_time.len() <= VISITOR_ALERT_MAX_ENTRIES && before.is_none_or(|b| t >= b) {
+                break;
+            }
+            self.by_time.pop_first();
+            if let Some(ips) = self.last_seen.get_mut(&name) {
+                ips.remove(&ip);
+                if ips.is_empty() {
+                    self.last_seen.remove(&name);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_visitor_alert() {
+        let alert = VisitorAlert::new(&VisitorAlertConfig {
+            window: 3600,
+            rate_limit: 2,
+            webhook: None,
+        });
+        let ip1: IpAddr = "1.1.1.1".parse().unwrap();
+        let ip2: IpAddr = "2.2.2.2".parse().unwrap();
+        let ip3: IpAddr = "3.3.3.3".parse().unwrap();
+
+        alert.visit("foo", ip1);
+        alert.visit("foo", ip1);
+        alert.visit("bar", ip1);
+        assert_eq!(alert.state.lock().unwrap().suppressed, 0);
+
+        // Out of tokens
+        alert.visit("foo", ip2);
+        alert.visit("foo", ip3);
+        let s = alert.state.lock().unwrap();
+        assert_eq!(s.suppressed, 2);
+        assert_eq!(s.by_time.len(), 4);
+    }
+
+    #[test]
+    fn test_visitor_alert_max_entries() {
+        let alert = VisitorAlert::new(&VisitorAlertConfig {
+            window: 3600,
+            rate_limit: 0,
+            webhook: None,
+        });
+        let ip = |i: u32| IpAddr::from(i.to_be_bytes());
+        for i in 0..VISITOR_ALERT_MAX_ENTRIES as u32 + 10 {
+            alert.visit("foo", ip(i));
+        }
+        {
+            let s = alert.state.lock().unwrap();
+            assert_eq!(s.by_time.len(), VISITOR_ALERT_MAX_ENTRIES);
+            assert!(!s.last_seen["foo"].contains_key(&ip(9)));
+            assert!(s.last_seen["foo"].contains_key(&ip(10)));
+        }
+        alert.visit("bar", ip(0));
+
+        let s = alert.state.lock().unwrap();
+        assert_eq!(s.by_time.len(), VISITOR_ALERT_MAX_ENTRIES);
+        assert!(!s.last_seen["foo"].contains_key(&ip(10)));
+        assert!(s.last_seen["foo"].contains_key(&ip(11)));
+        assert!(s.last_seen["bar"].contains_key(&ip(0)));
+    }
+}
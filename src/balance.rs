@@ -0,0 +1,381 @@
+// Distributes the visitors of a load balanced service among its clients, by their weights.
+// Sticky visitors are hashed to a client, so the visitors of a session keep reaching the
+// same replica behind the tunnel. Clients failing to forward can be ejected for a while
+use crate::config::{DuplicatePolicy, ServerServiceConfig, StickyPolicy};
+use crate::constants::{
+    HTTP_HEAD_PEEK_MAX_LEN, OUTLIER_EJECTION_TIME, OUTLIER_INTERVAL, OUTLIER_MAX_EJECTION_TIME,
+    OUTLIER_MIN_FAILURES, SLOW_START_MIN_RATIO, VISITOR_AUTH_TIMEOUT,
+};
+use crate::protocol::digest;
+use crate::visitor::VisitorStream;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time;
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+pub struct Balance {
+    sticky: Option<StickyPolicy>,
+    sticky_cookie: Option<String>,
+    slow_start: Duration,
+    pub outlier_detection: bool,
+}
+
+// A client to pick
+pub struct Candidate<T> {
+    pub value: T,
+    // What identifies the client across reconnections
+    pub id: Vec<u8>,
+    pub weight: u32,
+    // How long the client has been connected
+    pub age: Duration,
+}
+
+impl Balance {
+    // `None` if the service is not load balanced
+    pub fn from_config(service: &ServerServiceConfig) -> Option<Balance> {
+        if service.on_duplicate != DuplicatePolicy::LoadBalance {
+            return None;
+        }
+        Some(Balance {
+            sticky: service.sticky,
+            sticky_cookie: service.sticky_cookie.clone(),
+            slow_start: Duration::from_secs(service.slow_start.unwrap_or(0)),
+            outlier_detection: service.outlier_detection,
+        })
+    }
+
+    pub fn is_sticky(&self) -> bool {
+        self.sticky.is_some()
+    }
+
+    pub fn peeks_cookie(&self) -> bool {
+        self.sticky == Some(StickyPolicy::Cookie)
+    }
+
+    // The key that a visitor is hashed by, or `None` if the service is not sticky
+    pub async fn sticky_key(&self, conn: &VisitorStream, addr: SocketAddr) -> Option<Vec<u8>> {
+        self.sticky?;
+        if let (Some(name), VisitorStream::Tcp(conn)) = (&self.sticky_cookie, conn) {
+            if let Some(v) = peek_cookie(conn, name).await {
+                return Some(v.into_bytes());
+            }
+        }
+        // Visitors without the cookie, like the first request of a session, fall back to the IP
+        Some(addr.ip().to_string().into_bytes())
+    }
+
+    // Pick a client for a visitor. Sticky visitors are picked by weighted rendezvous hashing,
+    // so only the visitors of a client that leaves are moved, and a client that reconnects
+    // with the same identity gets its visitors back. Others are picked at random by weight
+    pub fn pick<T>(&self, key: Option<&[u8]>, candidates: Vec<Candidate<T>>) -> Option<T> {
+        let weighted = candidates
+            .into_iter()
+            .map(|c| (self.effective_weight(c.weight, c.age), c.id, c.value));
+        match key {
+            Some(key) => weighted
+                .map(|(w, id, value)| (w / -unit(&[&id[..], key].concat()).ln(), value))
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .map(|(_, value)| value),
+            None => {
+                let weighted: Vec<(f64, T)> = weighted.map(|(w, _, v)| (w, v)).collect();
+                let total: f64 = weighted.iter().map(|(w, _)| w).sum();
+                let mut r = rand::thread_rng().gen_range(0.0..total.max(f64::MIN_POSITIVE));
+                let mut weighted = weighted.into_iter();
+                let mut last = weighted.next()?;
+                for c in weighted {
+                    if r < last.0 {
+                        break;
+                    }
+                    r -= last.0;
+                    last = c;
+                }
+                Some(last.1)
+            }
+        }
+    }
+
+    // A client that just connected takes a growing share of its weight during the slow start,
+    // so it's not hit by all of its visitors at once
+    fn effective_weight(&self, weight: u32, age: Duration) -> f64 {
+        let ratio = if age < self.slow_start {
+            (age.as_secs_f64() / self.slow_start.as_secs_f64()).max(SLOW_START_MIN_RATIO)
+        } else {
+            1.0
+        };
+        weight as f64 * ratio
+    }
+}
+
+// Failures of forwarding of a client, which usually mean its local service is down.
+// A client failing at least half of its forwardings is ejected from the rotation for a while.
+// Then it's put back as a probe, starting over the slow start, and is ejected again for longer
+// if it keeps failing
+pub struct Outlier {
+    state: Mutex<OutlierState>,
+}
+
+struct OutlierState {
+    interval_start: Instant,
+    forwarded: u32,
+    failed: u32,
+    ejected_until: Option<Instant>,
+    // The number of ejections in a row, reset after an interval without any
+    ejections: u32,
+}
+
+impl Default for Outlier {
+    fn default() -> Outlier {
+        Outlier {
+            state: Mutex::new(OutlierState {
+                interval_start: Instant::now(),
+                forwarded: 0,
+                failed: 0,
+                ejected_until: None,
+                ejections: 0,
+            }),
+        }
+    }
+}
+
+impl Outlier {
+    pub fn is_ejected(&self) -> bool {
+        self.state.lock().unwrap().is_ejected(Instant::now())
+    }
+
+    // When the client was put back after the last ejection, for the slow start
+    pub fn returned_at(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.state
+            .lock()
+            .unwrap()
+            .ejected_until
+            .filter(|t| *t <= now)
+    }
+
+    pub fn forwarded(&self) {
+        let mut s = self.state.lock().unwrap();
+        s.roll(Instant::now());
+        s.forwarded += 1;
+    }
+
+    // Returns whether the client is ejected by the failure
+    pub fn failed(&self, service: &str) -> bool {
+        let now = Instant::now();
+        let mut s = self.state.lock().unwrap();
+        // Reports of the forwardings before the ejection may arrive late
+        if s.is_ejected(now) {
+            return false;
+        }
+        s.roll(now);
+        s.failed += 1;
+        // A probing client gets no second chance
+        let probing = s.ejections > 0;
+        if !probing && (s.failed < OUTLIER_MIN_FAILURES || s.failed * 2 < s.forwarded) {
+            return false;
+        }
+        let secs = OUTLIER_EJECTION_TIME
+            .saturating_mul(1 << s.ejections.min(16))
+            .min(OUTLIER_MAX_EJECTION_TIME);
+        warn!(
+            "A client of service {} failed {} of {} forwardings. Eject it for {}s",
+            service, s.failed, s.forwarded, secs
+        );
+        s.ejected_until = Some(now + Duration::from_secs(secs));
+        s.ejections += 1;
+        s.interval_start = now;
+        s.forwarded = 0;
+        s.failed = 0;
+        true
+    }
+}
+
+impl OutlierState {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.map(|t| now < t).unwrap_or(false)
+    }
+
+    // Start a new interval if the current one has passed. Intervals start over after ejections
+    fn roll(&mut self, now: Instant) {
+        let start = match self.ejected_until {
+            Some(t) if t > self.interval_start => t,
+            _ => self.interval_start,
+        };
+        if now < start + Duration::from_secs(OUTLIER_INTERVAL) {
+            return;
+        }
+        // A whole interval without failures. The client has recovered
+        if self.failed == 0 {
+            self.ejections = 0;
+        }
+        self.interval_start = now;
+        self.forwarded = 0;
+        self.failed = 0;
+    }
+}
+
+// Hash `data` to (0, 1)
+fn unit(data: &[u8]) -> f64 {
+    let d = digest(data);
+    let v = u64::from_be_bytes(d[..8].try_into().unwrap()) >> 11;
+    (v as f64 + 0.5) / (1u64 << 53) as f64
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum HttpHead {
+    // More data is needed
+    Incomplete,
+    // The head ends without the cookie
+    NoCookie,
+    Cookie(String),
+}
+
+// Peek at the request head of a HTTP visitor for the value of the cookie `name`,
+// leaving the data for the forwarding
+async fn peek_cookie(conn: &TcpStream, name: &str) -> Option<String> {
+    let mut buf = vec![0u8; HTTP_HEAD_PEEK_MAX_LEN];
+    let peek = async {
+        loop {
+            let n = conn.peek(&mut buf).await.ok()?;
+            match parse_cookie(&buf[..n], name) {
+                HttpHead::Cookie(v) => return Some(v),
+                HttpHead::Incomplete if n > 0 && n < buf.len() => {
+                    // Peeking returns at once while there's data, so wait for more to arrive
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+                _ => return None,
+            }
+        }
+    };
+    time::timeout(Duration::from_secs(VISITOR_AUTH_TIMEOUT), peek)
+        .await
+        .ok()
+        .flatten()
+}
+
+fn parse_cookie(buf: &[u8], name: &str) -> HttpHead {
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(v) => v,
+        None => return HttpHead::Incomplete,
+    };
+    let head = String::from_utf8_lossy(&buf[..end]);
+    head.lines()
+        .skip(1)
+        .filter_map(|l| l.split_once(':'))
+        .filter(|(k, _)| k.trim().eq_ignore_ascii_case("Cookie"))
+        .flat_map(|(_, v)| v.split(';'))
+        .filter_map(|c| c.split_once('='))
+        .find(|(k, _)| k.trim() == name)
+        .map(|(_, v)| HttpHead::Cookie(v.trim().to_string()))
+        .unwrap_or(HttpHead::NoCookie)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn balance(slow_start: u64) -> Balance {
+        Balance {
+            sticky: None,
+            sticky_cookie: None,
+            slow_start: Duration::from_secs(slow_start),
+            outlier_detection: false,
+        }
+    }
+
+    fn candidates(weights: &[u32]) -> Vec<Candidate<usize>> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, w)| Candidate {
+                value: i,
+                id: vec![i as u8],
+                weight: *w,
+                age: Duration::from_secs(60),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_cookie() {
+        let req = b"GET / HTTP/1.1\r\nHost: a\r\ncookie: theme=dark; SID=abc \r\n\r\n";
+        assert_eq!(parse_cookie(req, "SID"), HttpHead::Cookie("abc".into()));
+        assert_eq!(parse_cookie(req, "sid"), HttpHead::NoCookie);
+        assert_eq!(parse_cookie(&req[..20], "SID"), HttpHead::Incomplete);
+    }
+
+    #[test]
+    fn test_pick_sticky() {
+        let b = balance(0);
+        assert_eq!(b.pick(Some(b"k"), candidates(&[])), None);
+
+        // Removing a candidate only moves its own keys
+        let keys: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        for k in &keys {
+            let before = b.pick(Some(k), candidates(&[1, 1, 1, 1])).unwrap();
+            let after = b.pick(Some(k), candidates(&[1, 1, 1])).unwrap();
+            assert!(before == 3 || before == after);
+        }
+
+        // Keys are shared by weights
+        let n = keys
+            .iter()
+            .filter(|k| b.pick(Some(k), candidates(&[1, 9])) == Some(1))
+            .count();
+        assert!(n > 75, "{}", n);
+    }
+
+    #[test]
+    fn test_pick_weighted() {
+        let b = balance(0);
+        assert_eq!(b.pick(None, candidates(&[])), None);
+        assert_eq!(b.pick(None, candidates(&[0, 1])), Some(1));
+        let n = (0..1000)
+            .filter(|_| b.pick(None, candidates(&[1, 3])) == Some(1))
+            .count();
+        assert!((650..850).contains(&n), "{}", n);
+    }
+
+    #[test]
+    fn test_slow_start() {
+        let b = balance(100);
+        assert_eq!(b.effective_weight(10, Duration::from_secs(0)), 1.0);
+        assert_eq!(b.effective_weight(10, Duration::from_secs(50)), 5.0);
+        assert_eq!(b.effective_weight(10, Duration::from_secs(200)), 10.0);
+        assert_eq!(balance(0).effective_weight(10, Duration::ZERO), 10.0);
+    }
+
+    #[test]
+    fn test_outlier() {
+        let o = Outlier::default();
+        for _ in 0..10 {
+            o.forwarded();
+        }
+        for _ in 0..OUTLIER_MIN_FAILURES - 1 {
+            assert!(!o.failed("test"));
+        }
+        assert!(o.failed("test"));
+        assert!(o.is_ejected());
+        assert!(o.returned_at().is_none());
+
+        // Put back as a probe, which is ejected again for longer on the first failure
+        let past = Instant::now() - Duration::from_secs(1);
+        o.state.lock().unwrap().ejected_until = Some(past);
+        assert!(!o.is_ejected());
+        assert_eq!(o.returned_at(), Some(past));
+        o.forwarded();
+        assert!(o.failed("test"));
+        let until = o.state.lock().unwrap().ejected_until.unwrap();
+        assert!(until > Instant::now() + Duration::from_secs(OUTLIER_EJECTION_TIME));
+
+        // Recovered after an interval without failures
+        let mut s = o.state.lock().unwrap();
+        s.ejected_until = Some(past - Duration::from_secs(OUTLIER_INTERVAL));
+        s.interval_start = past - Duration::from_secs(OUTLIER_INTERVAL);
+        s.roll(Instant::now());
+        assert_eq!(s.ejections, 0);
+    }
+}
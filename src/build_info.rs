@@ -0,0 +1,80 @@
+use crate::protocol::{self, CURRENT_PROTO_VERSION};
+
+// Features that were enabled at build time
+pub fn features() -> Vec<&'static str> {
+    let mut v = Vec::new();
+    if cfg!(feature = "server") {
+        v.push("server");
+    }
+    if cfg!(feature = "client") {
+        v.push("client");
+    }
+    if cfg!(feature = "tls") {
+        v.push("tls");
+    }
+    if cfg!(feature = "tls-rustls") {
+        v.push("tls-rustls");
+    }
+    if cfg!(feature = "noise") {
+        v.push("noise");
+    }
+    if cfg!(feature = "noise-pq") {
+        v.push("noise-pq");
+    }
+    if cfg!(feature = "websocket") {
+        v.push("websocket");
+    }
+    if cfg!(feature = "http2") {
+        v.push("http2");
+    }
+    if cfg!(feature = "kcp") {
+        v.push("kcp");
+    }
+    if cfg!(feature = "mux") {
+        v.push("mux");
+    }
+    if cfg!(feature = "hot-reload") {
+        v.push("hot-reload");
+    }
+    if cfg!(feature = "self-update") {
+        v.push("self-update");
+    }
+    if cfg!(feature = "visitor-tls") {
+        v.push("visitor-tls");
+    }
+    if cfg!(feature = "console") {
+        v.push("console");
+    }
+    if cfg!(feature = "compression") {
+        v.push("compression");
+    }
+    v
+}
+
+// A one-line summary for the log
+pub fn summary() -> String {
+    format!(
+        "rathole {} (commit {}, {}, {}) features: {}, protocol: v{}, capabilities: {}",
+        *crate::cli::VERSION,
+        option_env!("VERGEN_GIT_SHA").unwrap_or("unknown"),
+        env!("VERGEN_CARGO_TARGET_TRIPLE"),
+        env!("VERGEN_CARGO_PROFILE"),
+        features().join(","),
+        CURRENT_PROTO_VERSION,
+        protocol::fmt_capabilities(protocol::local_capabilities())
+    )
+}
+
+pub fn to_json() -> serde_json::Value {
+    serde_json::json!({
+        "version": *crate::cli::VERSION,
+        "git_sha": option_env!("VERGEN_GIT_SHA"),
+        "build_timestamp": env!("VERGEN_BUILD_TIMESTAMP"),
+        "target": env!("VERGEN_CARGO_TARGET_TRIPLE"),
+        "profile": env!("VERGEN_CARGO_PROFILE"),
+        "features": features(),
+        "protocol_version": CURRENT_PROTO_VERSION,
+        "capabilities": protocol::fmt_capabilities(protocol::local_capabilities()),
+        "capability_bits": protocol::local_capabilities(),
+    })
+}
@@ -0,0 +1,295 @@
+// Bundles of a client config and the files it refers to, sealed with a passphrase, with
+// `rathole bundle`. A new device is provisioned with the bundle and the passphrase alone, and runs
+// it with `--bundle`. A bundle starts with this line, followed by the salt, the nonce and the
+// ciphertext in hex. The key is stretched from the passphrase like the hashes of tokens
+use crate::config::Config;
+use crate::config_crypto;
+use crate::protocol::{client_key, Salt};
+use crate::secret;
+use anyhow::{anyhow, bail, Context, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+const MAGIC: &str = "rathole-bundle-v1\n";
+
+// The passphrase is either in the variable, or in the file that the other variable points to.
+// It's read from stdin if neither is set
+const PASSPHRASE_ENV: &str = "RATHOLE_BUNDLE_PASSPHRASE";
+const PASSPHRASE_FILE_ENV: &str = "RATHOLE_BUNDLE_PASSPHRASE_FILE";
+
+// The keys of `client.transport.tls` whose files are packed. They name the files in the bundle
+const TLS_FILES: [&str; 4] = ["trusted_root", "pkcs12", "cert", "key"];
+
+// Secrets in the credential store are packed as they are, since the device has none of them
+const SECRETS: [&[&str]; 6] = [
+    &["client", "default_token"],
+    &["client", "proxy"],
+    &["client", "transport", "tls", "pkcs12_password"],
+    &["client", "transport", "noise", "local_private_key"],
+    &["client", "transport", "noise", "local_private_keys"],
+    &["client", "transport", "noise", "psk"],
+];
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct Contents {
+    // The config, with the paths of the files replaced by their names
+    pub(crate) config: String,
+    pub(crate) files: Vec<(String, Vec<u8>)>,
+}
+
+// The files of a bundle, in a directory of their own, which is removed on drop
+pub(crate) struct Extracted {
+    dir: PathBuf,
+    config_path: PathBuf,
+}
+
+impl Extracted {
+    pub(crate) fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    // Keep the files after drop, and return the path of the config
+    pub(crate) fn keep(mut self) -> PathBuf {
+        self.dir = PathBuf::new();
+        std::mem::take(&mut self.config_path)
+    }
+}
+
+impl Drop for Extracted {
+    fn drop(&mut self) {
+        if !self.dir.as_os_str().is_empty() {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+// Read a client config to be packed. It's validated like `rathole bundle` does, and an encrypted
+// one is decrypted, since the packed one is sealed anyway
+pub(crate) async fn read_config(path: &Path) -> Result<String> {
+    let config = Config::from_file(path).await?;
+    if config.client.is_none() || config.server.is_some() {
+        bail!("Only client configs can be bundled");
+    }
+    let s = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read the config {:?}", path))?;
+    match config_crypto::is_encrypted(&s) {
+        true => config_crypto::decrypt(&s),
+        false => Ok(s),
+    }
+}
+
+// Pack a config read by `read_config` with the files it refers to
+pub(crate) fn pack_files(config: &str) -> Result<Contents> {
+    pack(config, |path| {
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path))
+    })
+}
+
+// Seal a config read by `read_config`
+pub(crate) fn create(config: &str) -> Result<String> {
+    seal(&pack_files(config)?, &passphrase()?)
+}
+
+// Extract the bundle at `path` to a directory under the temporary one
+pub(crate) fn extract_file(path: &Path) -> Result<Extracted> {
+    let s = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the bundle {:?}", path))?;
+    let contents = open(&s, &passphrase()?)
+        .with_context(|| format!("Failed to open the bundle {:?}", path))?;
+    let mut name = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut name);
+    let dir = std::env::temp_dir().join(format!("rathole-bundle-{}", hex::encode(name)));
+    extract(contents, dir)
+}
+
+fn passphrase() -> Result<String> {
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(v) => Ok(v),
+        Err(_) => match std::env::var(PASSPHRASE_FILE_ENV) {
+            Ok(path) => Ok(std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read the passphrase file {}", path))?
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_string()),
+            Err(_) => crate::read_secret(&None, "passphrase"),
+        },
+    }
+}
+
+fn get_mut<'a>(v: &'a mut Value, path: &[&str]) -> Option<&'a mut Value> {
+    path.iter().try_fold(v, |v, k| v.get_mut(*k))
+}
+
+fn resolve_secret(v: &mut Value) -> Result<()> {
+    match v {
+        Value::String(s) => {
+            let mut secret = Some(std::mem::take(s));
+            secret::resolve(&mut secret)?;
+            *s = secret.unwrap_or_default();
+        }
+        Value::Array(a) => {
+            for v in a {
+                resolve_secret(v)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Pack `config` with the files it refers to, which are read by `read`
+fn pack(config: &str, read: impl Fn(&str) -> Result<Vec<u8>>) -> Result<Contents> {
+    let mut v: Value = toml::from_str(config).with_context(|| "Failed to parse the config")?;
+    for path in SECRETS {
+        if let Some(s) = get_mut(&mut v, path) {
+            resolve_secret(s)?;
+        }
+    }
+    let mut files = Vec::new();
+    if let Some(tls) = get_mut(&mut v, &["client", "transport", "tls"]) {
+        for name in TLS_FILES {
+            if let Some(path) = tls.get_mut(name) {
+                let data = match path.as_str() {
+                    Some(p) => read(p)?,
+                    None => bail!("client.transport.tls.{} must be a path", name),
+                };
+                files.push((name.to_string(), data));
+                *path = Value::String(name.to_string());
+            }
+        }
+    }
+    Ok(Contents {
+        config: toml::to_string(&v)?,
+        files,
+    })
+}
+
+fn seal(contents: &Contents, passphrase: &str) -> Result<String> {
+    let mut salt = Salt::default();
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut data = Vec::from(&salt[..]);
+    data.append(&mut config_crypto::seal(
+        &client_key(passphrase, &salt),
+        &bincode::serialize(contents)?,
+    )?);
+    Ok(format!("{}{}\n", MAGIC, hex::encode(data)))
+}
+
+fn open(s: &str, passphrase: &str) -> Result<Contents> {
+    let data = s
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| anyhow!("Not a bundle of rathole"))?;
+    let data = hex::decode(data.trim()).with_context(|| "Malformed bundle")?;
+    let mut salt = Salt::default();
+    if data.len() < salt.len() {
+        bail!("Malformed bundle");
+    }
+    let (s, data) = data.split_at(salt.len());
+    salt.copy_from_slice(s);
+    let plaintext = config_crypto::open(&client_key(passphrase, &salt), data)
+        .with_context(|| "Wrong passphrase, or the bundle is corrupted")?;
+    bincode::deserialize(&plaintext).with_context(|| "Malformed bundle")
+}
+
+// Write the files to `dir`, and the config pointing to them
+pub(crate) fn extract(contents: Contents, dir: PathBuf) -> Result<Extracted> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder
+        .create(&dir)
+        .with_context(|| format!("Failed to create {:?}", dir))?;
+    let extracted = Extracted {
+        config_path: dir.join("config.toml"),
+        dir,
+    };
+
+    let mut v: Value = toml::from_str(&contents.config).with_context(|| "Malformed bundle")?;
+    for (name, data) in &contents.files {
+        if !TLS_FILES.contains(&name.as_str()) {
+            bail!("Unknown file {} in the bundle", name);
+        }
+        let path = extracted.dir.join(name);
+        write_private(&path, data)?;
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("{:?} is not valid UTF-8", path))?;
+        match get_mut(&mut v, &["client", "transport", "tls"]).and_then(Value::as_table_mut) {
+            Some(tls) => tls.insert(name.clone(), Value::String(path.to_string())),
+            None => bail!("Malformed bundle"),
+        };
+    }
+    write_private(&extracted.config_path, toml::to_string(&v)?.as_bytes())?;
+    Ok(extracted)
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut f| f.write_all(data))
+        .with_context(|| format!("Failed to write {:?}", path))
+}
+
+#[cfg(all(test, feature = "config-encryption"))]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bundle() {
+        let config = r#"
+[client]
+remote_addr = "example.com:2333"
+default_token = "secret"
+
+[client.transport]
+type = "tls"
+
+[client.transport.tls]
+hostname = "example.com"
+trusted_root = "/etc/rathole/ca.pem"
+
+[client.services.ssh]
+local_addr = "127.0.0.1:22"
+"#;
+        let contents = pack(config, |path| {
+            assert_eq!(path, "/etc/rathole/ca.pem");
+            Ok(b"CA".to_vec())
+        })
+        .unwrap();
+        assert_eq!(
+            contents.files,
+            vec![("trusted_root".to_string(), b"CA".to_vec())]
+        );
+
+        let s = seal(&contents, "correct horse").unwrap();
+        assert!(open(&s, "wrong horse").is_err());
+        assert!(open(&s[1..], "correct horse").is_err());
+        let contents = open(&s, "correct horse").unwrap();
+
+        let dir = std::env::temp_dir().join(format!("rathole-bundle-{}", rand::random::<u32>()));
+        let extracted = extract(contents, dir.clone()).unwrap();
+        let config = Config::from_file(extracted.config_path()).await.unwrap();
+        let client = config.client.unwrap();
+        assert_eq!(client.default_token.as_deref(), Some("secret"));
+        let trusted_root = client.transport.tls.unwrap().trusted_root.unwrap();
+        assert_eq!(Path::new(&trusted_root), dir.join("trusted_root"));
+        assert_eq!(std::fs::read(&trusted_root).unwrap(), b"CA");
+        drop(extracted);
+        assert!(!dir.exists());
+
+        // Only the known files are written, so a bundle can't write anywhere else
+        let contents = Contents {
+            config: "[client]\n".into(),
+            files: vec![("../escape".into(), vec![])],
+        };
+        assert!(extract(contents, dir.clone()).is_err());
+        assert!(!dir.exists());
+    }
+}
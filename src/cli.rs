@@ -1,5 +1,6 @@
 use clap::{AppSettings, ArgGroup, Parser};
 use lazy_static::lazy_static;
+use std::num::NonZeroUsize;
 
 #[derive(clap::ArgEnum, Clone, Debug, Copy)]
 pub enum KeypairType {
@@ -8,7 +9,7 @@ pub enum KeypairType {
 }
 
 lazy_static! {
-    static ref VERSION: &'static str = {
+    pub(crate) static ref VERSION: &'static str = {
         match option_env!("VERGEN_GIT_SEMVER_LIGHTWEIGHT") {
             Some(v) => v,
             None => env!("VERGEN_BUILD_SEMVER"),
@@ -41,27 +42,43 @@ cargo Features:      {}
     about,
     version(*VERSION),
     long_version(LONG_VERSION.as_str()),
-    setting(AppSettings::DeriveDisplayOrder)
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::SubcommandsNegateReqs),
+    setting(AppSettings::ArgsNegateSubcommands)
 )]
 #[clap(group(
             ArgGroup::new("cmds")
                 .required(true)
-                .args(&["CONFIG", "genkey"]),
+                .args(&["CONFIG", "bundle", "genkey"]),
         ))]
 pub struct Cli {
     /// The path to the configuration file
     ///
     /// Running as a client or a server is automatically determined
-    /// according to the configuration file.
+    /// according to the configuration file. A configuration with both
+    /// runs both in one process.
     #[clap(parse(from_os_str), name = "CONFIG")]
     pub config_path: Option<std::path::PathBuf>,
 
+    /// Run the client config in a bundle made by `rathole bundle`
+    ///
+    /// The passphrase is taken from `RATHOLE_BUNDLE_PASSPHRASE`, the file
+    /// `RATHOLE_BUNDLE_PASSPHRASE_FILE` points to, or stdin.
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    pub bundle: Option<std::path::PathBuf>,
+
     /// Run as a server
-    #[clap(long, short, group = "mode")]
+    ///
+    /// Along with `--client`, runs both, and fails if the configuration
+    /// lacks either of them.
+    #[clap(long, short)]
     pub server: bool,
 
     /// Run as a client
-    #[clap(long, short, group = "mode")]
+    ///
+    /// Along with `--server`, runs both, and fails if the configuration
+    /// lacks either of them.
+    #[clap(long, short)]
     pub client: bool,
 
     /// Generate a keypair for the use of the noise protocol
@@ -69,4 +86,152 @@ pub struct Cli {
     /// The DH function to use is x25519
     #[clap(long, arg_enum, value_name = "CURVE")]
     pub genkey: Option<Option<KeypairType>>,
+
+    /// The number of worker threads
+    ///
+    /// Defaults to the number of CPUs available to the process, which honors
+    /// the CPU affinity and the CPU quota of cgroups.
+    #[clap(long, value_name = "N")]
+    pub worker_threads: Option<NonZeroUsize>,
+
+    /// The maximum number of threads for blocking operations, like reading files
+    ///
+    /// Defaults to 512. These threads are idle most of the time.
+    #[clap(long, value_name = "N")]
+    pub max_blocking_threads: Option<NonZeroUsize>,
+
+    /// Record the control channels of the client to files in DIR
+    ///
+    /// For debugging and regression tests of the protocol. The files contain
+    /// the authentication of services, so don't share recordings of production services.
+    #[cfg(feature = "record")]
+    #[clap(long, value_name = "DIR", parse(from_os_str))]
+    pub record: Option<std::path::PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Replace the running binary with the latest release
+    SelfUpdate(SelfUpdateArgs),
+    /// Hash a token for `token_hashes` of a service in the server config
+    HashToken(HashTokenArgs),
+    /// Encrypt a configuration file with the key in `RATHOLE_CONFIG_KEY`
+    EncryptConfig(EncryptConfigArgs),
+    /// Store a secret in the credential store of the OS, for `keyring:<NAME>` in the config
+    SetSecret(SetSecretArgs),
+    /// Convert the INI configs of frp into rathole configs
+    Migrate(MigrateArgs),
+    /// Seal a client config and the files it refers to into one file, to run with `--bundle`
+    Bundle(BundleArgs),
+    /// Fetch the config of a new client from the server with a pairing code
+    Pair(PairArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct PairArgs {
+    /// The pairing code, made through the admin API of the server
+    #[clap(value_name = "CODE")]
+    pub code: String,
+
+    /// The directory to write the config and the files it refers to, which must not exist
+    #[clap(long, short, parse(from_os_str), value_name = "DIR")]
+    pub output: std::path::PathBuf,
+
+    /// A client config whose `transport` reaches the server. Plain TCP if not given
+    #[clap(long, parse(from_os_str), value_name = "CONFIG")]
+    pub transport_from: Option<std::path::PathBuf>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct BundleArgs {
+    /// The path to the client configuration file
+    #[clap(parse(from_os_str), value_name = "CONFIG")]
+    pub config_path: std::path::PathBuf,
+
+    /// Where to write the bundle
+    ///
+    /// The passphrase is taken like the one of `--bundle`.
+    #[clap(long, short, parse(from_os_str), value_name = "FILE")]
+    pub output: std::path::PathBuf,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct MigrateArgs {
+    /// The path to frpc.ini or frps.ini. Give both to convert the proxies with the ports of frps
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "FILE",
+        required = true,
+        multiple_occurrences = true
+    )]
+    pub from: Vec<std::path::PathBuf>,
+
+    /// The directory to write server.toml and client.toml to
+    #[clap(
+        long,
+        short,
+        parse(from_os_str),
+        value_name = "DIR",
+        default_value = "."
+    )]
+    pub output: std::path::PathBuf,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct HashTokenArgs {
+    /// The token. It's read from stdin if not given, so it doesn't show up in the shell history
+    #[clap(value_name = "TOKEN")]
+    pub token: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct SetSecretArgs {
+    /// The name of the secret
+    #[clap(value_name = "NAME")]
+    pub name: String,
+
+    /// The secret. It's read from stdin if not given, so it doesn't show up in the shell history
+    #[clap(value_name = "SECRET")]
+    pub secret: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct SelfUpdateArgs {
+    /// The URL of the release binary
+    ///
+    /// `{target}` is replaced by the target triple of the binary, like
+    /// `x86_64-unknown-linux-gnu`. The minisign signature must be at the same
+    /// URL with `.minisig` appended, and its trusted comment must name the
+    /// version and the target, like `rathole 0.4.0 x86_64-unknown-linux-gnu`.
+    #[clap(long, value_name = "URL")]
+    pub url: String,
+
+    /// The minisign public key of the releases, encoded in base64
+    #[clap(long, value_name = "KEY")]
+    pub public_key: String,
+
+    /// Only check whether there's an update
+    #[clap(long)]
+    pub check: bool,
+
+    /// Install the release even if it's not newer than the running binary
+    #[clap(long)]
+    pub allow_downgrade: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct EncryptConfigArgs {
+    /// The path to the plaintext configuration file
+    #[clap(parse(from_os_str), value_name = "CONFIG")]
+    pub config_path: std::path::PathBuf,
+
+    /// Where to write the encrypted configuration
+    ///
+    /// Remember to delete the plaintext one, which is kept.
+    #[clap(long, short, parse(from_os_str), value_name = "FILE")]
+    pub output: std::path::PathBuf,
 }
@@ -1,30 +1,69 @@
-use crate::config::{ClientConfig, ClientServiceConfig, Config, TransportType};
+use crate::compression::{self, Codec, CompressedStream};
+use crate::config::{
+    ClientConfig, ClientServiceConfig, Config, ProtocolHelper, ProxyProtocol, TransportType,
+};
 use crate::config_watcher::ServiceChange;
-use crate::helper::udp_connect;
+use crate::ddns::Ddns;
+use crate::error::{self, Failure, Hint, WithHint};
+use crate::events::{self, DataChannelGuard, Event, ServiceUpGuard};
+use crate::health::{self, ConfiguredGuard};
+use crate::helper::{
+    is_transient_udp_error, is_udp_peer_unreachable, recv_shutdown, udp_connect, DatagramLimit,
+};
+use crate::log_filter::LogLevelGuard;
+use crate::privacy;
 use crate::protocol::Hello::{self, *};
 use crate::protocol::{
-    self, read_ack, read_control_cmd, read_data_cmd, read_hello, Ack, Auth, ControlChannelCmd,
-    DataChannelCmd, UdpTraffic, CURRENT_PROTO_VERSION, HASH_WIDTH_IN_BYTES,
+    self, read_ack, read_clock, read_control_cmd, read_data_cmd, read_hello, read_token_salts,
+    read_version, read_visitor_addr, Ack, Auth, ClientControlChannelCmd, Clock, ControlChannelCmd,
+    DataChannelCmd, DataChannelReply, Framing, InstanceId, UdpTraffic, Version, Weight, CAP_CLOCK,
+    CAP_FORWARD_CONFIRM, CAP_FORWARD_REPORT, CAP_PING, CAP_REVERSE, CAP_TOKEN_HASH,
+    CAP_VISITOR_ADDR, CAP_WEIGHT, CURRENT_PROTO_VERSION, HASH_WIDTH_IN_BYTES, PROTO_V0,
 };
-use crate::transport::{TcpTransport, Transport};
+use crate::protocol_helper;
+use crate::proxy_protocol;
+use crate::reuse::ReusableStream;
+use crate::splice;
+use crate::state_dump::{self, UdpSessionGuard};
+use crate::supervisor::catch_panic;
+use crate::task_group::TaskGroup;
+use crate::transport::{self, ExternalTransport, MemoryTransport, TcpTransport, Transport};
 use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
 use backoff::ExponentialBackoff;
 use bytes::{Bytes, BytesMut};
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::io::{self, copy_bidirectional, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
-use tokio::time::{self, Duration};
-use tracing::{debug, error, info, instrument, trace, warn, Instrument, Span};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::io::{
+    self, copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter,
+};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::runtime;
+use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::time::{self, Duration, Instant};
+use tokio_util::sync::DropGuard;
+use tracing::{debug, error, field, info, instrument, trace, warn, Instrument, Span};
 
+#[cfg(feature = "http2")]
+use crate::transport::Http2Transport;
+#[cfg(feature = "kcp")]
+use crate::transport::KcpTransport;
+#[cfg(feature = "mux")]
+use crate::transport::MuxTransport;
 #[cfg(feature = "noise")]
 use crate::transport::NoiseTransport;
 #[cfg(feature = "tls")]
 use crate::transport::TlsTransport;
+#[cfg(feature = "websocket")]
+use crate::transport::WebsocketTransport;
 
-use crate::constants::{UDP_BUFFER_SIZE, UDP_SENDQ_SIZE, UDP_TIMEOUT};
+use crate::constants::{
+    CLOCK_SKEW_WARN, FLAP_MAX_HOLD_DOWN, FLAP_STABLE_DURATION, FLAP_THRESHOLD, HEARTBEAT_INTERVAL,
+    HEARTBEAT_TIMEOUT, PANIC_RESTART_DELAY, PANIC_RESTART_MAX_DELAY, SHUTDOWN_TIMEOUT,
+    UDP_RECV_ARENA_SIZE, UDP_SENDQ_SIZE, UDP_TIMEOUT,
+};
 
 // The entrypoint of running a client
 pub async fn run_client(
@@ -35,20 +74,22 @@ pub async fn run_client(
     let config = match &config.client {
         Some(v) => v,
         None => {
-            return Err(anyhow!("Try to run as a client, but the configuration is missing. Please add the `[client]` block"))
+            return Err(anyhow!(
+                "Try to run as a client, but the configuration is missing"
+            ))
+            .hint(Hint::MissingBlock("client"))
+            .context(Failure::Config)
         }
     };
 
     match config.transport.transport_type {
         TransportType::Tcp => {
-            let mut client = Client::<TcpTransport>::from(config).await?;
-            client.run(shutdown_rx, service_rx).await
+            run_with_transport::<TcpTransport>(config, shutdown_rx, service_rx).await
         }
         TransportType::Tls => {
             #[cfg(feature = "tls")]
             {
-                let mut client = Client::<TlsTransport>::from(config).await?;
-                client.run(shutdown_rx, service_rx).await
+                run_with_transport::<TlsTransport>(config, shutdown_rx, service_rx).await
             }
             #[cfg(not(feature = "tls"))]
             crate::helper::feature_not_compile("tls")
@@ -56,15 +97,78 @@ pub async fn run_client(
         TransportType::Noise => {
             #[cfg(feature = "noise")]
             {
-                let mut client = Client::<NoiseTransport>::from(config).await?;
-                client.run(shutdown_rx, service_rx).await
+                run_with_transport::<NoiseTransport>(config, shutdown_rx, service_rx).await
             }
             #[cfg(not(feature = "noise"))]
             crate::helper::feature_not_compile("noise")
         }
+        TransportType::Websocket => {
+            #[cfg(feature = "websocket")]
+            {
+                run_with_transport::<WebsocketTransport>(config, shutdown_rx, service_rx).await
+            }
+            #[cfg(not(feature = "websocket"))]
+            crate::helper::feature_not_compile("websocket")
+        }
+        TransportType::Http2 => {
+            #[cfg(feature = "http2")]
+            {
+                run_with_transport::<Http2Transport>(config, shutdown_rx, service_rx).await
+            }
+            #[cfg(not(feature = "http2"))]
+            crate::helper::feature_not_compile("http2")
+        }
+        TransportType::Kcp => {
+            #[cfg(feature = "kcp")]
+            {
+                run_with_transport::<KcpTransport>(config, shutdown_rx, service_rx).await
+            }
+            #[cfg(not(feature = "kcp"))]
+            crate::helper::feature_not_compile("kcp")
+        }
+        TransportType::Memory => {
+            run_with_transport::<MemoryTransport>(config, shutdown_rx, service_rx).await
+        }
+        TransportType::External => {
+            run_with_transport::<ExternalTransport>(config, shutdown_rx, service_rx).await
+        }
+        TransportType::Custom => {
+            let run = transport::registered(&config.transport)
+                .context(Failure::Config)?
+                .client;
+            run(config, shutdown_rx, service_rx).await
+        }
     }
 }
 
+// `run_with_transport`, boxed to be registered for custom transports
+pub(crate) fn run_boxed<T: 'static + Transport>(
+    config: &ClientConfig,
+    shutdown_rx: broadcast::Receiver<bool>,
+    service_rx: mpsc::Receiver<ServiceChange>,
+) -> transport::RunFuture<'_> {
+    Box::pin(run_with_transport::<T>(config, shutdown_rx, service_rx))
+}
+
+// Channels are multiplexed into one connection of `T` with `transport.mux`
+async fn run_with_transport<T: 'static + Transport>(
+    config: &ClientConfig,
+    shutdown_rx: broadcast::Receiver<bool>,
+    service_rx: mpsc::Receiver<ServiceChange>,
+) -> Result<()> {
+    if config.transport.mux {
+        #[cfg(feature = "mux")]
+        {
+            let mut client = Client::<MuxTransport<T>>::from(config).await?;
+            return client.run(shutdown_rx, service_rx).await;
+        }
+        #[cfg(not(feature = "mux"))]
+        crate::helper::feature_not_compile("mux")
+    }
+    let mut client = Client::<T>::from(config).await?;
+    client.run(shutdown_rx, service_rx).await
+}
+
 type ServiceDigest = protocol::Digest;
 type Nonce = protocol::Digest;
 
@@ -73,6 +177,10 @@ struct Client<'a, T: Transport> {
     config: &'a ClientConfig,
     service_handles: HashMap<String, ControlChannelHandle>,
     transport: Arc<T>,
+    // Tells the server whether a control channel is from this run of the client
+    instance_id: InstanceId,
+    // All tasks of the client
+    tasks: TaskGroup,
 }
 
 impl<'a, T: 'static + Transport> Client<'a, T> {
@@ -84,8 +192,11 @@ impl<'a, T: 'static + Transport> Client<'a, T> {
             transport: Arc::new(
                 T::new(&config.transport)
                     .await
-                    .with_context(|| "Failed to create the transport")?,
+                    .with_context(|| "Failed to create the transport")
+                    .context(Failure::Config)?,
             ),
+            instance_id: rand::random(),
+            tasks: TaskGroup::new(),
         })
     }
 
@@ -95,68 +206,119 @@ impl<'a, T: 'static + Transport> Client<'a, T> {
         mut shutdown_rx: broadcast::Receiver<bool>,
         mut service_rx: mpsc::Receiver<ServiceChange>,
     ) -> Result<()> {
+        info!("Client instance {}", hex::encode(self.instance_id));
+
+        // Stop all tasks if this future is dropped rather than shutdown
+        let _tasks = self.tasks.cancel_on_drop();
+
+        // Receives the errors that stopped services for good. The client stops once all of
+        // them have
+        let (stopped_tx, mut stopped_rx) = mpsc::channel(1);
+        let mut stopped: HashMap<String, anyhow::Error> = HashMap::new();
+
         for (name, config) in &self.config.services {
             // Create a control channel for each service defined
             let handle = ControlChannelHandle::new(
                 (*config).clone(),
                 self.config.remote_addr.clone(),
                 self.transport.clone(),
+                self.instance_id,
+                stopped_tx.clone(),
+                &self.tasks,
             );
             self.service_handles.insert(name.clone(), handle);
         }
 
         // Wait for the shutdown signal
+        let mut ret = Ok(());
         loop {
             tokio::select! {
-                val = shutdown_rx.recv() => {
-                    match val {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("Unable to listen for shutdown signal: {}", err);
-                        }
-                    }
+                Some((name, err)) = stopped_rx.recv() => {
+                    stopped.insert(name, err);
+                },
+                _ = recv_shutdown(&mut shutdown_rx) => {
                     break;
                 },
                 e = service_rx.recv() => {
                     if let Some(e) = e {
                         match e {
-                            ServiceChange::ClientAdd(s)=> {
+                            ServiceChange::ClientAdd(mut s)=> {
+                                // Changes from library users are not validated yet
+                                if let Err(e) = Config::validate_client_service(&mut s, &self.config.default_token) {
+                                    error!("Failed to add the service: {:#}", e);
+                                    continue;
+                                }
                                 let name = s.name.clone();
+                                stopped.remove(&name);
                                 let handle = ControlChannelHandle::new(
-                                    s,
+                                    *s,
                                     self.config.remote_addr.clone(),
                                     self.transport.clone(),
+                                    self.instance_id,
+                                    stopped_tx.clone(),
+                                    &self.tasks,
                                 );
                                 let _ = self.service_handles.insert(name, handle);
                             },
                             ServiceChange::ClientDelete(s)=> {
                                 let _ = self.service_handles.remove(&s);
+                                stopped.remove(&s);
                             },
                             _ => ()
                         }
                     }
                 }
             }
-        }
 
-        // Shutdown all services
-        for (_, handle) in self.service_handles.drain() {
-            handle.shutdown();
+            // Stop once no service is left running, like when the token is wrong for all
+            let mut names = self.service_handles.keys();
+            if names.len() > 0 && names.all(|k| stopped.contains_key(k)) {
+                let name = self.service_handles.keys().next().unwrap();
+                ret = Err(stopped.remove(name).unwrap());
+                break;
+            }
         }
 
-        Ok(())
+        // Shutdown all services, then wait for the data channels
+        self.service_handles.clear();
+        self.tasks
+            .shutdown(Duration::from_secs(SHUTDOWN_TIMEOUT))
+            .await;
+
+        ret
     }
 }
 
 struct RunDataChannelArgs<T: Transport> {
+    service_name: String,
     session_key: Nonce,
     remote_addr: String,
     local_addr: String,
+    helper: Option<ProtocolHelper>,
     connector: Arc<T>,
+    tasks: TaskGroup,
+    // Reports failures of forwarding to the server. None if the server doesn't understand them
+    report_tx: Option<mpsc::UnboundedSender<ClientControlChannelCmd>>,
+    // Whether to confirm the connection to `local_addr` to the server
+    confirm: bool,
+    // How the commands on data channels are sent
+    framing: Framing,
+    // Whether the commands of TCP data channels are followed by their compression, and by the
+    // address of the visitor
+    compression: bool,
+    visitor_addr: bool,
+    proxy_protocol: Option<ProxyProtocol>,
+    // Limits the visitors forwarded at once, with `max_connections`
+    budget: Option<Arc<Semaphore>>,
+    // How the visitors of UDP services are forwarded
+    udp: UdpOptions,
+    // Keeps the runtime of an isolated service running until its data channels end
+    _runtime: Option<ServiceRuntime>,
 }
 
 async fn do_data_channel_handshake<T: Transport>(
     args: Arc<RunDataChannelArgs<T>>,
+    reverse: bool,
 ) -> Result<T::Stream> {
     // Retry at least every 100ms, at most for 10 seconds
     let backoff = ExponentialBackoff {
@@ -184,7 +346,11 @@ async fn do_data_channel_handshake<T: Transport>(
 
     // Send nonce
     let v: &[u8; HASH_WIDTH_IN_BYTES] = args.session_key[..].try_into().unwrap();
-    let hello = Hello::DataChannelHello(CURRENT_PROTO_VERSION, v.to_owned());
+    let hello = if reverse {
+        Hello::ReverseDataChannelHello(CURRENT_PROTO_VERSION, v.to_owned())
+    } else {
+        Hello::DataChannelHello(CURRENT_PROTO_VERSION, v.to_owned())
+    };
     conn.write_all(&bincode::serialize(&hello).unwrap()).await?;
     conn.flush().await?;
 
@@ -193,33 +359,208 @@ async fn do_data_channel_handshake<T: Transport>(
 
 async fn run_data_channel<T: Transport>(args: Arc<RunDataChannelArgs<T>>) -> Result<()> {
     // Do the handshake
-    let mut conn = do_data_channel_handshake(args.clone()).await?;
+    let mut conn = do_data_channel_handshake(args.clone(), false).await?;
+
+    let mut cmd = read_data_cmd(&mut conn, args.framing).await?;
+    // Reusable data channels forward visitors one after another, until the server closes them or
+    // one isn't finished cleanly
+    while let DataChannelCmd::StartForwardTcpReusable = cmd {
+        let params = read_forward_params(&mut conn, &args, true).await?;
+        let mut stats = DataChannelGuard::new(&args.service_name);
+        let _permit = match &args.budget {
+            Some(budget) => Some(budget.clone().acquire_owned().await?),
+            None => None,
+        };
+        match run_data_channel_for_tcp::<T>(
+            &mut conn,
+            &args.local_addr,
+            args.helper,
+            args.confirm.then_some(args.framing),
+            args.proxy_protocol,
+            params,
+        )
+        .await
+        {
+            Ok((v, reusable)) => {
+                (stats.inbound, stats.outbound) = v;
+                if !reusable {
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                if let Some(tx) = &args.report_tx {
+                    let _ = tx.send(ClientControlChannelCmd::ForwardFailed);
+                }
+                return Err(e);
+            }
+        }
+        cmd = match read_data_cmd(&mut conn, args.framing).await {
+            Ok(v) => v,
+            Err(_) => return Ok(()),
+        };
+    }
 
     // Forward
-    match read_data_cmd(&mut conn).await? {
-        DataChannelCmd::StartForwardTcp => {
-            run_data_channel_for_tcp::<T>(conn, &args.local_addr).await?;
+    let mut stats = DataChannelGuard::new(&args.service_name);
+    let local_addr = match cmd {
+        // Only ports of the host of `local_addr` can be asked for
+        DataChannelCmd::StartForwardTcpPort => {
+            let port = conn.read_u16().await?;
+            let host = args
+                .local_addr
+                .rsplit_once(':')
+                .map_or(args.local_addr.as_str(), |(host, _)| host);
+            format!("{}:{}", host, port)
         }
+        _ => args.local_addr.clone(),
+    };
+    match cmd {
+        DataChannelCmd::StartForwardTcp | DataChannelCmd::StartForwardTcpPort => {
+            let params = read_forward_params(&mut conn, &args, false).await?;
+            let _permit = match &args.budget {
+                Some(budget) => Some(budget.clone().acquire_owned().await?),
+                None => None,
+            };
+            match run_data_channel_for_tcp::<T>(
+                &mut conn,
+                &local_addr,
+                args.helper,
+                args.confirm.then_some(args.framing),
+                args.proxy_protocol,
+                params,
+            )
+            .await
+            {
+                Ok((v, _)) => (stats.inbound, stats.outbound) = v,
+                Err(e) => {
+                    if let Some(tx) = &args.report_tx {
+                        let _ = tx.send(ClientControlChannelCmd::ForwardFailed);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        // Handled above
+        DataChannelCmd::StartForwardTcpReusable => {}
         DataChannelCmd::StartForwardUdp => {
-            run_data_channel_for_udp::<T>(conn, &args.local_addr).await?;
+            run_data_channel_for_udp::<T>(
+                conn,
+                &args.service_name,
+                &args.local_addr,
+                &args.tasks,
+                &args.budget,
+                &args.udp,
+            )
+            .await?;
         }
     }
     Ok(())
 }
 
-// Simply copying back and forth for TCP
-#[instrument(skip(conn))]
+// What follows the command of a TCP data channel
+struct ForwardParams {
+    // Whether the data channel takes the next visitor once this one is done
+    reusable: bool,
+    codec: Option<Codec>,
+    // `None` if the server doesn't tell it
+    visitor_addr: Option<SocketAddr>,
+}
+
+async fn read_forward_params<S: AsyncRead + Unpin, T: Transport>(
+    conn: &mut S,
+    args: &RunDataChannelArgs<T>,
+    reusable: bool,
+) -> Result<ForwardParams> {
+    let codec = match args.compression {
+        true => compression::from_byte(conn.read_u8().await?)?
+            .map(Codec::new)
+            .transpose()?,
+        false => None,
+    };
+    let visitor_addr = match args.visitor_addr {
+        true => read_visitor_addr(conn).await?,
+        false => None,
+    };
+    Ok(ForwardParams {
+        reusable,
+        codec,
+        visitor_addr,
+    })
+}
+
+// Forward a visitor of a reverse service through the server, which connects to its `connect_addr`
+async fn run_reverse_data_channel<T: Transport>(
+    args: Arc<RunDataChannelArgs<T>>,
+    mut visitor: TcpStream,
+) -> Result<()> {
+    let _permit = match &args.budget {
+        Some(budget) => Some(budget.clone().acquire_owned().await?),
+        None => None,
+    };
+    let mut conn = do_data_channel_handshake(args.clone(), true).await?;
+    let mut stats = DataChannelGuard::new(&args.service_name);
+    let copied = match T::as_tcp(&mut conn) {
+        Some(conn) => splice::copy_bidirectional(conn, &mut visitor).await,
+        None => copy_bidirectional(&mut conn, &mut visitor).await,
+    };
+    let (outbound, inbound) = copied.unwrap_or_default();
+    (stats.inbound, stats.outbound) = (inbound, outbound);
+    Ok(())
+}
+
+// Simply copying back and forth for TCP, through the protocol helper if set. The connection to
+// `local_addr` is confirmed if `confirm` is set. Returns the bytes copied to and from local_addr,
+// and whether the data channel can take the next visitor. The connection to `local_addr` starts
+// with a header of `proxy_protocol` if set
+#[instrument(skip(conn, params))]
 async fn run_data_channel_for_tcp<T: Transport>(
-    mut conn: T::Stream,
+    conn: &mut T::Stream,
     local_addr: &str,
-) -> Result<()> {
+    helper: Option<ProtocolHelper>,
+    confirm: Option<Framing>,
+    proxy_protocol: Option<ProxyProtocol>,
+    params: ForwardParams,
+) -> Result<((u64, u64), bool)> {
     debug!("New data channel starts forwarding");
 
     let mut local = TcpStream::connect(local_addr)
         .await
         .with_context(|| "Failed to connect to local_addr")?;
-    let _ = copy_bidirectional(&mut conn, &mut local).await;
-    Ok(())
+    if let Some(ProxyProtocol::V2) = proxy_protocol {
+        let header = proxy_protocol::header_v2(params.visitor_addr, local.peer_addr()?);
+        local.write_all(&header).await?;
+    }
+    if let Some(framing) = confirm {
+        conn.write_all(&framing.encode(&DataChannelReply::Ready)?)
+            .await?;
+        conn.flush().await?;
+    }
+    // Nothing is done with what's forwarded, so it's copied in the kernel
+    if helper.is_none() && !params.reusable && params.codec.is_none() {
+        if let Some(conn) = T::as_tcp(conn) {
+            let copied = splice::copy_bidirectional(conn, &mut local).await;
+            return Ok((copied.unwrap_or_default(), false));
+        }
+    }
+    let mut framed = ReusableStream::new(conn, params.reusable);
+    let mut conn = CompressedStream::new(&mut framed, params.codec);
+    let copied = match helper {
+        Some(helper) => protocol_helper::forward(helper, &mut conn, &mut local, local_addr).await?,
+        None => copy_bidirectional(&mut conn, &mut local)
+            .await
+            .unwrap_or_default(),
+    };
+    Ok((copied, conn.get_ref().is_reusable()))
+}
+
+#[derive(Clone)]
+struct UdpOptions {
+    limit: DatagramLimit,
+    // How long the socket for a visitor is kept without traffic
+    timeout: Duration,
+    // Send empty datagrams to the local service at this interval while there's no traffic, and
+    // keep the socket for as long as it doesn't refuse them, instead of `timeout`
+    keepalive: Option<Duration>,
 }
 
 // Things get a little tricker when it gets to UDP because it's connection-less.
@@ -228,8 +569,15 @@ async fn run_data_channel_for_tcp<T: Transport>(
 // to the socket will work fine for the map's value.
 type UdpPortMap = Arc<RwLock<HashMap<SocketAddr, mpsc::Sender<Bytes>>>>;
 
-#[instrument(skip(conn))]
-async fn run_data_channel_for_udp<T: Transport>(conn: T::Stream, local_addr: &str) -> Result<()> {
+#[instrument(skip(conn, service_name, tasks, budget, udp))]
+async fn run_data_channel_for_udp<T: Transport>(
+    conn: T::Stream,
+    service_name: &str,
+    local_addr: &str,
+    tasks: &TaskGroup,
+    budget: &Option<Arc<Semaphore>>,
+    udp: &UdpOptions,
+) -> Result<()> {
     debug!("New data channel starts forwarding");
 
     let port_map: UdpPortMap = Arc::new(RwLock::new(HashMap::new()));
@@ -239,27 +587,41 @@ async fn run_data_channel_for_udp<T: Transport>(conn: T::Stream, local_addr: &st
 
     // FIXME: https://github.com/tokio-rs/tls/issues/40
     // Maybe this is our concern
-    let (mut rd, mut wr) = io::split(conn);
+    let (rd, wr) = io::split(conn);
+    // Buffered, so a packet is not split into several writes, and several packets can be read at once
+    let mut rd = BufReader::new(rd);
+    let mut wr = BufWriter::new(wr);
+
+    // Stop the writer and forwarders along with this data channel
+    let tasks = tasks.child();
+    let _guard = tasks.cancel_on_drop();
 
     // Keep sending items from the outbound channel to the server
-    tokio::spawn(async move {
-        while let Some(t) = outbound_rx.recv().await {
-            trace!("outbound {:?}", t);
-            if let Err(e) = t
-                .write(&mut wr)
-                .await
-                .with_context(|| "Failed to forward UDP traffic to the server")
-            {
-                debug!("{:?}", e);
-                break;
+    tasks.spawn(async move {
+        let ret: Result<()> = async {
+            while let Some(t) = outbound_rx.recv().await {
+                trace!("outbound {:?}", t);
+                t.write(&mut wr).await?;
+                // Write what's queued in a batch, and flush once the queue is drained
+                while let Ok(t) = outbound_rx.try_recv() {
+                    trace!("outbound {:?}", t);
+                    t.write(&mut wr).await?;
+                }
+                wr.flush().await?;
             }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = ret.with_context(|| "Failed to forward UDP traffic to the server") {
+            debug!("{:?}", e);
         }
     });
 
+    let mut arena = BytesMut::new();
     loop {
         // Read a packet from the server
         let hdr_len = rd.read_u8().await?;
-        let packet = UdpTraffic::read(&mut rd, hdr_len)
+        let packet = UdpTraffic::read(&mut rd, hdr_len, &mut arena)
             .await
             .with_context(|| "Failed to read UDPTraffic from the server")?;
         let m = port_map.read().await;
@@ -272,6 +634,20 @@ async fn run_data_channel_for_udp<T: Transport>(conn: T::Stream, local_addr: &st
             // Drop the reader lock
             drop(m);
 
+            let permit = match budget {
+                Some(budget) => match budget.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        trace!(
+                            "Too many visitors. Drop the packet from {}",
+                            privacy::addr(packet.from)
+                        );
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
             // Grab the writer lock
             // This is the only thread that will try to grab the writer lock
             // So no need to worry about some other thread has already set up
@@ -283,12 +659,16 @@ async fn run_data_channel_for_udp<T: Transport>(conn: T::Stream, local_addr: &st
                 Ok(s) => {
                     let (inbound_tx, inbound_rx) = mpsc::channel(UDP_SENDQ_SIZE);
                     m.insert(packet.from, inbound_tx);
-                    tokio::spawn(run_udp_forwarder(
+                    privacy::audit(service_name, packet.from);
+                    tasks.spawn(run_udp_forwarder(
                         s,
                         inbound_rx,
                         outbound_tx.clone(),
                         packet.from,
                         port_map.clone(),
+                        permit,
+                        UdpSessionGuard::new(service_name),
+                        udp.clone(),
                     ));
                 }
                 Err(e) => {
@@ -297,165 +677,593 @@ async fn run_data_channel_for_udp<T: Transport>(conn: T::Stream, local_addr: &st
             }
         }
 
-        // Now there should be a udp forwarder that can receive the packet
+        // Now there should be a udp forwarder that can receive the packet. If it falls behind,
+        // the packet is dropped rather than stalling other visitors
         let m = port_map.read().await;
         if let Some(tx) = m.get(&packet.from) {
-            let _ = tx.send(packet.data).await;
+            let _ = tx.try_send(packet.data);
+        }
+    }
+}
+
+// The traffic of a UDP session, recorded to the span of its forwarder and logged when it ends
+struct UdpSessionStats {
+    span: Span,
+    start: Instant,
+    packets_to_service: u64,
+    bytes_to_service: u64,
+    packets_from_service: u64,
+    bytes_from_service: u64,
+    // Failed to send to the local service, or oversized and dropped
+    dropped: u64,
+}
+
+impl UdpSessionStats {
+    fn new() -> UdpSessionStats {
+        UdpSessionStats {
+            span: Span::current(),
+            start: Instant::now(),
+            packets_to_service: 0,
+            bytes_to_service: 0,
+            packets_from_service: 0,
+            bytes_from_service: 0,
+            dropped: 0,
         }
     }
 }
 
+impl Drop for UdpSessionStats {
+    fn drop(&mut self) {
+        let span = &self.span;
+        span.record("packets_to_service", self.packets_to_service);
+        span.record("bytes_to_service", self.bytes_to_service);
+        span.record("packets_from_service", self.packets_from_service);
+        span.record("bytes_from_service", self.bytes_from_service);
+        span.record("dropped", self.dropped);
+        span.in_scope(|| debug!("Forwarder dropped after {:?}", self.start.elapsed()));
+    }
+}
+
 // Run a UdpSocket for the visitor `from`
-#[instrument(skip_all, fields(from))]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(
+    visitor = %privacy::addr(from),
+    packets_to_service = field::Empty,
+    bytes_to_service = field::Empty,
+    packets_from_service = field::Empty,
+    bytes_from_service = field::Empty,
+    dropped = field::Empty,
+))]
 async fn run_udp_forwarder(
     s: UdpSocket,
     mut inbound_rx: mpsc::Receiver<Bytes>,
     outbount_tx: mpsc::Sender<UdpTraffic>,
     from: SocketAddr,
     port_map: UdpPortMap,
+    _permit: Option<OwnedSemaphorePermit>,
+    _session: UdpSessionGuard,
+    udp: UdpOptions,
 ) -> Result<()> {
     debug!("Forwarder created");
+    let mut stats = UdpSessionStats::new();
     let mut buf = BytesMut::new();
-    buf.resize(UDP_BUFFER_SIZE, 0);
+    let mut limit = udp.limit;
 
     loop {
+        if buf.capacity() < limit.buffer_size() {
+            buf.reserve(UDP_RECV_ARENA_SIZE.max(limit.buffer_size()));
+        }
+
         tokio::select! {
             // Receive from the server
             data = inbound_rx.recv() => {
                 if let Some(data) = data {
-                    s.send(&data).await?;
+                    trace!("{} bytes to the service", data.len());
+                    match s.send(&data).await {
+                        Ok(_) => {
+                            stats.packets_to_service += 1;
+                            stats.bytes_to_service += data.len() as u64;
+                        }
+                        Err(e) if is_transient_udp_error(&e) => stats.dropped += 1,
+                        Err(e) => return Err(e.into()),
+                    }
                 } else {
                     break;
                 }
             },
 
             // Receive from the service
-            val = s.recv(&mut buf) => {
-                let len = match val {
-                    Ok(v) => v,
-                    Err(_) => {break;}
+            val = s.recv_buf(&mut buf) => {
+                match val {
+                    Ok(_) => {},
+                    // The ICMP error of a keepalive. The local service has gone
+                    Err(e) if udp.keepalive.is_some() && is_udp_peer_unreachable(&e) => break,
+                    Err(e) if is_transient_udp_error(&e) => continue,
+                    Err(_) => break,
                 };
+                match limit.apply(buf.len(), from) {
+                    Some(n) => buf.truncate(n),
+                    None => {
+                        stats.dropped += 1;
+                        buf.clear();
+                        continue;
+                    }
+                }
+                trace!("{} bytes from the service", buf.len());
+                stats.packets_from_service += 1;
+                stats.bytes_from_service += buf.len() as u64;
 
                 let t = UdpTraffic{
                     from,
-                    data: Bytes::copy_from_slice(&buf[..len])
+                    data: buf.split().freeze(),
                 };
 
                 outbount_tx.send(t).await?;
             },
 
-            // No traffic for the duration of UDP_TIMEOUT, clean up the state
-            _ = time::sleep(Duration::from_secs(UDP_TIMEOUT)) => {
-                break;
+            // No traffic for a while. Clean up the state, unless kept alive
+            _ = time::sleep(udp.keepalive.unwrap_or(udp.timeout)) => {
+                if udp.keepalive.is_none() {
+                    break;
+                }
+                trace!("Keepalive");
+                if let Err(e) = s.send(&[]).await {
+                    if is_udp_peer_unreachable(&e) || !is_transient_udp_error(&e) {
+                        break;
+                    }
+                }
             }
         }
     }
 
     let mut port_map = port_map.write().await;
     port_map.remove(&from);
-
-    debug!("Forwarder dropped");
     Ok(())
 }
 
+// The address the server reported for visitors of a service. The server only knows its public host
+// if it's configured, so an unspecified one is the host of `remote_addr`, which reached the server
+fn resolve_public_addr(addr: &str, remote_addr: &str) -> String {
+    let port = match addr.parse::<SocketAddr>() {
+        Ok(v) if v.ip().is_unspecified() => v.port(),
+        _ => return addr.to_string(),
+    };
+    let host = remote_addr
+        .rsplit_once(':')
+        .map_or(remote_addr, |(host, _)| host);
+    format!("{}:{}", host, port)
+}
+
+// Whether the connection was closed by the server, rather than failed otherwise
+fn closed_by_peer(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            )
+        })
+}
+
 // Control channel, using T as the transport layer
 struct ControlChannel<T: Transport> {
-    digest: ServiceDigest,              // SHA256 of the service name
-    service: ClientServiceConfig,       // `[client.services.foo]` config block
-    shutdown_rx: oneshot::Receiver<u8>, // Receives the shutdown signal
-    remote_addr: String,                // `client.remote_addr`
-    transport: Arc<T>,                  // Wrapper around the transport layer
+    digest: ServiceDigest,           // SHA256 of the service name
+    service: ClientServiceConfig,    // `[client.services.foo]` config block
+    remote_addr: String,             // `client.remote_addr`
+    transport: Arc<T>,               // Wrapper around the transport layer
+    instance_id: InstanceId,         // The instance ID of the client
+    established_at: Option<Instant>, // When the control channel was established
+    tasks: TaskGroup,                // Where data channels are spawned
+    budget: Option<Arc<Semaphore>>,  // Limits the visitors forwarded at once
+    runtime: Option<ServiceRuntime>, // The runtime of an isolated service
+    ddns: Option<Arc<Ddns>>,         // Publishes the public address of the service
+    // Tokens stretched with the salts of the hashes on the server. They're only stretched again
+    // once the salts change
+    client_keys: HashMap<protocol::Salt, protocol::Digest>,
 }
 
 // Handle of a control channel
-// Dropping it will also drop the actual control channel
+// Dropping it will also drop the actual control channel, but not the data channels
 struct ControlChannelHandle {
-    shutdown_tx: oneshot::Sender<u8>,
+    _tasks: DropGuard,
+    _configured: ConfiguredGuard,
+    _log_level: Option<LogLevelGuard>,
 }
 
 impl<T: 'static + Transport> ControlChannel<T> {
     #[instrument(skip_all)]
     async fn run(&mut self) -> Result<()> {
-        let mut conn = self
-            .transport
-            .connect(&self.remote_addr)
-            .await
-            .with_context(|| format!("Failed to connect to the server: {}", &self.remote_addr))?;
+        // Servers older than PROTO_V1 can't decode `ClientControlChannelHello`, and close the
+        // connection without a word. Say hello again as clients before it did
+        let mut legacy = false;
+        let (mut conn, nonce, capabilities) = loop {
+            let conn = self
+                .transport
+                .connect(&self.remote_addr)
+                .await
+                .with_context(|| format!("Failed to connect to the server: {}", &self.remote_addr))
+                .hint(Hint::Unreachable(self.remote_addr.clone()))?;
+            #[cfg(feature = "record")]
+            let conn = crate::record::Recorded::new(conn, &self.service.name);
+            let mut conn = conn;
 
-        // Send hello
-        debug!("Sending hello");
-        let hello_send =
-            Hello::ControlChannelHello(CURRENT_PROTO_VERSION, self.digest[..].try_into().unwrap());
-        conn.write_all(&bincode::serialize(&hello_send).unwrap())
-            .await?;
-        conn.flush().await?;
+            // Send hello
+            debug!("Sending hello");
+            let digest = self.digest[..].try_into().unwrap();
+            let hello_send = if legacy {
+                Hello::ControlChannelHello(PROTO_V0, digest)
+            } else {
+                Hello::ClientControlChannelHello(
+                    CURRENT_PROTO_VERSION,
+                    digest,
+                    self.instance_id,
+                    protocol::local_capabilities(),
+                )
+            };
+            conn.write_all(&bincode::serialize(&hello_send).unwrap())
+                .await?;
+            conn.flush().await?;
 
-        // Read hello
-        debug!("Reading hello");
-        let nonce = match read_hello(&mut conn).await? {
-            ControlChannelHello(_, d) => d,
-            _ => {
-                bail!("Unexpected type of hello");
+            // Read hello
+            debug!("Reading hello");
+            match read_hello(&mut conn).await {
+                Ok(ServerControlChannelHello(_, d, c)) => break (conn, d, c),
+                // Servers older than PROTO_V1 don't report capabilities
+                Ok(ControlChannelHello(_, d)) => break (conn, d, 0),
+                Ok(_) => bail!("Unexpected type of hello"),
+                Err(e) if !legacy && closed_by_peer(&e) => {
+                    info!("The server closed the connection on hello. Retry as an older client");
+                    legacy = true;
+                }
+                Err(e) => return Err(e),
             }
         };
+        let framing = Framing::new(capabilities);
+
+        // Servers with `CAP_CLOCK` send theirs right after the hello. It's positive if this
+        // device is ahead
+        let skew = if capabilities & CAP_CLOCK != 0 {
+            let clock = read_clock(&mut conn, framing).await?;
+            Some(Clock::now().skew(&clock))
+        } else {
+            None
+        };
+        // The server tells its version only after accepting the auth
+        let exchange_versions = protocol::versions_negotiated(capabilities);
+
+        // Servers with `CAP_TOKEN_HASH` send the salts if the token is hashed on their side
+        let salts = if capabilities & CAP_TOKEN_HASH != 0 {
+            read_token_salts(&mut conn, framing).await?.0
+        } else {
+            Vec::new()
+        };
+
+        if skew.is_some() {
+            conn.write_all(&framing.encode(&Clock::now())?).await?;
+        }
+        if exchange_versions {
+            conn.write_all(&framing.encode(&Version::local())?).await?;
+        }
 
         // Send auth
         debug!("Sending auth");
-        let mut concat = Vec::from(self.service.token.as_ref().unwrap().as_bytes());
-        concat.extend_from_slice(&nonce);
-
-        let session_key = protocol::digest(&concat);
-        let auth = Auth(session_key);
-        conn.write_all(&bincode::serialize(&auth).unwrap()).await?;
+        let token = self.service.token.clone().unwrap();
+        let session_key = if salts.is_empty() {
+            let d = match self.service.totp_step {
+                Some(step) => protocol::auth_digest(
+                    &protocol::totp_token(&token, protocol::totp_window(step)),
+                    &nonce,
+                ),
+                None => protocol::auth_digest(&token, &nonce),
+            };
+            conn.write_all(&framing.encode(&Auth(d))?).await?;
+            d
+        } else {
+            // Stretching the token is slow by design, so it's done once for each salt
+            let cached = std::mem::take(&mut self.client_keys);
+            let keys = tokio::task::spawn_blocking(move || {
+                salts
+                    .iter()
+                    .map(|salt| match cached.get(salt) {
+                        Some(v) => (*salt, *v),
+                        None => (*salt, protocol::client_key(&token, salt)),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await?;
+            let proofs: Vec<_> = keys
+                .iter()
+                .map(|(_, key)| protocol::token_proof(key, &nonce))
+                .collect();
+            // Keys of the salts the server no longer has are forgotten
+            self.client_keys = keys.into_iter().collect();
+            for p in &proofs {
+                conn.write_all(&framing.encode(&Auth(*p))?).await?;
+            }
+            protocol::digest(&proofs.concat())
+        };
+        if capabilities & CAP_WEIGHT != 0 {
+            let weight = Weight(self.service.weight.unwrap_or(1));
+            conn.write_all(&framing.encode(&weight)?).await?;
+        }
         conn.flush().await?;
 
         // Read ack
         debug!("Reading ack");
-        match read_ack(&mut conn).await? {
-            Ack::Ok => {}
+        let server_version = match read_ack(&mut conn, framing).await? {
+            Ack::Ok if exchange_versions => Some(read_version(&mut conn).await?.0),
+            Ack::Ok => None,
+            Ack::AuthFailed => {
+                let mut msg = format!("Authentication failed: {}", self.service.name);
+                let mut err = anyhow::Error::new(Ack::AuthFailed);
+                // Devices without a working RTC fail time-based tokens for no apparent reason
+                match (skew, self.service.totp_step) {
+                    (Some(skew), Some(_)) if skew.abs() >= CLOCK_SKEW_WARN => {
+                        msg += &format!(
+                            ". The clock of this device is {:+.1}s off from the server's, which breaks time-based tokens. Is it synced with NTP?",
+                            skew
+                        );
+                    }
+                    _ => err = error::hint(err, Hint::TokenMismatch(self.service.name.clone())),
+                }
+                return Err(err).context(msg).context(Failure::Auth);
+            }
             v => {
-                return Err(anyhow!("{}", v))
-                    .with_context(|| format!("Authentication failed: {}", self.service.name));
+                let name = &self.service.name;
+                let (msg, hint) = match v {
+                    Ack::ServiceNotFound => (
+                        format!("Service {} is not configured on the server", name),
+                        Some(Hint::UnknownService(name.clone())),
+                    ),
+                    Ack::ServiceBusy => (
+                        format!("Service {} is served by another client", name),
+                        Some(Hint::ServiceBusy(name.clone())),
+                    ),
+                    Ack::VersionMismatch => (
+                        format!("Service {} was refused by the server", name),
+                        Some(Hint::ClientTooOld(name.clone())),
+                    ),
+                    _ => (format!("Service {} was refused by the server", name), None),
+                };
+                let err = anyhow::Error::new(v);
+                let err = match hint {
+                    Some(h) => error::hint(err, h),
+                    None => err,
+                };
+                return Err(err).context(msg);
+            }
+        };
+
+        if let Some(skew) = skew {
+            if skew.abs() >= CLOCK_SKEW_WARN {
+                warn!(
+                    "The clock of this device is {:+.1}s off from the server's",
+                    skew
+                );
             }
         }
 
         // Channel ready
-        info!("Control channel established");
+        info!(
+            server_capabilities = %protocol::fmt_capabilities(capabilities),
+            server_version = %server_version.as_deref().unwrap_or("unknown"),
+            "Control channel established"
+        );
+        self.established_at = Some(Instant::now());
+        let _events = ServiceUpGuard::new(&self.service.name);
+        if self.service.proxy_protocol.is_some() && capabilities & CAP_VISITOR_ADDR == 0 {
+            warn!("The server doesn't send the addresses of visitors. Please update it");
+        }
 
         let remote_addr = self.remote_addr.clone();
         let local_addr = self.service.local_addr.clone();
+        let (report_tx, mut report_rx) = mpsc::unbounded_channel();
+        let heartbeat_tx = report_tx.clone();
         let data_ch_args = Arc::new(RunDataChannelArgs {
+            service_name: self.service.name.clone(),
             session_key,
             remote_addr,
             local_addr,
+            helper: self.service.helper,
             connector: self.transport.clone(),
+            tasks: self.tasks.clone(),
+            report_tx: (capabilities & CAP_FORWARD_REPORT != 0).then_some(report_tx),
+            confirm: capabilities & CAP_FORWARD_CONFIRM != 0,
+            framing,
+            compression: protocol::compression_negotiated(capabilities),
+            visitor_addr: capabilities & CAP_VISITOR_ADDR != 0,
+            proxy_protocol: self.service.proxy_protocol,
+            budget: self.budget.clone(),
+            udp: UdpOptions {
+                limit: DatagramLimit::new(
+                    self.service.max_datagram_size,
+                    self.service.oversized_datagram,
+                ),
+                timeout: Duration::from_secs(self.service.udp_timeout.unwrap_or(UDP_TIMEOUT)),
+                keepalive: self.service.udp_keepalive.map(Duration::from_secs),
+            },
+            _runtime: self.runtime.clone(),
         });
 
-        loop {
-            tokio::select! {
-                val = read_control_cmd(&mut conn) => {
-                    let val = val?;
-                    debug!( "Received {:?}", val);
-                    match val {
-                        ControlChannelCmd::CreateDataChannel => {
-                            let args = data_ch_args.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = run_data_channel(args).await.with_context(|| "Failed to run the data channel") {
-                                    error!("{:?}", e);
-                                }
-                            }.instrument(Span::current()));
+        let spawn_data_channel = || {
+            let args = data_ch_args.clone();
+            self.tasks.spawn(
+                async move {
+                    if let Err(e) = run_data_channel(args.clone())
+                        .await
+                        .with_context(|| "Failed to run the data channel")
+                    {
+                        events::emit_error(&args.service_name, &e);
+                        error!("{:?}", e);
+                    }
+                }
+                .instrument(Span::current()),
+            );
+        };
+        // The server asks for one more for every visitor, which keeps the pool filled
+        if let Some(n) = self.service.pool_size {
+            debug!("Open {} data channels in advance", n);
+            (0..n).for_each(|_| spawn_data_channel());
+        }
+
+        // Visitors of reverse services come here, and are forwarded to the server
+        let listener = match &self.service.listen_addr {
+            Some(_) if capabilities & CAP_REVERSE == 0 => {
+                bail!("The server doesn't support reverse services. Please update it")
+            }
+            Some(addr) => Some(
+                TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Failed to listen at {}", addr))?,
+            ),
+            None => None,
+        };
+        let reverse = async {
+            let l = match &listener {
+                Some(v) => v,
+                None => return std::future::pending::<Result<()>>().await,
+            };
+            loop {
+                let (visitor, addr) = l.accept().await?;
+                debug!("New visitor from {}", privacy::addr(addr));
+                privacy::audit(&self.service.name, addr);
+                let args = data_ch_args.clone();
+                self.tasks.spawn(
+                    async move {
+                        if let Err(e) = run_reverse_data_channel(args.clone(), visitor)
+                            .await
+                            .with_context(|| "Failed to run the data channel")
+                        {
+                            events::emit_error(&args.service_name, &e);
+                            error!("{:?}", e);
+                        }
+                    }
+                    .instrument(Span::current()),
+                );
+            }
+        };
+
+        // Pings are only sent to servers that answer them. Without them, the server may stay quiet
+        // for long, so nothing is timed out
+        let interval = self
+            .service
+            .heartbeat_interval
+            .unwrap_or(HEARTBEAT_INTERVAL);
+        let ping = interval != 0 && capabilities & CAP_PING != 0;
+        let interval = Duration::from_secs(interval.max(1));
+        let timeout =
+            Duration::from_secs(self.service.heartbeat_timeout.unwrap_or(HEARTBEAT_TIMEOUT));
+        // When the ping waiting for its answer was sent, for the round-trip time
+        let ping_sent = Mutex::new(None);
+        let pings = async {
+            if !ping {
+                return std::future::pending::<Result<()>>().await;
+            }
+            let mut ticker = time::interval_at(Instant::now() + interval, interval);
+            loop {
+                ticker.tick().await;
+                ping_sent.lock().unwrap().get_or_insert_with(Instant::now);
+                let _ = heartbeat_tx.send(ClientControlChannelCmd::Ping);
+            }
+        };
+
+        let (mut rd, mut wr) = io::split(conn);
+        let reports = async move {
+            while let Some(cmd) = report_rx.recv().await {
+                wr.write_all(&framing.encode(&cmd)?).await?;
+                wr.flush().await?;
+            }
+            // Not reached while the commands are read, which hold a sender. Keep the write half open
+            std::future::pending::<Result<()>>().await
+        };
+
+        // The control channel is shutdown by dropping this future
+        let cmds = async {
+            loop {
+                let val = if ping {
+                    // Pongs come at least every `interval`
+                    match time::timeout(timeout, read_control_cmd(&mut rd, framing)).await {
+                        Ok(v) => v?,
+                        Err(_) => bail!(
+                            "Nothing from the server for {:?}. Consider it dead",
+                            timeout
+                        ),
+                    }
+                } else {
+                    read_control_cmd(&mut rd, framing).await?
+                };
+                debug!("Received {:?}", val);
+                match val {
+                    ControlChannelCmd::CreateDataChannel => spawn_data_channel(),
+                    ControlChannelCmd::Replaced => {
+                        // Don't reconnect, or the two clients will keep replacing each other
+                        warn!(
+                            "Another client has registered the service. Stop the control channel"
+                        );
+                        break;
+                    }
+                    ControlChannelCmd::Heartbeat => {
+                        let _ = heartbeat_tx.send(ClientControlChannelCmd::Heartbeat);
+                    }
+                    ControlChannelCmd::PublicAddr(addr) => {
+                        let addr = resolve_public_addr(&addr, &self.remote_addr);
+                        info!("Visitors reach the service at {}", addr);
+                        health::set_public_addr(&self.service.name, &addr);
+                        if let Some(ddns) = &self.ddns {
+                            ddns.publish(&addr);
+                        }
+                        events::emit(|| Event::PublicAddr {
+                            service: self.service.name.clone(),
+                            addr,
+                        });
+                    }
+                    ControlChannelCmd::Pong => {
+                        let sent = ping_sent.lock().unwrap().take();
+                        if let Some(t) = sent {
+                            let rtt = t.elapsed();
+                            debug!(?rtt, "Ping answered");
+                            state_dump::control_rtt(&self.service.name, rtt);
                         }
                     }
-                },
-                _ = &mut self.shutdown_rx => {
-                    break;
                 }
             }
+            Ok::<(), anyhow::Error>(())
+        };
+        tokio::select! {
+            r = cmds => r?,
+            r = reports => r?,
+            r = reverse => r?,
+            r = pings => r?,
         }
 
         info!("Control channel shutdown");
         Ok(())
     }
+
+    // Run the control channel, and reconnect when it breaks. Errors that retrying doesn't
+    // help with stop the service, leaving the others running
+    async fn run_with_retry(mut self, stopped_tx: mpsc::Sender<(String, anyhow::Error)>) {
+        let name = self.service.name.clone();
+        if let Err(err) = reconnect_loop(&mut self, &name).await {
+            error!("{}\n\nStop the service", error::report(&err));
+            events::emit_stopped(&name, &err);
+            let _ = stopped_tx.send((name, err)).await;
+        }
+    }
+}
+
+#[async_trait]
+impl<T: 'static + Transport> Connect for ControlChannel<T> {
+    async fn connect(&mut self) -> Result<()> {
+        self.run().await
+    }
+
+    fn lifetime(&mut self) -> Option<Duration> {
+        self.established_at.take().map(|t| t.elapsed())
+    }
 }
 
 impl ControlChannelHandle {
@@ -464,43 +1272,651 @@ impl ControlChannelHandle {
         service: ClientServiceConfig,
         remote_addr: String,
         transport: Arc<T>,
+        instance_id: InstanceId,
+        stopped_tx: mpsc::Sender<(String, anyhow::Error)>,
+        tasks: &TaskGroup,
     ) -> ControlChannelHandle {
         let digest = protocol::digest(service.name.as_bytes());
 
         info!("Starting {}", hex::encode(digest));
-        let (shutdown_tx, shutdown_rx) = oneshot::channel();
-        let mut s = ControlChannel {
-            digest,
-            service,
-            shutdown_rx,
-            remote_addr,
-            transport,
+        let configured = ConfiguredGuard::new(&service.name);
+        let log_level = LogLevelGuard::new(&service.name, service.log_level.as_deref());
+        let service_tasks = tasks.child();
+        let guard = service_tasks.cancel_on_drop();
+        let tasks = tasks.clone();
+        let budget = service.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        let runtime = if service.isolated {
+            ServiceRuntime::new(&service.name)
+                .map_err(|e| error!("{:#}. Run the service on the shared runtime", e))
+                .ok()
+        } else {
+            None
         };
+        let handle = runtime.as_ref().map(|v| v.handle.clone());
+        let ddns = service
+            .ddns
+            .clone()
+            .map(|c| Arc::new(Ddns::new(&service.name, c, service_tasks.clone())));
 
-        tokio::spawn(
-            async move {
-                while let Err(err) = s
-                    .run()
-                    .await
-                    .with_context(|| "Failed to run the control channel")
-                {
-                    if s.shutdown_rx.try_recv() != Err(oneshot::error::TryRecvError::Empty) {
-                        break;
+        let supervisor = async move {
+            // Restart the control channel if it panics, without affecting other services
+            let mut delay = Duration::from_secs(PANIC_RESTART_DELAY);
+            loop {
+                let s = ControlChannel {
+                    digest,
+                    service: service.clone(),
+                    remote_addr: remote_addr.clone(),
+                    transport: transport.clone(),
+                    instance_id,
+                    established_at: None,
+                    tasks: tasks.clone(),
+                    budget: budget.clone(),
+                    runtime: runtime.clone(),
+                    ddns: ddns.clone(),
+                    client_keys: HashMap::new(),
+                };
+                let ret = catch_panic(
+                    &service.name,
+                    s.run_with_retry(stopped_tx.clone())
+                        .instrument(Span::current()),
+                )
+                .await;
+                if ret.is_some() {
+                    break;
+                }
+
+                warn!("Restarting the control channel in {:?}", delay);
+                time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(PANIC_RESTART_MAX_DELAY));
+            }
+        }
+        .instrument(Span::current());
+        // Tasks spawned by the supervisor, like data channels, run on the same runtime
+        match handle {
+            Some(handle) => service_tasks.spawn_on(supervisor, &handle),
+            None => service_tasks.spawn(supervisor),
+        };
+
+        ControlChannelHandle {
+            _tasks: guard,
+            _configured: configured,
+            _log_level: log_level,
+        }
+    }
+}
+
+// A runtime of its own for an isolated service, driven by a thread of its own. It stops once the
+// service and all its data channels are gone
+#[derive(Clone)]
+struct ServiceRuntime {
+    handle: runtime::Handle,
+    _alive: mpsc::Sender<()>,
+}
+
+impl ServiceRuntime {
+    fn new(name: &str) -> Result<ServiceRuntime> {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .with_context(|| "Failed to create the runtime of the service")?;
+        let handle = rt.handle().clone();
+        let (tx, mut rx) = mpsc::channel::<()>(1);
+        thread::Builder::new()
+            .name(format!("rathole-{}", name))
+            .spawn(move || rt.block_on(rx.recv()))
+            .with_context(|| "Failed to spawn the thread of the service")?;
+        Ok(ServiceRuntime { handle, _alive: tx })
+    }
+}
+
+// A control channel as `reconnect_loop` sees it. The reconnection is kept apart from the
+// transport, so that it can be run with simulated time in tests
+#[async_trait]
+trait Connect: Send {
+    // Connect, and run the control channel until it ends
+    async fn connect(&mut self) -> Result<()>;
+    // How long the last control channel lived after established, or `None` if it wasn't
+    fn lifetime(&mut self) -> Option<Duration>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Next {
+    Retry(Duration), // Reconnect after the delay
+    Stop,
+}
+
+// Decides whether and when a control channel reconnects, from how the last one ended.
+// Shutdown and reload are not its business. They drop the task running it
+#[derive(Default)]
+struct Reconnect {
+    dampener: FlapDampener,
+}
+
+impl Reconnect {
+    fn next(&mut self, ret: &Result<()>, lifetime: Option<Duration>) -> Next {
+        match ret {
+            // Replaced by another client. Reconnecting would replace it back
+            Ok(()) => Next::Stop,
+            // Retrying with a wrong token is pointless
+            Err(e) if e.downcast_ref::<Failure>() == Some(&Failure::Auth) => Next::Stop,
+            Err(_) => Next::Retry(self.dampener.next_delay(lifetime)),
+        }
+    }
+}
+
+// Run `c` until `Reconnect` stops it, and return how it ended
+async fn reconnect_loop<C: Connect>(c: &mut C, name: &str) -> Result<()> {
+    let mut reconnect = Reconnect::default();
+    loop {
+        let ret = c
+            .connect()
+            .await
+            .with_context(|| "Failed to run the control channel");
+        if let Err(err) = &ret {
+            events::emit_error(name, err);
+        }
+        match (reconnect.next(&ret, c.lifetime()), ret) {
+            (Next::Retry(delay), Err(err)) => {
+                error!("{}\n\nRetry in {:?}...", error::report(&err), delay);
+                time::sleep(delay).await;
+            }
+            (_, ret) => return ret,
+        }
+    }
+}
+
+// Dampens the reconnection of a control channel that keeps bouncing,
+// like when the server is crash-looping or a NAT is dropping connections
+#[derive(Default)]
+struct FlapDampener {
+    flaps: u32, // The number of consecutive short-lived control channels
+}
+
+impl FlapDampener {
+    // Returns how long to wait before reconnecting. `lifetime` is how long the last
+    // control channel lived after established, or `None` if it failed to establish
+    fn next_delay(&mut self, lifetime: Option<Duration>) -> Duration {
+        match lifetime {
+            Some(t) if t >= Duration::from_secs(FLAP_STABLE_DURATION) => {
+                if self.flaps >= FLAP_THRESHOLD {
+                    info!("Control channel was stable for {:?}. Stop dampening", t);
+                }
+                self.flaps = 0;
+            }
+            Some(t) => {
+                self.flaps += 1;
+                if self.flaps == FLAP_THRESHOLD {
+                    warn!(
+                        "Control channel is flapping, lasting only {:?} after {} reconnections. Dampening the reconnection",
+                        t, self.flaps
+                    );
+                }
+            }
+            None => (),
+        }
+
+        if self.flaps < FLAP_THRESHOLD {
+            return Duration::from_secs(1);
+        }
+        // Double the hold-down time for every flap
+        let exp = (self.flaps - FLAP_THRESHOLD + 1).min(16);
+        Duration::from_secs((1u64 << exp).min(FLAP_MAX_HOLD_DOWN))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_flap_dampener() {
+        let mut d = FlapDampener::default();
+        let short = Some(Duration::from_secs(1));
+        let stable = Some(Duration::from_secs(FLAP_STABLE_DURATION));
+
+        // Failing to connect is not a flap
+        for _ in 0..10 {
+            assert_eq!(d.next_delay(None), Duration::from_secs(1));
+        }
+
+        for _ in 1..FLAP_THRESHOLD {
+            assert_eq!(d.next_delay(short), Duration::from_secs(1));
+        }
+        assert_eq!(d.next_delay(short), Duration::from_secs(2));
+        assert_eq!(d.next_delay(short), Duration::from_secs(4));
+        // Keeps the hold-down time while the server is unreachable
+        assert_eq!(d.next_delay(None), Duration::from_secs(4));
+        for _ in 0..20 {
+            d.next_delay(short);
+        }
+        assert_eq!(d.next_delay(short), Duration::from_secs(FLAP_MAX_HOLD_DOWN));
+
+        assert_eq!(d.next_delay(stable), Duration::from_secs(1));
+        assert_eq!(d.next_delay(short), Duration::from_secs(1));
+    }
+
+    // How a simulated control channel ends, after the handshake taking `LATENCY`
+    #[derive(Debug, Clone, Copy)]
+    enum Outcome {
+        Refused,
+        Lived(u64), // Established, and broken after the seconds
+        AuthFailed,
+    }
+
+    const LATENCY: Duration = Duration::from_millis(100);
+
+    fn outcome() -> impl Strategy<Value = Outcome> {
+        // Mostly flaps, which long sequences are needed to dampen
+        prop_oneof![
+            2 => Just(Outcome::Refused),
+            8 => (0..FLAP_STABLE_DURATION).prop_map(Outcome::Lived),
+            1 => (FLAP_STABLE_DURATION..FLAP_STABLE_DURATION * 2).prop_map(Outcome::Lived),
+            1 => Just(Outcome::AuthFailed),
+        ]
+    }
+
+    #[derive(Default)]
+    struct Timeline {
+        attempts: Vec<Instant>, // When each connection started
+        ended: usize,           // The number of connections that ran to their end
+    }
+
+    // A control channel that ends as scripted, and stays up once the script runs out
+    struct Simulated {
+        script: VecDeque<Outcome>,
+        established_at: Option<Instant>,
+        timeline: Arc<Mutex<Timeline>>,
+    }
+
+    impl Simulated {
+        fn new(script: &[Outcome]) -> (Simulated, Arc<Mutex<Timeline>>) {
+            let timeline = Arc::new(Mutex::new(Timeline::default()));
+            let c = Simulated {
+                script: script.iter().copied().collect(),
+                established_at: None,
+                timeline: timeline.clone(),
+            };
+            (c, timeline)
+        }
+    }
+
+    #[async_trait]
+    impl Connect for Simulated {
+        async fn connect(&mut self) -> Result<()> {
+            self.timeline.lock().unwrap().attempts.push(Instant::now());
+            let outcome = self.script.pop_front();
+            time::sleep(LATENCY).await;
+            let ret = match outcome {
+                Some(Outcome::Refused) => Err(anyhow!("Connection refused")),
+                Some(Outcome::Lived(t)) => {
+                    self.established_at = Some(Instant::now());
+                    time::sleep(Duration::from_secs(t)).await;
+                    Err(anyhow!("Connection reset"))
+                }
+                Some(Outcome::AuthFailed) => Err(anyhow!("Incorrect token")).context(Failure::Auth),
+                None => std::future::pending().await,
+            };
+            self.timeline.lock().unwrap().ended += 1;
+            ret
+        }
+
+        fn lifetime(&mut self) -> Option<Duration> {
+            self.established_at.take().map(|t| t.elapsed())
+        }
+    }
+
+    // Time only advances when all tasks are waiting, and then jumps to the next timer. So a
+    // simulation runs in no time, and ends up the same every time
+    fn simulation() -> runtime::Runtime {
+        runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap()
+    }
+
+    // Run `c` like `ControlChannelHandle` does, until the returned guard is dropped
+    fn spawn_simulated(c: Simulated, tasks: &TaskGroup) -> DropGuard {
+        let service_tasks = tasks.child();
+        let guard = service_tasks.cancel_on_drop();
+        service_tasks.spawn(async move {
+            let mut c = c;
+            reconnect_loop(&mut c, "test_reconnect").await
+        });
+        guard
+    }
+
+    // The delays before reconnecting, in whole seconds
+    fn delays(script: &[Outcome], timeline: &Timeline) -> Vec<u64> {
+        timeline
+            .attempts
+            .windows(2)
+            .zip(script)
+            .map(|(w, o)| {
+                let lived = match o {
+                    Outcome::Lived(t) => Duration::from_secs(*t),
+                    _ => Duration::ZERO,
+                };
+                (w[1] - w[0] - LATENCY - lived).as_secs_f64().round() as u64
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn test_reconnect_flapping(script in vec(outcome(), 0..40)) {
+            simulation().block_on(async {
+                let (mut c, timeline) = Simulated::new(&script);
+                let ret = time::timeout(
+                    Duration::from_secs(86400),
+                    reconnect_loop(&mut c, "test_reconnect"),
+                )
+                .await;
+                let timeline = timeline.lock().unwrap();
+
+                // Stops at a wrong token, and otherwise keeps reconnecting until it stays up
+                match script.iter().position(|o| matches!(o, Outcome::AuthFailed)) {
+                    Some(i) => {
+                        let err = ret.unwrap().unwrap_err();
+                        assert_eq!(err.downcast_ref::<Failure>(), Some(&Failure::Auth));
+                        assert_eq!(timeline.attempts.len(), i + 1);
+                    }
+                    None => {
+                        assert!(ret.is_err());
+                        assert_eq!(timeline.attempts.len(), script.len() + 1);
                     }
+                }
+
+                let mut flaps = 0;
+                let mut last = 1;
+                for (o, delay) in script.iter().zip(delays(&script, &timeline)) {
+                    match o {
+                        Outcome::Lived(t) if *t >= FLAP_STABLE_DURATION => flaps = 0,
+                        Outcome::Lived(_) => flaps += 1,
+                        _ => (),
+                    }
+                    assert!((1..=FLAP_MAX_HOLD_DOWN).contains(&delay));
+                    if flaps < FLAP_THRESHOLD {
+                        assert_eq!(delay, 1);
+                    } else {
+                        // Holds down longer and longer while the server flaps
+                        assert!(delay >= last);
+                    }
+                    last = delay;
+                }
+            });
+        }
+
+        #[test]
+        fn test_reconnect_shutdown(
+            script in vec(outcome(), 0..20),
+            shutdown_at in 0..3_000_000u64,
+        ) {
+            simulation().block_on(async {
+                let tasks = TaskGroup::new();
+                let (c, timeline) = Simulated::new(&script);
+                let guard = spawn_simulated(c, &tasks);
+                time::sleep(Duration::from_millis(shutdown_at)).await;
+                let (attempts, ended) = {
+                    let t = timeline.lock().unwrap();
+                    (t.attempts.len(), t.ended)
+                };
+
+                // Whether waiting to retry, handshaking or connected, nothing happens afterwards
+                drop(guard);
+                time::sleep(Duration::from_secs(86400)).await;
+                let t = timeline.lock().unwrap();
+                assert_eq!(t.attempts.len(), attempts);
+                assert_eq!(t.ended, ended);
+            });
+        }
+
+        #[test]
+        fn test_reconnect_reload(
+            script in vec(outcome(), 1..20),
+            reload in any::<prop::sample::Index>(),
+        ) {
+            simulation().block_on(async {
+                let tasks = TaskGroup::new();
+                let start = Instant::now();
+
+                // The same script gives the same timeline, so a reload can be timed to happen
+                // halfway through a handshake
+                let (c, timeline) = Simulated::new(&script);
+                let _guard = spawn_simulated(c, &tasks);
+                time::sleep(Duration::from_secs(86400)).await;
+                let attempts: Vec<_> = timeline
+                    .lock()
+                    .unwrap()
+                    .attempts
+                    .iter()
+                    .map(|t| *t - start)
+                    .collect();
+                let k = reload.index(attempts.len());
 
-                    let duration = Duration::from_secs(1);
-                    error!("{:?}\n\nRetry in {:?}...", err, duration);
-                    time::sleep(duration).await;
+                let start = Instant::now();
+                let (c, old) = Simulated::new(&script);
+                let guard = spawn_simulated(c, &tasks);
+                time::sleep(attempts[k] + LATENCY / 2).await;
+                drop(guard);
+                // The new config starts afresh, without waiting for the old one to end
+                let reloaded_at = Instant::now();
+                let (c, new) = Simulated::new(&[Outcome::Refused]);
+                let _guard = spawn_simulated(c, &tasks);
+                time::sleep(Duration::from_secs(86400)).await;
+
+                let old = old.lock().unwrap();
+                assert_eq!(old.attempts.len(), k + 1);
+                assert_eq!(old.ended, k);
+                let old: Vec<_> = old.attempts.iter().map(|t| *t - start).collect();
+                assert_eq!(old[..], attempts[..=k]);
+                let new = new.lock().unwrap();
+                assert_eq!(new.attempts[0], reloaded_at);
+                assert_eq!(delays(&[Outcome::Refused], &new), [1]);
+            });
+        }
+    }
+
+    #[test]
+    fn test_resolve_public_addr() {
+        let remote = "tunnel.example.com:2333";
+        assert_eq!(
+            resolve_public_addr("0.0.0.0:8080", remote),
+            "tunnel.example.com:8080"
+        );
+        assert_eq!(
+            resolve_public_addr("[::]:8080", "[2001:db8::1]:2333"),
+            "[2001:db8::1]:8080"
+        );
+        assert_eq!(
+            resolve_public_addr("203.0.113.5:443", remote),
+            "203.0.113.5:443"
+        );
+        assert_eq!(
+            resolve_public_addr("www.example.com:443", remote),
+            "www.example.com:443"
+        );
+    }
+
+    // Run a forwarder for a visitor of `local`, and return when it ends
+    fn forwarder(local: SocketAddr, keepalive: Option<Duration>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let s = udp_connect(local).await.unwrap();
+            let (_inbound_tx, inbound_rx) = mpsc::channel(1);
+            let (outbound_tx, _outbound_rx) = mpsc::channel(1);
+            let udp = UdpOptions {
+                limit: DatagramLimit::new(None, Default::default()),
+                timeout: Duration::from_millis(200),
+                keepalive,
+            };
+            run_udp_forwarder(
+                s,
+                inbound_rx,
+                outbound_tx,
+                "127.0.0.1:1".parse().unwrap(),
+                Default::default(),
+                None,
+                UdpSessionGuard::new("test_udp_keepalive"),
+                udp,
+            )
+            .await
+            .unwrap();
+        })
+    }
+
+    #[tokio::test]
+    async fn test_udp_keepalive() {
+        let local = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = local.local_addr().unwrap();
+
+        let f = forwarder(addr, None);
+        time::timeout(Duration::from_secs(1), f)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Outlives the timeout, and ends once the local service has gone
+        let f = forwarder(addr, Some(Duration::from_millis(50)));
+        let mut buf = [0u8; 16];
+        for _ in 0..8 {
+            let n = local.recv(&mut buf).await.unwrap();
+            assert_eq!(n, 0);
+        }
+        assert!(!f.is_finished());
+        drop(local);
+        time::timeout(Duration::from_secs(1), f)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_hello() {
+        // A server older than PROTO_V1, which only knows hellos of 37 bytes tagged 0 or 1
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut tags = Vec::new();
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 37];
+                conn.read_exact(&mut buf).await.unwrap();
+                tags.push(buf[0]);
+                if buf[0] != 0 {
+                    continue;
                 }
+                let hello = Hello::ControlChannelHello(PROTO_V0, [0u8; HASH_WIDTH_IN_BYTES]);
+                conn.write_all(&bincode::serialize(&hello).unwrap())
+                    .await
+                    .unwrap();
+                let mut auth = [0u8; HASH_WIDTH_IN_BYTES];
+                conn.read_exact(&mut auth).await.unwrap();
+                conn.write_all(&bincode::serialize(&Ack::AuthFailed).unwrap())
+                    .await
+                    .unwrap();
+                return tags;
             }
-            .instrument(Span::current()),
+        });
+
+        let service = ClientServiceConfig {
+            token: Some("token".into()),
+            ..ClientServiceConfig::with_name("foo")
+        };
+        let mut s = ControlChannel {
+            digest: protocol::digest(b"foo"),
+            service,
+            remote_addr: addr,
+            transport: Arc::new(TcpTransport::new(&Default::default()).await.unwrap()),
+            instance_id: Default::default(),
+            established_at: None,
+            tasks: TaskGroup::new(),
+            budget: None,
+            runtime: None,
+            ddns: None,
+            client_keys: HashMap::new(),
+        };
+        let err = s.run().await.unwrap_err();
+        assert_eq!(
+            err.chain().find_map(|c| c.downcast_ref::<Ack>()),
+            Some(&Ack::AuthFailed)
         );
+        assert_eq!(server.await.unwrap(), [2, 0]);
+    }
 
-        ControlChannelHandle { shutdown_tx }
+    // The `u64` fields recorded to spans
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<std::sync::Mutex<HashMap<&'static str, u64>>>);
+
+    impl tracing::field::Visit for Recorder {
+        fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+            self.0.lock().unwrap().insert(field.name(), value);
+        }
+
+        fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
     }
 
-    fn shutdown(self) {
-        // A send failure shows that the actor has already shutdown.
-        let _ = self.shutdown_tx.send(0u8);
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Recorder {
+        fn on_record(
+            &self,
+            _: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_session_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let recorder = Recorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let local = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s = udp_connect(local.local_addr().unwrap()).await.unwrap();
+        let (inbound_tx, inbound_rx) = mpsc::channel(1);
+        let (outbound_tx, mut outbound_rx) = mpsc::channel(1);
+        let udp = UdpOptions {
+            limit: DatagramLimit::new(None, Default::default()),
+            timeout: Duration::from_secs(10),
+            keepalive: None,
+        };
+        let forwarder = run_udp_forwarder(
+            s,
+            inbound_rx,
+            outbound_tx,
+            "127.0.0.1:1".parse().unwrap(),
+            Default::default(),
+            None,
+            UdpSessionGuard::new("test_udp_session_span"),
+            udp,
+        );
+        let session = async {
+            inbound_tx.send(Bytes::from_static(b"ping")).await.unwrap();
+            let mut buf = [0u8; 16];
+            let (n, peer) = local.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"ping");
+            local.send_to(b"pong!", peer).await.unwrap();
+            assert_eq!(&outbound_rx.recv().await.unwrap().data[..], b"pong!");
+            // Ends the session
+            drop(inbound_tx);
+        };
+        let (ret, _) = tokio::join!(forwarder, session);
+        ret.unwrap();
+
+        let recorded = recorder.0.lock().unwrap().clone();
+        let expected = [
+            ("packets_to_service", 1),
+            ("bytes_to_service", 4),
+            ("packets_from_service", 1),
+            ("bytes_from_service", 5),
+            ("dropped", 0),
+        ];
+        assert_eq!(recorded, HashMap::from(expected));
     }
 }
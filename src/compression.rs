@@ -0,0 +1,468 @@
+// Compression of what's forwarded through TCP data channels, with `compression`. Every write is
+// compressed into a block of its own, so nothing is held back waiting for more. A block is a byte
+// of its kind, the length of its payload in a big-endian `u16`, and the payload. Blocks that don't
+// get smaller are stored as they are. After several of them in a row, like for video or archives,
+// blocks are stored without trying for a while, so already compressed streams cost little CPU
+use crate::config::{Compression, CompressionPreference};
+use crate::protocol::{local_capabilities, Capabilities, CAP_COMPRESSION, CAP_LZ4, CAP_ZSTD};
+use anyhow::{bail, Result};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// The most bytes of a block before compression
+const BLOCK_MAX_LEN: usize = 16 * 1024;
+const HEADER_LEN: usize = 3;
+const BLOCK_STORED: u8 = 0;
+const BLOCK_COMPRESSED: u8 = 1;
+// The blocks in a row that don't get smaller before compression is skipped
+const INCOMPRESSIBLE_LIMIT: u32 = 4;
+// The blocks skipped at first, doubled each time trying again doesn't help, up to the most
+const SKIP_MIN_BLOCKS: u32 = 16;
+const SKIP_MAX_BLOCKS: u32 = 1024;
+
+fn capability(compression: Compression) -> Capabilities {
+    match compression {
+        Compression::Zstd => CAP_ZSTD,
+        Compression::Lz4 => CAP_LZ4,
+    }
+}
+
+// The codecs of the peer, as told by its capabilities. Peers with `CAP_COMPRESSION` from before
+// the codecs were told had all of them
+fn peer_codecs(capabilities: Capabilities) -> Capabilities {
+    match capabilities & (CAP_ZSTD | CAP_LZ4) {
+        0 if capabilities & CAP_COMPRESSION != 0 => CAP_ZSTD | CAP_LZ4,
+        v => v,
+    }
+}
+
+// The first codec in `preference` both ends have, or none
+fn pick(
+    preference: Option<&CompressionPreference>,
+    local: Capabilities,
+    peer: Capabilities,
+) -> Option<Compression> {
+    let both = local & peer_codecs(peer);
+    preference?
+        .codecs()
+        .iter()
+        .copied()
+        .find(|c| both & capability(*c) != 0)
+}
+
+// The compression of the data channels of a service to the peer with `capabilities`
+pub(crate) fn negotiate(
+    preference: Option<&CompressionPreference>,
+    capabilities: Capabilities,
+) -> Option<Compression> {
+    pick(preference, local_capabilities(), capabilities)
+}
+
+// The byte of the compression of a TCP data channel, which follows its command if both ends have
+// `CAP_COMPRESSION`
+pub(crate) fn to_byte(compression: Option<Compression>) -> u8 {
+    match compression {
+        None => 0,
+        Some(Compression::Zstd) => 1,
+        Some(Compression::Lz4) => 2,
+    }
+}
+
+pub(crate) fn from_byte(v: u8) -> Result<Option<Compression>> {
+    Ok(match v {
+        0 => None,
+        1 => Some(Compression::Zstd),
+        2 => Some(Compression::Lz4),
+        v => bail!("Unknown compression {}", v),
+    })
+}
+
+// The state of compression of a data channel
+#[cfg(feature = "compression")]
+pub(crate) enum Codec {
+    Zstd(
+        zstd::bulk::Compressor<'static>,
+        zstd::bulk::Decompressor<'static>,
+    ),
+    Lz4,
+}
+
+#[cfg(feature = "compression")]
+impl Codec {
+    pub(crate) fn new(compression: Compression) -> io::Result<Codec> {
+        Ok(match compression {
+            Compression::Zstd => Codec::Zstd(
+                zstd::bulk::Compressor::new(zstd::DEFAULT_COMPRESSION_LEVEL)?,
+                zstd::bulk::Decompressor::new()?,
+            ),
+            Compression::Lz4 => Codec::Lz4,
+        })
+    }
+
+    // Append `data` compressed to `out`, unless it doesn't get smaller
+    fn compress(&mut self, data: &[u8], out: &mut Vec<u8>) -> bool {
+        let start = out.len();
+        let len = match self {
+            // Fails if it doesn't fit
+            Codec::Zstd(c, _) => {
+                out.resize(start + data.len() - 1, 0);
+                c.compress_to_buffer(data, &mut out[start..]).ok()
+            }
+            // Needs room for the worst case
+            Codec::Lz4 => {
+                out.resize(
+                    start + lz4_flex::block::get_maximum_output_size(data.len()),
+                    0,
+                );
+                lz4_flex::block::compress_into(data, &mut out[start..])
+                    .ok()
+                    .filter(|n| *n < data.len())
+            }
+        };
+        out.truncate(start + len.unwrap_or_default());
+        len.is_some()
+    }
+
+    fn decompress(&mut self, data: &[u8], out: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Codec::Zstd(_, d) => d.decompress_to_buffer(data, out),
+            Codec::Lz4 => lz4_flex::block::decompress_into(data, out)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+// Never built, since neither end asks for compression without the feature
+#[cfg(not(feature = "compression"))]
+pub(crate) enum Codec {}
+
+#[cfg(not(feature = "compression"))]
+impl Codec {
+    pub(crate) fn new(_: Compression) -> io::Result<Codec> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The feature 'compression' is not compiled",
+        ))
+    }
+
+    fn compress(&mut self, _: &[u8], _: &mut Vec<u8>) -> bool {
+        match *self {}
+    }
+
+    fn decompress(&mut self, _: &[u8], _: &mut [u8]) -> io::Result<usize> {
+        match *self {}
+    }
+}
+
+// Forwards through `inner` as it is without compression, so either kind of data channel can be
+// passed to the same code
+pub(crate) struct CompressedStream<S> {
+    inner: S,
+    codec: Option<Codec>,
+    // The header of the block being read, and how much of it has been
+    header: [u8; HEADER_LEN],
+    header_read: usize,
+    // The payload of the block being read, and how much of it has been
+    payload: Vec<u8>,
+    payload_read: usize,
+    // What's decompressed of the last block, and how much of it has been returned
+    decoded: Vec<u8>,
+    decoded_pos: usize,
+    // A block waiting to be written, and how much of it has been
+    out: Vec<u8>,
+    written: usize,
+    // The blocks in a row that didn't get smaller, the blocks left to store without trying, and
+    // how many to skip next time
+    misses: u32,
+    skip: u32,
+    backoff: u32,
+}
+
+impl<S> CompressedStream<S> {
+    pub(crate) fn new(inner: S, codec: Option<Codec>) -> CompressedStream<S> {
+        CompressedStream {
+            inner,
+            codec,
+            header: [0; HEADER_LEN],
+            header_read: 0,
+            payload: Vec::new(),
+            payload_read: 0,
+            decoded: Vec::new(),
+            decoded_pos: 0,
+            out: Vec::new(),
+            written: 0,
+            misses: 0,
+            skip: 0,
+            backoff: SKIP_MIN_BLOCKS,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    // Count a block tried, and skip the next ones if too many in a row didn't get smaller
+    fn count(&mut self, compressed: bool) {
+        if compressed {
+            self.misses = 0;
+            self.backoff = SKIP_MIN_BLOCKS;
+            return;
+        }
+        self.misses += 1;
+        if self.misses >= INCOMPRESSIBLE_LIMIT {
+            self.misses = 0;
+            self.skip = self.backoff;
+            self.backoff = (self.backoff * 2).min(SKIP_MAX_BLOCKS);
+        }
+    }
+
+    fn decode(&mut self) -> io::Result<()> {
+        let codec = self.codec.as_mut().unwrap();
+        match self.header[0] {
+            BLOCK_STORED => std::mem::swap(&mut self.decoded, &mut self.payload),
+            BLOCK_COMPRESSED => {
+                self.decoded.resize(BLOCK_MAX_LEN, 0);
+                let n = codec.decompress(&self.payload, &mut self.decoded)?;
+                self.decoded.truncate(n);
+            }
+            v => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown kind of block {}", v),
+                ))
+            }
+        }
+        self.decoded_pos = 0;
+        self.header_read = 0;
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CompressedStream<S> {
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.out.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CompressedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.codec.is_none() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if this.decoded_pos < this.decoded.len() {
+                let n = (this.decoded.len() - this.decoded_pos).min(buf.remaining());
+                buf.put_slice(&this.decoded[this.decoded_pos..this.decoded_pos + n]);
+                this.decoded_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.header_read < HEADER_LEN {
+                let mut header = ReadBuf::new(&mut this.header[this.header_read..]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut header))?;
+                let n = header.filled().len();
+                match n {
+                    // Only between blocks is the end clean
+                    0 if this.header_read == 0 => return Poll::Ready(Ok(())),
+                    0 => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                    _ => {}
+                }
+                this.header_read += n;
+                if this.header_read == HEADER_LEN {
+                    let len = u16::from_be_bytes([this.header[1], this.header[2]]) as usize;
+                    this.payload.resize(len, 0);
+                    this.payload_read = 0;
+                }
+                continue;
+            }
+            if this.payload_read < this.payload.len() {
+                let mut payload = ReadBuf::new(&mut this.payload[this.payload_read..]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut payload))?;
+                let n = payload.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.payload_read += n;
+                continue;
+            }
+            this.decode()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CompressedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.codec.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        ready!(this.poll_write_out(cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = data.len().min(BLOCK_MAX_LEN);
+        this.out.resize(HEADER_LEN, 0);
+        let compressed = if this.skip > 0 {
+            this.skip -= 1;
+            false
+        } else {
+            let codec = this.codec.as_mut().unwrap();
+            let compressed = codec.compress(&data[..n], &mut this.out);
+            this.count(compressed);
+            compressed
+        };
+        this.out[0] = match compressed {
+            true => BLOCK_COMPRESSED,
+            false => {
+                this.out.extend_from_slice(&data[..n]);
+                BLOCK_STORED
+            }
+        };
+        let len = (this.out.len() - HEADER_LEN) as u16;
+        this.out[1..HEADER_LEN].copy_from_slice(&len.to_be_bytes());
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "compression")]
+    use rand::RngCore;
+    #[cfg(feature = "compression")]
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_pick() {
+        let both = CompressionPreference::Many(vec![Compression::Lz4, Compression::Zstd]);
+        let zstd = CAP_COMPRESSION | CAP_ZSTD;
+        let lz4 = CAP_COMPRESSION | CAP_LZ4;
+        // The first codec in the preference that both ends have
+        assert_eq!(
+            pick(Some(&both), zstd | CAP_LZ4, zstd | CAP_LZ4),
+            Some(Compression::Lz4)
+        );
+        assert_eq!(
+            pick(Some(&both), zstd | CAP_LZ4, zstd),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(
+            pick(Some(&both), lz4, zstd | CAP_LZ4),
+            Some(Compression::Lz4)
+        );
+        // No codec in common, or none asked for
+        assert_eq!(pick(Some(&both), zstd, lz4), None);
+        let one = CompressionPreference::One(Compression::Zstd);
+        assert_eq!(pick(Some(&one), zstd | CAP_LZ4, lz4), None);
+        assert_eq!(pick(None, zstd, zstd), None);
+        // Peers without compression, and ones from before the codecs were told
+        assert_eq!(pick(Some(&both), lz4, 0), None);
+        assert_eq!(
+            pick(Some(&one), zstd, CAP_COMPRESSION),
+            Some(Compression::Zstd)
+        );
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_compressed_stream() {
+        let text = b"GET /api/v1/items HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(2000);
+        let mut noise = vec![0u8; 40000];
+        rand::thread_rng().fill_bytes(&mut noise);
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            for (data, compressible) in [(&text, true), (&noise, false)] {
+                let mut wire = Vec::new();
+                let mut w =
+                    CompressedStream::new(&mut wire, Some(Codec::new(compression).unwrap()));
+                w.write_all(data).await.unwrap();
+                w.shutdown().await.unwrap();
+                // Incompressible data costs no more than the headers
+                if compressible {
+                    assert!(wire.len() < data.len() / 4);
+                } else {
+                    assert!(
+                        wire.len() <= data.len() + HEADER_LEN * data.len().div_ceil(BLOCK_MAX_LEN)
+                    );
+                }
+
+                let mut r =
+                    CompressedStream::new(&wire[..], Some(Codec::new(compression).unwrap()));
+                let mut received = Vec::new();
+                r.read_to_end(&mut received).await.unwrap();
+                assert_eq!(&received, data);
+
+                // Cut in the middle of a block
+                let mut r = CompressedStream::new(
+                    &wire[..wire.len() - 1],
+                    Some(Codec::new(compression).unwrap()),
+                );
+                assert!(r.read_to_end(&mut Vec::new()).await.is_err());
+            }
+        }
+
+        // Stops trying after incompressible blocks, and tries again later
+        let mut noise = vec![0u8; BLOCK_MAX_LEN * INCOMPRESSIBLE_LIMIT as usize];
+        rand::thread_rng().fill_bytes(&mut noise);
+        let mut wire = Vec::new();
+        let mut w = CompressedStream::new(&mut wire, Some(Codec::new(Compression::Lz4).unwrap()));
+        w.write_all(&noise).await.unwrap();
+        assert_eq!(w.skip, SKIP_MIN_BLOCKS);
+        for _ in 0..SKIP_MIN_BLOCKS {
+            w.write_all(&text[..BLOCK_MAX_LEN]).await.unwrap();
+        }
+        w.write_all(&text[..BLOCK_MAX_LEN]).await.unwrap();
+        w.shutdown().await.unwrap();
+        let kinds: Vec<u8> = wire
+            .chunks(HEADER_LEN + BLOCK_MAX_LEN)
+            .map(|b| b[0])
+            .collect();
+        assert!(kinds[..kinds.len() - 1].iter().all(|k| *k == BLOCK_STORED));
+        assert_eq!(kinds.last(), Some(&BLOCK_COMPRESSED));
+
+        // Without compression, it's forwarded as it is
+        let mut wire = Vec::new();
+        let mut w = CompressedStream::new(&mut wire, None);
+        w.write_all(b"raw").await.unwrap();
+        assert_eq!(wire, b"raw");
+        assert_eq!(
+            from_byte(to_byte(Some(Compression::Lz4))).unwrap(),
+            Some(Compression::Lz4)
+        );
+        assert!(from_byte(3).is_err());
+    }
+}
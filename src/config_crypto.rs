@@ -0,0 +1,130 @@
+use anyhow::{bail, Context, Result};
+
+// An encrypted config starts with this line, followed by the nonce and the ciphertext in hex
+const MAGIC: &str = "rathole-encrypted-config-v1\n";
+
+// The key is 32 bytes in hex, either in the variable, or in the file that the other variable
+// points to. The file can be on a removable drive, or provided by systemd credentials
+const KEY_ENV: &str = "RATHOLE_CONFIG_KEY";
+const KEY_FILE_ENV: &str = "RATHOLE_CONFIG_KEY_FILE";
+
+pub fn is_encrypted(s: &str) -> bool {
+    s.starts_with(MAGIC)
+}
+
+#[cfg_attr(not(feature = "config-encryption"), allow(dead_code))]
+fn load_key() -> Result<[u8; 32]> {
+    let key = match std::env::var(KEY_ENV) {
+        Ok(k) => k,
+        Err(_) => match std::env::var(KEY_FILE_ENV) {
+            Ok(path) => std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read the key file {}", path))?,
+            Err(_) => bail!("Neither {} nor {} is set", KEY_ENV, KEY_FILE_ENV),
+        },
+    };
+    let mut buf = [0u8; 32];
+    hex::decode_to_slice(key.trim(), &mut buf)
+        .with_context(|| "The key must be 32 bytes in hex")?;
+    Ok(buf)
+}
+
+#[cfg(feature = "config-encryption")]
+mod aead {
+    use super::*;
+    use aes_gcm::aead::{Aead, NewAead};
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+    use rand::RngCore;
+
+    const NONCE_LEN: usize = 12;
+
+    // The nonce followed by the ciphertext
+    pub fn seal(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let cipher = Aes256Gcm::new(Key::from_slice(key));
+        let mut data = Vec::from(&nonce[..]);
+        data.append(
+            &mut cipher
+                .encrypt(Nonce::from_slice(&nonce), plaintext)
+                .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?,
+        );
+        Ok(data)
+    }
+
+    pub fn open(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < NONCE_LEN {
+            bail!("Malformed ciphertext");
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new(Key::from_slice(key));
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Wrong key, or the data is corrupted"))
+    }
+
+    pub fn encrypt(key: &[u8; 32], plaintext: &str) -> Result<String> {
+        let data = seal(key, plaintext.as_bytes())?;
+        Ok(format!("{}{}\n", MAGIC, hex::encode(data)))
+    }
+
+    pub fn decrypt(key: &[u8; 32], s: &str) -> Result<String> {
+        let data = hex::decode(s[MAGIC.len()..].trim()).with_context(|| "Malformed ciphertext")?;
+        let plaintext = open(key, &data)?;
+        String::from_utf8(plaintext).with_context(|| "The config is not valid UTF-8")
+    }
+}
+
+// For other secrets sealed with keys of their own, like bundles
+#[cfg(feature = "config-encryption")]
+pub(crate) use aead::{open, seal};
+
+#[cfg(feature = "config-encryption")]
+pub fn encrypt(plaintext: &str) -> Result<String> {
+    aead::encrypt(&load_key()?, plaintext)
+}
+
+#[cfg(feature = "config-encryption")]
+pub fn decrypt(s: &str) -> Result<String> {
+    aead::decrypt(&load_key()?, s)
+}
+
+#[cfg(not(feature = "config-encryption"))]
+pub fn encrypt(_plaintext: &str) -> Result<String> {
+    crate::helper::feature_not_compile("config-encryption")
+}
+
+// Hot-reloaded configs are decrypted as well, so don't exit here
+#[cfg(not(feature = "config-encryption"))]
+pub fn decrypt(_s: &str) -> Result<String> {
+    bail!("The feature 'config-encryption' is not compiled in this binary")
+}
+
+#[cfg(not(feature = "config-encryption"))]
+pub(crate) fn seal(_key: &[u8; 32], _plaintext: &[u8]) -> Result<Vec<u8>> {
+    bail!("The feature 'config-encryption' is not compiled in this binary")
+}
+
+#[cfg(not(feature = "config-encryption"))]
+pub(crate) fn open(_key: &[u8; 32], _data: &[u8]) -> Result<Vec<u8>> {
+    bail!("The feature 'config-encryption' is not compiled in this binary")
+}
+
+#[cfg(all(test, feature = "config-encryption"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = [7u8; 32];
+        let s = aead::encrypt(&key, "[client]\n").unwrap();
+        assert!(is_encrypted(&s));
+        assert_ne!(s, aead::encrypt(&key, "[client]\n").unwrap());
+        assert_eq!(aead::decrypt(&key, &s).unwrap(), "[client]\n");
+
+        assert!(aead::decrypt(&[8u8; 32], &s).is_err());
+        let mut tampered = s.into_bytes();
+        let i = tampered.len() - 2;
+        tampered[i] = if tampered[i] == b'0' { b'1' } else { b'0' };
+        assert!(aead::decrypt(&key, &String::from_utf8(tampered).unwrap()).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{
     config::{ClientConfig, ClientServiceConfig, ServerConfig, ServerServiceConfig},
+    helper::recv_shutdown,
     Config,
 };
 use anyhow::{Context, Result};
@@ -8,6 +9,7 @@ use std::{
     path::{Path, PathBuf},
 };
 use tokio::sync::{broadcast, mpsc};
+use tokio_util::task::AbortOnDropHandle;
 use tracing::{error, info, instrument};
 
 #[cfg(feature = "notify")]
@@ -21,21 +23,21 @@ pub enum ConfigChange {
 
 #[derive(Debug, PartialEq)]
 pub enum ServiceChange {
-    ClientAdd(ClientServiceConfig),
+    ClientAdd(Box<ClientServiceConfig>),
     ClientDelete(String),
-    ServerAdd(ServerServiceConfig),
+    ServerAdd(Box<ServerServiceConfig>),
     ServerDelete(String),
 }
 
 impl From<ClientServiceConfig> for ServiceChange {
     fn from(c: ClientServiceConfig) -> Self {
-        ServiceChange::ClientAdd(c)
+        ServiceChange::ClientAdd(Box::new(c))
     }
 }
 
 impl From<ServerServiceConfig> for ServiceChange {
     fn from(c: ServerServiceConfig) -> Self {
-        ServiceChange::ServerAdd(c)
+        ServiceChange::ServerAdd(Box::new(c))
     }
 }
 
@@ -94,6 +96,8 @@ impl InstanceConfig for ClientConfig {
 
 pub struct ConfigWatcherHandle {
     pub event_rx: mpsc::Receiver<ConfigChange>,
+    // Stop watching when the handle is dropped
+    _watcher: AbortOnDropHandle<Result<()>>,
 }
 
 impl ConfigWatcherHandle {
@@ -108,14 +112,17 @@ impl ConfigWatcherHandle {
             .await
             .unwrap();
 
-        tokio::spawn(config_watcher(
+        let watcher = tokio::spawn(config_watcher(
             path.to_owned(),
             shutdown_rx,
             event_tx,
             origin_cfg,
         ));
 
-        Ok(ConfigWatcherHandle { event_rx })
+        Ok(ConfigWatcherHandle {
+            event_rx,
+            _watcher: AbortOnDropHandle::new(watcher),
+        })
     }
 }
 
@@ -128,7 +135,7 @@ async fn config_watcher(
     _old: Config,
 ) -> Result<()> {
     // Do nothing except waiting for ctrl-c
-    let _ = shutdown_rx.recv().await;
+    recv_shutdown(&mut shutdown_rx).await;
     Ok(())
 }
 
@@ -179,7 +186,7 @@ async fn config_watcher(
               None => break
             }
           },
-          _ = shutdown_rx.recv() => break
+          _ = recv_shutdown(&mut shutdown_rx) => break
         }
     }
 
@@ -193,6 +200,10 @@ fn calculate_events(old: &Config, new: &Config) -> Vec<ConfigChange> {
         return vec![];
     }
 
+    if old.admin != new.admin {
+        return vec![ConfigChange::General(Box::new(new.clone()))];
+    }
+
     let mut ret = vec![];
 
     if old.server != new.server {
@@ -267,7 +278,7 @@ fn calculate_service_add_events<T: PartialEq + Clone + Into<ServiceChange>>(
 
 #[cfg(test)]
 mod test {
-    use crate::config::ServerConfig;
+    use crate::config::{AdminConfig, ServerConfig};
 
     use super::*;
 
@@ -292,10 +303,14 @@ mod test {
                 old: Config {
                     server: Some(Default::default()),
                     client: None,
+                    admin: None,
+                    privacy: None,
                 },
                 new: Config {
                     server: Some(Default::default()),
                     client: Some(Default::default()),
+                    admin: None,
+                    privacy: None,
                 },
             },
             Test {
@@ -305,6 +320,8 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    admin: None,
+                    privacy: None,
                 },
                 new: Config {
                     server: Some(ServerConfig {
@@ -313,12 +330,16 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    admin: None,
+                    privacy: None,
                 },
             },
             Test {
                 old: Config {
                     server: Some(Default::default()),
                     client: None,
+                    admin: None,
+                    privacy: None,
                 },
                 new: Config {
                     server: Some(ServerConfig {
@@ -326,6 +347,8 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    admin: None,
+                    privacy: None,
                 },
             },
             Test {
@@ -335,10 +358,14 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    admin: None,
+                    privacy: None,
                 },
                 new: Config {
                     server: Some(Default::default()),
                     client: None,
+                    admin: None,
+                    privacy: None,
                 },
             },
             Test {
@@ -351,6 +378,8 @@ mod test {
                         services: collection!(String::from("foo1") => ClientServiceConfig::with_name("foo1"), String::from("foo2") => ClientServiceConfig::with_name("foo2")),
                         ..Default::default()
                     }),
+                    admin: None,
+                    privacy: None,
                 },
                 new: Config {
                     server: Some(ServerConfig {
@@ -361,6 +390,26 @@ mod test {
                         services: collection!(String::from("bar1") => ClientServiceConfig::with_name("bar1"), String::from("bar2") => ClientServiceConfig::with_name("bar2")),
                         ..Default::default()
                     }),
+                    admin: None,
+                    privacy: None,
+                },
+            },
+            Test {
+                old: Config {
+                    server: Some(Default::default()),
+                    client: None,
+                    admin: None,
+                    privacy: None,
+                },
+                new: Config {
+                    server: Some(Default::default()),
+                    client: None,
+                    admin: Some(AdminConfig {
+                        bind_addr: String::from("127.0.0.1:7000"),
+                        token: None,
+                        dump_dir: None,
+                    }),
+                    privacy: None,
                 },
             },
         ];
@@ -376,18 +425,19 @@ mod test {
             ))],
             vec![
                 ConfigChange::ServiceChange(ServiceChange::ServerDelete(String::from("foo1"))),
-                ConfigChange::ServiceChange(ServiceChange::ServerAdd(
+                ConfigChange::ServiceChange(ServiceChange::ServerAdd(Box::new(
                     tests[4].new.server.as_ref().unwrap().services["bar1"].clone(),
-                )),
+                ))),
                 ConfigChange::ServiceChange(ServiceChange::ClientDelete(String::from("foo1"))),
                 ConfigChange::ServiceChange(ServiceChange::ClientDelete(String::from("foo2"))),
-                ConfigChange::ServiceChange(ServiceChange::ClientAdd(
+                ConfigChange::ServiceChange(ServiceChange::ClientAdd(Box::new(
                     tests[4].new.client.as_ref().unwrap().services["bar1"].clone(),
-                )),
-                ConfigChange::ServiceChange(ServiceChange::ClientAdd(
+                ))),
+                ConfigChange::ServiceChange(ServiceChange::ClientAdd(Box::new(
                     tests[4].new.client.as_ref().unwrap().services["bar2"].clone(),
-                )),
+                ))),
             ],
+            vec![ConfigChange::General(Box::new(tests[5].new.clone()))],
         ];
 
         assert_eq!(tests.len(), expected.len());
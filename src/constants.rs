@@ -4,8 +4,203 @@ use std::time::Duration;
 // FIXME: Determine reasonable size
 /// UDP MTU. Currently far larger than necessary
 pub const UDP_BUFFER_SIZE: usize = 2048;
+
+/// The largest payload of a UDP datagram over IPv4
+pub const UDP_MAX_DATAGRAM_SIZE: usize = 65507;
 pub const UDP_SENDQ_SIZE: usize = 1024;
 pub const UDP_TIMEOUT: u64 = 60;
+/// Datagrams are received into slices of a shared buffer of this size, saving an allocation for each
+pub const UDP_RECV_ARENA_SIZE: usize = 64 * 1024;
+
+/// The maximum length of a visitor key, excluding the trailing newline
+pub const VISITOR_KEY_MAX_LEN: usize = 256;
+/// Timeout in seconds for a visitor to present its key
+pub const VISITOR_AUTH_TIMEOUT: u64 = 5;
+/// The maximum number of visitors waiting for the authentication. Further ones are closed at once
+pub const VISITOR_AUTH_MAX_PENDING: usize = 1024;
+/// The maximum number of bytes peeked for the SNI, which is a whole TLS record
+pub const SNI_PEEK_MAX_LEN: usize = 5 + 16384;
+/// The maximum number of bytes peeked for the request head of a HTTP visitor
+pub const HTTP_HEAD_PEEK_MAX_LEN: usize = 8 * 1024;
+/// The default size in bytes of the response cache of a HTTP service
+pub const HTTP_CACHE_MAX_SIZE: usize = 64 * 1024 * 1024;
+/// The default size in bytes of the largest response body cached
+pub const HTTP_CACHE_MAX_ENTRY_SIZE: usize = 1024 * 1024;
+/// The default size in bytes of the largest range prefetched for a visitor of a HTTP service
+pub const RANGE_PREFETCH_MAX_SIZE: usize = 8 * 1024 * 1024;
+
+/// A control channel that lives shorter than this, in seconds, is counted as a flap
+pub const FLAP_STABLE_DURATION: u64 = 60;
+/// The number of consecutive flaps before the reconnection is dampened
+pub const FLAP_THRESHOLD: u32 = 3;
+/// The maximum time in seconds to hold down a flapping control channel
+pub const FLAP_MAX_HOLD_DOWN: u64 = 300;
+
+/// The delay in seconds before restarting a panicked service task. Doubled for every further panic
+pub const PANIC_RESTART_DELAY: u64 = 1;
+/// The maximum delay in seconds before restarting a panicked service task
+pub const PANIC_RESTART_MAX_DELAY: u64 = 60;
+
+/// The share of its weight a client of a load balanced service starts with during the slow start
+pub const SLOW_START_MIN_RATIO: f64 = 0.1;
+/// The interval in seconds over which failures of forwarding of a client are counted
+pub const OUTLIER_INTERVAL: u64 = 10;
+/// The number of failures in an interval before a client can be ejected. It's ejected if at least half of its forwardings fail
+pub const OUTLIER_MIN_FAILURES: u32 = 5;
+/// The time in seconds a client is ejected for. Doubled for every further ejection
+pub const OUTLIER_EJECTION_TIME: u64 = 30;
+/// The maximum time in seconds a client is ejected for
+pub const OUTLIER_MAX_EJECTION_TIME: u64 = 300;
+/// Timeout in seconds for a client to connect to its local service, before the visitor is tried on another client
+pub const FORWARD_CONFIRM_TIMEOUT: u64 = 10;
+
+/// The default interval in seconds between heartbeats of a control channel
+pub const HEARTBEAT_INTERVAL: u64 = 30;
+/// The default time in seconds without hearing from a client before it's declared dead
+pub const HEARTBEAT_TIMEOUT: u64 = 90;
+/// The default interval in seconds between keep-warm probes of a service
+pub const KEEP_WARM_INTERVAL: u64 = 10;
+/// The most data channels a client opens in advance for a service, with `pool_size`
+pub const POOL_SIZE_MAX: usize = 256;
+/// The default number of data channels per second requested for waiting visitors after a client connects
+pub const WARMUP_RATE: u32 = 50;
+
+/// The interval in seconds between the bytes fed to a connection in the tarpit
+pub const TARPIT_INTERVAL: u64 = 10;
+/// The maximum time in seconds a connection is held in the tarpit
+pub const TARPIT_MAX_DURATION: u64 = 600;
+/// The maximum number of connections in the tarpit. Further ones are closed at once
+pub const TARPIT_MAX_CONNECTIONS: usize = 1024;
+
+/// The maximum number of bytes recorded from a visitor of a honeypot
+pub const HONEYPOT_MAX_PAYLOAD_LEN: usize = 4096;
+/// Timeout in seconds for a visitor of a honeypot to send its payload
+pub const HONEYPOT_READ_TIMEOUT: u64 = 10;
+
+/// Timeout in seconds to wait for the tasks of an instance to finish on shutdown
+pub const SHUTDOWN_TIMEOUT: u64 = 5;
+/// The default maximum number of DNS queries per visitor IP per second
+pub const DNS_RATE_LIMIT: u32 = 20;
+/// The default size above which DNS responses over UDP are truncated, which avoids IP fragmentation
+pub const DNS_MAX_UDP_RESPONSE: usize = 1232;
+
+/// Seconds to wait for all services to be established before logging the startup summary
+pub const STARTUP_SUMMARY_TIMEOUT: u64 = 30;
+
+/// Timeout in seconds for a request to the admin API
+pub const ADMIN_REQUEST_TIMEOUT: u64 = 10;
+/// The maximum size of a request to the admin API, including the body
+pub const ADMIN_MAX_REQUEST_SIZE: usize = 64 * 1024;
+/// Timeout in seconds for a request to the status page
+pub const STATUS_PAGE_REQUEST_TIMEOUT: u64 = 10;
+/// Timeout in seconds for a visitor to receive the maintenance page
+pub const MAINTENANCE_REQUEST_TIMEOUT: u64 = 10;
+
+/// The number of SHA-256 rounds that stretch a hashed token
+pub const TOKEN_HASH_ROUNDS: usize = 100_000;
+/// The maximum number of hashed tokens of a service
+pub const TOKEN_HASH_MAX_NUM: usize = 16;
+/// The maximum `totp_tolerance`, in multiples of `totp_step`
+pub const TOTP_MAX_TOLERANCE_STEPS: u64 = 10;
+/// The clock skew in seconds from the server that the client warns about
+pub const CLOCK_SKEW_WARN: f64 = 10.0;
+
+/// The maximum size of the config handed out to a new client, along with the files it refers to
+pub const PAIRING_MAX_SIZE: usize = 1024 * 1024;
+/// Timeout in seconds for a new client to fetch its config with a pairing code
+pub const PAIRING_TIMEOUT: u64 = 30;
+/// The maximum number of pairing codes that can be used at once
+pub const PAIRING_MAX_CODES: usize = 64;
+
+/// The maximum size of the request head that opens a websocket
+#[cfg(feature = "websocket")]
+pub const WEBSOCKET_MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// The interval in seconds at which the TLS transport checks if its certificates have changed
+pub const TLS_RELOAD_INTERVAL: u64 = 30;
+
+/// The number of TLS sessions kept for resumption, with `tls.session_resumption`
+#[cfg(feature = "tls-rustls")]
+pub const TLS_SESSION_CACHE_SIZE: usize = 1024;
+
+/// Timeout in seconds for the handshakes of a connection of the http2 transport
+#[cfg(feature = "http2")]
+pub const HTTP2_HANDSHAKE_TIMEOUT: u64 = 5;
+/// The flow control window of each stream of the http2 transport
+#[cfg(feature = "http2")]
+pub const HTTP2_STREAM_WINDOW: u32 = 1024 * 1024;
+/// The flow control window of each connection of the http2 transport
+#[cfg(feature = "http2")]
+pub const HTTP2_CONNECTION_WINDOW: u32 = 16 * 1024 * 1024;
+/// The number of streams of the http2 transport that may wait to be accepted
+#[cfg(feature = "http2")]
+pub const HTTP2_ACCEPT_BACKLOG: usize = 1024;
+
+/// Timeout in seconds for conversations of the kcp transport that receive nothing
+#[cfg(feature = "kcp")]
+pub const KCP_IDLE_TIMEOUT: u64 = 30;
+/// The interval in seconds of keepalives of idle conversations of the kcp transport
+#[cfg(feature = "kcp")]
+pub const KCP_KEEPALIVE_INTERVAL: u64 = 10;
+/// The number of packets that may wait for a conversation of the kcp transport
+#[cfg(feature = "kcp")]
+pub const KCP_INPUT_QUEUE: usize = 1024;
+/// The number of conversations of the kcp transport that may wait to be accepted
+#[cfg(feature = "kcp")]
+pub const KCP_ACCEPT_BACKLOG: usize = 1024;
+
+/// Timeout in seconds for the handshakes of a connection that carries multiplexed channels
+#[cfg(feature = "mux")]
+pub const MUX_HANDSHAKE_TIMEOUT: u64 = 5;
+/// The flow control window of each multiplexed channel
+#[cfg(feature = "mux")]
+pub const MUX_STREAM_WINDOW: u32 = 1024 * 1024;
+/// The number of multiplexed channels that may wait to be accepted
+#[cfg(feature = "mux")]
+pub const MUX_ACCEPT_BACKLOG: usize = 1024;
+
+/// The maximum size of the response head of a HTTP proxy to a CONNECT request
+pub const PROXY_MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+/// The maximum size of a RTSP or SIP message rewritten by a protocol helper. Larger ones are
+/// forwarded as they are
+pub const PROTOCOL_HELPER_MAX_MESSAGE: usize = 64 * 1024;
+
+/// Timeout in seconds for a visitor of a shared port to send the first bytes, which tell its
+/// protocol. Visitors that don't are taken as of the protocol `other`
+pub const PROTOCOL_SNIFF_TIMEOUT: u64 = 3;
+/// The maximum number of visitors of shared ports whose protocol is being told. Further ones are
+/// closed at once
+pub const PROTOCOL_SNIFF_MAX_PENDING: usize = 1024;
+
+/// The directory of Let's Encrypt, the default ACME server
+pub const ACME_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// Certificates obtained with ACME are renewed this many days before they expire
+#[cfg(feature = "acme")]
+pub const ACME_RENEW_BEFORE_DAYS: u64 = 30;
+/// The interval in seconds of checking whether the certificate obtained with ACME needs renewal
+#[cfg(feature = "acme")]
+pub const ACME_CHECK_INTERVAL: u64 = 12 * 60 * 60;
+/// The interval in seconds of polling the ACME server for authorizations and orders
+#[cfg(feature = "acme")]
+pub const ACME_POLL_INTERVAL: u64 = 2;
+/// The number of polls before an authorization or order of ACME is given up
+#[cfg(feature = "acme")]
+pub const ACME_POLL_ATTEMPTS: usize = 60;
+
+/// Timeout in seconds for a visitor to open the passive data connection that it negotiated over FTP
+pub const FTP_PASSIVE_TIMEOUT: u64 = 30;
+/// The maximum length of a line of the FTP control connection that's looked into
+pub const FTP_MAX_LINE: usize = 1024;
+
+/// The buffer size of each direction of a memory transport connection
+pub const MEMORY_TRANSPORT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The number of events kept for a subscriber that falls behind
+pub const EVENT_QUEUE_SIZE: usize = 1024;
+
+/// The number of the latest errors kept for state dumps
+pub const STATE_DUMP_MAX_ERRORS: usize = 100;
 
 pub fn listen_backoff() -> ExponentialBackoff {
     ExponentialBackoff {
@@ -0,0 +1,177 @@
+// Publishing of the address visitors reach a service at to a dynamic DNS provider, with `ddns`.
+// It's the address the server reports, so a new one is published whenever the server or the
+// service moves. Failed updates are retried until they succeed, or a newer address comes
+use crate::config::DdnsConfig;
+use crate::http;
+use crate::task_group::TaskGroup;
+use anyhow::{anyhow, bail, Result};
+use backoff::ExponentialBackoff;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tracing::{info, warn};
+
+pub(crate) struct Ddns {
+    service: String,
+    config: DdnsConfig,
+    tasks: TaskGroup,
+    // The address published last, and the group of the task publishing it
+    last: Mutex<Option<(String, TaskGroup)>>,
+}
+
+impl Ddns {
+    pub(crate) fn new(service: &str, config: DdnsConfig, tasks: TaskGroup) -> Ddns {
+        Ddns {
+            service: service.to_string(),
+            config,
+            tasks,
+            last: Default::default(),
+        }
+    }
+
+    // Publish `addr`, unless it's what was published last
+    pub(crate) fn publish(&self, addr: &str) {
+        let mut last = self.last.lock().unwrap();
+        if last.as_ref().is_some_and(|(v, _)| v == addr) {
+            return;
+        }
+        if let Some((_, tasks)) = last.take() {
+            tasks.cancel();
+        }
+        let tasks = self.tasks.child();
+        let service = self.service.clone();
+        let config = self.config.clone();
+        let addr = addr.to_string();
+        *last = Some((addr.clone(), tasks.clone()));
+        tasks.spawn(async move {
+            let backoff = ExponentialBackoff {
+                initial_interval: Duration::from_secs(5),
+                max_interval: Duration::from_secs(300),
+                max_elapsed_time: None,
+                ..Default::default()
+            };
+            let r = backoff::future::retry_notify(
+                backoff,
+                || async { Ok(update(&service, &config, &addr).await?) },
+                |e: anyhow::Error, duration| {
+                    warn!(
+                        "Failed to publish {} to the dynamic DNS: {:#}. Retry in {:?}",
+                        addr, e, duration
+                    );
+                },
+            )
+            .await;
+            if r.is_ok() {
+                info!("Published {} to the dynamic DNS", addr);
+            }
+        });
+    }
+}
+
+// The values of the placeholders for `addr`. A host that isn't an IP is resolved for `{ip}`
+async fn vars(service: &str, addr: &str) -> Result<Vec<(&'static str, String)>> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("Invalid address {}", addr))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let ip = match host.parse::<IpAddr>() {
+        Ok(v) => v,
+        Err(_) => lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("{} resolves to no address", host))?
+            .ip(),
+    };
+    Ok(vec![
+        ("service", service.to_string()),
+        ("addr", addr.to_string()),
+        ("host", host.to_string()),
+        ("port", port.to_string()),
+        ("ip", ip.to_string()),
+    ])
+}
+
+fn render(template: &str, vars: &[(&str, String)]) -> String {
+    vars.iter().fold(template.to_string(), |s, (k, v)| {
+        s.replace(&format!("{{{}}}", k), v)
+    })
+}
+
+async fn update(service: &str, config: &DdnsConfig, addr: &str) -> Result<()> {
+    let vars = vars(service, addr).await?;
+    let url = render(&config.url, &vars);
+    let headers: Vec<_> = config
+        .headers
+        .iter()
+        .map(|(k, v)| (k.as_str(), render(v, &vars)))
+        .collect();
+    let headers: Vec<_> = headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    let body = config
+        .body
+        .as_deref()
+        .map(|v| render(v, &vars))
+        .unwrap_or_default();
+    let resp = http::request(&config.method, &url, &headers, body.as_bytes()).await?;
+    if !(200..300).contains(&resp.status) {
+        bail!(
+            "The provider responded with status {}: {}",
+            resp.status,
+            String::from_utf8_lossy(&resp.body).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_update() {
+        let vars = vars("web", "[::1]:8080").await.unwrap();
+        assert_eq!(
+            render("{host} {port} {ip} {addr} {service} {other}", &vars),
+            "::1 8080 ::1 [::1]:8080 web {other}"
+        );
+
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let provider = l.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for status in ["200 OK", "401 Unauthorized"] {
+                let (mut conn, _) = l.accept().await.unwrap();
+                // Up to the end of the body
+                let mut req = Vec::new();
+                while !req.ends_with(b"}") {
+                    let mut buf = [0u8; 1024];
+                    let n = conn.read(&mut buf).await.unwrap();
+                    assert!(n > 0);
+                    req.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8(req).unwrap());
+                let resp = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                conn.write_all(resp.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        let config = DdnsConfig {
+            url: format!(
+                "http://{}/update?hostname={{service}}&myip={{ip}}",
+                provider
+            ),
+            method: "POST".into(),
+            headers: [("Authorization".into(), "Bearer secret".into())].into(),
+            body: Some(r#"{"port":{port}}"#.into()),
+        };
+        assert!(update("web", &config, "127.0.0.1:8080").await.is_ok());
+        assert!(update("web", &config, "127.0.0.1:8080").await.is_err());
+        let requests = server.await.unwrap();
+        let req = &requests[0];
+        assert!(req.starts_with("POST /update?hostname=web&myip=127.0.0.1 HTTP/1.1\r\n"));
+        assert!(req.contains("Authorization: Bearer secret\r\n"));
+        assert!(req.ends_with("\r\n\r\n{\"port\":8080}"));
+    }
+}
@@ -0,0 +1,305 @@
+// Hardening of services that expose DNS resolvers, with `dns`, so they can't be abused as open
+// amplifiers. Visitors may only send plain queries, at a limited rate per IP, and large
+// responses over UDP are truncated, so resolvers ask again over TCP, which can't be spoofed
+use crate::config::ServerServiceConfig;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const HEADER_LEN: usize = 12;
+// The maximum number of IPs tracked by the rate limit
+const DNS_RATE_LIMIT_MAX_ENTRIES: usize = 65536;
+const DNS_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+fn count(msg: &[u8], i: usize) -> u16 {
+    u16::from_be_bytes([msg[i], msg[i + 1]])
+}
+
+// The end of the single question of a message, along with its type and class
+fn question(msg: &[u8]) -> Option<(usize, u16, u16)> {
+    if msg.len() < HEADER_LEN || count(msg, 4) != 1 {
+        return None;
+    }
+    let mut i = HEADER_LEN;
+    loop {
+        let len = *msg.get(i)? as usize;
+        i += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers never appear in the question of a query
+        if len > 63 {
+            return None;
+        }
+        i += len;
+        if i - HEADER_LEN > 255 {
+            return None;
+        }
+    }
+    let qtype = u16::from_be_bytes([*msg.get(i)?, *msg.get(i + 1)?]);
+    let qclass = u16::from_be_bytes([*msg.get(i + 2)?, *msg.get(i + 3)?]);
+    Some((i + 4, qtype, qclass))
+}
+
+// A standard query of one question in the IN class, and an OPT record at most.
+// ANY and zone transfers, the favorites of amplification, aren't allowed
+fn is_query(msg: &[u8]) -> bool {
+    if msg.len() < HEADER_LEN {
+        return false;
+    }
+    // QR and OPCODE
+    if msg[2] & 0xf8 != 0 {
+        return false;
+    }
+    if count(msg, 6) != 0 || count(msg, 8) != 0 || count(msg, 10) > 1 {
+        return false;
+    }
+    match question(msg) {
+        Some((_, qtype, qclass)) => qclass == 1 && !matches!(qtype, 251 | 252 | 255),
+        None => false,
+    }
+}
+
+// The header and the question of a response, with TC set
+fn truncate(resp: &[u8]) -> Vec<u8> {
+    if resp.len() < HEADER_LEN {
+        return resp.to_vec();
+    }
+    let mut v = resp[..HEADER_LEN].to_vec();
+    v[2] |= 0x02;
+    v[4..].fill(0);
+    if let Some((end, _, _)) = question(resp) {
+        v[5] = 1;
+        v.extend_from_slice(&resp[HEADER_LEN..end]);
+    }
+    v
+}
+
+pub struct DnsGuard {
+    rate_limit: u32,
+    max_udp_response: usize,
+    // The start of the current window and the number of queries in it
+    queries: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl DnsGuard {
+    pub fn from_config(service: &ServerServiceConfig) -> Option<Arc<DnsGuard>> {
+        service.dns.as_ref().map(|c| {
+            Arc::new(DnsGuard {
+                rate_limit: c.rate_limit,
+                max_udp_response: c.max_udp_response,
+                queries: Default::default(),
+            })
+        })
+    }
+
+    // Whether `msg` from `ip` is a query allowed to reach the resolver
+    pub fn admit(&self, ip: IpAddr, msg: &[u8]) -> bool {
+        if !is_query(msg) {
+            return false;
+        }
+        let now = Instant::now();
+        let mut queries = self.queries.lock().unwrap();
+        if queries.len() >= DNS_RATE_LIMIT_MAX_ENTRIES {
+            queries.retain(|_, (start, _)| now.duration_since(*start) < DNS_RATE_LIMIT_WINDOW);
+        }
+        let (start, count) = queries.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= DNS_RATE_LIMIT_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.rate_limit
+    }
+
+    // The response to send over UDP
+    pub fn udp_response<'a>(&self, resp: &'a [u8]) -> Cow<'a, [u8]> {
+        if resp.len() > self.max_udp_response {
+            Cow::Owned(truncate(resp))
+        } else {
+            Cow::Borrowed(resp)
+        }
+    }
+}
+
+// DNS over TCP from a visitor. Messages are only passed on in whole, once `DnsGuard` admits them.
+// A message that isn't admitted fails the stream
+pub struct DnsStream<S> {
+    inner: S,
+    guard: Option<(Arc<DnsGuard>, IpAddr)>,
+    // Bytes of the message being read, along with its length prefix
+    frame: Vec<u8>,
+    // Admitted messages not yet read
+    admitted: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> DnsStream<S> {
+    // Passes everything through if `guard` is `None`
+    pub fn new(inner: S, guard: Option<(Arc<DnsGuard>, IpAddr)>) -> DnsStream<S> {
+        DnsStream {
+            inner,
+            guard,
+            frame: Vec::new(),
+            admitted: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DnsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let (guard, ip) = match &this.guard {
+            Some(v) => v,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        loop {
+            if this.pos < this.admitted.len() {
+                let n = buf.remaining().min(this.admitted.len() - this.pos);
+                buf.put_slice(&this.admitted[this.pos..this.pos + n]);
+                this.pos += n;
+                if this.pos == this.admitted.len() {
+                    this.admitted.clear();
+                    this.pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0u8; 4096];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            // A message cut off by the end of the stream is dropped
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.frame.extend_from_slice(chunk.filled());
+            while this.frame.len() >= 2 {
+                let len = count(&this.frame, 0) as usize;
+                if this.frame.len() < 2 + len {
+                    break;
+                }
+                if !guard.admit(*ip, &this.frame[2..2 + len]) {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Not an allowed DNS query",
+                    )));
+                }
+                this.admitted.extend(this.frame.drain(..2 + len));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DnsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A query of `example.com` with the type
+    fn query(qtype: u16) -> Vec<u8> {
+        let mut v = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        v.extend_from_slice(b"\x07example\x03com\x00");
+        v.extend_from_slice(&qtype.to_be_bytes());
+        v.extend_from_slice(&[0, 1]);
+        v
+    }
+
+    fn guard(rate_limit: u32) -> DnsGuard {
+        DnsGuard {
+            rate_limit,
+            max_udp_response: 512,
+            queries: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_admit() {
+        let g = guard(2);
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        // ANY
+        assert!(!g.admit(ip, &query(255)));
+        // A response
+        let mut resp = query(1);
+        resp[2] |= 0x80;
+        assert!(!g.admit(ip, &resp));
+        assert!(!g.admit(ip, &query(1)[..20]));
+        assert!(!g.admit(ip, b"GET / HTTP/1.1\r\n\r\n"));
+
+        assert!(g.admit(ip, &query(1)));
+        assert!(g.admit(ip, &query(28)));
+        assert!(!g.admit(ip, &query(1)));
+        assert!(g.admit("1.2.3.5".parse().unwrap(), &query(1)));
+    }
+
+    #[test]
+    fn test_udp_response() {
+        let g = guard(1);
+        let mut resp = query(1);
+        resp[2] |= 0x80;
+        assert_eq!(g.udp_response(&resp), &resp[..]);
+
+        resp[7] = 1;
+        resp.extend_from_slice(&[0u8; 600]);
+        let truncated = g.udp_response(&resp);
+        assert_eq!(truncated.len(), query(1).len());
+        assert_eq!(truncated[2], 0x83);
+        assert_eq!(truncated[4..12], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(truncated[12..], query(1)[12..]);
+    }
+
+    #[tokio::test]
+    async fn test_dns_stream() {
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut a = DnsStream::new(a, Some((Arc::new(guard(10)), ip)));
+
+        let q = query(1);
+        let mut framed = (q.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&q);
+        // Split in the middle of a message
+        b.write_all(&framed[..5]).await.unwrap();
+        b.write_all(&framed[5..]).await.unwrap();
+        let mut buf = vec![0u8; framed.len()];
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, framed);
+
+        let q = query(252);
+        let mut framed = (q.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&q);
+        b.write_all(&framed).await.unwrap();
+        assert!(a.read(&mut buf).await.is_err());
+    }
+}
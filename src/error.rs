@@ -0,0 +1,244 @@
+use std::fmt::{self, Display, Formatter};
+
+// Exit codes, following sysexits.h
+pub const EXIT_FAILURE: i32 = 1; // Errors not classified below
+pub const EXIT_PANIC: i32 = 70; // EX_SOFTWARE
+const EXIT_BIND: i32 = 75; // EX_TEMPFAIL
+const EXIT_AUTH: i32 = 77; // EX_NOPERM
+const EXIT_CONFIG: i32 = 78; // EX_CONFIG
+
+// Classes of fatal errors. Attach one as the context of an error to decide the exit code,
+// so that supervisors can tell whether restarting helps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Config, // The configuration is invalid, or needs a feature not compiled
+    Bind,   // Failed to listen at an address
+    Auth,   // The server rejected the token of a service
+}
+
+impl Failure {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Config => EXIT_CONFIG,
+            Failure::Bind => EXIT_BIND,
+            Failure::Auth => EXIT_AUTH,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Config => "Configuration error",
+            Failure::Bind => "Bind error",
+            Failure::Auth => "Authentication error",
+        })
+    }
+}
+
+impl std::error::Error for Failure {}
+
+// The exit code for an error returned by `run`
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    e.downcast_ref::<Failure>()
+        .map(Failure::exit_code)
+        .unwrap_or(EXIT_FAILURE)
+}
+
+// How to fix an error, where it's known. Attach one with `WithHint::hint` or `hint`, and it's
+// shown at the end by `report` and `summary` rather than among the causes. An error has one at
+// most
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hint {
+    MissingBlock(&'static str), // The block of the config for the mode, like `server`
+    Bind(&'static str),         // The key of the address, like `server.bind_addr`
+    Unreachable(String),        // The address of the server
+    TokenMismatch(String),      // The name of the service, as are the ones below
+    UnknownService(String),
+    ServiceBusy(String),
+    ClientTooOld(String),
+}
+
+impl Display for Hint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Hint::MissingBlock(block) => write!(f, "Add the `[{}]` block to the config", block),
+            Hint::Bind(key) => write!(
+                f,
+                "Check that no other process listens at `{}`, and that ports below 1024 are allowed, like with CAP_NET_BIND_SERVICE",
+                key
+            ),
+            Hint::Unreachable(addr) => write!(
+                f,
+                "Check that `client.remote_addr` ({}) is right, the server is running, `client.transport` matches `server.transport`, and no firewall is in the way",
+                addr
+            ),
+            Hint::TokenMismatch(service) => write!(
+                f,
+                "Check that the token of `[client.services.{0}]` matches `[server.services.{0}].token` or `token_hashes`, or `default_token` if the service has none",
+                service
+            ),
+            Hint::UnknownService(service) => write!(
+                f,
+                "Check that the server has `[server.services.{0}]`. The names on both sides must be the same",
+                service
+            ),
+            Hint::ServiceBusy(service) => write!(
+                f,
+                "Stop the other client of the service, or set `on_duplicate` of `[server.services.{}]` to `replace` or `load_balance`",
+                service
+            ),
+            Hint::ClientTooOld(service) => write!(
+                f,
+                "Upgrade the client to the version of the server. `[server.services.{}]` needs what it lacks",
+                service
+            ),
+        }
+    }
+}
+
+// An error with its hint. Unlike a context, it's an error of its own in the chain, so that it's
+// told apart from the causes by its type. `downcast_ref` doesn't see through it, so look for the
+// errors under a hint in `chain`
+#[derive(Debug)]
+struct Hinted {
+    hint: Hint,
+    source: anyhow::Error,
+}
+
+impl Display for Hinted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.hint.fmt(f)
+    }
+}
+
+impl std::error::Error for Hinted {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+// Attach `hint` to `e`
+pub fn hint(e: anyhow::Error, hint: Hint) -> anyhow::Error {
+    anyhow::Error::new(Hinted { hint, source: e })
+}
+
+pub trait WithHint<T> {
+    fn hint(self, hint: Hint) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithHint<T> for Result<T, E> {
+    fn hint(self, h: Hint) -> anyhow::Result<T> {
+        self.map_err(|e| hint(e.into(), h))
+    }
+}
+
+fn find_hint(e: &anyhow::Error) -> Option<&Hint> {
+    e.chain()
+        .find_map(|c| c.downcast_ref::<Hinted>())
+        .map(|h| &h.hint)
+}
+
+// The causes of `e`, without its hint
+fn causes(e: &anyhow::Error) -> Vec<String> {
+    e.chain()
+        .filter(|c| !c.is::<Hinted>())
+        .map(|c| c.to_string())
+        .collect()
+}
+
+// The error with its causes on lines of their own, followed by its hint. For logs and the exit
+// message, in place of `{:?}`
+pub fn report(e: &anyhow::Error) -> String {
+    let causes = causes(e);
+    let (mut s, rest) = match causes.split_first() {
+        Some((first, rest)) => (first.clone(), rest),
+        None => (String::new(), &[][..]),
+    };
+    // Laid out like anyhow does
+    match rest.len() {
+        0 => (),
+        1 => s += &format!("\n\nCaused by:\n    {}", rest[0]),
+        _ => {
+            s += "\n\nCaused by:";
+            for (i, c) in rest.iter().enumerate() {
+                s += &format!("\n    {}: {}", i, c);
+            }
+        }
+    }
+    if let Some(h) = find_hint(e) {
+        s += &format!("\n\nHint: {}", h);
+    }
+    s
+}
+
+// The error and its causes on a line, followed by its hint. In place of `{:#}`
+pub fn summary(e: &anyhow::Error) -> String {
+    let mut s = causes(e).join(": ");
+    if let Some(h) = find_hint(e) {
+        s += &format!(". Hint: {}", h);
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_exit_code() {
+        let e = anyhow!("foo");
+        assert_eq!(exit_code(&e), EXIT_FAILURE);
+
+        let e = Err::<(), _>(anyhow!("foo"))
+            .context(Failure::Auth)
+            .with_context(|| "bar")
+            .unwrap_err();
+        assert_eq!(exit_code(&e), EXIT_AUTH);
+
+        let e = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::AddrInUse))
+            .context(Failure::Bind)
+            .unwrap_err();
+        assert_eq!(exit_code(&e), EXIT_BIND);
+    }
+
+    #[test]
+    fn test_report() {
+        let e = Err::<(), _>(anyhow!("Incorrect token"))
+            .hint(Hint::TokenMismatch("foo".to_string()))
+            .context("Authentication failed: foo")
+            .context(Failure::Auth)
+            .unwrap_err();
+        assert_eq!(exit_code(&e), EXIT_AUTH);
+        let hint = "Hint: Check that the token of `[client.services.foo]` matches `[server.services.foo].token` or `token_hashes`, or `default_token` if the service has none";
+        assert_eq!(
+            report(&e),
+            format!(
+                "Authentication error\n\nCaused by:\n    0: Authentication failed: foo\n    1: Incorrect token\n\n{}",
+                hint
+            )
+        );
+        assert_eq!(
+            summary(&e),
+            format!(
+                "Authentication error: Authentication failed: foo: Incorrect token. {}",
+                hint
+            )
+        );
+
+        // A cause isn't taken for the hint for having the same message
+        let hint = Hint::MissingBlock("client");
+        let e = Err::<(), _>(anyhow!(hint.to_string()))
+            .hint(hint)
+            .unwrap_err();
+        assert_eq!(
+            summary(&e),
+            "Add the `[client]` block to the config. Hint: Add the `[client]` block to the config"
+        );
+
+        let e = anyhow!("foo").context("bar");
+        assert_eq!(report(&e), "bar\n\nCaused by:\n    foo");
+        assert_eq!(summary(&e), format!("{:#}", e));
+    }
+}
@@ -0,0 +1,222 @@
+// Typed events of services, for applications embedding rathole to reflect the
+// state of tunnels without parsing logs
+use crate::constants::EVENT_QUEUE_SIZE;
+use crate::error;
+use crate::health;
+use crate::protocol::Ack;
+use crate::state_dump;
+use lazy_static::lazy_static;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    // A control channel of the service is established. On the server, a load
+    // balanced service has one for each client
+    ServiceUp {
+        service: String,
+    },
+    // A control channel of the service is closed, or the service is removed
+    ServiceDown {
+        service: String,
+    },
+    DataChannelOpened {
+        service: String,
+    },
+    // `inbound` is the bytes from visitors to the service, and `outbound` the other way round.
+    // They're only counted for TCP services, and are 0 for UDP ones
+    DataChannelClosed {
+        service: String,
+        inbound: u64,
+        outbound: u64,
+    },
+    // An error that is recovered from by retrying or skipping, like a broken control channel.
+    // `code` is set if the server refused the control channel, like `auth_failed` or
+    // `service_not_found`
+    Error {
+        service: String,
+        message: String,
+        code: Option<&'static str>,
+    },
+    // The control channel of the service has stopped for good, like when the server rejected its
+    // token. It starts over once the service is added again, like by reloading the config
+    ServiceStopped {
+        service: String,
+        message: String,
+    },
+    // The address visitors reach the service at, as reported by the server to the client
+    PublicAddr {
+        service: String,
+        addr: String,
+    },
+    // A visitor of a honeypot service, and what it sent, cut at `HONEYPOT_MAX_PAYLOAD_LEN`
+    HoneypotVisited {
+        service: String,
+        visitor: SocketAddr,
+        payload: Vec<u8>,
+    },
+}
+
+lazy_static! {
+    static ref EVENTS: broadcast::Sender<Event> = broadcast::channel(EVENT_QUEUE_SIZE).0;
+}
+
+// Subscribe to events of all clients and servers running in the process.
+// A receiver that falls behind by more than `EVENT_QUEUE_SIZE` events misses the oldest ones
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    EVENTS.subscribe()
+}
+
+// The event is only built if anyone is listening
+pub(crate) fn emit(f: impl FnOnce() -> Event) {
+    if EVENTS.receiver_count() > 0 {
+        let _ = EVENTS.send(f());
+    }
+}
+
+// Emits `ServiceUp` when created and `ServiceDown` when dropped, which also
+// covers the control channel being cancelled. The service counts as ready meanwhile
+pub(crate) struct ServiceUpGuard(String);
+
+impl ServiceUpGuard {
+    pub(crate) fn new(service: &str) -> ServiceUpGuard {
+        health::up(service);
+        emit(|| Event::ServiceUp {
+            service: service.to_string(),
+        });
+        ServiceUpGuard(service.to_string())
+    }
+}
+
+impl Drop for ServiceUpGuard {
+    fn drop(&mut self) {
+        health::down(&self.0);
+        emit(|| Event::ServiceDown {
+            service: std::mem::take(&mut self.0),
+        });
+    }
+}
+
+// Emits `DataChannelOpened` when created and `DataChannelClosed` when dropped
+pub(crate) struct DataChannelGuard {
+    service: String,
+    pub(crate) inbound: u64,
+    pub(crate) outbound: u64,
+}
+
+impl DataChannelGuard {
+    pub(crate) fn new(service: &str) -> DataChannelGuard {
+        state_dump::data_channel_opened(service);
+        emit(|| Event::DataChannelOpened {
+            service: service.to_string(),
+        });
+        DataChannelGuard {
+            service: service.to_string(),
+            inbound: 0,
+            outbound: 0,
+        }
+    }
+}
+
+impl Drop for DataChannelGuard {
+    fn drop(&mut self) {
+        state_dump::data_channel_closed(&self.service);
+        emit(|| Event::DataChannelClosed {
+            service: std::mem::take(&mut self.service),
+            inbound: self.inbound,
+            outbound: self.outbound,
+        });
+    }
+}
+
+// Errors are also kept for state dumps, whether anyone is listening or not
+pub(crate) fn emit_error(service: &str, err: &anyhow::Error) {
+    let message = error::summary(err);
+    state_dump::record_error(service, message.clone());
+    emit(|| Event::Error {
+        service: service.to_string(),
+        message,
+        code: err
+            .chain()
+            .find_map(|c| c.downcast_ref::<Ack>())
+            .map(Ack::code),
+    });
+}
+
+// Also marks the service as stopped in the health state
+pub(crate) fn emit_stopped(service: &str, err: &anyhow::Error) {
+    let message = error::summary(err);
+    health::set_stopped(service, &message);
+    emit(|| Event::ServiceStopped {
+        service: service.to_string(),
+        message,
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events() {
+        // Other tests may emit events concurrently, so only look at the ones of this service
+        let mut rx = subscribe();
+        let name = "test_events";
+        {
+            let _up = ServiceUpGuard::new(name);
+            let mut ch = DataChannelGuard::new(name);
+            ch.inbound = 1;
+            ch.outbound = 2;
+        }
+        emit_error(name, &anyhow::anyhow!("boom").context("Failed"));
+        emit_error(
+            name,
+            &anyhow::Error::new(Ack::ServiceBusy).context("Failed"),
+        );
+
+        let mut events = vec![];
+        while events.len() < 6 {
+            match rx.recv().await.unwrap() {
+                Event::ServiceUp { service }
+                | Event::ServiceDown { service }
+                | Event::DataChannelOpened { service }
+                | Event::DataChannelClosed { service, .. }
+                | Event::Error { service, .. }
+                | Event::HoneypotVisited { service, .. }
+                    if service != name => {}
+                e => events.push(e),
+            }
+        }
+        let service = name.to_string();
+        assert_eq!(
+            events,
+            vec![
+                Event::ServiceUp {
+                    service: service.clone()
+                },
+                Event::DataChannelOpened {
+                    service: service.clone()
+                },
+                Event::DataChannelClosed {
+                    service: service.clone(),
+                    inbound: 1,
+                    outbound: 2
+                },
+                Event::ServiceDown {
+                    service: service.clone()
+                },
+                Event::Error {
+                    service: service.clone(),
+                    message: "Failed: boom".to_string(),
+                    code: None,
+                },
+                Event::Error {
+                    service,
+                    message: "Failed: Service already registered by another client".to_string(),
+                    code: Some("service_busy"),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,274 @@
+// Helps passive FTP through the tunnel. The `227` and `229` replies of the FTP server announce a
+// port that visitors can't reach, so they're rewritten with a port opened on the server instead.
+// The port takes one connection, from the visitor of the control connection only, for
+// `FTP_PASSIVE_TIMEOUT` seconds. The connection is forwarded to the port that the FTP server
+// announced, on the host of `local_addr` of the client
+use crate::constants::{FTP_MAX_LINE, FTP_PASSIVE_TIMEOUT};
+use crate::privacy;
+use crate::task_group::TaskGroup;
+use rand::Rng;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{debug, warn};
+
+// A data connection, with the port of the FTP server it goes to
+pub type DataConnection = (TcpStream, u16);
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    // `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)`
+    Passive(u16),
+    // `229 Entering Extended Passive Mode (|||port|)`
+    ExtendedPassive(u16),
+}
+
+pub struct Passive {
+    // The visitor of the control connection, the only one let in
+    pub visitor: IpAddr,
+    // The address of the server that the visitor connected to, where the ports are opened
+    pub local_ip: IpAddr,
+    // Ephemeral ports if not set
+    pub ports: Option<RangeInclusive<u16>>,
+    pub tx: mpsc::UnboundedSender<DataConnection>,
+    pub tasks: TaskGroup,
+}
+
+impl Passive {
+    async fn listen(&self) -> io::Result<TcpListener> {
+        let ports = match &self.ports {
+            Some(v) => v.clone(),
+            None => return TcpListener::bind((self.local_ip, 0)).await,
+        };
+        // Start at a random port, so that the ports in use are spread over the range
+        let len = (*ports.end() - *ports.start()) as u32 + 1;
+        let offset = rand::thread_rng().gen_range(0..len);
+        for i in 0..len {
+            let port = *ports.start() + ((offset + i) % len) as u16;
+            if let Ok(l) = TcpListener::bind((self.local_ip, port)).await {
+                return Ok(l);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "All the passive ports are in use",
+        ))
+    }
+
+    // Open a port for the data connection to `port` of the FTP server, and rewrite the reply with it
+    async fn rewrite(&self, line: &[u8]) -> Option<Vec<u8>> {
+        let reply = parse_reply(line)?;
+        let port = match reply {
+            Reply::Passive(v) | Reply::ExtendedPassive(v) => v,
+        };
+        // PASV can only announce IPv4 addresses
+        let ip = match (&reply, self.local_ip) {
+            (Reply::Passive(_), IpAddr::V4(v)) => Some(v),
+            (Reply::Passive(_), IpAddr::V6(v)) => Some(v.to_ipv4_mapped()?),
+            (Reply::ExtendedPassive(_), _) => None,
+        };
+        let l = match self.listen().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to open a port for a passive data connection: {}", e);
+                return None;
+            }
+        };
+        let public_port = l.local_addr().ok()?.port();
+        debug!(
+            "Opened port {} for a passive data connection to port {}",
+            public_port, port
+        );
+
+        let visitor = self.visitor;
+        let tx = self.tx.clone();
+        self.tasks.spawn(async move {
+            let accept = async {
+                loop {
+                    match l.accept().await {
+                        Ok((conn, addr)) if addr.ip() == visitor => return Some(conn),
+                        Ok((_, addr)) => {
+                            debug!("Refused a data connection from {}", privacy::addr(addr))
+                        }
+                        Err(e) => {
+                            debug!("Failed to accept a data connection: {}", e);
+                            return None;
+                        }
+                    }
+                }
+            };
+            match time::timeout(Duration::from_secs(FTP_PASSIVE_TIMEOUT), accept).await {
+                Ok(Some(conn)) => {
+                    let _ = tx.send((conn, port));
+                }
+                Ok(None) => (),
+                Err(_) => debug!("No data connection came to port {}", public_port),
+            }
+        });
+
+        Some(
+            match ip {
+                Some(ip) => {
+                    let [a, b, c, d] = ip.octets();
+                    format!(
+                        "227 Entering Passive Mode ({},{},{},{},{},{}).\r\n",
+                        a,
+                        b,
+                        c,
+                        d,
+                        public_port >> 8,
+                        public_port & 0xff
+                    )
+                }
+                None => format!(
+                    "229 Entering Extended Passive Mode (|||{}|)\r\n",
+                    public_port
+                ),
+            }
+            .into_bytes(),
+        )
+    }
+}
+
+fn parse_reply(line: &[u8]) -> Option<Reply> {
+    let line = std::str::from_utf8(line).ok()?;
+    if let Some(rest) = line.strip_prefix("227 ") {
+        // The numbers may or may not be in parentheses
+        let start = rest.find(|c: char| c.is_ascii_digit())?;
+        let v: Vec<u16> = rest[start..]
+            .split(|c: char| !c.is_ascii_digit() && c != ',')
+            .next()?
+            .split(',')
+            .map(|v| v.parse().ok().filter(|&v| v <= 255))
+            .collect::<Option<_>>()?;
+        return match v[..] {
+            [_, _, _, _, p1, p2] => Some(Reply::Passive(p1 << 8 | p2)),
+            _ => None,
+        };
+    }
+    if let Some(rest) = line.strip_prefix("229 ") {
+        // Like `(|||6446|)`, where `|` may be another delimiter
+        let rest = &rest[rest.find('(')? + 1..];
+        let d = rest.chars().next()?;
+        let port = rest.strip_prefix(d.to_string().repeat(3).as_str())?;
+        let port = &port[..port.find(d)?];
+        return port.parse().ok().map(Reply::ExtendedPassive);
+    }
+    None
+}
+
+// Forward between the data channel and the visitor of the control connection, rewriting the
+// passive replies. Returns the bytes copied from and to the data channel, like `copy_bidirectional`
+pub async fn forward<C, V>(ch: &mut C, visitor: &mut V, passive: Passive) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    V: AsyncRead + AsyncWrite + Unpin,
+{
+    let (ch_rd, mut ch_wr) = io::split(ch);
+    let (mut visitor_rd, mut visitor_wr) = io::split(visitor);
+
+    let inbound = async {
+        let n = io::copy(&mut visitor_rd, &mut ch_wr).await?;
+        ch_wr.shutdown().await?;
+        Ok::<u64, io::Error>(n)
+    };
+    let outbound = async {
+        let mut rd = BufReader::new(ch_rd);
+        let mut line = Vec::new();
+        let mut n = 0;
+        loop {
+            line.clear();
+            // Longer lines are forwarded in pieces, which are never passive replies
+            if (&mut rd)
+                .take(FTP_MAX_LINE as u64)
+                .read_until(b'\n', &mut line)
+                .await?
+                == 0
+            {
+                visitor_wr.shutdown().await?;
+                return Ok::<u64, io::Error>(n);
+            }
+            let line = match passive.rewrite(&line).await {
+                Some(v) => v,
+                None => std::mem::take(&mut line),
+            };
+            visitor_wr.write_all(&line).await?;
+            visitor_wr.flush().await?;
+            n += line.len() as u64;
+        }
+    };
+    let (inbound, outbound) = tokio::try_join!(inbound, outbound)?;
+    Ok((outbound, inbound))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_reply() {
+        let cases: [(&[u8], Option<Reply>); 6] = [
+            (
+                b"227 Entering Passive Mode (10,0,0,5,195,80).\r\n",
+                Some(Reply::Passive(50000)),
+            ),
+            (b"227 Passive 10,0,0,5,0,21\r\n", Some(Reply::Passive(21))),
+            (b"227 Entering Passive Mode (10,0,0,5,195).\r\n", None),
+            (
+                b"229 Entering Extended Passive Mode (|||6446|)\r\n",
+                Some(Reply::ExtendedPassive(6446)),
+            ),
+            (
+                b"229 Entering Extended Passive Mode (!!!6446!)\r\n",
+                Some(Reply::ExtendedPassive(6446)),
+            ),
+            (b"230 Login successful.\r\n", None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_reply(line), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let passive = Passive {
+            visitor: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            local_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            ports: None,
+            tx,
+            tasks: TaskGroup::new(),
+        };
+        let (mut ch, mut ch_peer) = io::duplex(1024);
+        let (mut visitor, mut visitor_peer) = io::duplex(1024);
+        let forward =
+            tokio::spawn(async move { forward(&mut ch_peer, &mut visitor_peer, passive).await });
+
+        ch.write_all(b"220 Welcome\r\n227 Entering Passive Mode (10,0,0,5,195,80).\r\n")
+            .await
+            .unwrap();
+        drop(ch);
+        let mut buf = String::new();
+        visitor.read_to_string(&mut buf).await.unwrap();
+        let (welcome, reply) = buf.split_at(13);
+        assert_eq!(welcome, "220 Welcome\r\n");
+        let port = match parse_reply(reply.as_bytes()) {
+            Some(Reply::Passive(v)) => v,
+            v => panic!("Unexpected reply {:?}", v),
+        };
+        assert!(reply.starts_with("227 Entering Passive Mode (127,0,0,1,"));
+
+        let _conn = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (_, to) = rx.recv().await.unwrap();
+        assert_eq!(to, 50000);
+        drop(visitor);
+        forward.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+// Named groups of services on the server, so that a whole group can be taken out of service at
+// once through the admin API. The shared ACL of a group is kept in `acl` with those of services
+#![cfg_attr(not(feature = "server"), allow(dead_code))]
+use crate::config::ServerConfig;
+use anyhow::{anyhow, bail, Result};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupState {
+    Enabled,
+    // New visitors are refused, while the connected ones stay until they leave
+    Draining,
+    // New visitors are refused, and the connected ones are cut
+    Disabled,
+}
+
+struct Group {
+    state: GroupState,
+    services: BTreeSet<String>,
+    // Cancelled to cut the connections of the group, and replaced once it's enabled again
+    cut: CancellationToken,
+}
+
+#[derive(Default)]
+pub struct Groups {
+    groups: Mutex<BTreeMap<String, Group>>,
+}
+
+lazy_static! {
+    pub static ref GROUPS: Groups = Groups::default();
+}
+
+impl Groups {
+    // Set up the groups of `config`, replacing the current ones
+    pub fn load(&self, config: &ServerConfig) {
+        let mut groups = self.groups.lock().unwrap();
+        for group in groups.values() {
+            group.cut.cancel();
+        }
+        *groups = config
+            .groups
+            .iter()
+            .map(|(name, g)| {
+                let group = Group {
+                    state: if g.disabled {
+                        GroupState::Disabled
+                    } else {
+                        GroupState::Enabled
+                    },
+                    services: BTreeSet::new(),
+                    cut: CancellationToken::new(),
+                };
+                (name.clone(), group)
+            })
+            .collect();
+        for s in config.services.values() {
+            if let Some(group) = s.group.as_ref().and_then(|g| groups.get_mut(g)) {
+                group.services.insert(s.name.clone());
+            }
+        }
+    }
+
+    // Move `service` to `group`, or out of any if `None`
+    pub fn join(&self, service: &str, group: Option<&str>) -> Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        if let Some(group) = group.filter(|g| !groups.contains_key(*g)) {
+            bail!("The group {} is not defined", group);
+        }
+        for g in groups.values_mut() {
+            g.services.remove(service);
+        }
+        if let Some(g) = group.and_then(|g| groups.get_mut(g)) {
+            g.services.insert(service.to_string());
+        }
+        Ok(())
+    }
+
+    // Whether new visitors of the services in `group` are accepted
+    pub fn admits(&self, group: Option<&str>) -> bool {
+        match group {
+            Some(group) => self
+                .groups
+                .lock()
+                .unwrap()
+                .get(group)
+                .is_none_or(|g| g.state == GroupState::Enabled),
+            None => true,
+        }
+    }
+
+    // Cancelled when the connections of `group` are to be cut
+    pub fn cut_token(&self, group: Option<&str>) -> Option<CancellationToken> {
+        self.groups
+            .lock()
+            .unwrap()
+            .get(group?)
+            .map(|g| g.cut.clone())
+    }
+
+    pub fn set_state(&self, name: &str, state: GroupState) -> Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("The group {} is not defined", name))?;
+        match state {
+            GroupState::Disabled => group.cut.cancel(),
+            _ if group.cut.is_cancelled() => group.cut = CancellationToken::new(),
+            _ => (),
+        }
+        if group.state != state {
+            info!(group = %name, "Group is {:?} now", state);
+            group.state = state;
+        }
+        Ok(())
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.groups.lock().unwrap().contains_key(name)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let groups: serde_json::Map<String, Value> = self
+            .groups
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, g)| (name.clone(), g.to_json()))
+            .collect();
+        json!({ "groups": groups })
+    }
+
+    pub fn get_json(&self, name: &str) -> Option<Value> {
+        self.groups.lock().unwrap().get(name).map(Group::to_json)
+    }
+}
+
+impl Group {
+    fn to_json(&self) -> Value {
+        json!({ "state": self.state, "services": self.services })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{ServerServiceConfig, ServiceGroupConfig};
+
+    #[test]
+    fn test_groups() {
+        let mut config = ServerConfig::default();
+        config
+            .groups
+            .insert("a".to_string(), ServiceGroupConfig::default());
+        let mut s = ServerServiceConfig::with_name("foo");
+        s.group = Some("a".to_string());
+        config.services.insert(s.name.clone(), s);
+        let groups = Groups::default();
+        groups.load(&config);
+
+        assert_eq!(
+            groups.get_json("a").unwrap(),
+            json!({ "state": "enabled", "services": ["foo"] })
+        );
+        assert!(groups.admits(Some("a")));
+        assert!(groups.admits(Some("b")));
+        assert!(groups.admits(None));
+
+        let cut = groups.cut_token(Some("a")).unwrap();
+        groups.set_state("a", GroupState::Draining).unwrap();
+        assert!(!groups.admits(Some("a")));
+        assert!(!cut.is_cancelled());
+        groups.set_state("a", GroupState::Disabled).unwrap();
+        assert!(cut.is_cancelled());
+        groups.set_state("a", GroupState::Enabled).unwrap();
+        assert!(groups.admits(Some("a")));
+        assert!(!groups.cut_token(Some("a")).unwrap().is_cancelled());
+
+        groups.join("foo", None).unwrap();
+        assert_eq!(groups.get_json("a").unwrap()["services"], json!([]));
+        assert!(groups.join("foo", Some("b")).is_err());
+        assert!(groups.set_state("b", GroupState::Enabled).is_err());
+    }
+}
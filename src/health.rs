@@ -0,0 +1,272 @@
+// Readiness of the services running in the process, for the `/readyz` endpoint of the admin API.
+// It's ready when every configured service has a control channel established. Also logs a
+// summary once the startup is done, so it takes no scrolling to tell whether it succeeded
+use crate::build_info;
+use crate::constants::STARTUP_SUMMARY_TIMEOUT;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+use tracing::{info, warn};
+
+#[derive(Default)]
+struct Registry {
+    // The number of times each service is configured. A client and a server in the same
+    // process may have services of the same name
+    configured: HashMap<String, usize>,
+    // The number of established control channels of each service
+    up: HashMap<String, usize>,
+    // The addresses bound, along with what listens there
+    listening: Vec<(String, String)>,
+    // Where visitors reach the services of the client, as reported by the server
+    public_addrs: HashMap<String, String>,
+    // Why the control channel of each stopped service stopped, until it's configured again
+    stopped: HashMap<String, String>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Default::default();
+}
+
+fn inc(m: &mut HashMap<String, usize>, service: &str) {
+    *m.entry(service.to_string()).or_default() += 1;
+}
+
+fn dec(m: &mut HashMap<String, usize>, service: &str) {
+    if let Some(n) = m.get_mut(service) {
+        *n -= 1;
+        if *n == 0 {
+            m.remove(service);
+        }
+    }
+}
+
+// Counts a service as configured as long as it lives
+pub(crate) struct ConfiguredGuard(String);
+
+impl ConfiguredGuard {
+    pub(crate) fn new(service: &str) -> ConfiguredGuard {
+        let mut r = REGISTRY.lock().unwrap();
+        inc(&mut r.configured, service);
+        r.stopped.remove(service);
+        ConfiguredGuard(service.to_string())
+    }
+}
+
+impl Drop for ConfiguredGuard {
+    fn drop(&mut self) {
+        let mut r = REGISTRY.lock().unwrap();
+        dec(&mut r.configured, &self.0);
+        if !r.configured.contains_key(&self.0) {
+            r.public_addrs.remove(&self.0);
+            r.stopped.remove(&self.0);
+        }
+    }
+}
+
+// Kept while the service is configured, even if the control channel is reconnecting
+pub(crate) fn set_public_addr(service: &str, addr: &str) {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .public_addrs
+        .insert(service.to_string(), addr.to_string());
+}
+
+pub(crate) fn public_addrs() -> Value {
+    let r = REGISTRY.lock().unwrap();
+    let services: BTreeMap<&String, &String> = r.public_addrs.iter().collect();
+    json!({ "services": services })
+}
+
+// Called along with the event of `ServiceStopped`
+pub(crate) fn set_stopped(service: &str, reason: &str) {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .stopped
+        .insert(service.to_string(), reason.to_string());
+}
+
+// Called along with the events of `ServiceUpGuard`
+pub(crate) fn up(service: &str) {
+    inc(&mut REGISTRY.lock().unwrap().up, service);
+}
+
+pub(crate) fn down(service: &str) {
+    dec(&mut REGISTRY.lock().unwrap().up, service);
+}
+
+// Configured services without any control channel established, sorted by their names
+pub(crate) fn not_ready() -> Vec<String> {
+    let r = REGISTRY.lock().unwrap();
+    let mut v: Vec<String> = r
+        .configured
+        .keys()
+        .filter(|k| !r.up.contains_key(*k))
+        .cloned()
+        .collect();
+    v.sort();
+    v
+}
+
+// The configured services with the numbers of their control channels, and the addresses bound
+pub(crate) fn to_json() -> Value {
+    let r = REGISTRY.lock().unwrap();
+    let services: BTreeMap<&String, Value> = r
+        .configured
+        .keys()
+        .chain(r.up.keys())
+        .map(|name| {
+            let v = json!({
+                "configured": r.configured.get(name).copied().unwrap_or_default(),
+                "control_channels": r.up.get(name).copied().unwrap_or_default(),
+                "stopped": r.stopped.get(name),
+            });
+            (name, v)
+        })
+        .collect();
+    let listening: Vec<Value> = r
+        .listening
+        .iter()
+        .map(|(name, addr)| json!({ "name": name, "addr": addr }))
+        .collect();
+    json!({ "services": services, "listening": listening })
+}
+
+// Records the address bound by a listener as long as it lives
+pub(crate) struct ListeningGuard(String, String);
+
+impl ListeningGuard {
+    // `local_addr` is the address actually bound, which tells the port if `bind_addr` has none
+    pub(crate) fn new(
+        name: &str,
+        local_addr: Option<SocketAddr>,
+        bind_addr: &str,
+    ) -> ListeningGuard {
+        let addr = local_addr
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| bind_addr.to_string());
+        let v = (name.to_string(), addr);
+        REGISTRY.lock().unwrap().listening.push(v.clone());
+        ListeningGuard(v.0, v.1)
+    }
+}
+
+impl Drop for ListeningGuard {
+    fn drop(&mut self) {
+        let mut r = REGISTRY.lock().unwrap();
+        if let Some(i) = r
+            .listening
+            .iter()
+            .position(|(name, addr)| *name == self.0 && *addr == self.1)
+        {
+            r.listening.remove(i);
+        }
+    }
+}
+
+// Log the summary once every service is established, or after `STARTUP_SUMMARY_TIMEOUT`
+// with warnings about the ones that aren't
+pub(crate) async fn log_startup_summary(mode: &str, transport: &str) {
+    let deadline = Instant::now() + Duration::from_secs(STARTUP_SUMMARY_TIMEOUT);
+    while !not_ready().is_empty() && Instant::now() < deadline {
+        time::sleep(Duration::from_secs(1)).await;
+    }
+
+    let not_ready = not_ready();
+    let (configured, listening) = {
+        let r = REGISTRY.lock().unwrap();
+        let listening: Vec<String> = r
+            .listening
+            .iter()
+            .map(|(name, addr)| format!("{}={}", name, addr))
+            .collect();
+        (r.configured.len(), listening)
+    };
+    info!(
+        mode = %mode,
+        transport = %transport,
+        features = %build_info::features().join(","),
+        services = configured,
+        established = configured - not_ready.len(),
+        listening = %listening.join(" "),
+        "Startup summary"
+    );
+    for service in not_ready {
+        warn!(service = %service, "No control channel established after the startup");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_not_ready() {
+        let name = "test_health_not_ready";
+        let a = ConfiguredGuard::new(name);
+        let b = ConfiguredGuard::new(name);
+        assert!(not_ready().contains(&name.to_string()));
+        up(name);
+        assert!(!not_ready().contains(&name.to_string()));
+        drop(a);
+        assert!(!not_ready().contains(&name.to_string()));
+        down(name);
+        assert!(not_ready().contains(&name.to_string()));
+        drop(b);
+        assert!(!not_ready().contains(&name.to_string()));
+    }
+
+    #[test]
+    fn test_public_addrs() {
+        let name = "test_health_public_addrs";
+        let configured = ConfiguredGuard::new(name);
+        set_public_addr(name, "tunnel.example.com:8080");
+        assert_eq!(public_addrs()["services"][name], "tunnel.example.com:8080");
+        drop(configured);
+        assert!(public_addrs()["services"].get(name).is_none());
+    }
+
+    #[test]
+    fn test_stopped() {
+        let name = "test_health_stopped";
+        let a = ConfiguredGuard::new(name);
+        set_stopped(name, "Authentication error");
+        assert_eq!(
+            to_json()["services"][name]["stopped"],
+            "Authentication error"
+        );
+        // Configured again, like by a reload
+        let b = ConfiguredGuard::new(name);
+        drop(a);
+        assert!(to_json()["services"][name]["stopped"].is_null());
+        set_stopped(name, "Authentication error");
+        drop(b);
+        assert!(!REGISTRY.lock().unwrap().stopped.contains_key(name));
+    }
+
+    #[test]
+    fn test_listening() {
+        let name = "test_health_listening";
+        let listening = || {
+            REGISTRY
+                .lock()
+                .unwrap()
+                .listening
+                .iter()
+                .filter(|(n, _)| n == name)
+                .count()
+        };
+        let a = ListeningGuard::new(name, "127.0.0.1:1".parse().ok(), "");
+        let b = ListeningGuard::new(name, None, "127.0.0.1:2");
+        assert_eq!(listening(), 2);
+        drop(a);
+        assert_eq!(listening(), 1);
+        drop(b);
+        assert_eq!(listening(), 0);
+    }
+}
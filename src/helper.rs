@@ -1,14 +1,20 @@
 use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
+    io,
     net::SocketAddr,
     time::Duration,
 };
 
+use crate::config::{AcceptFilter, OversizedDatagram, ServerServiceConfig};
+use crate::constants::UDP_BUFFER_SIZE;
+use crate::error::Failure;
+use crate::proxy::Proxy;
 use anyhow::{anyhow, Context, Result};
-use socket2::{SockRef, TcpKeepalive};
-use tokio::net::{lookup_host, TcpStream, ToSocketAddrs, UdpSocket};
-use tracing::error;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use tokio::sync::broadcast;
+use tracing::{debug, error, warn};
 
 // Tokio hesitates to expose this option...So we have to do it on our own :(
 // The good news is that using socket2 it can be easily done, without losing portability.
@@ -29,12 +35,125 @@ pub fn set_tcp_keepalive(conn: &TcpStream) {
     }
 }
 
+// Connect to `addr` over TCP, through `proxy` if set
+pub async fn tcp_connect(addr: &str, proxy: Option<&Proxy>) -> Result<TcpStream> {
+    if let Some(proxy) = proxy {
+        return proxy.connect(addr).await;
+    }
+    let conn = TcpStream::connect(addr).await?;
+    set_tcp_keepalive(&conn);
+    Ok(conn)
+}
+
+// Socket options of the listener of a service, which only some platforms have
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListenOptions {
+    pub reuse_port: bool,
+    pub accept_filter: Option<AcceptFilter>,
+}
+
+impl ListenOptions {
+    pub fn from_config(service: &ServerServiceConfig) -> ListenOptions {
+        ListenOptions {
+            reuse_port: service.reuse_port,
+            accept_filter: service.accept_filter,
+        }
+    }
+}
+
+// Listen on `addr` over TCP, with the socket options in `opts`
+pub async fn tcp_listen(addr: &str, opts: ListenOptions) -> io::Result<TcpListener> {
+    if !opts.reuse_port && opts.accept_filter.is_none() {
+        return TcpListener::bind(addr).await;
+    }
+    let addr = lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::AddrNotAvailable, "Failed to lookup the host")
+    })?;
+
+    let s = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // As `TcpListener::bind` does
+    #[cfg(unix)]
+    s.set_reuse_address(true)?;
+    if opts.reuse_port {
+        set_reuse_port(&s)?;
+    }
+    s.set_nonblocking(true)?;
+    s.bind(&addr.into())?;
+    s.listen(1024)?;
+    // Filters can only be set on a listening socket
+    if let Some(filter) = opts.accept_filter {
+        set_accept_filter(&s, filter).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to set the accept filter {}. Is the kernel module {} loaded? {}",
+                    filter.name(),
+                    filter.module(),
+                    e
+                ),
+            )
+        })?;
+    }
+    TcpListener::from_std(s.into())
+}
+
+#[cfg(unix)]
+fn setsockopt<T>(s: &Socket, name: libc::c_int, value: &T) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let ret = unsafe {
+        libc::setsockopt(
+            s.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// FreeBSD hands all the connections to the last socket bound with SO_REUSEPORT, and only
+// spreads them over the sockets with SO_REUSEPORT_LB
+#[cfg(target_os = "freebsd")]
+fn set_reuse_port(s: &Socket) -> io::Result<()> {
+    setsockopt(s, libc::SO_REUSEPORT_LB, &(1 as libc::c_int))
+}
+
+#[cfg(all(unix, not(target_os = "freebsd")))]
+fn set_reuse_port(s: &Socket) -> io::Result<()> {
+    setsockopt(s, libc::SO_REUSEPORT, &(1 as libc::c_int))
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_: &Socket) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "freebsd")]
+fn set_accept_filter(s: &Socket, filter: AcceptFilter) -> io::Result<()> {
+    let mut arg: libc::accept_filter_arg = unsafe { std::mem::zeroed() };
+    for (c, b) in arg.af_name.iter_mut().zip(filter.name().bytes()) {
+        *c = b as libc::c_char;
+    }
+    setsockopt(s, libc::SO_ACCEPTFILTER, &arg)
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn set_accept_filter(_: &Socket, _: AcceptFilter) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// The configuration needs a feature that's not compiled. Exit as a configuration error
 #[allow(dead_code)]
 pub fn feature_not_compile(feature: &str) -> ! {
-    panic!(
+    error!(
         "The feature '{}' is not compiled in this binary. Please re-compile rathole",
         feature
-    )
+    );
+    std::process::exit(Failure::Config.exit_code())
 }
 
 /// Create a UDP socket and connect to `addr`
@@ -54,6 +173,133 @@ pub async fn udp_connect<A: ToSocketAddrs>(addr: A) -> Result<UdpSocket> {
     Ok(s)
 }
 
+/// Bind a UDP socket to receive datagrams from many peers
+pub async fn udp_bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+    let s = UdpSocket::bind(addr).await?;
+    #[cfg(windows)]
+    disable_udp_connreset(&s)?;
+    Ok(s)
+}
+
+// Windows reports an ICMP port unreachable, caused by a datagram sent to one peer, as
+// WSAECONNRESET from the next receive of the socket, whoever the datagram is from.
+// SIO_UDP_CONNRESET turns that off
+#[cfg(windows)]
+fn disable_udp_connreset(s: &UdpSocket) -> io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+    use windows_sys::Win32::Networking::WinSock::{WSAIoctl, SIO_UDP_CONNRESET, SOCKET_ERROR};
+
+    let enable: i32 = 0;
+    let mut returned: u32 = 0;
+    let ret = unsafe {
+        WSAIoctl(
+            s.as_raw_socket() as _,
+            SIO_UDP_CONNRESET,
+            &enable as *const i32 as *const _,
+            std::mem::size_of::<i32>() as u32,
+            std::ptr::null_mut(),
+            0,
+            &mut returned,
+            std::ptr::null_mut(),
+            None,
+        )
+    };
+    if ret == SOCKET_ERROR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Errors of receiving or sending a datagram that only concern that datagram or a peer,
+// rather than the socket. The socket should keep being used
+pub fn is_transient_udp_error(e: &io::Error) -> bool {
+    // An ICMP port unreachable from a previous send. Sockets from `udp_bind` don't report it on
+    // Windows, but connected ones do, as WSAECONNRESET rather than ECONNREFUSED
+    if matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused
+    ) {
+        return true;
+    }
+    // WSAEMSGSIZE. Windows fails the receive of a datagram larger than the buffer,
+    // where other platforms truncate it
+    #[cfg(windows)]
+    if e.raw_os_error() == Some(10040) {
+        return true;
+    }
+    false
+}
+
+// Whether the peer of a connected UDP socket is unreachable, by an error from it
+pub fn is_udp_peer_unreachable(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => true,
+        // WSAECONNRESET
+        io::ErrorKind::ConnectionReset => cfg!(windows),
+        _ => false,
+    }
+}
+
+// `max_datagram_size` of a UDP service. Datagrams are received into buffers of a byte more, so
+// that the oversized ones are noticed rather than silently cut by the socket
+#[derive(Debug, Clone)]
+pub struct DatagramLimit {
+    max: usize,
+    oversized: OversizedDatagram,
+    warned: bool, // Only the first oversized datagram is warned about, and the rest are debug logs
+}
+
+impl DatagramLimit {
+    pub fn new(max: Option<usize>, oversized: OversizedDatagram) -> DatagramLimit {
+        DatagramLimit {
+            max: max.unwrap_or(UDP_BUFFER_SIZE),
+            oversized,
+            warned: false,
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.max + 1
+    }
+
+    // The length of a datagram of `n` bytes to forward, or None to drop it
+    pub fn apply(&mut self, n: usize, visitor: SocketAddr) -> Option<usize> {
+        if n <= self.max {
+            return Some(n);
+        }
+        let action = match self.oversized {
+            OversizedDatagram::Truncate => "Truncate",
+            OversizedDatagram::Drop => "Drop",
+        };
+        if self.warned {
+            debug!(
+                "{} a datagram of the visitor {} over `max_datagram_size` {}",
+                action,
+                crate::privacy::addr(visitor),
+                self.max
+            );
+        } else {
+            self.warned = true;
+            warn!(
+                "{} a datagram of the visitor {} over `max_datagram_size` {}. Further ones are logged at debug level",
+                action, crate::privacy::addr(visitor), self.max
+            );
+        }
+        match self.oversized {
+            OversizedDatagram::Truncate => Some(self.max),
+            OversizedDatagram::Drop => None,
+        }
+    }
+}
+
+// Wait for a shutdown signal. A closed channel never signals, so callers don't
+// have to keep the sender alive. Dropping the future being run stops it as well
+pub async fn recv_shutdown(rx: &mut broadcast::Receiver<bool>) {
+    if let Err(broadcast::error::RecvError::Closed) = rx.recv().await {
+        std::future::pending::<()>().await;
+    }
+}
+
 // FIXME: These functions are for the load balance for UDP. But not used for now.
 #[allow(dead_code)]
 pub fn hash_socket_addr(a: &SocketAddr) -> u64 {
@@ -81,9 +327,22 @@ pub fn floor_to_pow_of_2(x: usize) -> usize {
 mod test {
     use tokio::net::UdpSocket;
 
-    use crate::helper::{floor_to_pow_of_2, log2_floor};
+    use crate::config::OversizedDatagram;
+    use crate::helper::{floor_to_pow_of_2, log2_floor, DatagramLimit};
 
-    use super::udp_connect;
+    use super::{tcp_listen, udp_connect, ListenOptions};
+
+    #[test]
+    fn test_datagram_limit() {
+        let from = "127.0.0.1:2333".parse().unwrap();
+        let mut limit = DatagramLimit::new(Some(1200), OversizedDatagram::Truncate);
+        assert_eq!(limit.buffer_size(), 1201);
+        assert_eq!(limit.apply(1200, from), Some(1200));
+        assert_eq!(limit.apply(1201, from), Some(1200));
+        let mut limit = DatagramLimit::new(None, OversizedDatagram::Drop);
+        assert_eq!(limit.apply(2048, from), Some(2048));
+        assert_eq!(limit.apply(2049, from), None);
+    }
 
     #[test]
     fn test_log2_floor() {
@@ -149,4 +408,19 @@ mod test {
             handle.await.unwrap();
         }
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_tcp_listen_reuse_port() {
+        let opts = ListenOptions {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let a = tcp_listen("127.0.0.1:0", opts).await.unwrap();
+        let addr = a.local_addr().unwrap().to_string();
+        // Both listen on the port, rather than the second failing with `AddrInUse`
+        let b = tcp_listen(&addr, opts).await.unwrap();
+        assert_eq!(b.local_addr().unwrap(), a.local_addr().unwrap());
+        assert!(tcp_listen(&addr, ListenOptions::default()).await.is_err());
+    }
 }
@@ -0,0 +1,91 @@
+// Honeypot services of the server. They have no client behind them, and only record what
+// visitors send, for the telemetry of scans on ports that are otherwise unused
+use crate::acl::ACL;
+use crate::config::ServerServiceConfig;
+use crate::constants::{listen_backoff, HONEYPOT_MAX_PAYLOAD_LEN, HONEYPOT_READ_TIMEOUT};
+use crate::events::{self, Event};
+use crate::health::ListeningGuard;
+use crate::privacy;
+use crate::task_group::TaskGroup;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{error, info, warn};
+
+// Listen at `bind_addr` of the service until `tasks` is cancelled
+pub fn run_honeypot(service: &ServerServiceConfig, tasks: TaskGroup) {
+    let (name, bind_addr) = (service.name.clone(), service.bind_addr.clone());
+    let group = service.group.clone();
+    let listener_tasks = tasks.clone();
+    listener_tasks.spawn(async move {
+        let l: TcpListener = match backoff::future::retry_notify(
+            listen_backoff(),
+            || async { Ok(TcpListener::bind(&bind_addr).await?) },
+            |e, duration| warn!("{:?}. Retry in {:?}", e, duration),
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to listen for the honeypot: {:#}", e);
+                return;
+            }
+        };
+        info!(service = %name, "Honeypot listening at {}", bind_addr);
+        let _listening = ListeningGuard::new(&name, l.local_addr().ok(), &bind_addr);
+
+        loop {
+            let (conn, addr) = match l.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Failed to accept a visitor: {}", e);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            if !ACL.admit(&name, group.as_deref(), addr.ip()) {
+                continue;
+            }
+            let name = name.clone();
+            tasks.spawn(async move {
+                let payload = read_payload(conn).await;
+                record(&name, addr, payload);
+            });
+        }
+    });
+}
+
+// Read until the visitor stops sending, or the payload reaches the limit
+async fn read_payload(mut conn: TcpStream) -> Vec<u8> {
+    let mut payload = Vec::new();
+    let _ = time::timeout(Duration::from_secs(HONEYPOT_READ_TIMEOUT), async {
+        let mut buf = [0u8; 4096];
+        while payload.len() < HONEYPOT_MAX_PAYLOAD_LEN {
+            match conn.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => payload.extend_from_slice(&buf[..n]),
+            }
+        }
+    })
+    .await;
+    payload.truncate(HONEYPOT_MAX_PAYLOAD_LEN);
+    payload
+}
+
+fn record(service: &str, visitor: SocketAddr, payload: Vec<u8>) {
+    privacy::audit(service, visitor);
+    warn!(
+        service = %service,
+        visitor = %privacy::addr(visitor),
+        len = payload.len(),
+        "Honeypot visited. Payload: {:?}",
+        String::from_utf8_lossy(&payload)
+    );
+    events::emit(|| Event::HoneypotVisited {
+        service: service.to_string(),
+        visitor,
+        payload,
+    });
+}
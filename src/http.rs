@@ -0,0 +1,358 @@
+// A minimal HTTP/1.1 client for webhooks and other outbound requests.
+// Only `http://` is supported, plus `https://` when the `tls` feature is enabled.
+use anyhow::{anyhow, bail, Context, Result};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+const HTTP_TIMEOUT: u64 = 10; // Timeout in seconds for a whole request
+const HTTP_MAX_RESPONSE_SIZE: u64 = 64 * 1024 * 1024;
+#[cfg(feature = "self-update")]
+const HTTP_MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Url<'a> {
+    https: bool,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+fn parse_url(url: &str) -> Result<Url<'_>> {
+    let (https, rest) = if let Some(v) = url.strip_prefix("http://") {
+        (false, v)
+    } else if let Some(v) = url.strip_prefix("https://") {
+        (true, v)
+    } else {
+        bail!("Unsupported URL {}. Only http and https are supported", url);
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    let (host, port) = match authority.rsplit_once(':') {
+        // Skip the colons of a bare IPv6 address
+        Some((h, p)) if !p.contains(']') => (
+            h,
+            p.parse::<u16>()
+                .with_context(|| format!("Invalid port in URL {}", url))?,
+        ),
+        _ => (authority, if https { 443 } else { 80 }),
+    };
+
+    if host.is_empty() {
+        bail!("Missing host in URL {}", url);
+    }
+
+    Ok(Url {
+        https,
+        host,
+        port,
+        path,
+    })
+}
+
+pub struct Response {
+    pub status: u16,
+    #[cfg_attr(not(any(feature = "self-update", feature = "acme")), allow(dead_code))]
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    #[cfg(any(feature = "self-update", feature = "acme", test))]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// Send a request and read the whole response
+pub async fn request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<Response> {
+    time::timeout(
+        Duration::from_secs(HTTP_TIMEOUT),
+        do_request(method, url, headers, body),
+    )
+    .await
+    .with_context(|| format!("Timeout requesting {}", url))?
+}
+
+// Send a POST request with a JSON body, and fail if the status is not 2xx
+pub async fn post_json(url: &str, body: &serde_json::Value) -> Result<()> {
+    let body = serde_json::to_vec(body)?;
+    let resp = request("POST", url, &[("Content-Type", "application/json")], &body).await?;
+    if !(200..300).contains(&resp.status) {
+        bail!(
+            "{} responded with status {}: {}",
+            url,
+            resp.status,
+            String::from_utf8_lossy(&resp.body).trim()
+        );
+    }
+    Ok(())
+}
+
+// Download `url`, following redirects. `timeout` applies to the whole download
+#[cfg(feature = "self-update")]
+pub async fn get(url: &str, timeout: Duration) -> Result<Vec<u8>> {
+    time::timeout(timeout, async {
+        let mut url = url.to_string();
+        for _ in 0..=HTTP_MAX_REDIRECTS {
+            let resp = do_request("GET", &url, &[], &[]).await?;
+            match resp.status {
+                200..=299 => return Ok(resp.body),
+                301 | 302 | 303 | 307 | 308 => {
+                    let location = resp
+                        .header("Location")
+                        .ok_or_else(|| anyhow!("{} redirected without a location", url))?;
+                    url = resolve_redirect(&url, location)?;
+                }
+                status => bail!("{} responded with status {}", url, status),
+            }
+        }
+        bail!("Too many redirects")
+    })
+    .await
+    .with_context(|| format!("Timeout downloading {}", url))?
+}
+
+#[cfg(feature = "self-update")]
+fn resolve_redirect(url: &str, location: &str) -> Result<String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Ok(location.to_string());
+    }
+    if !location.starts_with('/') {
+        bail!("Unsupported redirect to {}", location);
+    }
+    let u = parse_url(url)?;
+    Ok(format!(
+        "{}://{}:{}{}",
+        if u.https { "https" } else { "http" },
+        u.host,
+        u.port,
+        location
+    ))
+}
+
+async fn do_request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<Response> {
+    let u = parse_url(url)?;
+    let conn = TcpStream::connect((u.host.trim_matches(|c| c == '[' || c == ']'), u.port))
+        .await
+        .with_context(|| format!("Failed to connect to {}", url))?;
+
+    if u.https {
+        #[cfg(feature = "tls")]
+        {
+            let connector = tokio_native_tls::TlsConnector::from(
+                tokio_native_tls::native_tls::TlsConnector::new()?,
+            );
+            let conn = connector
+                .connect(u.host, conn)
+                .await
+                .with_context(|| format!("Failed to do TLS handshake with {}", url))?;
+            return send(conn, method, &u, headers, body).await;
+        }
+        #[cfg(not(feature = "tls"))]
+        crate::helper::feature_not_compile("tls")
+    }
+
+    send(conn, method, &u, headers, body).await
+}
+
+async fn send<T: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: T,
+    method: &str,
+    u: &Url<'_>,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<Response> {
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rathole/{}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        u.path,
+        u.host,
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+    for (k, v) in headers {
+        req.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    req.push_str("\r\n");
+
+    conn.write_all(req.as_bytes()).await?;
+    conn.write_all(body).await?;
+    conn.flush().await?;
+
+    let mut resp = Vec::new();
+    conn.take(HTTP_MAX_RESPONSE_SIZE + 1)
+        .read_to_end(&mut resp)
+        .await
+        .with_context(|| "Failed to read the response")?;
+    if resp.len() as u64 > HTTP_MAX_RESPONSE_SIZE {
+        bail!(
+            "The response is larger than {} bytes",
+            HTTP_MAX_RESPONSE_SIZE
+        );
+    }
+
+    parse_response(resp)
+}
+
+fn parse_response(mut resp: Vec<u8>) -> Result<Response> {
+    let header_end = resp
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("Malformed HTTP response"))?;
+    let head = String::from_utf8_lossy(&resp[..header_end]).to_string();
+
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("Malformed HTTP status line"))?;
+
+    let headers: Vec<(String, String)> = head
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let chunked = headers.iter().any(|(k, v)| {
+        k.eq_ignore_ascii_case("transfer-encoding") && v.to_ascii_lowercase().contains("chunked")
+    });
+
+    let body = resp.split_off(header_end + 4);
+    let body = if chunked { dechunk(&body)? } else { body };
+
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| anyhow!("Malformed chunked body"))?;
+        let size = String::from_utf8_lossy(&data[..line_end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).with_context(|| "Malformed chunk size")?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            break;
+        }
+        if data.len() < size {
+            bail!("Truncated chunked body");
+        }
+        body.extend_from_slice(&data[..size]);
+        data = data.get(size + 2..).unwrap_or_default();
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let t = [
+            (
+                "http://example.com",
+                Url {
+                    https: false,
+                    host: "example.com",
+                    port: 80,
+                    path: "/",
+                },
+            ),
+            (
+                "https://example.com/hook?a=1",
+                Url {
+                    https: true,
+                    host: "example.com",
+                    port: 443,
+                    path: "/hook?a=1",
+                },
+            ),
+            (
+                "http://127.0.0.1:8080/a/b",
+                Url {
+                    https: false,
+                    host: "127.0.0.1",
+                    port: 8080,
+                    path: "/a/b",
+                },
+            ),
+            (
+                "http://[::1]:8080/",
+                Url {
+                    https: false,
+                    host: "[::1]",
+                    port: 8080,
+                    path: "/",
+                },
+            ),
+        ];
+        for (url, expected) in t {
+            assert_eq!(parse_url(url).unwrap(), expected);
+        }
+
+        assert!(parse_url("ftp://example.com").is_err());
+        assert!(parse_url("http://:80/").is_err());
+        assert!(parse_url("http://example.com:port/").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let resp =
+            parse_response(b"HTTP/1.1 204 No Content\r\nServer: x\r\n\r\n".to_vec()).unwrap();
+        assert_eq!(resp.status, 204);
+        assert_eq!(resp.header("server"), Some("x"));
+        assert!(resp.body.is_empty());
+
+        let resp = parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"
+                .to_vec(),
+        )
+        .unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body, b"hello world");
+
+        assert!(parse_response(b"garbage".to_vec()).is_err());
+    }
+
+    #[cfg(feature = "self-update")]
+    #[test]
+    fn test_resolve_redirect() {
+        assert_eq!(
+            resolve_redirect("https://a.com/x", "https://b.com/y").unwrap(),
+            "https://b.com/y"
+        );
+        assert_eq!(
+            resolve_redirect("http://a.com:8080/x", "/y").unwrap(),
+            "http://a.com:8080/y"
+        );
+        assert!(resolve_redirect("http://a.com/x", "y").is_err());
+    }
+}
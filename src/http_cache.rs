@@ -0,0 +1,260 @@
+// A small in-memory cache of the responses of HTTP services, so that static assets aren't pulled
+// through the uplink of the client again for every visitor. Only fresh `200` responses to `GET`,
+// with a `Content-Length` and a `max-age` or `s-maxage`, are cached. It's looked up and filled by
+// `http_proxy`
+use crate::config::ServerServiceConfig;
+use crate::http_proxy::Head;
+use crate::state_dump;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+pub(crate) struct Entry {
+    // The status line and headers, without `Age`, hop-by-hop ones, and the blank line
+    head: Vec<u8>,
+    body: Vec<u8>,
+    // The request headers that the response varies on, with their values
+    vary: Vec<(String, Option<String>)>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl Entry {
+    pub(crate) fn new(req: &Head, resp: &Head, body: Vec<u8>, ttl: Duration) -> Entry {
+        let mut head = format!("{}\r\n", resp.start);
+        for (k, v) in &resp.headers {
+            if !["age", "connection", "keep-alive"]
+                .iter()
+                .any(|h| k.eq_ignore_ascii_case(h))
+            {
+                head += &format!("{}: {}\r\n", k, v);
+            }
+        }
+        let vary = resp
+            .tokens("vary")
+            .into_iter()
+            .map(|k| {
+                let v = req.header(&k).map(str::to_string);
+                (k, v)
+            })
+            .collect();
+        let stored = Instant::now();
+        Entry {
+            head: head.into_bytes(),
+            body,
+            vary,
+            stored,
+            expires: stored + ttl,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.head.len() + self.body.len()
+    }
+
+    fn matches(&self, req: &Head) -> bool {
+        self.vary.iter().all(|(k, v)| req.header(k) == v.as_deref())
+    }
+
+    pub(crate) async fn write<W: AsyncWrite + Unpin>(
+        &self,
+        w: &mut W,
+        head_only: bool,
+    ) -> io::Result<()> {
+        let age = self.stored.elapsed().as_secs();
+        w.write_all(&self.head).await?;
+        w.write_all(format!("Age: {}\r\n\r\n", age).as_bytes())
+            .await?;
+        if !head_only {
+            w.write_all(&self.body).await?;
+        }
+        Ok(())
+    }
+}
+
+struct Cached {
+    entry: Arc<Entry>,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Cached>,
+    size: usize,
+    // Bumped on every use, for evicting the least recently used entries
+    clock: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(v) = self.map.remove(key) {
+            self.size -= v.entry.size();
+        }
+    }
+}
+
+// The cache of a service, shared by all its visitors
+pub struct HttpCache {
+    service: String,
+    max_size: usize,
+    max_entry_size: usize,
+    entries: Mutex<Entries>,
+}
+
+impl HttpCache {
+    pub fn from_config(service: &ServerServiceConfig) -> Option<HttpCache> {
+        service.http_cache.as_ref().map(|config| HttpCache {
+            service: service.name.clone(),
+            max_size: config.max_size,
+            max_entry_size: config.max_entry_size,
+            entries: Default::default(),
+        })
+    }
+
+    // Whether a body of `len` bytes may be cached
+    pub(crate) fn fits(&self, len: u64) -> bool {
+        len <= self.max_entry_size as u64
+    }
+
+    pub(crate) fn get(&self, key: &str, req: &Head) -> Option<Arc<Entry>> {
+        let hit = {
+            let mut entries = self.entries.lock().unwrap();
+            let entries = &mut *entries;
+            match entries.map.get_mut(key) {
+                Some(v) if v.entry.expires > Instant::now() && v.entry.matches(req) => {
+                    entries.clock += 1;
+                    v.used = entries.clock;
+                    Some(v.entry.clone())
+                }
+                _ => None,
+            }
+        };
+        state_dump::http_cache_lookup(&self.service, hit.is_some());
+        hit
+    }
+
+    pub(crate) fn insert(&self, key: String, entry: Entry) {
+        let size = entry.size();
+        if size > self.max_size {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        // Expired entries go first, and then the least recently used ones
+        if entries.size + size > self.max_size {
+            let now = Instant::now();
+            let expired: Vec<_> = entries
+                .map
+                .iter()
+                .filter(|(_, v)| v.entry.expires <= now)
+                .map(|(k, _)| k.clone())
+                .collect();
+            expired.iter().for_each(|k| entries.remove(k));
+        }
+        while entries.size + size > self.max_size {
+            let oldest = entries
+                .map
+                .iter()
+                .min_by_key(|(_, v)| v.used)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(k) => entries.remove(&k),
+                None => break,
+            }
+        }
+        entries.clock += 1;
+        entries.size += size;
+        let used = entries.clock;
+        entries.map.insert(
+            key,
+            Cached {
+                entry: Arc::new(entry),
+                used,
+            },
+        );
+    }
+
+    pub(crate) fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+// How long a response may be served from the cache. `None` if it can't be cached
+pub(crate) fn freshness(resp: &Head) -> Option<Duration> {
+    if resp.has("set-cookie") || resp.tokens("vary").iter().any(|v| v == "*") {
+        return None;
+    }
+    let directives = resp.tokens("cache-control");
+    let directive = |v: &String| v.split('=').next().unwrap_or_default().trim().to_string();
+    if directives
+        .iter()
+        .any(|v| matches!(directive(v).as_str(), "no-store" | "no-cache" | "private"))
+    {
+        return None;
+    }
+    let max_age = |name: &str| {
+        directives.iter().find_map(|v| {
+            let (k, v) = v.split_once('=')?;
+            (k.trim() == name)
+                .then(|| v.trim().trim_matches('"').parse::<u64>().ok())
+                .flatten()
+        })
+    };
+    let ttl = max_age("s-maxage").or_else(|| max_age("max-age"))?;
+    // The time it has spent in other caches already
+    let age = resp
+        .header("age")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or_default();
+    (ttl > age).then(|| Duration::from_secs(ttl - age))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn head(raw: &str) -> Head {
+        Head::parse(raw.as_bytes().to_vec()).ok().unwrap()
+    }
+
+    #[test]
+    fn test_freshness() {
+        let resp = |cache_control: &str| {
+            head(&format!(
+                "HTTP/1.1 200 OK\r\nCache-Control: {}\r\n\r\n",
+                cache_control
+            ))
+        };
+        assert_eq!(
+            freshness(&resp("max-age=60")),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            freshness(&resp("public, max-age=60, s-maxage=\"600\"")),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(freshness(&resp("max-age=0")), None);
+        assert_eq!(freshness(&resp("private, max-age=60")), None);
+        assert_eq!(
+            freshness(&resp("no-cache=\"Set-Cookie\", max-age=60")),
+            None
+        );
+        assert_eq!(freshness(&resp("no-store")), None);
+        assert_eq!(
+            freshness(&head(
+                "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nAge: 50\r\n\r\n"
+            )),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            freshness(&head(
+                "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nSet-Cookie: a=b\r\n\r\n"
+            )),
+            None
+        );
+        assert_eq!(freshness(&head("HTTP/1.1 200 OK\r\n\r\n")), None);
+    }
+}
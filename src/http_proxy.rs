@@ -0,0 +1,691 @@
+// Forwarding of the visitors of HTTP services request by request, for what needs to follow HTTP:
+// the response cache, and prefetching of ranges. Anything the proxy doesn't follow, like upgrades
+// or bodies of unknown length, is forwarded as it is from then on
+use crate::config::ServerServiceConfig;
+use crate::constants::HTTP_HEAD_PEEK_MAX_LEN;
+use crate::http_cache::{freshness, Entry, HttpCache};
+use crate::state_dump;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{copy_bidirectional, sink, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub struct HttpProxy {
+    service: String,
+    cache: Option<HttpCache>,
+    // The largest range prefetched. Off if not set
+    prefetch: Option<u64>,
+}
+
+impl HttpProxy {
+    pub fn from_config(service: &ServerServiceConfig) -> Option<Arc<HttpProxy>> {
+        if service.http_cache.is_none() && service.range_prefetch.is_none() {
+            return None;
+        }
+        Some(Arc::new(HttpProxy {
+            service: service.name.clone(),
+            cache: HttpCache::from_config(service),
+            prefetch: service.range_prefetch.as_ref().map(|v| v.max_size as u64),
+        }))
+    }
+}
+
+// The head of a request or a response
+pub(crate) struct Head {
+    pub(crate) raw: Vec<u8>,
+    // The request line or the status line
+    pub(crate) start: String,
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl Head {
+    pub(crate) fn parse(raw: Vec<u8>) -> Result<Head, Vec<u8>> {
+        let parsed = std::str::from_utf8(&raw).ok().and_then(|text| {
+            let mut lines = text.split("\r\n").filter(|l| !l.is_empty());
+            let start = lines.next()?.to_string();
+            let headers = lines
+                .map(|l| {
+                    l.split_once(':')
+                        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some((start, headers))
+        });
+        match parsed {
+            Some((start, headers)) => Ok(Head {
+                raw,
+                start,
+                headers,
+            }),
+            None => Err(raw),
+        }
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub(crate) fn has(&self, name: &str) -> bool {
+        self.header(name).is_some()
+    }
+
+    // The comma separated values of all the headers `name`, in lowercase
+    pub(crate) fn tokens(&self, name: &str) -> Vec<String> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .map(|v| v.trim().to_ascii_lowercase())
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+}
+
+// A stream, along with what has been read from it but not handled yet
+struct Buffered<'a, S> {
+    inner: &'a mut S,
+    buf: Vec<u8>,
+    read: u64,
+}
+
+impl<'a, S: AsyncRead + Unpin> Buffered<'a, S> {
+    fn new(inner: &'a mut S) -> Self {
+        Buffered {
+            inner,
+            buf: Vec::new(),
+            read: 0,
+        }
+    }
+
+    // Put back what has been taken from the buffer
+    fn unread(&mut self, data: Vec<u8>) {
+        self.buf.splice(0..0, data);
+    }
+
+    async fn fill(&mut self) -> io::Result<usize> {
+        self.buf.reserve(8 * 1024);
+        let n = self.inner.read_buf(&mut self.buf).await?;
+        self.read += n as u64;
+        Ok(n)
+    }
+
+    // Up to and including the first `delimiter`. `None` if the stream ends before it, or it
+    // isn't found within `HTTP_HEAD_PEEK_MAX_LEN` bytes. The buffer is left as it is then
+    async fn until(&mut self, delimiter: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(i) = self
+                .buf
+                .windows(delimiter.len())
+                .position(|w| w == delimiter)
+            {
+                return Ok(Some(self.buf.drain(..i + delimiter.len()).collect()));
+            }
+            if self.buf.len() > HTTP_HEAD_PEEK_MAX_LEN || self.fill().await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    async fn head(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.until(b"\r\n\r\n").await
+    }
+
+    async fn line(&mut self) -> io::Result<Vec<u8>> {
+        self.until(b"\r\n")
+            .await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed chunked body"))
+    }
+
+    async fn take(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        while self.buf.len() < n {
+            if self.fill().await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    // Forward `n` bytes to `w`
+    async fn forward<W: AsyncWrite + Unpin>(&mut self, n: u64, w: &mut W) -> io::Result<()> {
+        let buffered = self.buf.len().min(n as usize);
+        w.write_all(&self.buf[..buffered]).await?;
+        self.buf.drain(..buffered);
+        let rest = n - buffered as u64;
+        if rest > 0 {
+            let copied = tokio::io::copy(&mut (&mut *self.inner).take(rest), w).await?;
+            self.read += copied;
+            if copied < rest {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(())
+    }
+
+    // Forward a chunked body to `w`, up to and including its trailers
+    async fn forward_chunked<W: AsyncWrite + Unpin>(&mut self, w: &mut W) -> io::Result<()> {
+        loop {
+            let line = self.line().await?;
+            w.write_all(&line).await?;
+            let size = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|v| v.split(';').next())
+                .and_then(|v| u64::from_str_radix(v.trim(), 16).ok())
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Malformed chunk size")
+                })?;
+            if size == 0 {
+                loop {
+                    let line = self.line().await?;
+                    w.write_all(&line).await?;
+                    if line == b"\r\n" {
+                        return Ok(());
+                    }
+                }
+            }
+            self.forward(size + 2, w).await?;
+        }
+    }
+}
+
+// A range requested from the service ahead of the visitor, who reads a large file piece by piece.
+// The visitor is expected to ask for it with the same request, apart from `Range`
+struct Prefetch {
+    start: String,
+    headers: Vec<(String, String)>,
+    range: String,
+}
+
+impl Prefetch {
+    fn new(req: &Head, range: String) -> Prefetch {
+        Prefetch {
+            start: req.start.clone(),
+            headers: without_range(req),
+            range,
+        }
+    }
+
+    fn request(&self) -> Vec<u8> {
+        let mut req = format!("{}\r\n", self.start);
+        for (k, v) in &self.headers {
+            req += &format!("{}: {}\r\n", k, v);
+        }
+        req += &format!("Range: {}\r\n\r\n", self.range);
+        req.into_bytes()
+    }
+
+    fn matches(&self, req: &Head) -> bool {
+        req.start == self.start
+            && req.header("range") == Some(self.range.as_str())
+            && without_range(req) == self.headers
+    }
+}
+
+fn without_range(req: &Head) -> Vec<(String, String)> {
+    req.headers
+        .iter()
+        .filter(|(k, _)| !k.eq_ignore_ascii_case("range"))
+        .cloned()
+        .collect()
+}
+
+// The range after the one in `resp`, of the same size, which the visitor is likely to ask for
+// next. Only bounded ranges of at most `max` bytes are followed
+fn next_range(req: &Head, resp: &Head, max: u64) -> Option<String> {
+    let (first, last) = req
+        .header("range")?
+        .strip_prefix("bytes=")?
+        .split_once('-')?;
+    first.trim().parse::<u64>().ok()?;
+    last.trim().parse::<u64>().ok()?;
+    let (range, total) = resp
+        .header("content-range")?
+        .strip_prefix("bytes ")?
+        .split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (
+        start.trim().parse::<u64>().ok()?,
+        end.trim().parse::<u64>().ok()?,
+    );
+    let len = end.checked_sub(start)? + 1;
+    if len > max {
+        return None;
+    }
+    let next = end + 1;
+    let last = match total.trim().parse::<u64>() {
+        Ok(total) if next >= total => return None,
+        Ok(total) => (end + len).min(total - 1),
+        // The size of the file isn't known
+        Err(_) => end + len,
+    };
+    Some(format!("bytes={}-{}", next, last))
+}
+
+// Read the response to a prefetched range. `None` if it can't be held, and is dropped
+async fn read_prefetched<C: AsyncRead + Unpin>(
+    ch: &mut Buffered<'_, C>,
+    max: u64,
+) -> io::Result<Option<(Head, Vec<u8>)>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed prefetched response");
+    let resp = loop {
+        let resp = ch.head().await?.ok_or_else(invalid)?;
+        let resp = Head::parse(resp).map_err(|_| invalid())?;
+        if !resp
+            .start
+            .split(' ')
+            .nth(1)
+            .is_some_and(|v| v.starts_with('1'))
+        {
+            break resp;
+        }
+    };
+    if resp.has("transfer-encoding") {
+        ch.forward_chunked(&mut sink()).await?;
+        return Ok(None);
+    }
+    let len = resp
+        .header("content-length")
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(invalid)?;
+    if resp.start.split(' ').nth(1) != Some("206") || len > max {
+        ch.forward(len, &mut sink()).await?;
+        return Ok(None);
+    }
+    let body = ch.take(len as usize).await?;
+    Ok(Some((resp, body)))
+}
+
+// Proxy HTTP visitors. Returns the bytes read from the data channel and from the visitor, like
+// `copy_bidirectional`
+pub async fn forward<C, V>(ch: &mut C, visitor: &mut V, proxy: &HttpProxy) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    V: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut ch = Buffered::new(ch);
+    let mut visitor = Buffered::new(visitor);
+    let mut prefetch = None;
+    while exchange(&mut ch, &mut visitor, proxy, &mut prefetch).await? {}
+
+    // What's left of both is forwarded as it is
+    ch.inner.write_all(&visitor.buf).await?;
+    ch.inner.flush().await?;
+    visitor.inner.write_all(&ch.buf).await?;
+    visitor.inner.flush().await?;
+    let (outbound, inbound) = copy_bidirectional(ch.inner, visitor.inner).await?;
+    Ok((ch.read + outbound, visitor.read + inbound))
+}
+
+// Handle a request of the visitor, and the response to it. `false` if the rest of the connection
+// isn't followed. `prefetch` is the range requested ahead of the visitor, if any
+async fn exchange<C, V>(
+    ch: &mut Buffered<'_, C>,
+    visitor: &mut Buffered<'_, V>,
+    proxy: &HttpProxy,
+    prefetch: &mut Option<Prefetch>,
+) -> io::Result<bool>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    V: AsyncRead + AsyncWrite + Unpin,
+{
+    let req = visitor.head().await?;
+    // The response to a prefetched range comes first, whatever the visitor asks for
+    let prefetched = match (prefetch.take(), proxy.prefetch) {
+        (Some(p), Some(max)) => Some((p, read_prefetched(ch, max).await?)),
+        _ => None,
+    };
+    let req = match req {
+        Some(v) => v,
+        None => return Ok(false),
+    };
+    let req = match Head::parse(req) {
+        Ok(v) => v,
+        Err(raw) => {
+            visitor.unread(raw);
+            return Ok(false);
+        }
+    };
+    let mut parts = req.start.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m.to_string(), t.to_string()),
+        _ => {
+            visitor.unread(req.raw);
+            return Ok(false);
+        }
+    };
+    // Upgrades, bodies of unknown length, and visitors waiting for `100 Continue`
+    let body_len = req.header("content-length").map(|v| v.parse::<u64>());
+    let body_len = match body_len {
+        _ if method == "CONNECT"
+            || req.has("upgrade")
+            || req.has("transfer-encoding")
+            || req.has("expect") =>
+        {
+            visitor.unread(req.raw);
+            return Ok(false);
+        }
+        Some(Err(_)) => {
+            visitor.unread(req.raw);
+            return Ok(false);
+        }
+        Some(Ok(v)) => v,
+        None => 0,
+    };
+    let req_close = req.tokens("connection").iter().any(|v| v == "close");
+
+    if let Some((p, resp)) = prefetched {
+        let hit = match resp {
+            Some((resp, body)) if p.matches(&req) => Some((resp, body)),
+            _ => None,
+        };
+        state_dump::range_prefetch(&proxy.service, hit.is_some());
+        if let Some((resp, body)) = hit {
+            visitor.inner.write_all(&resp.raw).await?;
+            visitor.inner.write_all(&body).await?;
+            visitor.inner.flush().await?;
+            let close = req_close || resp.tokens("connection").iter().any(|v| v == "close");
+            if !close {
+                start_prefetch(ch, proxy, &req, &resp, prefetch).await?;
+            }
+            return Ok(!close);
+        }
+    }
+
+    let key = format!(
+        "{} {}",
+        req.header("host").unwrap_or_default().to_ascii_lowercase(),
+        target
+    );
+    let head_only = method == "HEAD";
+    let cacheable = (method == "GET" || head_only)
+        && body_len == 0
+        && !req.has("authorization")
+        && !req.has("range");
+    let revalidate = req
+        .tokens("cache-control")
+        .iter()
+        .chain(req.tokens("pragma").iter())
+        .any(|v| v == "no-cache" || v == "no-store");
+    if let Some(cache) = &proxy.cache {
+        if cacheable && !revalidate {
+            if let Some(entry) = cache.get(&key, &req) {
+                entry.write(visitor.inner, head_only).await?;
+                visitor.inner.flush().await?;
+                return Ok(true);
+            }
+        }
+        // Unsafe methods invalidate what's cached for the target
+        if !matches!(method.as_str(), "GET" | "HEAD" | "OPTIONS" | "TRACE") {
+            cache.remove(&key);
+        }
+    }
+
+    ch.inner.write_all(&req.raw).await?;
+    visitor.forward(body_len, ch.inner).await?;
+    ch.inner.flush().await?;
+
+    let (resp, status) = loop {
+        let resp = match ch.head().await? {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let resp = match Head::parse(resp) {
+            Ok(v) => v,
+            Err(raw) => {
+                ch.unread(raw);
+                return Ok(false);
+            }
+        };
+        let status = resp
+            .start
+            .split(' ')
+            .nth(1)
+            .and_then(|v| v.parse::<u16>().ok());
+        match status {
+            // Informational responses come before the final one
+            Some(v) if (100..200).contains(&v) && v != 101 => {
+                visitor.inner.write_all(&resp.raw).await?;
+            }
+            Some(v) if v != 101 => break (resp, v),
+            _ => {
+                ch.unread(resp.raw);
+                return Ok(false);
+            }
+        }
+    };
+    let close = req_close || resp.tokens("connection").iter().any(|v| v == "close");
+
+    if head_only || status == 204 || status == 304 {
+        visitor.inner.write_all(&resp.raw).await?;
+    } else if resp.has("transfer-encoding") {
+        // Anything but chunked ends with the connection
+        if resp.tokens("transfer-encoding").last().map(String::as_str) != Some("chunked") {
+            ch.unread(resp.raw);
+            return Ok(false);
+        }
+        visitor.inner.write_all(&resp.raw).await?;
+        ch.forward_chunked(visitor.inner).await?;
+    } else {
+        let len = match resp.header("content-length").map(|v| v.parse::<u64>()) {
+            Some(Ok(v)) => v,
+            _ => {
+                ch.unread(resp.raw);
+                return Ok(false);
+            }
+        };
+        let cache = proxy
+            .cache
+            .as_ref()
+            .filter(|c| cacheable && status == 200 && c.fits(len));
+        match cache.and_then(|c| Some((c, freshness(&resp)?))) {
+            Some((cache, ttl)) => {
+                let body = ch.take(len as usize).await?;
+                visitor.inner.write_all(&resp.raw).await?;
+                visitor.inner.write_all(&body).await?;
+                cache.insert(key, Entry::new(&req, &resp, body, ttl));
+            }
+            None => {
+                visitor.inner.write_all(&resp.raw).await?;
+                ch.forward(len, visitor.inner).await?;
+            }
+        }
+        if status == 206 && method == "GET" && !close {
+            start_prefetch(ch, proxy, &req, &resp, prefetch).await?;
+        }
+    }
+    visitor.inner.flush().await?;
+    Ok(!close)
+}
+
+// Request the range after the one just sent to the visitor, so it's on the way while the visitor
+// handles this one
+async fn start_prefetch<C: AsyncWrite + Unpin>(
+    ch: &mut Buffered<'_, C>,
+    proxy: &HttpProxy,
+    req: &Head,
+    resp: &Head,
+    prefetch: &mut Option<Prefetch>,
+) -> io::Result<()> {
+    let range = match proxy.prefetch.and_then(|max| next_range(req, resp, max)) {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let p = Prefetch::new(req, range);
+    ch.inner.write_all(&p.request()).await?;
+    ch.inner.flush().await?;
+    *prefetch = Some(p);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{HttpCacheConfig, RangePrefetchConfig};
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    fn head(raw: &[u8]) -> Head {
+        Head::parse(raw.to_vec()).ok().unwrap()
+    }
+
+    // Proxy a visitor to a service that answers with `respond`. The service returns the requests
+    // it has got once the visitor is gone
+    fn spawn<F>(service: &ServerServiceConfig, respond: F) -> (DuplexStream, JoinHandle<Vec<Head>>)
+    where
+        F: Fn(&Head) -> String + Send + 'static,
+    {
+        let proxy = HttpProxy::from_config(service).unwrap();
+        let (mut ch, mut origin) = duplex(64 * 1024);
+        let (visitor, mut conn) = duplex(64 * 1024);
+        tokio::spawn(async move { forward(&mut ch, &mut conn, &proxy).await });
+        let origin = tokio::spawn(async move {
+            let mut origin = Buffered::new(&mut origin);
+            let mut requests = Vec::new();
+            while let Some(req) = origin.head().await.unwrap() {
+                let req = head(&req);
+                let resp = respond(&req);
+                origin.inner.write_all(resp.as_bytes()).await.unwrap();
+                requests.push(req);
+            }
+            requests
+        });
+        (visitor, origin)
+    }
+
+    async fn get(visitor: &mut Buffered<'_, DuplexStream>, req: &str) -> (Head, Vec<u8>) {
+        visitor.inner.write_all(req.as_bytes()).await.unwrap();
+        let resp = head(&visitor.head().await.unwrap().unwrap());
+        let len = resp.header("content-length").unwrap().parse().unwrap();
+        let body = visitor.take(len).await.unwrap();
+        (resp, body)
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let mut service = ServerServiceConfig::with_name("web");
+        service.http_cache = Some(HttpCacheConfig {
+            max_size: 1024,
+            max_entry_size: 512,
+        });
+        let (mut visitor, origin) = spawn(&service, |req| {
+            let cache_control = match req.start.contains("/static") {
+                true => "max-age=60",
+                false => "no-store",
+            };
+            format!(
+                "HTTP/1.1 200 OK\r\nCache-Control: {}\r\nContent-Length: 5\r\n\r\nhello",
+                cache_control
+            )
+        });
+
+        let mut visitor = Buffered::new(&mut visitor);
+        for (i, path) in ["/static/app.js", "/api", "/static/app.js", "/api"]
+            .into_iter()
+            .enumerate()
+        {
+            let req = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path);
+            let (resp, body) = get(&mut visitor, &req).await;
+            assert_eq!(resp.start, "HTTP/1.1 200 OK");
+            assert_eq!(body, b"hello");
+            // Served from the cache
+            assert_eq!(resp.has("age"), i == 2);
+        }
+        visitor.inner.shutdown().await.unwrap();
+
+        let requests: Vec<_> = origin.await.unwrap().into_iter().map(|v| v.start).collect();
+        assert_eq!(
+            requests,
+            [
+                "GET /static/app.js HTTP/1.1",
+                "GET /api HTTP/1.1",
+                "GET /api HTTP/1.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_next_range() {
+        let next = |range: &str, content_range: &str| {
+            let req = head(format!("GET / HTTP/1.1\r\nRange: {}\r\n\r\n", range).as_bytes());
+            let resp = head(
+                format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: {}\r\n\r\n",
+                    content_range
+                )
+                .as_bytes(),
+            );
+            next_range(&req, &resp, 100)
+        };
+        assert_eq!(
+            next("bytes=0-9", "bytes 0-9/1000").as_deref(),
+            Some("bytes=10-19")
+        );
+        assert_eq!(
+            next("bytes=0-9", "bytes 0-9/15").as_deref(),
+            Some("bytes=10-14")
+        );
+        assert_eq!(
+            next("bytes=0-9", "bytes 0-9/*").as_deref(),
+            Some("bytes=10-19")
+        );
+        assert_eq!(next("bytes=90-99", "bytes 90-99/100"), None);
+        // Open ended ranges are read at once
+        assert_eq!(next("bytes=0-", "bytes 0-999/1000"), None);
+        assert_eq!(next("bytes=0-199", "bytes 0-199/1000"), None);
+    }
+
+    #[tokio::test]
+    async fn test_range_prefetch() {
+        let mut service = ServerServiceConfig::with_name("files");
+        service.range_prefetch = Some(RangePrefetchConfig { max_size: 64 });
+        let file: Vec<u8> = (0..100).collect();
+        let (mut visitor, origin) = spawn(&service, move |req| {
+            let range = req.header("range").unwrap().strip_prefix("bytes=").unwrap();
+            let (first, last) = range.split_once('-').unwrap();
+            let (first, last): (usize, usize) = (first.parse().unwrap(), last.parse().unwrap());
+            let body = &file[first..=last];
+            format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/100\r\nContent-Length: {}\r\n\r\n{}",
+                first,
+                last,
+                body.len(),
+                // Only the length of the body is checked
+                "x".repeat(body.len())
+            )
+        });
+
+        let mut visitor = Buffered::new(&mut visitor);
+        for range in ["0-9", "10-19", "50-59"] {
+            let req = format!(
+                "GET /video.mp4 HTTP/1.1\r\nHost: example.com\r\nRange: bytes={}\r\n\r\n",
+                range
+            );
+            let (resp, body) = get(&mut visitor, &req).await;
+            assert!(resp
+                .header("content-range")
+                .unwrap()
+                .starts_with(&format!("bytes {}/", range)));
+            assert_eq!(body.len(), 10);
+        }
+        visitor.inner.shutdown().await.unwrap();
+
+        // `10-19` is served from what's prefetched, and `20-29` is dropped
+        let requests: Vec<_> = origin
+            .await
+            .unwrap()
+            .iter()
+            .map(|v| v.header("range").unwrap().to_string())
+            .collect();
+        assert_eq!(
+            requests,
+            [
+                "bytes=0-9",
+                "bytes=10-19",
+                "bytes=20-29",
+                "bytes=50-59",
+                "bytes=60-69"
+            ]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+// Keep-warm probes of a service, for load balancers in front of the server that can only check
+// the node, not whether a client of the service is up. While one is, `url` is requested and
+// `probe_addr` connected to every `interval`. They stop once the last client is gone, so whatever
+// watches them marks the node unhealthy along with the tunnel
+use crate::config::KeepWarmConfig;
+use crate::http;
+use anyhow::{anyhow, bail, Context, Result};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+async fn probe(config: &KeepWarmConfig) -> Result<()> {
+    if let Some(url) = &config.url {
+        let resp = http::request("GET", url, &[], &[]).await?;
+        if !(200..300).contains(&resp.status) {
+            bail!("{} responded with status {}", url, resp.status);
+        }
+    }
+    if let Some(addr) = &config.probe_addr {
+        TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("Failed to connect to {}", addr))?;
+    }
+    Ok(())
+}
+
+// Probe every `interval` while `up` tells that a client of the service is connected
+pub(crate) async fn run<F: Fn() -> bool>(config: KeepWarmConfig, up: F) {
+    let interval = Duration::from_secs(config.interval);
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    // Only the first of consecutive failures is a warning
+    let mut failing = false;
+    loop {
+        ticker.tick().await;
+        if !up() {
+            continue;
+        }
+        let r = time::timeout(interval, probe(&config))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Timeout after {:?}", interval)));
+        match r {
+            Ok(()) if failing => {
+                info!("Keep-warm probes succeed again");
+                failing = false;
+            }
+            Ok(()) => {}
+            Err(e) if failing => debug!("Keep-warm probe failed: {:#}", e),
+            Err(e) => {
+                warn!("Keep-warm probe failed: {:#}", e);
+                failing = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_probe() {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap().to_string();
+        let mut config = KeepWarmConfig {
+            url: None,
+            probe_addr: Some(addr.clone()),
+            interval: 1,
+        };
+        assert!(probe(&config).await.is_ok());
+        l.accept().await.unwrap();
+
+        // Only a 2xx response keeps the node warm
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            for status in ["200 OK", "503 Service Unavailable"] {
+                let (mut conn, _) = l.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = conn.read(&mut buf).await.unwrap();
+                let resp = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                conn.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        config.probe_addr = None;
+        config.url = Some(format!("http://{}/healthz", addr));
+        assert!(probe(&config).await.is_ok());
+        assert!(probe(&config).await.is_err());
+        server.await.unwrap();
+
+        // Nothing listens there anymore
+        config.url = None;
+        config.probe_addr = Some(addr);
+        assert!(probe(&config).await.is_err());
+    }
+}
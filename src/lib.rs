@@ -1,21 +1,97 @@
+mod acl;
+#[cfg(feature = "acme")]
+mod acme;
+mod admin;
+mod alert;
+#[cfg(feature = "server")]
+mod balance;
+mod build_info;
+mod bundle;
 mod cli;
+mod compression;
 mod config;
+mod config_crypto;
 mod config_watcher;
 mod constants;
+#[cfg(feature = "client")]
+mod ddns;
+#[cfg(feature = "server")]
+mod dns;
+mod error;
+mod events;
+#[cfg(feature = "server")]
+mod ftp;
+mod groups;
+mod health;
 mod helper;
+#[cfg(feature = "server")]
+mod honeypot;
+mod http;
+#[cfg(feature = "server")]
+mod http_cache;
+#[cfg(feature = "server")]
+mod http_proxy;
+#[cfg(feature = "server")]
+mod keep_warm;
+mod log_filter;
+mod maintenance;
+mod migrate;
 mod multi_map;
+mod pairing;
+mod privacy;
 mod protocol;
+#[cfg(feature = "client")]
+mod protocol_helper;
+mod proxy;
+#[cfg(feature = "client")]
+mod proxy_protocol;
+#[cfg(any(feature = "record", test))]
+mod record;
+mod reuse;
+mod sampling;
+mod secret;
+#[cfg(feature = "server")]
+mod shaping;
+mod sni;
+#[cfg(feature = "server")]
+mod sniff;
+mod splice;
+mod state_dump;
+#[cfg(feature = "server")]
+mod status_page;
+mod supervisor;
+mod tarpit;
+mod task_group;
 mod transport;
+#[cfg(feature = "server")]
+mod upstream;
+mod visitor;
 
 pub use cli::Cli;
-use cli::KeypairType;
-pub use config::Config;
-use config_watcher::ServiceChange;
+use cli::{Command, KeypairType};
+pub use config::{
+    AdminConfig, ClientConfig, ClientServiceConfig, Compression, CompressionPreference, Config,
+    CustomTransportConfig, DdnsConfig, DuplicatePolicy, HttpCacheConfig, KeepWarmConfig,
+    NoiseConfig, OversizedDatagram, PairingConfig, PrivacyConfig, PrivacyMode, ProtocolHelper,
+    ProxyProtocol, ServerConfig, ServerServiceConfig, ServiceGroupConfig, ServiceType,
+    SharedProtocol, StatusPageConfig, StickyPolicy, TlsConfig, TlsVersion, TransportConfig,
+    TransportType, UpstreamConfig, VisitorAlertConfig, VisitorTlsConfig, WarmupConfig,
+};
+pub use config_watcher::ServiceChange;
 pub use constants::UDP_BUFFER_SIZE;
+use error::Failure;
+pub use error::{exit_code, report, EXIT_PANIC};
+pub use events::{subscribe, Event};
+pub use log_filter::LogFilter;
+pub use transport::{
+    register_dialer, register_listener, register_transport, Diverted, ExternalStream, StreamSender,
+    Transport,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use tokio::sync::{broadcast, mpsc};
-use tracing::{debug, info};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, info, warn};
 
 #[cfg(feature = "client")]
 mod client;
@@ -27,6 +103,9 @@ mod server;
 #[cfg(feature = "server")]
 use server::run_server;
 
+#[cfg(feature = "self-update")]
+mod update;
+
 use crate::config_watcher::{ConfigChange, ConfigWatcherHandle};
 
 const DEFAULT_CURVE: KeypairType = KeypairType::X25519;
@@ -60,96 +139,364 @@ fn genkey(curve: Option<KeypairType>) -> Result<()> {
     crate::helper::feature_not_compile("nosie")
 }
 
+#[cfg(feature = "self-update")]
+async fn self_update(args: &cli::SelfUpdateArgs) -> Result<()> {
+    update::self_update(args).await
+}
+
+#[cfg(not(feature = "self-update"))]
+async fn self_update(_args: &cli::SelfUpdateArgs) -> Result<()> {
+    crate::helper::feature_not_compile("self-update")
+}
+
+// Secrets are read from stdin if not given, so they don't show up in the shell history
+fn read_secret(arg: &Option<String>, what: &str) -> Result<String> {
+    let secret = match arg {
+        Some(t) => t.clone(),
+        None => {
+            let mut t = String::new();
+            std::io::stdin()
+                .read_line(&mut t)
+                .with_context(|| format!("Failed to read the {}", what))?;
+            t.trim_end_matches(&['\r', '\n'][..]).to_string()
+        }
+    };
+    if secret.is_empty() {
+        return Err(anyhow!("The {} is empty", what));
+    }
+    Ok(secret)
+}
+
+fn hash_token(args: &cli::HashTokenArgs) -> Result<()> {
+    let token = read_secret(&args.token, "token")?;
+    println!("{}", protocol::TokenHash::new(&token));
+    Ok(())
+}
+
+fn set_secret(args: &cli::SetSecretArgs) -> Result<()> {
+    let secret = read_secret(&args.secret, "secret")?;
+    secret::set(&args.name, &secret)?;
+    info!(
+        "Stored. Refer to it as \"keyring:{}\" in the config",
+        args.name
+    );
+    Ok(())
+}
+
+// The plaintext is validated first, so a broken config won't be found out only after it's deployed
+async fn encrypt_config(args: &cli::EncryptConfigArgs) -> Result<()> {
+    let s = tokio::fs::read_to_string(&args.config_path)
+        .await
+        .with_context(|| format!("Failed to read the config {:?}", args.config_path))?;
+    if config_crypto::is_encrypted(&s) {
+        return Err(anyhow!("{:?} is already encrypted", args.config_path));
+    }
+    Config::from_file(&args.config_path).await?;
+    let s = config_crypto::encrypt(&s)?;
+    tokio::fs::write(&args.output, s)
+        .await
+        .with_context(|| format!("Failed to write {:?}", args.output))?;
+    info!("Encrypted config written to {:?}", args.output);
+    Ok(())
+}
+
+async fn bundle(args: &cli::BundleArgs) -> Result<()> {
+    let s = bundle::read_config(&args.config_path).await?;
+    let s = bundle::create(&s)?;
+    tokio::fs::write(&args.output, s)
+        .await
+        .with_context(|| format!("Failed to write {:?}", args.output))?;
+    info!("Bundle written to {:?}", args.output);
+    Ok(())
+}
+
+// The files are written with absolute paths in the config, so it can be run from anywhere
+async fn pair(args: &cli::PairArgs) -> Result<()> {
+    let transport = match &args.transport_from {
+        Some(path) => match Config::from_file(path).await?.client {
+            Some(client) => client.transport,
+            None => return Err(anyhow!("{:?} is not a client config", path)),
+        },
+        None => Default::default(),
+    };
+    let contents = pairing::fetch(&args.code, &transport).await?;
+    let dir = std::env::current_dir()?.join(&args.output);
+    let path = bundle::extract(contents, dir)?.keep();
+    info!("Paired. Run the client with {:?}", path);
+    Ok(())
+}
+
+// Existing files are never overwritten
+async fn migrate(args: &cli::MigrateArgs) -> Result<()> {
+    let mut files = Vec::new();
+    for path in &args.from {
+        let s = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        files.push((path.display().to_string(), s));
+    }
+    let migrated = migrate::migrate(&files)?;
+    let outputs: Vec<_> = [
+        ("server.toml", Some(migrated.server)),
+        ("client.toml", migrated.client),
+    ]
+    .into_iter()
+    .filter_map(|(name, s)| Some((args.output.join(name), s?)))
+    .collect();
+    if let Some((path, _)) = outputs.iter().find(|(path, _)| path.exists()) {
+        return Err(anyhow!(
+            "{:?} exists. Remove it or choose another --output",
+            path
+        ));
+    }
+    for note in &migrated.notes {
+        warn!("{}", note);
+    }
+    for (path, s) in outputs {
+        tokio::fs::write(&path, s)
+            .await
+            .with_context(|| format!("Failed to write {:?}", path))?;
+        info!("Migrated config written to {:?}", path);
+    }
+    Ok(())
+}
+
+// Run as the command line does. Like `run_with_config`, dropping the future stops everything at once
 pub async fn run(args: Cli, shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
+    match &args.command {
+        Some(Command::SelfUpdate(args)) => return self_update(args).await,
+        Some(Command::HashToken(args)) => return hash_token(args),
+        Some(Command::EncryptConfig(args)) => return encrypt_config(args).await,
+        Some(Command::SetSecret(args)) => return set_secret(args),
+        Some(Command::Migrate(args)) => return migrate(args).await,
+        Some(Command::Bundle(args)) => return bundle(args).await,
+        Some(Command::Pair(args)) => return pair(args).await,
+        None => {}
+    }
+
     if args.genkey.is_some() {
         return genkey(args.genkey.unwrap());
     }
 
+    info!("{}", build_info::summary());
+
+    #[cfg(feature = "record")]
+    record::set_dir(args.record.clone());
+
     // Raise `nofile` limit on linux and mac
     fdlimit::raise_fd_limit();
 
     // Spawn a config watcher. The watcher will send a initial signal to start the instance with a config
-    let config_path = args.config_path.as_ref().unwrap();
-    let mut cfg_watcher = ConfigWatcherHandle::new(config_path, shutdown_rx).await?;
+    // A bundle is extracted for as long as it runs
+    let bundle = match &args.bundle {
+        Some(path) => Some(bundle::extract_file(path).context(Failure::Config)?),
+        None => None,
+    };
+    let config_path = match &bundle {
+        Some(b) => b.config_path(),
+        None => args.config_path.as_ref().unwrap(),
+    };
+    let mut cfg_watcher = ConfigWatcherHandle::new(config_path, shutdown_rx)
+        .await
+        .context(Failure::Config)?;
 
     // shutdown_tx owns the instance
     let (shutdown_tx, _) = broadcast::channel(1);
 
     // (The join handle of the last instance, The service update channel sender)
-    let mut last_instance: Option<(tokio::task::JoinHandle<_>, mpsc::Sender<ServiceChange>)> = None;
-
-    while let Some(e) = cfg_watcher.event_rx.recv().await {
-        match e {
-            ConfigChange::General(config) => {
-                if let Some((i, _)) = last_instance {
-                    info!("General configuration change detected. Restarting...");
-                    shutdown_tx.send(true)?;
-                    i.await?;
-                }
+    // The instance is aborted if this future is dropped
+    let mut last_instance: Option<(AbortOnDropHandle<Result<()>>, mpsc::Sender<ServiceChange>)> =
+        None;
 
-                debug!("{:?}", config);
+    loop {
+        tokio::select! {
+            e = cfg_watcher.event_rx.recv() => {
+                match e {
+                    Some(ConfigChange::General(config)) => {
+                        if let Some((i, _)) = last_instance.take() {
+                            info!("General configuration change detected. Restarting...");
+                            shutdown_tx.send(true)?;
+                            i.await??;
+                        }
 
-                let (service_update_tx, service_update_rx) = mpsc::channel(1024);
+                        debug!("{:?}", config);
 
-                last_instance = Some((
-                    tokio::spawn(run_instance(
-                        *(config.clone()),
-                        args.clone(),
-                        shutdown_tx.subscribe(),
-                        service_update_rx,
-                    )),
-                    service_update_tx,
-                ));
-            }
-            ConfigChange::ServiceChange(service_event) => {
-                info!("Service change detcted. {:?}", service_event);
-                if let Some((_, service_update_tx)) = &last_instance {
-                    let _ = service_update_tx.send(service_event).await;
+                        let (service_update_tx, service_update_rx) = mpsc::channel(1024);
+
+                        last_instance = Some((
+                            AbortOnDropHandle::new(tokio::spawn(run_instance(
+                                *(config.clone()),
+                                args.clone(),
+                                shutdown_tx.subscribe(),
+                                service_update_rx,
+                            ))),
+                            service_update_tx,
+                        ));
+                    }
+                    Some(ConfigChange::ServiceChange(service_event)) => {
+                        info!("Service change detcted. {:?}", service_event);
+                        if let Some((_, service_update_tx)) = &last_instance {
+                            let _ = service_update_tx.send(service_event).await;
+                        }
+                    }
+                    None => break,
                 }
+            },
+            // Without a shutdown signal, an instance only exits on fatal errors
+            ret = async { (&mut last_instance.as_mut().unwrap().0).await }, if last_instance.is_some() => {
+                last_instance = None;
+                ret??;
             }
         }
     }
 
+    // Wait for the instance to shutdown gracefully
     let _ = shutdown_tx.send(true);
+    if let Some((i, _)) = last_instance {
+        i.await??;
+    }
 
     Ok(())
 }
 
+// Run with a config built in code, rather than read from a file and watched.
+// Services can be added and removed by sending `ServiceChange`s to `service_rx`.
+//
+// The future is cancellation safe: dropping it stops all services and connections at once,
+// so it can be raced in `select!` or aborted in a `JoinSet`. A signal on `shutdown_rx` stops
+// it gracefully instead, waiting for forwarded connections to finish. A closed `shutdown_rx`
+// never signals
+pub async fn run_with_config(
+    mut config: Config,
+    shutdown_rx: broadcast::Receiver<bool>,
+    service_rx: mpsc::Receiver<ServiceChange>,
+) -> Result<()> {
+    config.validate().context(Failure::Config)?;
+    run_instance(config, Cli::default(), shutdown_rx, service_rx).await
+}
+
 async fn run_instance(
     config: Config,
     args: Cli,
     shutdown_rx: broadcast::Receiver<bool>,
     service_update: mpsc::Receiver<ServiceChange>,
-) {
-    let ret: Result<()> = match determine_run_mode(&config, &args) {
-        RunMode::Undetermine => panic!("Cannot determine running as a server or a client"),
-        RunMode::Client => {
-            #[cfg(not(feature = "client"))]
-            crate::helper::feature_not_compile("client");
-            #[cfg(feature = "client")]
-            run_client(&config, shutdown_rx, service_update).await
+) -> Result<()> {
+    privacy::init(config.privacy.as_ref()).context(Failure::Config)?;
+    let mode = determine_run_mode(&config, &args);
+    let fmt_transport =
+        |t: &config::TransportConfig| format!("{:?}", t.transport_type).to_lowercase();
+    let transport = match mode {
+        RunMode::Server => config.server.as_ref().map(|v| match &v.upstream {
+            Some(upstream) => format!(
+                "{} (server), {} (upstream)",
+                fmt_transport(&v.transport),
+                fmt_transport(&upstream.transport)
+            ),
+            None => fmt_transport(&v.transport),
+        }),
+        RunMode::Client => config.client.as_ref().map(|v| fmt_transport(&v.transport)),
+        // Each hop has its own transport
+        RunMode::Relay => config
+            .server
+            .as_ref()
+            .zip(config.client.as_ref())
+            .map(|(s, c)| {
+                format!(
+                    "{} (server), {} (client)",
+                    fmt_transport(&s.transport),
+                    fmt_transport(&c.transport)
+                )
+            }),
+        RunMode::Undetermine => None,
+    };
+    let _summary = transport.map(|transport| {
+        let mode = format!("{:?}", mode).to_lowercase();
+        AbortOnDropHandle::new(tokio::spawn(async move {
+            health::log_startup_summary(&mode, &transport).await
+        }))
+    });
+
+    let instance = async {
+        match mode {
+            RunMode::Undetermine => {
+                Err(anyhow!("Cannot determine running as a server or a client"))
+                    .context(Failure::Config)
+            }
+            RunMode::Client => {
+                #[cfg(not(feature = "client"))]
+                crate::helper::feature_not_compile("client");
+                #[cfg(feature = "client")]
+                run_client(&config, shutdown_rx, service_update).await
+            }
+            RunMode::Server => {
+                #[cfg(not(feature = "server"))]
+                crate::helper::feature_not_compile("server");
+                #[cfg(feature = "server")]
+                run_server(&config, shutdown_rx, service_update).await
+            }
+            RunMode::Relay => {
+                #[cfg(not(feature = "server"))]
+                crate::helper::feature_not_compile("server");
+                #[cfg(not(feature = "client"))]
+                crate::helper::feature_not_compile("client");
+                #[cfg(all(feature = "server", feature = "client"))]
+                run_relay(&config, shutdown_rx, service_update).await
+            }
         }
-        RunMode::Server => {
-            #[cfg(not(feature = "server"))]
-            crate::helper::feature_not_compile("server");
-            #[cfg(feature = "server")]
-            run_server(&config, shutdown_rx, service_update).await
+    };
+
+    // The admin API lives as long as the instance
+    match config.admin.clone() {
+        Some(admin) => tokio::select! {
+            ret = instance => ret,
+            ret = admin::run_admin(admin, state_dump::redacted_config(&config)) => ret,
+        },
+        None => instance.await,
+    }
+}
+
+// A relay runs both the server and the client in one process, sharing the runtime and the admin
+// API. Traffic can be chained through it from one server to another, each hop with its own
+// transport, or the two can forward services of their own
+#[cfg(all(feature = "server", feature = "client"))]
+async fn run_relay(
+    config: &Config,
+    shutdown_rx: broadcast::Receiver<bool>,
+    mut service_update: mpsc::Receiver<ServiceChange>,
+) -> Result<()> {
+    let (server_tx, server_rx) = mpsc::channel(1024);
+    let (client_tx, client_rx) = mpsc::channel(1024);
+    let route = async move {
+        while let Some(e) = service_update.recv().await {
+            let tx = match e {
+                ServiceChange::ServerAdd(_) | ServiceChange::ServerDelete(_) => &server_tx,
+                ServiceChange::ClientAdd(_) | ServiceChange::ClientDelete(_) => &client_tx,
+            };
+            let _ = tx.send(e).await;
         }
+        // The senders are kept, so the server and the client don't see closed channels
+        std::future::pending::<()>().await
     };
-    ret.unwrap();
+    let server = run_server(config, shutdown_rx.resubscribe(), server_rx);
+    let client = run_client(config, shutdown_rx, client_rx);
+    tokio::select! {
+        ret = async { tokio::try_join!(server, client).map(|_| ()) } => ret,
+        _ = route => unreachable!(),
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
 enum RunMode {
     Server,
     Client,
+    Relay,
     Undetermine,
 }
 
 fn determine_run_mode(config: &Config, args: &Cli) -> RunMode {
     use RunMode::*;
     if args.client && args.server {
-        Undetermine
+        Relay
     } else if args.client {
         Client
     } else if args.server {
@@ -158,6 +505,8 @@ fn determine_run_mode(config: &Config, args: &Cli) -> RunMode {
         Client
     } else if config.server.is_some() && config.client.is_none() {
         Server
+    } else if config.server.is_some() && config.client.is_some() {
+        Relay
     } else {
         Undetermine
     }
@@ -207,7 +556,7 @@ mod tests {
                 cfg_c: true,
                 arg_s: false,
                 arg_c: false,
-                run_mode: Undetermine,
+                run_mode: Relay,
             },
             T {
                 cfg_s: true,
@@ -228,7 +577,14 @@ mod tests {
                 cfg_c: true,
                 arg_s: true,
                 arg_c: true,
-                run_mode: Undetermine,
+                run_mode: Relay,
+            },
+            T {
+                cfg_s: true,
+                cfg_c: false,
+                arg_s: true,
+                arg_c: true,
+                run_mode: Relay,
             },
         ];
 
@@ -242,6 +598,8 @@ mod tests {
                     true => Some(ClientConfig::default()),
                     false => None,
                 },
+                admin: None,
+                privacy: None,
             };
 
             let args = Cli {
@@ -0,0 +1,238 @@
+// The tracing filter of the process. It's an `EnvFilter` that can be changed at runtime through
+// the admin API, so debugging doesn't need a restart with another RUST_LOG. Within the spans of
+// services with `log_level`, the level of the service is used instead, either way
+use anyhow::{anyhow, Context as _, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{info, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+struct Filter {
+    env: EnvFilter,
+    // The directives at startup, restored on resets
+    initial: String,
+}
+
+lazy_static! {
+    static ref FILTER: RwLock<Option<Filter>> = Default::default();
+    // The levels of services, pushed by `LogLevelGuard`s. The last one wins
+    static ref LEVELS: RwLock<HashMap<String, Vec<LevelFilter>>> = Default::default();
+}
+
+// Installed by the binary in place of an `EnvFilter`
+pub struct LogFilter;
+
+impl LogFilter {
+    pub fn new(env: EnvFilter) -> LogFilter {
+        let initial = env.to_string();
+        *FILTER.write().unwrap() = Some(Filter { env, initial });
+        LogFilter
+    }
+}
+
+// The current directives, or `None` if `LogFilter` isn't installed
+pub(crate) fn get() -> Option<String> {
+    FILTER.read().unwrap().as_ref().map(|f| f.env.to_string())
+}
+
+// Replace the directives with `directives`, like `info,rathole::server=debug`, or restore the
+// initial ones if it's `None`. Returns the new directives
+pub(crate) fn set(directives: Option<&str>) -> Result<String> {
+    let current = {
+        let mut filter = FILTER.write().unwrap();
+        let filter = filter
+            .as_mut()
+            .ok_or_else(|| anyhow!("The log filter can't be changed in this build"))?;
+        let directives = directives.unwrap_or(&filter.initial);
+        filter.env = EnvFilter::try_new(directives).with_context(|| "Invalid log filter")?;
+        filter.env.to_string()
+    };
+    // Not while holding the locks, which are taken again while registering callsites
+    tracing::callsite::rebuild_interest_cache();
+    info!("Log filter changed to {}", current);
+    Ok(current)
+}
+
+// Applies the `log_level` of a service as long as it lives
+pub(crate) struct LogLevelGuard {
+    service: String,
+    level: LevelFilter,
+}
+
+impl LogLevelGuard {
+    // `level` is validated with the config
+    pub(crate) fn new(service: &str, level: Option<&str>) -> Option<LogLevelGuard> {
+        let level: LevelFilter = level?.parse().ok()?;
+        LEVELS
+            .write()
+            .unwrap()
+            .entry(service.to_string())
+            .or_default()
+            .push(level);
+        tracing::callsite::rebuild_interest_cache();
+        Some(LogLevelGuard {
+            service: service.to_string(),
+            level,
+        })
+    }
+}
+
+impl Drop for LogLevelGuard {
+    fn drop(&mut self) {
+        {
+            let mut levels = LEVELS.write().unwrap();
+            if let Some(v) = levels.get_mut(&self.service) {
+                if let Some(i) = v.iter().rposition(|l| *l == self.level) {
+                    v.remove(i);
+                }
+                if v.is_empty() {
+                    levels.remove(&self.service);
+                }
+            }
+        }
+        tracing::callsite::rebuild_interest_cache();
+    }
+}
+
+// The name of the service a span belongs to, from its `service` field
+struct ServiceName(String);
+
+struct ServiceVisitor(Option<String>);
+
+impl Visit for ServiceVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "service" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "service" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+// The level of the service of the innermost span that has one
+fn service_level<S>(ctx: &Context<'_, S>) -> Option<LevelFilter>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let levels = LEVELS.read().unwrap();
+    if levels.is_empty() {
+        return None;
+    }
+    let mut span = ctx.lookup_current();
+    while let Some(s) = span {
+        if let Some(name) = s.extensions().get::<ServiceName>() {
+            return levels.get(&name.0).and_then(|v| v.last().copied());
+        }
+        span = s.parent();
+    }
+    None
+}
+
+fn with_env<T>(f: impl FnOnce(&EnvFilter) -> T) -> Option<T> {
+    FILTER.read().unwrap().as_ref().map(|v| f(&v.env))
+}
+
+impl<S> Layer<S> for LogFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        // Whether events are enabled depends on the spans they're in
+        if !LEVELS.read().unwrap().is_empty() {
+            return Interest::sometimes();
+        }
+        with_env(|f| Layer::<S>::register_callsite(f, metadata)).unwrap_or_else(Interest::always)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let env = with_env(|f| Layer::<S>::max_level_hint(f)).flatten()?;
+        let services = LEVELS
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|v| v.last().copied())
+            .max();
+        Some(services.map_or(env, |v| v.max(env)))
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        if !metadata.is_span() {
+            if let Some(level) = service_level(&ctx) {
+                return *metadata.level() <= level;
+            }
+        }
+        with_env(|f| f.enabled(metadata, ctx)).unwrap_or(true)
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = ServiceVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(name), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(ServiceName(name));
+        }
+        with_env(|f| f.new_span(attrs, id, ctx));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        with_env(|f| f.on_record(id, values, ctx));
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        with_env(|f| f.on_enter(id, ctx));
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        with_env(|f| f.on_exit(id, ctx));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        with_env(|f| f.on_close(id, ctx));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_filter() {
+        LogFilter::new(EnvFilter::new("info"));
+        assert_eq!(get().unwrap(), "info");
+        assert_eq!(
+            set(Some("warn,rathole::server=debug")).unwrap(),
+            "rathole::server=debug,warn"
+        );
+        assert!(set(Some("rathole=nonsense")).is_err());
+        assert_eq!(get().unwrap(), "rathole::server=debug,warn");
+        assert_eq!(set(None).unwrap(), "info");
+
+        let name = "test_log_filter";
+        let level = |v: &str| {
+            LEVELS
+                .read()
+                .unwrap()
+                .get(v)
+                .and_then(|v| v.last().copied())
+        };
+        assert!(LogLevelGuard::new(name, None).is_none());
+        let a = LogLevelGuard::new(name, Some("warn")).unwrap();
+        let b = LogLevelGuard::new(name, Some("trace")).unwrap();
+        assert_eq!(level(name), Some(LevelFilter::TRACE));
+        drop(b);
+        assert_eq!(level(name), Some(LevelFilter::WARN));
+        drop(a);
+        assert_eq!(level(name), None);
+    }
+}
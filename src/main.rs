@@ -1,13 +1,30 @@
-use anyhow::Result;
 use clap::Parser;
-use rathole::{run, Cli};
-use tokio::{signal, sync::broadcast};
-use tracing_subscriber::EnvFilter;
+use rathole::{exit_code, report, run, Cli, EXIT_PANIC};
+use std::thread;
+use tokio::{runtime, signal, sync::broadcast};
+#[cfg(not(feature = "console"))]
+use tracing_subscriber::{filter::LevelFilter, prelude::*, EnvFilter};
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() {
     let args = Cli::parse();
 
+    // `available_parallelism` takes the CPU affinity and cgroup quotas into account
+    let worker_threads = args
+        .worker_threads
+        .or_else(|| thread::available_parallelism().ok())
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder.enable_all().worker_threads(worker_threads);
+    if let Some(n) = args.max_blocking_threads {
+        builder.max_blocking_threads(n.get());
+    }
+    let rt = builder.build().expect("Failed to create the tokio runtime");
+
+    rt.block_on(async_main(args, worker_threads));
+}
+
+async fn async_main(args: Cli, worker_threads: usize) {
     let (shutdown_tx, shutdown_rx) = broadcast::channel::<bool>(1);
     tokio::spawn(async move {
         if let Err(e) = signal::ctrl_c().await {
@@ -33,13 +50,28 @@ async fn main() -> Result<()> {
         let is_atty = atty::is(atty::Stream::Stdout);
 
         let level = "info"; // if RUST_LOG not present, use `info` level
+
+        // The filter can be changed later through the admin API, and per service
+        let filter = rathole::LogFilter::new(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::from(level)),
+        );
         tracing_subscriber::fmt()
-            .with_env_filter(
-                EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::from(level)),
-            )
+            .with_max_level(LevelFilter::TRACE)
             .with_ansi(is_atty)
+            .finish()
+            .with(filter)
             .init();
     }
 
-    run(args, shutdown_rx).await
+    tracing::debug!("Running with {} worker threads", worker_threads);
+
+    // Panics of service tasks are isolated. Other panics stop the process
+    match tokio::spawn(run(args, shutdown_rx)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            eprintln!("Error: {}", report(&e));
+            std::process::exit(exit_code(&e));
+        }
+        Err(_) => std::process::exit(EXIT_PANIC),
+    }
 }
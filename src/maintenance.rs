@@ -0,0 +1,182 @@
+// Maintenance pages of HTTP services, answered by the server while no client is connected
+// for the service, instead of leaving visitors with a connection error.
+// The ETA shown on the pages is set through the admin API
+#![cfg_attr(not(feature = "server"), allow(dead_code))]
+use crate::acl::ACL;
+use crate::admin::read_request;
+use crate::config::ServerServiceConfig;
+use crate::constants::{listen_backoff, MAINTENANCE_REQUEST_TIMEOUT};
+use crate::health::ListeningGuard;
+use crate::privacy;
+use crate::tarpit;
+use crate::task_group::TaskGroup;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::{fs, time};
+use tracing::{debug, error, info, warn};
+
+lazy_static! {
+    // The ETA of the maintenance, indexed by the service name
+    static ref ETAS: Mutex<BTreeMap<String, String>> = Default::default();
+}
+
+pub fn etas() -> BTreeMap<String, String> {
+    ETAS.lock().unwrap().clone()
+}
+
+pub fn set_eta(service: &str, eta: Option<String>) {
+    let mut etas = ETAS.lock().unwrap();
+    match eta {
+        Some(eta) => etas.insert(service.to_string(), eta),
+        None => etas.remove(service),
+    };
+}
+
+// The template of a page, where `{service}` and `{eta}` are replaced
+#[derive(Debug)]
+pub struct MaintenancePage {
+    service: String,
+    template: String,
+}
+
+impl MaintenancePage {
+    pub async fn load(service: &str, path: &str) -> Result<MaintenancePage> {
+        Ok(MaintenancePage {
+            service: service.to_string(),
+            template: fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read the maintenance page {}", path))?,
+        })
+    }
+
+    fn render(&self) -> String {
+        let eta = ETAS
+            .lock()
+            .unwrap()
+            .get(&self.service)
+            .cloned()
+            .unwrap_or_else(|| "unknown".to_string());
+        self.template
+            .replace("{service}", &escape_html(&self.service))
+            .replace("{eta}", &escape_html(&eta))
+    }
+
+    // Answer a visitor with the page, whatever it requests
+    pub async fn serve(&self, mut conn: TcpStream) -> Result<()> {
+        time::timeout(Duration::from_secs(MAINTENANCE_REQUEST_TIMEOUT), async {
+            read_request(&mut conn).await?;
+            let body = self.render();
+            let head = format!(
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            conn.write_all(head.as_bytes()).await?;
+            conn.write_all(body.as_bytes()).await?;
+            conn.flush().await?;
+            Ok(())
+        })
+        .await
+        .with_context(|| "Timeout")?
+    }
+}
+
+// Listen at `bind_addr` of a service that has no control channel yet, and answer all
+// visitors with the page. Cancelling `tasks` releases the address for the service
+pub fn run_maintenance_listener(service: &ServerServiceConfig, tarpit: bool, tasks: TaskGroup) {
+    let (name, bind_addr) = (service.name.clone(), service.bind_addr.clone());
+    let group = service.group.clone();
+    let path = match &service.maintenance_page {
+        Some(v) => v.clone(),
+        None => return,
+    };
+    let listener_tasks = tasks.clone();
+    listener_tasks.spawn(async move {
+        let page = match MaintenancePage::load(&name, &path).await {
+            Ok(v) => Arc::new(v),
+            Err(e) => {
+                error!("{:#}", e);
+                return;
+            }
+        };
+        let l: TcpListener = match backoff::future::retry_notify(
+            listen_backoff(),
+            || async { Ok(TcpListener::bind(&bind_addr).await?) },
+            |e, duration| warn!("{:?}. Retry in {:?}", e, duration),
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to listen for the maintenance page: {:#}", e);
+                return;
+            }
+        };
+        info!(service = %name, "Serving the maintenance page at {}", bind_addr);
+        let _listening = ListeningGuard::new(&name, l.local_addr().ok(), &bind_addr);
+
+        loop {
+            let (conn, addr) = match l.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Failed to accept a visitor: {}", e);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            if !ACL.admit(&page.service, group.as_deref(), addr.ip()) {
+                if tarpit {
+                    tarpit::trap(conn, addr, &tasks);
+                }
+                continue;
+            }
+            let page = page.clone();
+            tasks.spawn(async move {
+                if let Err(e) = page.serve(conn).await {
+                    debug!(
+                        "Failed to serve the maintenance page to {}: {:#}",
+                        privacy::addr(addr),
+                        e
+                    );
+                }
+            });
+        }
+    });
+}
+
+fn escape_html(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => r.push_str("&amp;"),
+            '<' => r.push_str("&lt;"),
+            '>' => r.push_str("&gt;"),
+            '"' => r.push_str("&quot;"),
+            '\'' => r.push_str("&#39;"),
+            c => r.push(c),
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let page = MaintenancePage {
+            service: "test_render".to_string(),
+            template: "<p>{service} is back at {eta}</p>".to_string(),
+        };
+        assert_eq!(page.render(), "<p>test_render is back at unknown</p>");
+        set_eta("test_render", Some("<10:00>".to_string()));
+        assert_eq!(page.render(), "<p>test_render is back at &lt;10:00&gt;</p>");
+        set_eta("test_render", None);
+        assert!(!etas().contains_key("test_render"));
+    }
+}
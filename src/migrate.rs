@@ -0,0 +1,473 @@
+// Conversion of the INI configs of frp, `frpc.ini` and `frps.ini`, into rathole configs, with
+// `rathole migrate`. Each proxy of frpc becomes a service of the server and one of the client.
+// What can't be converted is reported, and left as comments at the top of the configs
+use crate::config::Config;
+use anyhow::{anyhow, bail, Context, Result};
+use rand::RngCore;
+use std::collections::{HashMap, HashSet};
+use toml::value::{Table, Value};
+
+type Section = HashMap<String, String>;
+
+// The keys that are converted. Others are reported
+const FRPC_COMMON_KEYS: &[&str] = &["server_addr", "server_port", "token", "protocol"];
+const FRPS_COMMON_KEYS: &[&str] = &[
+    "bind_addr",
+    "bind_port",
+    "token",
+    "proxy_bind_addr",
+    "vhost_http_port",
+    "vhost_https_port",
+];
+const PROXY_KEYS: &[&str] = &[
+    "type",
+    "local_ip",
+    "local_port",
+    "remote_port",
+    "sk",
+    "use_compression",
+    "bandwidth_limit",
+];
+
+pub(crate) struct Migrated {
+    pub(crate) server: String,
+    // Only with frpc.ini
+    pub(crate) client: Option<String>,
+    // What isn't converted, or needs a look
+    pub(crate) notes: Vec<String>,
+}
+
+// The sections of an INI file in order, with their keys
+fn parse_ini(s: &str) -> Result<Vec<(String, Section)>> {
+    let mut sections: Vec<(String, Section)> = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with("[[") {
+            bail!("Only the INI configs of frp are supported, not the TOML or YAML ones");
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            sections.push((name.trim().to_string(), Section::new()));
+            continue;
+        }
+        let (k, v) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Line {}: expected `key = value`", i + 1))?;
+        let (_, section) = sections
+            .last_mut()
+            .ok_or_else(|| anyhow!("Line {}: `{}` is outside of any section", i + 1, k.trim()))?;
+        section.insert(k.trim().to_string(), v.trim().to_string());
+    }
+    Ok(sections)
+}
+
+fn parse_port(section: &str, key: &str, v: &str) -> Result<u16> {
+    v.parse()
+        .with_context(|| format!("[{}] `{}` is not a port: {}", section, key, v))
+}
+
+fn join_host_port(host: &str, port: u16) -> String {
+    match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
+
+// `bandwidth_limit` of frp, like `1MB` or `100KB`, in bytes
+fn parse_bandwidth(v: &str) -> Option<u64> {
+    let (n, unit) = match v.strip_suffix("MB") {
+        Some(n) => (n, 1024 * 1024),
+        None => (v.strip_suffix("KB")?, 1024),
+    };
+    n.trim().parse::<u64>().ok().map(|n| n * unit)
+}
+
+fn unsupported(notes: &mut Vec<String>, section: &str, keys: &Section, known: &[&str]) {
+    let mut keys: Vec<_> = keys
+        .keys()
+        .filter(|k| !known.contains(&k.as_str()))
+        .collect();
+    keys.sort();
+    for k in keys {
+        let hint = match k.as_str() {
+            "tls_enable" => ". Set up the `tls` transport on both ends instead",
+            "use_encryption" => ". Use the `noise` or `tls` transport instead",
+            "custom_domains" | "subdomain" | "locations" => {
+                ". rathole doesn't route by host, so the service has the port to itself"
+            }
+            _ => "",
+        };
+        notes.push(format!("[{}] `{}` is not supported{}", section, k, hint));
+    }
+}
+
+fn table<const N: usize>(entries: [(&str, Value); N]) -> Table {
+    entries
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+}
+
+// Convert `files`, pairs of names and contents. At most one frpc.ini and one frps.ini, told apart
+// by their keys
+pub(crate) fn migrate(files: &[(String, String)]) -> Result<Migrated> {
+    let mut frpc = None;
+    let mut frps = None;
+    for (name, s) in files {
+        let sections = parse_ini(s).with_context(|| format!("Failed to parse {}", name))?;
+        let common = sections
+            .iter()
+            .find(|(k, _)| k == "common")
+            .map(|(_, v)| v.clone())
+            .unwrap_or_default();
+        let is_client = sections.iter().any(|(k, _)| k != "common")
+            || common.contains_key("server_addr")
+            || common.contains_key("server_port");
+        let (slot, kind) = match is_client {
+            true => (&mut frpc, "frpc"),
+            false => (&mut frps, "frps"),
+        };
+        if slot.replace((common, sections)).is_some() {
+            bail!("More than one {} config is given", kind);
+        }
+    }
+
+    let mut notes = Vec::new();
+    let (frps_common, _) = frps.unwrap_or_default();
+    unsupported(&mut notes, "common", &frps_common, FRPS_COMMON_KEYS);
+    let (frpc_common, proxies) = frpc.clone().unwrap_or_default();
+    unsupported(&mut notes, "common", &frpc_common, FRPC_COMMON_KEYS);
+
+    // The port of frps is the one frpc connects to
+    let bind_host = frps_common
+        .get("bind_addr")
+        .map_or("0.0.0.0", String::as_str);
+    let bind_port = match frps_common
+        .get("bind_port")
+        .or_else(|| frpc_common.get("server_port"))
+    {
+        Some(v) => parse_port("common", "bind_port", v)?,
+        None => 7000,
+    };
+    let proxy_host = frps_common
+        .get("proxy_bind_addr")
+        .map_or(bind_host, String::as_str);
+    let token = match frpc_common
+        .get("token")
+        .or_else(|| frps_common.get("token"))
+    {
+        Some(v) => v.clone(),
+        None => {
+            notes.push(
+                "There's no `token`, which rathole needs, so a random one is generated".into(),
+            );
+            let mut token = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut token);
+            hex::encode(token)
+        }
+    };
+    let transport = match frpc_common.get("protocol").map(String::as_str) {
+        None | Some("tcp") => None,
+        Some(v @ ("kcp" | "websocket")) => Some(table([("type", Value::from(v))])),
+        Some(v) => {
+            notes.push(format!(
+                "[common] `protocol = {}` is not supported. The tcp transport is used",
+                v
+            ));
+            None
+        }
+    };
+
+    // stcp proxies are reached at the port of their visitors, if those are in the same config
+    let visitors: HashMap<_, _> = proxies
+        .iter()
+        .filter(|(_, v)| v.get("role").is_some_and(|v| v == "visitor"))
+        .filter_map(|(_, v)| Some((v.get("server_name")?.clone(), v.get("bind_port")?.clone())))
+        .collect();
+    let mut server_services = Table::new();
+    let mut client_services = Table::new();
+    let mut vhost_ports = HashSet::new();
+    for (name, v) in proxies.iter().filter(|(k, _)| k != "common") {
+        if v.get("role").is_some_and(|v| v == "visitor") {
+            notes.push(format!(
+                "[{}] is a visitor, which isn't needed. Visitors connect to the server",
+                name
+            ));
+            continue;
+        }
+        if let Some(plugin) = v.get("plugin") {
+            notes.push(format!(
+                "[{}] `plugin = {}` is not supported. The proxy is not migrated",
+                name, plugin
+            ));
+            continue;
+        }
+        let Some(local_port) = v.get("local_port") else {
+            notes.push(format!(
+                "[{}] has no `local_port`, or a range of them. The proxy is not migrated",
+                name
+            ));
+            continue;
+        };
+        let local_port = parse_port(name, "local_port", local_port)?;
+        let local_ip = v.get("local_ip").map_or("127.0.0.1", String::as_str);
+        let ty = v.get("type").map_or("tcp", String::as_str);
+        let bind_addr = match ty {
+            "tcp" | "udp" => match v.get("remote_port") {
+                Some(p) if p != "0" => {
+                    join_host_port(proxy_host, parse_port(name, "remote_port", p)?)
+                }
+                _ => {
+                    notes.push(format!(
+                        "[{}] has no `remote_port`. Random ports are not supported, so the proxy is not migrated",
+                        name
+                    ));
+                    continue;
+                }
+            },
+            "stcp" => {
+                let port = match visitors.get(name) {
+                    Some(p) => parse_port(name, "bind_port", p)?,
+                    None => local_port,
+                };
+                notes.push(format!(
+                    "[{}] is an stcp proxy. It's bound to 127.0.0.1:{} of the server, so it's not exposed. Change `bind_addr` to expose it",
+                    name, port
+                ));
+                join_host_port("127.0.0.1", port)
+            }
+            "http" | "https" => {
+                let (key, default) = match ty {
+                    "http" => ("vhost_http_port", 80),
+                    _ => ("vhost_https_port", 443),
+                };
+                let port = match frps_common.get(key) {
+                    Some(p) => parse_port("common", key, p)?,
+                    None => default,
+                };
+                if !vhost_ports.insert(port) {
+                    notes.push(format!(
+                        "[{}] shares port {} with another proxy, but rathole doesn't route by host. Give it a port of its own",
+                        name, port
+                    ));
+                    continue;
+                }
+                join_host_port(proxy_host, port)
+            }
+            _ => {
+                notes.push(format!(
+                    "[{}] `type = {}` is not supported. The proxy is not migrated",
+                    name, ty
+                ));
+                continue;
+            }
+        };
+        unsupported(&mut notes, name, v, PROXY_KEYS);
+
+        let service_type = match ty {
+            "udp" => "udp",
+            _ => "tcp",
+        };
+        let mut server = table([
+            ("type", service_type.into()),
+            ("bind_addr", bind_addr.into()),
+        ]);
+        let mut client = table([
+            ("type", service_type.into()),
+            ("local_addr", join_host_port(local_ip, local_port).into()),
+        ]);
+        if let Some(sk) = v.get("sk") {
+            server.insert("token".into(), sk.as_str().into());
+            client.insert("token".into(), sk.as_str().into());
+        }
+        if v.get("use_compression").is_some_and(|v| v == "true") {
+            match service_type {
+                "tcp" if cfg!(feature = "compression") => {
+                    server.insert("compression".into(), "zstd".into());
+                }
+                "tcp" => notes.push(format!(
+                    "[{}] `use_compression` needs the feature 'compression', which is not compiled",
+                    name
+                )),
+                _ => notes.push(format!(
+                    "[{}] `use_compression` is only supported for tcp services",
+                    name
+                )),
+            }
+        }
+        if let Some(limit) = v.get("bandwidth_limit") {
+            match parse_bandwidth(limit) {
+                Some(limit) => {
+                    server.insert("bandwidth_limit".into(), Value::Integer(limit as i64));
+                }
+                None => notes.push(format!(
+                    "[{}] `bandwidth_limit = {}` is not supported",
+                    name, limit
+                )),
+            }
+        }
+        server_services.insert(name.clone(), server.into());
+        client_services.insert(name.clone(), client.into());
+    }
+
+    let mut server = table([
+        ("bind_addr", join_host_port(bind_host, bind_port).into()),
+        ("default_token", token.as_str().into()),
+    ]);
+    if let Some(transport) = &transport {
+        server.insert("transport".into(), transport.clone().into());
+    }
+    server.insert("services".into(), server_services.into());
+    let client = frpc.map(|_| {
+        let server_addr = frpc_common
+            .get("server_addr")
+            .map_or("127.0.0.1", String::as_str);
+        let mut client = table([
+            ("remote_addr", join_host_port(server_addr, bind_port).into()),
+            ("default_token", token.as_str().into()),
+        ]);
+        if let Some(transport) = transport {
+            client.insert("transport".into(), transport.into());
+        }
+        client.insert("services".into(), client_services.into());
+        client
+    });
+
+    let render = |key: &str, t: Table| -> Result<String> {
+        let body = toml::to_string(&Value::from(table([(key, t.into())])))?;
+        // A bug if it's not a valid config
+        let mut config: Config = toml::from_str(&body)?;
+        config
+            .validate()
+            .with_context(|| "The migrated config is invalid")?;
+        let mut s = "# Migrated from frp by `rathole migrate`\n".to_string();
+        if !notes.is_empty() {
+            s += "# Review these before using it:\n";
+            notes.iter().for_each(|v| s += &format!("# - {}\n", v));
+        }
+        Ok(s + "\n" + &body)
+    };
+    Ok(Migrated {
+        server: render("server", server)?,
+        client: client.map(|v| render("client", v)).transpose()?,
+        notes,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let frpc = r#"
+[common]
+server_addr = 203.0.113.1
+server_port = 7000
+token = secret
+# Comments are skipped
+tls_enable = true
+
+[ssh]
+type = tcp
+local_ip = 127.0.0.1
+local_port = 22
+remote_port = 6000
+use_compression = true
+bandwidth_limit = 1MB
+
+[dns]
+type = udp
+local_ip = 10.0.0.2
+local_port = 53
+remote_port = 6053
+
+[secret_ssh]
+type = stcp
+sk = abcdefg
+local_port = 22
+
+[secret_ssh_visitor]
+type = stcp
+role = visitor
+server_name = secret_ssh
+sk = abcdefg
+bind_port = 6022
+
+[web]
+type = http
+local_port = 80
+custom_domains = www.example.com
+
+[blog]
+type = http
+local_port = 8080
+
+[p2p]
+type = xtcp
+local_port = 22
+"#;
+        let frps = "[common]\nbind_port = 7000\nvhost_http_port = 8080\ndashboard_port = 7500\n";
+        let m = migrate(&[
+            ("frpc.ini".into(), frpc.into()),
+            ("frps.ini".into(), frps.into()),
+        ])
+        .unwrap();
+
+        let server: Config = toml::from_str(&m.server).unwrap();
+        let server = server.server.unwrap();
+        assert_eq!(server.bind_addr, "0.0.0.0:7000");
+        assert_eq!(server.default_token.as_deref(), Some("secret"));
+        let ssh = &server.services["ssh"];
+        assert_eq!(ssh.bind_addr, "0.0.0.0:6000");
+        assert_eq!(
+            ssh.compression,
+            cfg!(feature = "compression").then_some(crate::config::CompressionPreference::One(
+                crate::config::Compression::Zstd
+            ))
+        );
+        assert_eq!(ssh.bandwidth_limit, Some(1024 * 1024));
+        assert_eq!(server.services["dns"].bind_addr, "0.0.0.0:6053");
+        assert_eq!(server.services["secret_ssh"].bind_addr, "127.0.0.1:6022");
+        assert_eq!(
+            server.services["secret_ssh"].token.as_deref(),
+            Some("abcdefg")
+        );
+        assert_eq!(server.services["web"].bind_addr, "0.0.0.0:8080");
+        assert_eq!(server.services.len(), 4);
+
+        let client: Config = toml::from_str(&m.client.unwrap()).unwrap();
+        let client = client.client.unwrap();
+        assert_eq!(client.remote_addr, "203.0.113.1:7000");
+        assert_eq!(client.services["dns"].local_addr, "10.0.0.2:53");
+        assert_eq!(
+            client.services["secret_ssh"].token.as_deref(),
+            Some("abcdefg")
+        );
+        assert_eq!(client.services.len(), 4);
+
+        // What's left behind is reported, and written to the configs
+        for v in [
+            "[common] `tls_enable`",
+            "[common] `dashboard_port`",
+            "[secret_ssh_visitor] is a visitor",
+            "[web] `custom_domains`",
+            "[blog] shares port 8080",
+            "[p2p] `type = xtcp`",
+        ] {
+            assert!(m.notes.iter().any(|n| n.starts_with(v)), "{}", v);
+            assert!(m.server.contains(v));
+        }
+
+        // Only frps.ini, without a token
+        let m = migrate(&[("frps.ini".into(), "[common]\nbind_port = 7001\n".into())]).unwrap();
+        assert!(m.client.is_none());
+        assert!(m.server.contains("bind_addr = \"0.0.0.0:7001\""));
+        assert!(m.notes[0].contains("random"));
+
+        assert!(migrate(&[("frpc.toml".into(), "[[proxies]]\n".into())]).is_err());
+        assert!(migrate(&[("a".into(), "key = value\n".into())]).is_err());
+    }
+}
@@ -92,6 +92,10 @@ where
         Some(&mut item.2)
     }
 
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.map1.values().map(|item| unsafe { &(*item.0).2 })
+    }
+
     pub fn remove1(&mut self, k1: &K1) -> Option<V> {
         let item = self.map1.remove(k1)?;
         let item = unsafe { Box::from_raw(item.0) };
@@ -0,0 +1,343 @@
+// Pairing of new clients with one-time codes, with `[server.pairing]`. A code is made through the
+// admin API, and typed in or scanned as a QR code on the new device, which fetches its config with
+// `rathole pair`, along with the files and secrets it refers to, like `rathole bundle` packs them.
+// A code is `<secret>@<remote_addr>`. The config is sealed with a key stretched from the secret,
+// and the server tells the code by the digest of the key, so the secret never goes over the wire
+// Every code hands out the same config, so the devices paired with it share its tokens and are one
+// client to the server. The config is read for each code, and can be changed for the next device
+#![cfg_attr(not(feature = "server"), allow(dead_code))]
+use crate::bundle::{self, Contents};
+use crate::config::{PairingConfig, TransportConfig};
+use crate::config_crypto;
+use crate::constants::{PAIRING_MAX_CODES, PAIRING_MAX_SIZE, PAIRING_TIMEOUT};
+use crate::protocol::{
+    client_key, digest, read_ack, Ack, Digest, Framing, Hello, Salt, CURRENT_PROTO_VERSION,
+};
+use crate::transport::{with_transport, Transport};
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+use tracing::info;
+
+// Without the characters that are easily mistaken for others
+const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+// In groups of 4, separated by `-`
+const SECRET_LEN: usize = 16;
+// Codes are random, so the key is stretched with a fixed salt
+const SALT: Salt = *b"rathole-pairing!";
+
+struct Code {
+    sealed: Vec<u8>,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    config: Option<PairingConfig>,
+    // Indexed by the digests of their keys
+    codes: HashMap<Digest, Code>,
+}
+
+#[derive(Default)]
+pub struct Pairing {
+    inner: Mutex<Inner>,
+}
+
+lazy_static! {
+    pub static ref PAIRING: Pairing = Pairing::default();
+}
+
+// A code made by `Pairing::create`
+pub struct NewCode {
+    pub code: String,
+    pub expires_in: u64, // In seconds
+}
+
+impl Pairing {
+    // Set up the pairing of `config`. Codes made before are dropped
+    pub fn load(&self, config: Option<&PairingConfig>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.config = config.cloned();
+        inner.codes.clear();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.lock().unwrap().config.is_some()
+    }
+
+    // Make a code for the client config, which is read now, so a broken one is found out here, and
+    // the config can be changed between devices
+    pub async fn create(&self) -> Result<NewCode> {
+        let config = self
+            .inner
+            .lock()
+            .unwrap()
+            .config
+            .clone()
+            .ok_or_else(|| anyhow!("Pairing is not configured"))?;
+        let s = bundle::read_config(Path::new(&config.client_config)).await?;
+        let contents = bundle::pack_files(&s)?;
+        let remote_addr = remote_addr(&contents)?;
+
+        let mut rng = rand::thread_rng();
+        let secret: String = (0..SECRET_LEN)
+            .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+            .collect();
+        let key = code_key(&secret);
+        let sealed = config_crypto::seal(&key, &bincode::serialize(&contents)?)?;
+        if sealed.len() > PAIRING_MAX_SIZE {
+            bail!(
+                "The client config is too large to be handed out, with {} bytes",
+                sealed.len()
+            );
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.codes.retain(|_, c| c.expires_at > now);
+        if inner.codes.len() >= PAIRING_MAX_CODES {
+            bail!("Too many pairing codes in use. Revoke them, or wait for them to expire");
+        }
+        inner.codes.insert(
+            digest(&key),
+            Code {
+                sealed,
+                expires_at: now + Duration::from_secs(config.code_ttl),
+            },
+        );
+        Ok(NewCode {
+            code: format!("{}@{}", group(&secret), remote_addr),
+            expires_in: config.code_ttl,
+        })
+    }
+
+    // The sealed config of a code, which can't be used again
+    fn take(&self, id: &Digest) -> Option<Vec<u8>> {
+        let code = self.inner.lock().unwrap().codes.remove(id)?;
+        (code.expires_at > Instant::now()).then_some(code.sealed)
+    }
+
+    // Revoke all codes, and return how many were in use
+    pub fn revoke(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let n = inner.codes.values().filter(|c| c.expires_at > now).count();
+        inner.codes.clear();
+        n
+    }
+}
+
+fn remote_addr(contents: &Contents) -> Result<String> {
+    let v: toml::Value = toml::from_str(&contents.config)?;
+    v.get("client")
+        .and_then(|c| c.get("remote_addr"))
+        .and_then(toml::Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("The client config has no `remote_addr`"))
+}
+
+fn group(secret: &str) -> String {
+    secret
+        .as_bytes()
+        .chunks(4)
+        .map(|c| String::from_utf8_lossy(c))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// Dashes and case don't matter, so codes are easy to type
+fn code_key(secret: &str) -> Digest {
+    let secret: String = secret
+        .chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    client_key(&secret, &SALT)
+}
+
+// Split a code into the secret and the address of the server
+fn parse_code(code: &str) -> Result<(&str, &str)> {
+    match code.trim().split_once('@') {
+        Some((secret, addr)) if !secret.is_empty() && !addr.is_empty() => Ok((secret, addr)),
+        _ => bail!("Expect a pairing code like `abcd-efgh-jkmn-pqrs@example.com:2333`"),
+    }
+}
+
+// A QR code of `code` in unicode blocks, to be printed on a terminal
+#[cfg(feature = "pairing-qr")]
+pub fn qr(code: &str) -> Option<String> {
+    use qrcode::render::unicode::Dense1x2;
+    let qr = qrcode::QrCode::new(code.as_bytes()).ok()?;
+    Some(
+        qr.render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build(),
+    )
+}
+
+#[cfg(not(feature = "pairing-qr"))]
+pub fn qr(_code: &str) -> Option<String> {
+    None
+}
+
+// Answer a `PairingHello` on the server. The sealed config is prefixed by its length in a
+// big-endian `u32`
+pub async fn serve<T: AsyncWrite + Unpin>(conn: &mut T, id: &Digest) -> Result<()> {
+    match PAIRING.take(id) {
+        Some(sealed) => {
+            conn.write_all(&Framing::Raw.encode(&Ack::Ok)?).await?;
+            conn.write_u32(sealed.len() as u32).await?;
+            conn.write_all(&sealed).await?;
+            conn.flush().await?;
+            info!("A new client is paired");
+            Ok(())
+        }
+        None => {
+            conn.write_all(&Framing::Raw.encode(&Ack::AuthFailed)?)
+                .await?;
+            conn.flush().await?;
+            bail!("Unknown, expired or used pairing code")
+        }
+    }
+}
+
+// Fetch the config of `code` from the server over `transport`
+pub async fn fetch(code: &str, transport: &TransportConfig) -> Result<Contents> {
+    let (secret, remote_addr) = parse_code(code)?;
+    let key = code_key(secret);
+    let sealed = time::timeout(Duration::from_secs(PAIRING_TIMEOUT), async {
+        with_transport!(transport.transport_type, T => {
+            fetch_with_transport::<T>(transport, remote_addr, &key).await
+        })
+    })
+    .await
+    .map_err(|_| anyhow!("Timeout fetching the config from {}", remote_addr))??;
+    let plaintext = config_crypto::open(&key, &sealed)
+        .with_context(|| "Failed to open the config handed out")?;
+    bincode::deserialize(&plaintext).with_context(|| "Malformed config handed out")
+}
+
+async fn fetch_with_transport<T: 'static + Transport>(
+    config: &TransportConfig,
+    remote_addr: &str,
+    key: &Digest,
+) -> Result<Vec<u8>> {
+    if config.mux {
+        #[cfg(feature = "mux")]
+        {
+            let t = crate::transport::MuxTransport::<T>::new(config).await?;
+            return request(&t, remote_addr, key).await;
+        }
+        #[cfg(not(feature = "mux"))]
+        crate::helper::feature_not_compile("mux")
+    }
+    let t = T::new(config)
+        .await
+        .with_context(|| "Failed to create the transport")?;
+    request(&t, remote_addr, key).await
+}
+
+async fn request<T: Transport>(transport: &T, remote_addr: &str, key: &Digest) -> Result<Vec<u8>> {
+    let mut conn = transport
+        .connect(remote_addr)
+        .await
+        .with_context(|| format!("Failed to connect to {}", remote_addr))?;
+    let hello = Hello::PairingHello(CURRENT_PROTO_VERSION, digest(key));
+    conn.write_all(&bincode::serialize(&hello)?).await?;
+    conn.flush().await?;
+    match read_ack(&mut conn, Framing::Raw).await? {
+        Ack::Ok => read_sealed(&mut conn).await,
+        Ack::AuthFailed => bail!("The pairing code is wrong, expired or used"),
+        v => bail!("Failed to pair: {}", v),
+    }
+}
+
+async fn read_sealed<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Vec<u8>> {
+    let len = conn.read_u32().await? as usize;
+    if len > PAIRING_MAX_SIZE {
+        bail!("The config handed out is too large, with {} bytes", len);
+    }
+    let mut buf = vec![0u8; len];
+    conn.read_exact(&mut buf)
+        .await
+        .with_context(|| "Failed to read the config handed out")?;
+    Ok(buf)
+}
+
+#[cfg(all(test, feature = "config-encryption"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_code() {
+        assert_eq!(group("abcdefghjkmnpqrs"), "abcd-efgh-jkmn-pqrs");
+        assert_eq!(
+            code_key("abcd-efgh-jkmn-pqrs"),
+            code_key("ABCDEFGHJKMNPQRS")
+        );
+        assert_ne!(code_key("abcdefghjkmnpqrs"), code_key("abcdefghjkmnpqrt"));
+        assert_eq!(
+            parse_code(" abcd-efgh@example.com:2333\n").unwrap(),
+            ("abcd-efgh", "example.com:2333")
+        );
+        assert!(parse_code("abcd-efgh").is_err());
+        assert!(parse_code("@example.com:2333").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let pairing = Pairing::default();
+        let key = code_key("abcd");
+        let contents = Contents {
+            config: "[client]\nremote_addr = \"example.com:2333\"\n".into(),
+            files: vec![("trusted_root".into(), b"CA".to_vec())],
+        };
+        let sealed = config_crypto::seal(&key, &bincode::serialize(&contents).unwrap()).unwrap();
+        let code = |ttl| Code {
+            sealed: sealed.clone(),
+            expires_at: Instant::now() + Duration::from_secs(ttl),
+        };
+        {
+            let mut inner = pairing.inner.lock().unwrap();
+            inner.codes.insert(digest(&key), code(60));
+            inner.codes.insert(digest(b"expired"), code(0));
+        }
+
+        // A code can be used once
+        assert_eq!(pairing.take(&digest(&key)), Some(sealed.clone()));
+        assert_eq!(pairing.take(&digest(&key)), None);
+        assert_eq!(pairing.take(&digest(b"expired")), None);
+        assert_eq!(remote_addr(&contents).unwrap(), "example.com:2333");
+
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        PAIRING.load(Some(&PairingConfig {
+            client_config: String::new(),
+            code_ttl: 60,
+        }));
+        PAIRING
+            .inner
+            .lock()
+            .unwrap()
+            .codes
+            .insert(digest(&key), code(60));
+        serve(&mut a, &digest(&key)).await.unwrap();
+        assert!(matches!(
+            read_ack(&mut b, Framing::Raw).await.unwrap(),
+            Ack::Ok
+        ));
+        assert_eq!(read_sealed(&mut b).await.unwrap(), sealed);
+        assert!(serve(&mut a, &digest(&key)).await.is_err());
+        assert!(matches!(
+            read_ack(&mut b, Framing::Raw).await.unwrap(),
+            Ack::AuthFailed
+        ));
+        assert_eq!(PAIRING.revoke(), 0);
+    }
+}
@@ -0,0 +1,167 @@
+// Privacy mode. Visitor addresses are hashed or truncated wherever they're logged or reported,
+// and only kept in full in the audit log, if one is configured
+use crate::config::{PrivacyConfig, PrivacyMode};
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+struct Privacy {
+    mode: PrivacyMode,
+    salt: Vec<u8>,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    audit_log: Option<Mutex<File>>,
+}
+
+impl Privacy {
+    fn obscure(&self, ip: IpAddr) -> String {
+        match self.mode {
+            PrivacyMode::Hash => {
+                let mut hasher = Sha256::new();
+                hasher.update(&self.salt);
+                match ip {
+                    IpAddr::V4(v) => hasher.update(v.octets()),
+                    IpAddr::V6(v) => hasher.update(v.octets()),
+                }
+                format!("ip-{}", hex::encode(&hasher.finalize()[..8]))
+            }
+            PrivacyMode::Truncate => {
+                let prefix = match ip {
+                    IpAddr::V4(_) => self.ipv4_prefix,
+                    IpAddr::V6(_) => self.ipv6_prefix,
+                };
+                // The prefixes are validated with the config
+                IpNet::new(ip, prefix)
+                    .map(|v| v.trunc().to_string())
+                    .unwrap_or_default()
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref PRIVACY: RwLock<Option<Arc<Privacy>>> = RwLock::new(None);
+}
+
+// Apply `[privacy]` to the process. Without it, addresses are shown in full
+pub fn init(config: Option<&PrivacyConfig>) -> Result<()> {
+    let privacy = match config {
+        Some(config) => {
+            let audit_log = match &config.audit_log {
+                Some(path) => Some(Mutex::new(
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .with_context(|| format!("Failed to open the audit log {}", path))?,
+                )),
+                None => None,
+            };
+            Some(Arc::new(Privacy {
+                mode: config.mode,
+                salt: match &config.salt {
+                    Some(v) => v.as_bytes().to_vec(),
+                    None => rand::random::<[u8; 32]>().to_vec(),
+                },
+                ipv4_prefix: config.ipv4_prefix,
+                ipv6_prefix: config.ipv6_prefix,
+                audit_log,
+            }))
+        }
+        None => None,
+    };
+    *PRIVACY.write().unwrap() = privacy;
+    Ok(())
+}
+
+fn current() -> Option<Arc<Privacy>> {
+    PRIVACY.read().unwrap().clone()
+}
+
+// The address of a visitor, as it may be shown. The port is left out in privacy mode
+pub struct Visitor {
+    ip: IpAddr,
+    port: Option<u16>,
+}
+
+impl fmt::Display for Visitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (current(), self.port) {
+            (Some(p), _) => f.write_str(&p.obscure(self.ip)),
+            (None, Some(port)) => SocketAddr::new(self.ip, port).fmt(f),
+            (None, None) => self.ip.fmt(f),
+        }
+    }
+}
+
+pub fn addr(addr: SocketAddr) -> Visitor {
+    Visitor {
+        ip: addr.ip(),
+        port: Some(addr.port()),
+    }
+}
+
+pub fn ip(ip: IpAddr) -> Visitor {
+    Visitor { ip, port: None }
+}
+
+// Record a visitor of `service` in full in the audit log, if there is one
+pub fn audit(service: &str, visitor: SocketAddr) {
+    let privacy = match current() {
+        Some(v) => v,
+        None => return,
+    };
+    if let Some(log) = &privacy.audit_log {
+        let line = serde_json::json!({
+            "timestamp": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            "service": service,
+            "visitor": visitor.to_string(),
+        });
+        if let Err(e) = writeln!(log.lock().unwrap(), "{}", line) {
+            warn!("Failed to write the audit log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_obscure() {
+        let mut privacy = Privacy {
+            mode: PrivacyMode::Hash,
+            salt: b"salt".to_vec(),
+            ipv4_prefix: 24,
+            ipv6_prefix: 48,
+            audit_log: None,
+        };
+        let ip1: IpAddr = "203.0.113.7".parse().unwrap();
+        let ip2: IpAddr = "203.0.113.8".parse().unwrap();
+        let h = privacy.obscure(ip1);
+        assert!(h.starts_with("ip-") && h.len() == 19);
+        assert!(!h.contains("203"));
+        assert_eq!(h, privacy.obscure(ip1));
+        assert_ne!(h, privacy.obscure(ip2));
+        privacy.salt = b"pepper".to_vec();
+        assert_ne!(h, privacy.obscure(ip1));
+
+        privacy.mode = PrivacyMode::Truncate;
+        assert_eq!(privacy.obscure(ip1), "203.0.113.0/24");
+        assert_eq!(
+            privacy.obscure("2001:db8:1:2::1".parse().unwrap()),
+            "2001:db8:1::/48"
+        );
+    }
+}
@@ -1,59 +1,439 @@
 pub const HASH_WIDTH_IN_BYTES: usize = 32;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bytes::{Bytes, BytesMut};
 use lazy_static::lazy_static;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tracing::trace;
+use tracing::{debug, trace};
+
+use crate::constants::{TOKEN_HASH_MAX_NUM, TOKEN_HASH_ROUNDS, UDP_RECV_ARENA_SIZE};
 
 type ProtocolVersion = u8;
-const PROTO_V0: u8 = 0u8;
+pub const PROTO_V0: u8 = 0u8;
+const PROTO_V1: u8 = 1u8; // `ClientControlChannelHello` and `ServerControlChannelHello` are used
 
-pub const CURRENT_PROTO_VERSION: ProtocolVersion = PROTO_V0;
+pub const CURRENT_PROTO_VERSION: ProtocolVersion = PROTO_V1;
 
 pub type Digest = [u8; HASH_WIDTH_IN_BYTES];
 
+// Randomly generated for every run of a client
+pub type InstanceId = [u8; 16];
+
+// Randomly generated for every hashed token
+pub type Salt = [u8; 16];
+
+// A bitmap of what a peer supports, exchanged in the hello of control channels.
+// Unknown bits must be ignored, so new capabilities can be added freely
+pub type Capabilities = u64;
+pub const CAP_REPLACED_CMD: Capabilities = 1 << 0; // Understands `ControlChannelCmd::Replaced`
+pub const CAP_TLS: Capabilities = 1 << 1; // Built with the `tls` transport
+pub const CAP_NOISE: Capabilities = 1 << 2; // Built with the `noise` transport
+pub const CAP_TOKEN_HASH: Capabilities = 1 << 3; // Exchanges `TokenSalts` and proofs for hashed tokens
+pub const CAP_WEIGHT: Capabilities = 1 << 4; // Exchanges `Weight` of the client
+pub const CAP_FORWARD_REPORT: Capabilities = 1 << 5; // Understands `ClientControlChannelCmd`
+pub const CAP_FORWARD_CONFIRM: Capabilities = 1 << 6; // Confirms TCP data channels with `DataChannelReply`
+pub const CAP_HEARTBEAT: Capabilities = 1 << 7; // Answers `ControlChannelCmd::Heartbeat`
+pub const CAP_CLOCK: Capabilities = 1 << 8; // Exchanges `Clock` after the hello
+pub const CAP_WEBSOCKET: Capabilities = 1 << 9; // Built with the `websocket` transport
+pub const CAP_HTTP2: Capabilities = 1 << 10; // Built with the `http2` transport
+pub const CAP_KCP: Capabilities = 1 << 11; // Built with the `kcp` transport
+pub const CAP_FORWARD_PORT: Capabilities = 1 << 12; // Understands `DataChannelCmd::StartForwardTcpPort`
+pub const CAP_REVERSE: Capabilities = 1 << 13; // Accepts `ReverseDataChannelHello` for reverse services
+pub const CAP_FRAMED: Capabilities = 1 << 14; // Sends the messages after the hello in frames
+pub const CAP_PING: Capabilities = 1 << 15; // Answers `ClientControlChannelCmd::Ping`
+pub const CAP_PUBLIC_ADDR: Capabilities = 1 << 16; // Understands `ControlChannelCmd::PublicAddr`
+pub const CAP_REUSE: Capabilities = 1 << 17; // Understands `DataChannelCmd::StartForwardTcpReusable`
+pub const CAP_COMPRESSION: Capabilities = 1 << 18; // Built with the `compression` feature
+pub const CAP_VISITOR_ADDR: Capabilities = 1 << 19; // Sends the address of the visitor on TCP data channels
+pub const CAP_ACK_CODES: Capabilities = 1 << 20; // Understands `Ack::VersionMismatch` and `Ack::Rejected`
+pub const CAP_VERSION: Capabilities = 1 << 21; // Exchanges `Version`, the server's after the ack
+pub const CAP_ZSTD: Capabilities = 1 << 22; // Decompresses data channels of zstd
+pub const CAP_LZ4: Capabilities = 1 << 23; // Decompresses data channels of lz4
+
+const CAPABILITY_NAMES: [(Capabilities, &str); 24] = [
+    (CAP_REPLACED_CMD, "replaced_cmd"),
+    (CAP_TLS, "tls"),
+    (CAP_NOISE, "noise"),
+    (CAP_TOKEN_HASH, "token_hash"),
+    (CAP_WEIGHT, "weight"),
+    (CAP_FORWARD_REPORT, "forward_report"),
+    (CAP_FORWARD_CONFIRM, "forward_confirm"),
+    (CAP_HEARTBEAT, "heartbeat"),
+    (CAP_CLOCK, "clock"),
+    (CAP_WEBSOCKET, "websocket"),
+    (CAP_HTTP2, "http2"),
+    (CAP_KCP, "kcp"),
+    (CAP_FORWARD_PORT, "forward_port"),
+    (CAP_REVERSE, "reverse"),
+    (CAP_FRAMED, "framed"),
+    (CAP_PING, "ping"),
+    (CAP_PUBLIC_ADDR, "public_addr"),
+    (CAP_REUSE, "reuse"),
+    (CAP_COMPRESSION, "compression"),
+    (CAP_VISITOR_ADDR, "visitor_addr"),
+    (CAP_ACK_CODES, "ack_codes"),
+    (CAP_VERSION, "version"),
+    (CAP_ZSTD, "zstd"),
+    (CAP_LZ4, "lz4"),
+];
+
+// The capabilities of this build
+pub fn local_capabilities() -> Capabilities {
+    let mut c = CAP_REPLACED_CMD
+        | CAP_TOKEN_HASH
+        | CAP_WEIGHT
+        | CAP_FORWARD_REPORT
+        | CAP_FORWARD_CONFIRM
+        | CAP_HEARTBEAT
+        | CAP_CLOCK
+        | CAP_FORWARD_PORT
+        | CAP_REVERSE
+        | CAP_FRAMED
+        | CAP_PING
+        | CAP_PUBLIC_ADDR
+        | CAP_REUSE
+        | CAP_VISITOR_ADDR
+        | CAP_ACK_CODES
+        | CAP_VERSION;
+    if cfg!(feature = "tls") {
+        c |= CAP_TLS;
+    }
+    if cfg!(feature = "noise") {
+        c |= CAP_NOISE;
+    }
+    if cfg!(feature = "websocket") {
+        c |= CAP_WEBSOCKET;
+    }
+    if cfg!(feature = "http2") {
+        c |= CAP_HTTP2;
+    }
+    if cfg!(feature = "kcp") {
+        c |= CAP_KCP;
+    }
+    if cfg!(feature = "compression") {
+        c |= CAP_COMPRESSION | CAP_ZSTD | CAP_LZ4;
+    }
+    c
+}
+
+// Whether `Version` is exchanged. It's of variable length, so it's only sent in frames
+pub fn versions_negotiated(capabilities: Capabilities) -> bool {
+    capabilities & local_capabilities() & CAP_VERSION != 0
+        && Framing::new(capabilities) == Framing::Framed
+}
+
+// Whether a byte of the compression follows the commands of TCP data channels, which both ends
+// must be built with
+pub fn compression_negotiated(capabilities: Capabilities) -> bool {
+    capabilities & local_capabilities() & CAP_COMPRESSION != 0
+}
+
+pub fn fmt_capabilities(c: Capabilities) -> String {
+    let mut v: Vec<String> = CAPABILITY_NAMES
+        .iter()
+        .filter(|(bit, _)| c & bit != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    let unknown = CAPABILITY_NAMES.iter().fold(c, |c, (bit, _)| c & !bit);
+    if unknown != 0 {
+        v.push(format!("{:#x}", unknown));
+    }
+    if v.is_empty() {
+        "none".to_string()
+    } else {
+        v.join(",")
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Hello {
     ControlChannelHello(ProtocolVersion, Digest), // sha256sum(service name) or a nonce
     DataChannelHello(ProtocolVersion, Digest),    // token provided by CreateDataChannel
+    ClientControlChannelHello(ProtocolVersion, Digest, InstanceId, Capabilities), // sha256sum(service name), the instance ID and capabilities
+    ServerControlChannelHello(ProtocolVersion, Digest, Capabilities), // Reply to `ClientControlChannelHello` with a nonce and capabilities
+    ReverseDataChannelHello(ProtocolVersion, Digest), // Like `DataChannelHello`, opened by the client for a visitor of a reverse service
+    PairingHello(ProtocolVersion, Digest), // The digest of the key of a pairing code, answered by an `Ack` and the sealed config
+}
+
+// The messages after the hello. Each type has its own tag in frames
+pub trait Message: Serialize + DeserializeOwned {
+    const TAG: u8;
+    const NAME: &'static str;
+    // Commands may gain variants unknown to older peers, which skip them. Other messages can't
+    // be done without
+    const SKIPPABLE: bool = false;
+}
+
+// How the messages after the hello are sent, as agreed in the hello. Hellos themselves are
+// always bincode alone, since they tell the capabilities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    // Bincode alone. Messages are told apart by what's expected next, and their lengths are
+    // fixed by their types, so an unknown one desynchronizes the control channel
+    Raw,
+    // A `u8` tag of the type, the length of the payload in a big-endian `u16`, and bincode.
+    // Frames of unexpected types are skipped as a whole, and so are trailing bytes of a payload,
+    // which leaves room for extending messages
+    Framed,
+}
+
+const FRAME_HEADER_LEN: usize = 3;
+
+impl Framing {
+    // For the capabilities of the peer
+    pub fn new(capabilities: Capabilities) -> Framing {
+        if capabilities & local_capabilities() & CAP_FRAMED != 0 {
+            Framing::Framed
+        } else {
+            Framing::Raw
+        }
+    }
+
+    // Fails rather than truncate the length of a payload that doesn't fit in a frame
+    pub fn encode<M: Message>(self, msg: &M) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(msg).unwrap();
+        match self {
+            Framing::Raw => Ok(payload),
+            Framing::Framed => {
+                let len = match u16::try_from(payload.len()) {
+                    Ok(len) => len,
+                    Err(_) => bail!("Too large a {} of {} bytes", M::NAME, payload.len()),
+                };
+                let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+                buf.push(M::TAG);
+                buf.extend_from_slice(&len.to_be_bytes());
+                buf.extend_from_slice(&payload);
+                Ok(buf)
+            }
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Auth(pub Digest);
 
+// Sent by the server after the hello if both sides have `CAP_TOKEN_HASH`. The salts of the
+// hashed tokens of the service, or empty if the service has a plain token. The client
+// answers with an `Auth` carrying a proof for each of the salts
+#[derive(Deserialize, Serialize, Debug)]
+pub struct TokenSalts(pub Vec<Salt>);
+
+// Sent by the client after the auth if both sides have `CAP_WEIGHT`. The share of visitors
+// the client takes in a load balanced service, relative to other clients
 #[derive(Deserialize, Serialize, Debug)]
+pub struct Weight(pub u32);
+
+// Sent by both sides right after the hello if both have `CAP_CLOCK`, the client answering the
+// server's. The clock skew between them breaks time-based tokens, so it's reported on both sides
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clock(pub u64); // Milliseconds since the UNIX epoch
+
+impl Clock {
+    pub fn now() -> Clock {
+        Clock(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        )
+    }
+
+    // In seconds, positive if `self` is ahead of `other`
+    pub fn skew(&self, other: &Clock) -> f64 {
+        (self.0 as i64 - other.0 as i64) as f64 / 1000.0
+    }
+}
+
+// Sent if `versions_negotiated`, by the client after its clock, and by the server after
+// `Ack::Ok`, so it's not told to whoever connects. The version of rathole, for telling outdated
+// peers in logs and the admin API
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Version(pub String);
+
+impl Version {
+    pub fn local() -> Version {
+        Version(crate::cli::VERSION.to_string())
+    }
+}
+
+// The variants are told apart by their index, so new ones go last. `Rejected` is of variable
+// length, which is fine since both ends of `CAP_ACK_CODES` send the messages in frames
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub enum Ack {
     Ok,
-    ServiceNotExist,
+    ServiceNotFound,
     AuthFailed,
+    ServiceBusy, // Registered by another client, and `on_duplicate` is `reject`
+    // Sent if the client has `CAP_ACK_CODES`
+    VersionMismatch, // The client lacks what the service needs, like hashed tokens
+    Rejected { reason: String },
+}
+
+impl Ack {
+    // Clients without `CAP_ACK_CODES` only know the variants before it. The others are
+    // reported as a failed authentication, as servers before it did
+    pub fn for_peer(self, capabilities: Capabilities) -> Ack {
+        match self {
+            Ack::VersionMismatch | Ack::Rejected { .. }
+                if capabilities & local_capabilities() & CAP_ACK_CODES == 0 =>
+            {
+                Ack::AuthFailed
+            }
+            v => v,
+        }
+    }
+
+    // A stable name for scripts and the events of errors
+    pub fn code(&self) -> &'static str {
+        match self {
+            Ack::Ok => "ok",
+            Ack::ServiceNotFound => "service_not_found",
+            Ack::AuthFailed => "auth_failed",
+            Ack::ServiceBusy => "service_busy",
+            Ack::VersionMismatch => "version_mismatch",
+            Ack::Rejected { .. } => "rejected",
+        }
+    }
 }
 
 impl std::fmt::Display for Ack {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Ack::Ok => "Ok",
-                Ack::ServiceNotExist => "Service not exist",
-                Ack::AuthFailed => "Incorrect token",
-            }
-        )
+        match self {
+            Ack::Ok => write!(f, "Ok"),
+            Ack::ServiceNotFound => write!(f, "Service not found on the server"),
+            Ack::AuthFailed => write!(f, "Incorrect token"),
+            Ack::ServiceBusy => write!(f, "Service already registered by another client"),
+            Ack::VersionMismatch => write!(
+                f,
+                "The server needs a newer client for the service. Please upgrade rathole"
+            ),
+            Ack::Rejected { reason } => write!(f, "Rejected by the server: {}", reason),
+        }
     }
 }
 
+// Attached to the errors of failed handshakes, so the code can be told from them
+impl std::error::Error for Ack {}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub enum ControlChannelCmd {
     CreateDataChannel,
+    Replaced,  // Another client has registered the service
+    Heartbeat, // Answered with `ClientControlChannelCmd::Heartbeat`
+    Pong,      // Answers `ClientControlChannelCmd::Ping`
+    // The address visitors reach the service at. Its host is unspecified if the server doesn't
+    // know it, which is the host the client reached the server at then
+    PublicAddr(String),
+}
+
+// Sent by the client on the control channel if the server has `CAP_FORWARD_REPORT`
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum ClientControlChannelCmd {
+    ForwardFailed, // Failed to connect to `local_addr` for a data channel
+    Heartbeat,     // Answers `ControlChannelCmd::Heartbeat`
+    Ping,          // Answered with `ControlChannelCmd::Pong`, if the server has `CAP_PING`
 }
 
+// The commands of TCP data channels are followed by a byte of the compression, after the port if
+// any, if both ends have `CAP_COMPRESSION`. Then by the address of the visitor, if both ends have
+// `CAP_VISITOR_ADDR`
 #[derive(Deserialize, Serialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum DataChannelCmd {
     StartForwardTcp,
     StartForwardUdp,
+    // Forward to another port of the host of `local_addr`, which follows as a big-endian `u16`.
+    // Sent if the client has `CAP_FORWARD_PORT`
+    StartForwardTcpPort,
+    // Like `StartForwardTcp`, but what's forwarded is in the frames of `reuse`, and the data
+    // channel takes the next command once both directions have ended. Sent if the client has
+    // `CAP_REUSE`
+    StartForwardTcpReusable,
+}
+
+// The address of the visitor on TCP data channels is serialized, and prefixed by its length in a
+// `u8`. `None` if the server can't tell it
+pub async fn write_visitor_addr<T: AsyncWrite + Unpin>(
+    writer: &mut T,
+    addr: Option<SocketAddr>,
+) -> Result<()> {
+    let buf = bincode::serialize(&addr).unwrap();
+    writer.write_u8(buf.len() as u8).await?;
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+pub async fn read_visitor_addr<T: AsyncRead + Unpin>(reader: &mut T) -> Result<Option<SocketAddr>> {
+    let len = reader.read_u8().await? as usize;
+    let mut buf = [0u8; u8::MAX as usize];
+    reader.read_exact(&mut buf[..len]).await?;
+    bincode::deserialize(&buf[..len]).with_context(|| "Failed to deserialize the visitor address")
+}
+
+// Sent by the client on a TCP data channel once `local_addr` is connected, if both sides have
+// `CAP_FORWARD_CONFIRM`. The client closes the data channel instead if it fails to connect.
+// Nothing is forwarded before it, so the server can still retry the visitor on another client
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum DataChannelReply {
+    Ready,
+}
+
+impl Message for Auth {
+    const TAG: u8 = 1;
+    const NAME: &'static str = "auth";
+}
+
+impl Message for TokenSalts {
+    const TAG: u8 = 2;
+    const NAME: &'static str = "token salts";
+}
+
+impl Message for Weight {
+    const TAG: u8 = 3;
+    const NAME: &'static str = "weight";
+}
+
+impl Message for Clock {
+    const TAG: u8 = 4;
+    const NAME: &'static str = "clock";
+}
+
+impl Message for Ack {
+    const TAG: u8 = 5;
+    const NAME: &'static str = "ack";
+}
+
+impl Message for ControlChannelCmd {
+    const TAG: u8 = 6;
+    const NAME: &'static str = "control cmd";
+    const SKIPPABLE: bool = true;
+}
+
+impl Message for ClientControlChannelCmd {
+    const TAG: u8 = 7;
+    const NAME: &'static str = "client control cmd";
+    const SKIPPABLE: bool = true;
+}
+
+impl Message for DataChannelCmd {
+    const TAG: u8 = 8;
+    const NAME: &'static str = "data cmd";
+}
+
+impl Message for DataChannelReply {
+    const TAG: u8 = 9;
+    const NAME: &'static str = "data channel reply";
+}
+
+impl Message for Version {
+    const TAG: u8 = 10;
+    const NAME: &'static str = "version";
 }
 
 type UdpPacketLen = u16; // `u16` should be enough for any practical UDP traffic on the Internet
@@ -69,25 +449,15 @@ pub struct UdpTraffic {
     pub data: Bytes,
 }
 
+// The serialized `UdpHeader` is prefixed by its length in a `u8`
+const UDP_HEADER_MAX_LEN: usize = u8::MAX as usize;
+
 impl UdpTraffic {
     pub async fn write<T: AsyncWrite + Unpin>(&self, writer: &mut T) -> Result<()> {
-        let hdr = UdpHeader {
-            from: self.from,
-            len: self.data.len() as UdpPacketLen,
-        };
-
-        let v = bincode::serialize(&hdr).unwrap();
-
-        trace!("Write {:?} of length {}", hdr, v.len());
-        writer.write_u8(v.len() as u8).await?;
-        writer.write_all(&v).await?;
-
-        writer.write_all(&self.data).await?;
-
-        Ok(())
+        UdpTraffic::write_slice(writer, self.from, &self.data).await
     }
 
-    #[allow(dead_code)]
+    // `writer` should be buffered, or each packet takes several writes to the underlying stream
     pub async fn write_slice<T: AsyncWrite + Unpin>(
         writer: &mut T,
         from: SocketAddr,
@@ -98,37 +468,48 @@ impl UdpTraffic {
             len: data.len() as UdpPacketLen,
         };
 
-        let v = bincode::serialize(&hdr).unwrap();
-
-        trace!("Write {:?} of length {}", hdr, v.len());
-        writer.write_u8(v.len() as u8).await?;
-        writer.write_all(&v).await?;
+        // Serialize to the stack to save an allocation for every packet
+        let mut buf = [0u8; 1 + UDP_HEADER_MAX_LEN];
+        let mut w = &mut buf[1..];
+        bincode::serialize_into(&mut w, &hdr).with_context(|| "Failed to serialize UdpHeader")?;
+        let hdr_len = UDP_HEADER_MAX_LEN - w.len();
+        buf[0] = hdr_len as u8;
 
+        trace!("Write {:?} of length {}", hdr, hdr_len);
+        writer.write_all(&buf[..1 + hdr_len]).await?;
         writer.write_all(data).await?;
 
         Ok(())
     }
 
-    pub async fn read<T: AsyncRead + Unpin>(reader: &mut T, hdr_len: u8) -> Result<UdpTraffic> {
-        let mut buf = Vec::new();
-        buf.resize(hdr_len as usize, 0);
+    // The data is read into `arena`, and split off from it
+    pub async fn read<T: AsyncRead + Unpin>(
+        reader: &mut T,
+        hdr_len: u8,
+        arena: &mut BytesMut,
+    ) -> Result<UdpTraffic> {
+        let mut buf = [0u8; UDP_HEADER_MAX_LEN];
+        let buf = &mut buf[..hdr_len as usize];
         reader
-            .read_exact(&mut buf)
+            .read_exact(buf)
             .await
             .with_context(|| "Failed to read udp header")?;
 
         let hdr: UdpHeader =
-            bincode::deserialize(&buf).with_context(|| "Failed to deserialize UdpHeader")?;
+            bincode::deserialize(buf).with_context(|| "Failed to deserialize UdpHeader")?;
 
         trace!("hdr {:?}", hdr);
 
-        let mut data = BytesMut::new();
-        data.resize(hdr.len as usize, 0);
-        reader.read_exact(&mut data).await?;
+        let len = hdr.len as usize;
+        if arena.capacity() < len {
+            arena.reserve(UDP_RECV_ARENA_SIZE.max(len));
+        }
+        arena.resize(len, 0);
+        reader.read_exact(arena).await?;
 
         Ok(UdpTraffic {
             from: hdr.from,
-            data: data.freeze(),
+            data: arena.split().freeze(),
         })
     }
 }
@@ -139,12 +520,139 @@ pub fn digest(data: &[u8]) -> Digest {
     d.into()
 }
 
+// The digest that proves the knowledge of `token`, which is also the session key
+pub fn auth_digest(token: &str, nonce: &[u8]) -> Digest {
+    let mut concat = Vec::from(token.as_bytes());
+    concat.extend_from_slice(nonce);
+    digest(&concat)
+}
+
+// The index of the current time window of time-based tokens, which are `step` seconds long
+pub fn totp_window(step: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    now / step
+}
+
+// The indexes of the time windows within `tolerance` seconds from now
+pub fn totp_windows(step: u64, tolerance: u64) -> std::ops::RangeInclusive<u64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    now.saturating_sub(tolerance) / step..=(now + tolerance) / step
+}
+
+// The token of a time window, derived from the shared secret. A captured handshake is
+// useless once the window passes
+pub fn totp_token(secret: &str, window: u64) -> String {
+    let mut concat = Vec::from(secret.as_bytes());
+    concat.extend_from_slice(&window.to_be_bytes());
+    hex::encode(digest(&concat))
+}
+
+// Compare in constant time, so the time taken doesn't tell how much of a guess is right
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// A token as stored in the server config, in the form of `<salt>$<stored key>` in hex.
+// The stored key can verify the proofs of the token, but can't make them
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenHash {
+    pub salt: Salt,
+    pub stored_key: Digest,
+}
+
+impl TokenHash {
+    pub fn new(token: &str) -> TokenHash {
+        let mut salt = Salt::default();
+        rand::thread_rng().fill_bytes(&mut salt);
+        TokenHash {
+            salt,
+            stored_key: digest(&client_key(token, &salt)),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<TokenHash> {
+        let (salt, stored_key) = s
+            .split_once('$')
+            .with_context(|| "Expect `<salt>$<stored key>`")?;
+        let mut h = TokenHash {
+            salt: Default::default(),
+            stored_key: Default::default(),
+        };
+        hex::decode_to_slice(salt, &mut h.salt).with_context(|| "Invalid salt")?;
+        hex::decode_to_slice(stored_key, &mut h.stored_key)
+            .with_context(|| "Invalid stored key")?;
+        Ok(h)
+    }
+
+    // Check the proof made by `token_proof`
+    pub fn verify(&self, nonce: &[u8], proof: &Digest) -> bool {
+        let key = xor(proof, &proof_mask(&self.stored_key, nonce));
+        ct_eq(&digest(&key), &self.stored_key)
+    }
+}
+
+impl std::fmt::Display for TokenHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}${}",
+            hex::encode(self.salt),
+            hex::encode(self.stored_key)
+        )
+    }
+}
+
+// Stretch the token with the salt, so guessing the token from a leaked stored key is slow
+pub fn client_key(token: &str, salt: &Salt) -> Digest {
+    let mut concat = Vec::from(&salt[..]);
+    concat.extend_from_slice(token.as_bytes());
+    let mut key = digest(&concat);
+    for _ in 1..TOKEN_HASH_ROUNDS {
+        concat.clear();
+        concat.extend_from_slice(&key);
+        concat.extend_from_slice(token.as_bytes());
+        key = digest(&concat);
+    }
+    key
+}
+
+// The client key masked with what only the holders of the stored key can derive
+pub fn token_proof(client_key: &Digest, nonce: &[u8]) -> Digest {
+    xor(client_key, &proof_mask(&digest(client_key), nonce))
+}
+
+fn proof_mask(stored_key: &Digest, nonce: &[u8]) -> Digest {
+    let mut concat = Vec::from(&stored_key[..]);
+    concat.extend_from_slice(nonce);
+    digest(&concat)
+}
+
+fn xor(a: &Digest, b: &Digest) -> Digest {
+    let mut r = *a;
+    r.iter_mut().zip(b).for_each(|(x, y)| *x ^= y);
+    r
+}
+
 struct PacketLength {
+    hello_tag: usize,
     hello: usize,
+    client_hello: usize,
+    server_hello: usize,
     ack: usize,
     auth: usize,
+    salts_len: usize,
+    weight: usize,
+    clock: usize,
     c_cmd: usize,
+    client_c_cmd: usize,
     d_cmd: usize,
+    d_reply: usize,
 }
 
 impl PacketLength {
@@ -153,19 +661,47 @@ impl PacketLength {
         let d = digest(username.as_bytes());
         let hello = bincode::serialized_size(&Hello::ControlChannelHello(CURRENT_PROTO_VERSION, d))
             .unwrap() as usize;
+        let client_hello = bincode::serialized_size(&Hello::ClientControlChannelHello(
+            CURRENT_PROTO_VERSION,
+            d,
+            Default::default(),
+            0,
+        ))
+        .unwrap() as usize;
+        let server_hello = bincode::serialized_size(&Hello::ServerControlChannelHello(
+            CURRENT_PROTO_VERSION,
+            d,
+            0,
+        ))
+        .unwrap() as usize;
+        let hello_tag = bincode::serialized_size(&0u32).unwrap() as usize;
         let c_cmd =
             bincode::serialized_size(&ControlChannelCmd::CreateDataChannel).unwrap() as usize;
+        let client_c_cmd =
+            bincode::serialized_size(&ClientControlChannelCmd::ForwardFailed).unwrap() as usize;
         let d_cmd = bincode::serialized_size(&DataChannelCmd::StartForwardTcp).unwrap() as usize;
+        let d_reply = bincode::serialized_size(&DataChannelReply::Ready).unwrap() as usize;
         let ack = Ack::Ok;
         let ack = bincode::serialized_size(&ack).unwrap() as usize;
 
         let auth = bincode::serialized_size(&Auth(d)).unwrap() as usize;
+        let salts_len = bincode::serialized_size(&0u64).unwrap() as usize;
+        let weight = bincode::serialized_size(&Weight(0)).unwrap() as usize;
+        let clock = bincode::serialized_size(&Clock(0)).unwrap() as usize;
         PacketLength {
+            hello_tag,
             hello,
+            client_hello,
+            server_hello,
             ack,
             auth,
+            salts_len,
+            weight,
+            clock,
             c_cmd,
+            client_c_cmd,
             d_cmd,
+            d_reply,
         }
     }
 }
@@ -174,47 +710,422 @@ lazy_static! {
     static ref PACKET_LEN: PacketLength = PacketLength::new();
 }
 
+// Variants of `Hello` are of different lengths, so the tag is read first
 pub async fn read_hello<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Hello> {
-    let mut buf = vec![0u8; PACKET_LEN.hello];
+    let mut buf = vec![0u8; PACKET_LEN.hello_tag];
     conn.read_exact(&mut buf)
         .await
         .with_context(|| "Failed to read hello")?;
+    let len = match bincode::deserialize::<u32>(&buf)? {
+        0 | 1 | 4 | 5 => PACKET_LEN.hello,
+        2 => PACKET_LEN.client_hello,
+        3 => PACKET_LEN.server_hello,
+        v => bail!("Unknown type of hello {}", v),
+    };
+    buf.resize(len, 0);
+    conn.read_exact(&mut buf[PACKET_LEN.hello_tag..])
+        .await
+        .with_context(|| "Failed to read hello")?;
     let hello = bincode::deserialize(&buf).with_context(|| "Failed to deserialize hello")?;
     Ok(hello)
 }
 
-pub async fn read_auth<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Auth> {
-    let mut buf = vec![0u8; PACKET_LEN.auth];
-    conn.read_exact(&mut buf)
+// Read a message of `len` bytes, or in a frame. `first` is its first byte, if it has been read
+async fn read_msg<M: Message, T: AsyncRead + Unpin>(
+    conn: &mut T,
+    framing: Framing,
+    len: usize,
+    first: Option<u8>,
+) -> Result<M> {
+    if framing == Framing::Framed {
+        return read_frame(conn, first).await;
+    }
+    let mut buf = vec![0u8; len];
+    let rest = match first {
+        Some(b) => {
+            buf[0] = b;
+            &mut buf[1..]
+        }
+        None => &mut buf[..],
+    };
+    conn.read_exact(rest)
         .await
-        .with_context(|| "Failed to read auth")?;
-    bincode::deserialize(&buf).with_context(|| "Failed to deserialize auth")
+        .with_context(|| format!("Failed to read {}", M::NAME))?;
+    bincode::deserialize(&buf).with_context(|| format!("Failed to deserialize {}", M::NAME))
+}
+
+// Read frames until one of `M`, skipping the ones of other types
+async fn read_frame<M: Message, T: AsyncRead + Unpin>(
+    conn: &mut T,
+    mut first: Option<u8>,
+) -> Result<M> {
+    loop {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        match first.take() {
+            Some(b) => {
+                header[0] = b;
+                conn.read_exact(&mut header[1..]).await
+            }
+            None => conn.read_exact(&mut header).await,
+        }
+        .with_context(|| format!("Failed to read {}", M::NAME))?;
+        let mut payload = vec![0u8; u16::from_be_bytes([header[1], header[2]]) as usize];
+        conn.read_exact(&mut payload)
+            .await
+            .with_context(|| format!("Failed to read {}", M::NAME))?;
+        if header[0] != M::TAG {
+            debug!(
+                "Skip a frame of type {} while reading {}",
+                header[0],
+                M::NAME
+            );
+            continue;
+        }
+        match bincode::deserialize(&payload) {
+            Ok(v) => return Ok(v),
+            Err(e) if M::SKIPPABLE => debug!("Skip an unknown {}: {}", M::NAME, e),
+            Err(e) => return Err(e).with_context(|| format!("Failed to deserialize {}", M::NAME)),
+        }
+    }
+}
+
+pub async fn read_auth<T: AsyncRead + Unpin>(conn: &mut T, framing: Framing) -> Result<Auth> {
+    read_msg(conn, framing, PACKET_LEN.auth, None).await
 }
 
-pub async fn read_ack<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Ack> {
-    let mut bytes = vec![0u8; PACKET_LEN.ack];
-    conn.read_exact(&mut bytes)
+// Salts are prefixed by their number in a `u64`
+pub async fn read_token_salts<T: AsyncRead + Unpin>(
+    conn: &mut T,
+    framing: Framing,
+) -> Result<TokenSalts> {
+    if framing == Framing::Framed {
+        let salts: TokenSalts = read_frame(conn, None).await?;
+        if salts.0.len() > TOKEN_HASH_MAX_NUM {
+            bail!("Too many token salts {}", salts.0.len());
+        }
+        return Ok(salts);
+    }
+    let mut buf = vec![0u8; PACKET_LEN.salts_len];
+    conn.read_exact(&mut buf)
+        .await
+        .with_context(|| "Failed to read token salts")?;
+    let n = bincode::deserialize::<u64>(&buf)? as usize;
+    if n > TOKEN_HASH_MAX_NUM {
+        bail!("Too many token salts {}", n);
+    }
+    buf.resize(PACKET_LEN.salts_len + n * std::mem::size_of::<Salt>(), 0);
+    conn.read_exact(&mut buf[PACKET_LEN.salts_len..])
         .await
-        .with_context(|| "Failed to read ack")?;
-    bincode::deserialize(&bytes).with_context(|| "Failed to deserialize ack")
+        .with_context(|| "Failed to read token salts")?;
+    bincode::deserialize(&buf).with_context(|| "Failed to deserialize token salts")
 }
 
-pub async fn read_control_cmd<T: AsyncRead + AsyncWrite + Unpin>(
+pub async fn read_weight<T: AsyncRead + Unpin>(conn: &mut T, framing: Framing) -> Result<Weight> {
+    read_msg(conn, framing, PACKET_LEN.weight, None).await
+}
+
+pub async fn read_clock<T: AsyncRead + Unpin>(conn: &mut T, framing: Framing) -> Result<Clock> {
+    read_msg(conn, framing, PACKET_LEN.clock, None).await
+}
+
+pub async fn read_version<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Version> {
+    read_frame(conn, None).await
+}
+
+pub async fn read_ack<T: AsyncRead + Unpin>(conn: &mut T, framing: Framing) -> Result<Ack> {
+    read_msg(conn, framing, PACKET_LEN.ack, None).await
+}
+
+pub async fn read_control_cmd<T: AsyncRead + Unpin>(
     conn: &mut T,
+    framing: Framing,
 ) -> Result<ControlChannelCmd> {
-    let mut bytes = vec![0u8; PACKET_LEN.c_cmd];
-    conn.read_exact(&mut bytes)
-        .await
-        .with_context(|| "Failed to read control cmd")?;
-    bincode::deserialize(&bytes).with_context(|| "Failed to deserialize control cmd")
+    read_msg(conn, framing, PACKET_LEN.c_cmd, None).await
 }
 
-pub async fn read_data_cmd<T: AsyncRead + AsyncWrite + Unpin>(
+// The first byte is read by the caller, which waits for it in a `select!`, where reading a
+// single byte is cancel safe
+pub async fn read_client_control_cmd<T: AsyncRead + Unpin>(
+    first: u8,
     conn: &mut T,
+    framing: Framing,
+) -> Result<ClientControlChannelCmd> {
+    read_msg(conn, framing, PACKET_LEN.client_c_cmd, Some(first)).await
+}
+
+pub async fn read_data_cmd<T: AsyncRead + Unpin>(
+    conn: &mut T,
+    framing: Framing,
 ) -> Result<DataChannelCmd> {
-    let mut bytes = vec![0u8; PACKET_LEN.d_cmd];
-    conn.read_exact(&mut bytes)
-        .await
-        .with_context(|| "Failed to read data cmd")?;
-    bincode::deserialize(&bytes).with_context(|| "Failed to deserialize data cmd")
+    read_msg(conn, framing, PACKET_LEN.d_cmd, None).await
+}
+
+pub async fn read_data_reply<T: AsyncRead + Unpin>(
+    conn: &mut T,
+    framing: Framing,
+) -> Result<DataChannelReply> {
+    read_msg(conn, framing, PACKET_LEN.d_reply, None).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_hello() {
+        let d = digest(b"foo");
+        let mut buf = bincode::serialize(&Hello::ControlChannelHello(PROTO_V1, d)).unwrap();
+        buf.append(
+            &mut bincode::serialize(&Hello::ClientControlChannelHello(
+                PROTO_V1, d, [1u8; 16], CAP_TLS,
+            ))
+            .unwrap(),
+        );
+        buf.append(
+            &mut bincode::serialize(&Hello::ServerControlChannelHello(PROTO_V1, d, CAP_NOISE))
+                .unwrap(),
+        );
+        buf.append(&mut bincode::serialize(&Hello::DataChannelHello(PROTO_V1, d)).unwrap());
+        buf.append(&mut bincode::serialize(&Hello::PairingHello(PROTO_V1, d)).unwrap());
+
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        a.write_all(&buf).await.unwrap();
+
+        assert!(matches!(
+            read_hello(&mut b).await.unwrap(),
+            Hello::ControlChannelHello(PROTO_V1, x) if x == d
+        ));
+        assert!(matches!(
+            read_hello(&mut b).await.unwrap(),
+            Hello::ClientControlChannelHello(PROTO_V1, x, id, CAP_TLS) if x == d && id == [1u8; 16]
+        ));
+        assert!(matches!(
+            read_hello(&mut b).await.unwrap(),
+            Hello::ServerControlChannelHello(PROTO_V1, x, CAP_NOISE) if x == d
+        ));
+        assert!(matches!(
+            read_hello(&mut b).await.unwrap(),
+            Hello::DataChannelHello(PROTO_V1, x) if x == d
+        ));
+        assert!(matches!(
+            read_hello(&mut b).await.unwrap(),
+            Hello::PairingHello(PROTO_V1, x) if x == d
+        ));
+
+        a.write_all(&[9, 0, 0, 0]).await.unwrap();
+        assert!(read_hello(&mut b).await.is_err());
+    }
+
+    #[test]
+    fn test_totp_token() {
+        let t = totp_token("secret", 42);
+        assert_eq!(t, totp_token("secret", 42));
+        assert_ne!(t, totp_token("secret", 43));
+        assert_ne!(t, totp_token("another", 42));
+        assert_ne!(
+            auth_digest(&t, &[0u8; 32]),
+            auth_digest(&totp_token("secret", 43), &[0u8; 32])
+        );
+    }
+
+    #[test]
+    fn test_totp_windows() {
+        let w = totp_window(30);
+        assert!(totp_windows(30, 0).contains(&w));
+        let windows = totp_windows(30, 30);
+        assert_eq!(windows.clone().count(), 3);
+        assert!(windows.contains(&(w - 1)) && windows.contains(&(w + 1)));
+        assert!(totp_windows(30, 60).contains(&(w + 2)));
+    }
+
+    #[tokio::test]
+    async fn test_clock() {
+        assert_eq!(Clock(12_500).skew(&Clock(10_000)), 2.5);
+        assert_eq!(Clock(10_000).skew(&Clock(12_500)), -2.5);
+
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let clock = Clock::now();
+        a.write_all(&bincode::serialize(&clock).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(read_clock(&mut b, Framing::Raw).await.unwrap(), clock);
+    }
+
+    #[test]
+    fn test_token_hash() {
+        let h = TokenHash::new("secret");
+        assert_eq!(TokenHash::parse(&h.to_string()).unwrap(), h);
+        assert!(TokenHash::parse("00$00").is_err());
+        assert!(TokenHash::parse(&hex::encode([0u8; 48])).is_err());
+
+        let nonce = [1u8; 32];
+        let key = client_key("secret", &h.salt);
+        assert!(h.verify(&nonce, &token_proof(&key, &nonce)));
+        // A proof is bound to the nonce
+        assert!(!h.verify(&[2u8; 32], &token_proof(&key, &nonce)));
+        let key = client_key("another", &h.salt);
+        assert!(!h.verify(&nonce, &token_proof(&key, &nonce)));
+        // The stored key alone can't make a proof
+        assert!(!h.verify(&nonce, &token_proof(&h.stored_key, &nonce)));
+    }
+
+    #[test]
+    fn test_ct_eq() {
+        assert!(ct_eq(b"abc", b"abc"));
+        assert!(!ct_eq(b"abc", b"abd"));
+        assert!(!ct_eq(b"abc", b"ab"));
+    }
+
+    #[test]
+    fn test_fmt_capabilities() {
+        assert_eq!(fmt_capabilities(0), "none");
+        assert_eq!(
+            fmt_capabilities(CAP_REPLACED_CMD | CAP_NOISE),
+            "replaced_cmd,noise"
+        );
+        assert_eq!(fmt_capabilities(CAP_TLS | 1 << 31), "tls,0x80000000");
+    }
+
+    #[tokio::test]
+    async fn test_read_client_control_cmd() {
+        let buf = bincode::serialize(&ClientControlChannelCmd::ForwardFailed).unwrap();
+        let mut r = &buf[..];
+        let first = r.read_u8().await.unwrap();
+        assert_eq!(
+            read_client_control_cmd(first, &mut r, Framing::Raw)
+                .await
+                .unwrap(),
+            ClientControlChannelCmd::ForwardFailed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_framing() {
+        assert_eq!(Framing::new(CAP_FRAMED | CAP_TLS), Framing::Framed);
+        assert_eq!(Framing::new(CAP_TLS), Framing::Raw);
+
+        let f = Framing::Framed;
+        let mut buf = f.encode(&Clock(42)).unwrap();
+        // A frame of a type unknown to this build
+        buf.extend_from_slice(&[200, 0, 2, 1, 2]);
+        // A command unknown to this build
+        let unknown = bincode::serialize(&99u32).unwrap();
+        buf.extend_from_slice(&[ControlChannelCmd::TAG, 0, unknown.len() as u8]);
+        buf.extend_from_slice(&unknown);
+        buf.extend(f.encode(&ControlChannelCmd::Heartbeat).unwrap());
+        // A message extended with a field unknown to this build
+        let mut extended = f.encode(&Ack::Ok).unwrap();
+        extended[2] += 4;
+        extended.extend_from_slice(&[0; 4]);
+        buf.extend(extended);
+        buf.extend(f.encode(&DataChannelCmd::StartForwardUdp).unwrap());
+
+        let mut r = &buf[..];
+        assert_eq!(read_clock(&mut r, f).await.unwrap(), Clock(42));
+        assert!(matches!(
+            read_control_cmd(&mut r, f).await.unwrap(),
+            ControlChannelCmd::Heartbeat
+        ));
+        assert!(matches!(read_ack(&mut r, f).await.unwrap(), Ack::Ok));
+        // Messages that can't be done without aren't skipped
+        let mut bad = f.encode(&DataChannelCmd::StartForwardUdp).unwrap();
+        bad[3] = 99;
+        assert!(read_data_cmd(&mut &bad[..], f).await.is_err());
+        assert!(matches!(
+            read_data_cmd(&mut r, f).await.unwrap(),
+            DataChannelCmd::StartForwardUdp
+        ));
+        assert!(r.is_empty());
+
+        // The length of a payload that doesn't fit in a frame isn't truncated
+        let reason = "x".repeat(u16::MAX as usize);
+        assert!(f.encode(&Ack::Rejected { reason }).is_err());
+
+        // Raw messages are the same as before
+        assert_eq!(
+            Framing::Raw.encode(&Ack::Ok).unwrap(),
+            bincode::serialize(&Ack::Ok).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ack() {
+        // The legacy variants keep their indices, and so their bytes
+        for (ack, tag) in [
+            (Ack::Ok, 0u32),
+            (Ack::ServiceNotFound, 1),
+            (Ack::AuthFailed, 2),
+            (Ack::ServiceBusy, 3),
+        ] {
+            assert_eq!(
+                Framing::Raw.encode(&ack).unwrap(),
+                bincode::serialize(&tag).unwrap()
+            );
+        }
+
+        let rejected = Ack::Rejected {
+            reason: "foo".to_string(),
+        };
+        assert_eq!(rejected.clone().for_peer(CAP_FRAMED), Ack::AuthFailed);
+        assert_eq!(Ack::VersionMismatch.for_peer(CAP_FRAMED), Ack::AuthFailed);
+        assert_eq!(Ack::ServiceBusy.for_peer(0), Ack::ServiceBusy);
+        let peer = CAP_FRAMED | CAP_ACK_CODES;
+        assert_eq!(rejected.clone().for_peer(peer), rejected);
+
+        let f = Framing::Framed;
+        let buf = f.encode(&rejected.clone().for_peer(peer)).unwrap();
+        assert_eq!(read_ack(&mut &buf[..], f).await.unwrap(), rejected);
+        assert_eq!(rejected.code(), "rejected");
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        assert!(versions_negotiated(CAP_FRAMED | CAP_VERSION));
+        assert!(!versions_negotiated(CAP_VERSION));
+        assert!(!versions_negotiated(CAP_FRAMED));
+
+        let v = Version("1.2.3-rc.1".to_string());
+        let mut buf = Framing::Framed.encode(&Clock(0)).unwrap();
+        buf.extend(Framing::Framed.encode(&v).unwrap());
+        let mut r = &buf[..];
+        read_clock(&mut r, Framing::Framed).await.unwrap();
+        assert_eq!(read_version(&mut r).await.unwrap(), v);
+    }
+
+    #[tokio::test]
+    async fn test_udp_traffic() {
+        let t = [
+            ("1.2.3.4:5678".parse().unwrap(), &b"hello"[..]),
+            ("[::1]:5678".parse().unwrap(), &b""[..]),
+        ];
+        for (from, data) in t {
+            let mut buf = Vec::new();
+            UdpTraffic::write_slice(&mut buf, from, data).await.unwrap();
+
+            let mut r = &buf[..];
+            let hdr_len = r.read_u8().await.unwrap();
+            let t = UdpTraffic::read(&mut r, hdr_len, &mut BytesMut::new())
+                .await
+                .unwrap();
+            assert_eq!(t.from, from);
+            assert_eq!(t.data, data);
+            assert!(r.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_visitor_addr() {
+        for addr in [
+            Some("1.2.3.4:5678".parse().unwrap()),
+            Some("[::1]:80".parse().unwrap()),
+            None,
+        ] {
+            let mut buf = Vec::new();
+            write_visitor_addr(&mut buf, addr).await.unwrap();
+            buf.extend_from_slice(b"next");
+            let mut r = &buf[..];
+            assert_eq!(read_visitor_addr(&mut r).await.unwrap(), addr);
+            assert_eq!(r, b"next");
+        }
+    }
 }
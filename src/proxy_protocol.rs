@@ -0,0 +1,81 @@
+// Headers of the PROXY protocol that the client prepends to the connections to `local_addr`, with
+// `proxy_protocol`, so the local service sees the address of the visitor rather than the client.
+// See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+use std::net::{IpAddr, SocketAddr};
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// The version 2, with the command `LOCAL` or `PROXY`
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+// TCP over IPv4 or IPv6
+const TCP_V4: u8 = 0x11;
+const TCP_V6: u8 = 0x21;
+
+// The header of a connection from `src` to `dst`. Without `src`, it's `LOCAL`, which tells the
+// service to use the address of the connection itself
+pub(crate) fn header_v2(src: Option<SocketAddr>, dst: SocketAddr) -> Vec<u8> {
+    let mut v = SIGNATURE.to_vec();
+    let Some(src) = src else {
+        v.extend_from_slice(&[V2_LOCAL, 0, 0, 0]);
+        return v;
+    };
+    v.push(V2_PROXY);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            v.push(TCP_V4);
+            v.extend_from_slice(&12u16.to_be_bytes());
+            v.extend_from_slice(&s.octets());
+            v.extend_from_slice(&d.octets());
+        }
+        // Both must be of the same family
+        (s, d) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            v.push(TCP_V6);
+            v.extend_from_slice(&36u16.to_be_bytes());
+            v.extend_from_slice(&v6(s).octets());
+            v.extend_from_slice(&v6(d).octets());
+        }
+    }
+    v.extend_from_slice(&src.port().to_be_bytes());
+    v.extend_from_slice(&dst.port().to_be_bytes());
+    v
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_v2() {
+        let h = header_v2(
+            Some("1.2.3.4:5678".parse().unwrap()),
+            "127.0.0.1:80".parse().unwrap(),
+        );
+        assert_eq!(&h[..12], SIGNATURE);
+        assert_eq!(
+            &h[12..],
+            [0x21, 0x11, 0, 12, 1, 2, 3, 4, 127, 0, 0, 1, 0x16, 0x2e, 0, 80]
+        );
+
+        // IPv4 is mapped into IPv6 along with an IPv6 one
+        let h = header_v2(
+            Some("1.2.3.4:5678".parse().unwrap()),
+            "[::1]:80".parse().unwrap(),
+        );
+        assert_eq!(&h[12..16], [0x21, 0x21, 0, 36]);
+        assert_eq!(
+            &h[16..32],
+            "::ffff:1.2.3.4"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+        assert_eq!(h.len(), 16 + 36);
+
+        let h = header_v2(None, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(&h[12..], [0x20, 0, 0, 0]);
+    }
+}
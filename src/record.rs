@@ -0,0 +1,225 @@
+// Recording of control channel sessions, to catch protocol regressions between versions.
+// With the `record` feature and `--record <DIR>`, the client writes the bytes of every control
+// channel to a file in DIR. Recordings under `tests/record` are replayed against the parser in tests.
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    // From the server
+    In,
+    // To the server
+    Out,
+}
+
+// A line of a recording
+#[derive(Debug, Serialize, Deserialize)]
+struct Frame {
+    dir: Direction,
+    // Encoded in hex
+    data: String,
+}
+
+#[cfg(feature = "record")]
+pub use recorder::{set_dir, Recorded};
+
+#[cfg(feature = "record")]
+mod recorder {
+    use super::{Direction, Frame};
+    use anyhow::Result;
+    use lazy_static::lazy_static;
+    use std::path::PathBuf;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::fs;
+    use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+    use tokio::sync::mpsc;
+    use tracing::{error, info};
+
+    lazy_static! {
+        static ref DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+    }
+
+    pub fn set_dir(dir: Option<PathBuf>) {
+        *DIR.lock().unwrap() = dir;
+    }
+
+    // A stream that records what's read and written, if a directory is set
+    #[derive(Debug)]
+    pub struct Recorded<S> {
+        inner: S,
+        tx: Option<mpsc::UnboundedSender<Frame>>,
+    }
+
+    impl<S> Recorded<S> {
+        pub fn new(inner: S, service: &str) -> Recorded<S> {
+            let dir = match DIR.lock().unwrap().clone() {
+                Some(v) => v,
+                None => return Recorded { inner, tx: None },
+            };
+            let ts = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let path = dir.join(format!("{}-{}.jsonl", service, ts));
+            info!("Recording the control channel to {:?}", path);
+
+            // Written by a task, so the stream is never blocked by the file
+            let (tx, mut rx) = mpsc::unbounded_channel::<Frame>();
+            tokio::spawn(async move {
+                let ret: Result<()> = async {
+                    let mut f = fs::File::create(&path).await?;
+                    while let Some(frame) = rx.recv().await {
+                        let mut line = serde_json::to_vec(&frame)?;
+                        line.push(b'\n');
+                        f.write_all(&line).await?;
+                    }
+                    Ok(())
+                }
+                .await;
+                if let Err(e) = ret {
+                    error!("Failed to record to {:?}: {:#}", path, e);
+                }
+            });
+
+            Recorded {
+                inner,
+                tx: Some(tx),
+            }
+        }
+
+        fn record(&self, dir: Direction, data: &[u8]) {
+            if let (Some(tx), false) = (&self.tx, data.is_empty()) {
+                let _ = tx.send(Frame {
+                    dir,
+                    data: hex::encode(data),
+                });
+            }
+        }
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            let filled = buf.filled().len();
+            let ret = Pin::new(&mut this.inner).poll_read(cx, buf);
+            if let Poll::Ready(Ok(())) = ret {
+                this.record(Direction::In, &buf.filled()[filled..]);
+            }
+            ret
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            let ret = Pin::new(&mut this.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = ret {
+                this.record(Direction::Out, &buf[..n]);
+            }
+            ret
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::{self, Framing, Hello};
+    use anyhow::{bail, Result};
+    use std::io::Cursor;
+
+    // Parse both directions of a recording with the parsers of the server and the client
+    async fn replay(recording: &str) -> Result<Vec<String>> {
+        let (mut inbound, mut outbound) = (Vec::new(), Vec::new());
+        for line in recording.lines().filter(|l| !l.trim().is_empty()) {
+            let frame: Frame = serde_json::from_str(line)?;
+            let data = hex::decode(&frame.data)?;
+            match frame.dir {
+                Direction::In => inbound.extend(data),
+                Direction::Out => outbound.extend(data),
+            }
+        }
+
+        let mut msgs = Vec::new();
+        let (mut out, mut inb) = (Cursor::new(outbound), Cursor::new(inbound));
+        let client_hello = protocol::read_hello(&mut out).await?;
+        let client_capabilities = match client_hello {
+            Hello::ControlChannelHello(..) => 0,
+            Hello::ClientControlChannelHello(_, _, _, c) => c,
+            _ => bail!("Unexpected hello from the client {:?}", client_hello),
+        };
+        let server_hello = protocol::read_hello(&mut inb).await?;
+        let server_capabilities = match server_hello {
+            Hello::ControlChannelHello(..) => 0,
+            Hello::ServerControlChannelHello(_, _, c) => c,
+            _ => bail!("Unexpected hello from the server {:?}", server_hello),
+        };
+        // The messages after the hello are framed if both sides can
+        let framing = Framing::new(client_capabilities & server_capabilities);
+
+        // What the server reads
+        msgs.push(format!("{:?}", client_hello));
+        msgs.push(format!(
+            "{:?}",
+            protocol::read_auth(&mut out, framing).await?
+        ));
+        if out.position() != out.get_ref().len() as u64 {
+            bail!("Trailing bytes from the client");
+        }
+
+        // What the client reads
+        msgs.push(format!("{:?}", server_hello));
+        msgs.push(format!(
+            "{:?}",
+            protocol::read_ack(&mut inb, framing).await?
+        ));
+        while inb.position() != inb.get_ref().len() as u64 {
+            msgs.push(format!(
+                "{:?}",
+                protocol::read_control_cmd(&mut inb, framing).await?
+            ));
+        }
+
+        Ok(msgs)
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/record");
+        let mut n = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() != Some("jsonl".as_ref()) {
+                continue;
+            }
+            let recording = std::fs::read_to_string(&path).unwrap();
+            let msgs = replay(&recording)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to replay {:?}: {:#}", path, e));
+            assert_eq!(msgs[3], "Ok", "{:?}", path);
+            n += 1;
+        }
+        assert!(n > 0);
+
+        assert!(replay(r#"{"dir":"out","data":"09000000"}"#).await.is_err());
+    }
+}
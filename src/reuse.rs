@@ -0,0 +1,205 @@
+// TCP data channels that carry visitors one after another, with `reuse_data_channels`. What's
+// forwarded is split into frames of a big-endian `u16` length and the bytes, and an empty frame
+// ends a direction in place of shutting down the connection. Once both directions have ended, the
+// data channel takes the next command
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const MAX_FRAME_LEN: usize = u16::MAX as usize;
+
+// Forwards through `inner` as it is if not `framed`, so either kind of data channel can be passed
+// to the same code
+pub(crate) struct ReusableStream<S> {
+    inner: S,
+    framed: bool,
+    // The header of the frame being read, and how much of it has been
+    header: [u8; 2],
+    header_read: usize,
+    // What's left of the payload of the frame being read
+    remaining: usize,
+    read_eof: bool,
+    // A frame waiting to be written, and how much of it has been
+    out: Vec<u8>,
+    written: usize,
+    write_eof: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ReusableStream<S> {
+    pub(crate) fn new(inner: S, framed: bool) -> ReusableStream<S> {
+        ReusableStream {
+            inner,
+            framed,
+            header: [0; 2],
+            header_read: 0,
+            remaining: 0,
+            read_eof: false,
+            out: Vec::new(),
+            written: 0,
+            write_eof: false,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    // Whether both directions have ended, so the data channel can be reused
+    pub(crate) fn is_reusable(&self) -> bool {
+        self.framed && self.read_eof && self.write_eof && self.out.is_empty()
+    }
+
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.out.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ReusableStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.framed {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if this.read_eof || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if this.remaining > 0 {
+                let n = this.remaining.min(buf.remaining());
+                let mut payload = ReadBuf::new(buf.initialize_unfilled_to(n));
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut payload))?;
+                let n = payload.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                buf.advance(n);
+                this.remaining -= n;
+                return Poll::Ready(Ok(()));
+            }
+            let mut header = ReadBuf::new(&mut this.header[this.header_read..]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut header))?;
+            let n = header.filled().len();
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.header_read += n;
+            if this.header_read == this.header.len() {
+                this.header_read = 0;
+                this.remaining = u16::from_be_bytes(this.header) as usize;
+                this.read_eof = this.remaining == 0;
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ReusableStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.framed {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        ready!(this.poll_write_out(cx))?;
+        // An empty frame would end the direction
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = data.len().min(MAX_FRAME_LEN);
+        this.out.extend_from_slice(&(n as u16).to_be_bytes());
+        this.out.extend_from_slice(&data[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    // The connection is kept open for the next visitor
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.framed {
+            return Pin::new(&mut this.inner).poll_shutdown(cx);
+        }
+        if !this.write_eof {
+            ready!(this.poll_write_out(cx))?;
+            this.out.extend_from_slice(&[0, 0]);
+            this.write_eof = true;
+        }
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{copy_bidirectional, duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_reusable_stream() {
+        let (mut a, mut b) = duplex(16);
+        // Two visitors, one after another, over the same connection
+        for _ in 0..2 {
+            let (mut visitor, mut visitor_peer) = duplex(1024);
+            let (mut local, mut local_peer) = duplex(1024);
+            let server = async {
+                let mut ch = ReusableStream::new(&mut a, true);
+                copy_bidirectional(&mut ch, &mut visitor).await.unwrap();
+                ch.is_reusable()
+            };
+            let client = async {
+                let mut ch = ReusableStream::new(&mut b, true);
+                copy_bidirectional(&mut ch, &mut local).await.unwrap();
+                ch.is_reusable()
+            };
+            let ends = async {
+                // More than the buffers in between hold, so it's read while written
+                let data = vec![7u8; 70000];
+                let send = async {
+                    visitor_peer.write_all(&data).await.unwrap();
+                    visitor_peer.shutdown().await.unwrap();
+                };
+                let mut received = Vec::new();
+                let recv = local_peer.read_to_end(&mut received);
+                let (_, n) = tokio::join!(send, recv);
+                assert_eq!(n.unwrap(), data.len());
+                assert_eq!(received, data);
+
+                local_peer.write_all(b"pong").await.unwrap();
+                local_peer.shutdown().await.unwrap();
+                let mut received = Vec::new();
+                visitor_peer.read_to_end(&mut received).await.unwrap();
+                assert_eq!(received, b"pong");
+            };
+            let (server, client, _) = tokio::join!(server, client, ends);
+            assert!(server && client);
+        }
+
+        // Unframed ones are never reused
+        let mut ch = ReusableStream::new(&mut a, false);
+        ch.write_all(b"raw").await.unwrap();
+        let mut buf = [0u8; 3];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"raw");
+        assert!(!ch.is_reusable());
+    }
+}
@@ -0,0 +1,238 @@
+// Sampling of the traffic of services with `sample_traffic`. Only the sizes of payloads and
+// the gaps between them are recorded, never the content, which helps with capacity planning
+// and tuning MTUs and buffers. The histograms are read through the admin API
+#![cfg_attr(not(feature = "server"), allow(dead_code))]
+use crate::config::ServerServiceConfig;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const BUCKETS: usize = u64::BITS as usize + 1;
+
+// Counts of values in buckets of powers of two. Bucket `i` holds the values of `i` bits
+#[derive(Clone)]
+struct Histogram([u64; BUCKETS]);
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram([0; BUCKETS])
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, v: u64) {
+        self.0[(u64::BITS - v.leading_zeros()) as usize] += 1;
+    }
+
+    // Non-empty buckets, with the largest value each of them holds
+    fn to_json(&self) -> Value {
+        let v: Vec<Value> = self
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| **n != 0)
+            .map(|(i, n)| {
+                let le = if i == 0 {
+                    0
+                } else {
+                    u64::MAX >> (BUCKETS - 1 - i)
+                };
+                json!({ "le": le, "count": n })
+            })
+            .collect();
+        Value::Array(v)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Inbound,  // From visitors to the service
+    Outbound, // From the service to visitors
+}
+
+#[derive(Default, Clone)]
+struct FlowSamples {
+    sizes: Histogram,
+    gaps: Histogram, // In microseconds
+}
+
+#[derive(Default, Clone)]
+struct ServiceSamples {
+    inbound: FlowSamples,
+    outbound: FlowSamples,
+}
+
+lazy_static! {
+    // Indexed by the service name
+    static ref SAMPLES: Mutex<BTreeMap<String, ServiceSamples>> = Default::default();
+}
+
+pub fn to_json() -> Value {
+    let flow = |f: &FlowSamples| json!({ "sizes": f.sizes.to_json(), "gaps_us": f.gaps.to_json() });
+    let services: serde_json::Map<String, Value> = SAMPLES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, s)| {
+            (
+                name.clone(),
+                json!({ "inbound": flow(&s.inbound), "outbound": flow(&s.outbound) }),
+            )
+        })
+        .collect();
+    json!({ "services": services })
+}
+
+pub fn reset() {
+    SAMPLES.lock().unwrap().clear();
+}
+
+pub struct Sampler {
+    service: String,
+    rate: f64,
+}
+
+impl Sampler {
+    pub fn from_config(service: &ServerServiceConfig) -> Option<Arc<Sampler>> {
+        service.sample_traffic.map(|rate| {
+            Arc::new(Sampler {
+                service: service.name.clone(),
+                rate,
+            })
+        })
+    }
+
+    // `gap` is the time since the previous payload of the same flow, if there's any
+    pub fn record(&self, flow: Flow, len: usize, gap: Option<Duration>) {
+        if rand::random::<f64>() >= self.rate {
+            return;
+        }
+        let mut samples = SAMPLES.lock().unwrap();
+        let s = samples.entry(self.service.clone()).or_default();
+        let f = match flow {
+            Flow::Inbound => &mut s.inbound,
+            Flow::Outbound => &mut s.outbound,
+        };
+        f.sizes.record(len as u64);
+        if let Some(gap) = gap {
+            f.gaps.record(gap.as_micros() as u64);
+        }
+    }
+}
+
+// Samples what is read from and written to a visitor
+pub struct SampledStream<S> {
+    inner: S,
+    sampler: Arc<Sampler>,
+    last_read: Option<Instant>,
+    last_write: Option<Instant>,
+}
+
+impl<S> SampledStream<S> {
+    pub fn new(inner: S, sampler: Arc<Sampler>) -> SampledStream<S> {
+        SampledStream {
+            inner,
+            sampler,
+            last_read: None,
+            last_write: None,
+        }
+    }
+}
+
+// The time since `last`, which is then updated
+pub fn gap(last: &mut Option<Instant>) -> Option<Duration> {
+    let now = Instant::now();
+    last.replace(now).map(|v| now - v)
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SampledStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let n = buf.filled().len() - before;
+        if n != 0 {
+            let gap = gap(&mut self.last_read);
+            self.sampler.record(Flow::Inbound, n, gap);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SampledStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        if n != 0 {
+            let gap = gap(&mut self.last_write);
+            self.sampler.record(Flow::Outbound, n, gap);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::default();
+        for v in [0, 1, 2, 3, 4, 1500, u64::MAX] {
+            h.record(v);
+        }
+        assert_eq!(
+            h.to_json(),
+            json!([
+                { "le": 0, "count": 1 },
+                { "le": 1, "count": 1 },
+                { "le": 3, "count": 2 },
+                { "le": 7, "count": 1 },
+                { "le": 2047, "count": 1 },
+                { "le": u64::MAX, "count": 1 },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sampled_stream() {
+        let name = "test_sampled_stream";
+        let sampler = Arc::new(Sampler {
+            service: name.to_string(),
+            rate: 1.0,
+        });
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut a = SampledStream::new(a, sampler);
+        b.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        a.read_exact(&mut buf).await.unwrap();
+        a.write_all(b"hi").await.unwrap();
+
+        let v = to_json();
+        let s = &v["services"][name];
+        assert_eq!(s["inbound"]["sizes"], json!([{ "le": 7, "count": 1 }]));
+        assert_eq!(s["outbound"]["sizes"], json!([{ "le": 3, "count": 1 }]));
+        assert_eq!(s["inbound"]["gaps_us"], json!([]));
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+
+// A secret in the config, like a token or a private key, can be kept in the credential store
+// of the OS instead, and referred to as `keyring:<name>`. It's fetched when the config is loaded
+const KEYRING_PREFIX: &str = "keyring:";
+
+// The service that the entries of rathole belong to in the credential store
+#[cfg_attr(not(feature = "os-keyring"), allow(dead_code))]
+const KEYRING_SERVICE: &str = "rathole";
+
+// Replace a reference to the credential store with the secret
+pub fn resolve(secret: &mut Option<String>) -> Result<()> {
+    if let Some(s) = secret {
+        if let Some(name) = s.strip_prefix(KEYRING_PREFIX) {
+            *s = get(name)
+                .with_context(|| format!("Failed to fetch the secret {} from the keyring", name))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "os-keyring")]
+fn get(name: &str) -> Result<String> {
+    Ok(keyring::Entry::new(KEYRING_SERVICE, name)?.get_password()?)
+}
+
+#[cfg(feature = "os-keyring")]
+pub fn set(name: &str, secret: &str) -> Result<()> {
+    Ok(keyring::Entry::new(KEYRING_SERVICE, name)?.set_password(secret)?)
+}
+
+// Hot-reloaded configs are resolved as well, so don't exit here
+#[cfg(not(feature = "os-keyring"))]
+fn get(_name: &str) -> Result<String> {
+    anyhow::bail!("The feature 'os-keyring' is not compiled in this binary")
+}
+
+#[cfg(not(feature = "os-keyring"))]
+pub fn set(_name: &str, _secret: &str) -> Result<()> {
+    crate::helper::feature_not_compile("os-keyring")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let mut s = None;
+        resolve(&mut s).unwrap();
+        assert_eq!(s, None);
+
+        let mut s = Some("plain".to_string());
+        resolve(&mut s).unwrap();
+        assert_eq!(s.as_deref(), Some("plain"));
+
+        #[cfg(feature = "os-keyring")]
+        keyring::set_default_credential_builder(keyring::mock::default_credential_builder());
+        let mut s = Some("keyring:missing".to_string());
+        assert!(resolve(&mut s).is_err());
+    }
+}
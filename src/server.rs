@@ -1,39 +1,134 @@
-use crate::config::{Config, ServerConfig, ServerServiceConfig, ServiceType, TransportType};
+use crate::acl::ACL;
+use crate::alert::VisitorAlert;
+use crate::balance::{Balance, Candidate, Outlier};
+use crate::compression::{self, Codec, CompressedStream};
+use crate::config::{
+    Config, DuplicatePolicy, ProtocolHelper, ServerConfig, ServerServiceConfig, ServiceType,
+    SharedProtocol, TransportType,
+};
 use crate::config_watcher::ServiceChange;
-use crate::constants::{listen_backoff, UDP_BUFFER_SIZE};
+use crate::constants::{
+    listen_backoff, FORWARD_CONFIRM_TIMEOUT, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT,
+    SHUTDOWN_TIMEOUT,
+};
+use crate::dns::{DnsGuard, DnsStream};
+use crate::error::{Failure, Hint, WithHint};
+use crate::events::{self, DataChannelGuard, ServiceUpGuard};
+use crate::ftp::{self, Passive};
+use crate::groups::GROUPS;
+use crate::health::{ConfiguredGuard, ListeningGuard};
+use crate::helper::{
+    is_transient_udp_error, recv_shutdown, tcp_listen, udp_bind, DatagramLimit, ListenOptions,
+};
+use crate::honeypot::run_honeypot;
+use crate::http_proxy::{self, HttpProxy};
+use crate::keep_warm;
+use crate::log_filter::LogLevelGuard;
+use crate::maintenance::{run_maintenance_listener, MaintenancePage};
 use crate::multi_map::MultiMap;
-use crate::protocol::Hello::{ControlChannelHello, DataChannelHello};
+use crate::pairing::{self, PAIRING};
+use crate::privacy;
+use crate::protocol::Hello::{
+    ClientControlChannelHello, ControlChannelHello, DataChannelHello, PairingHello,
+    ReverseDataChannelHello, ServerControlChannelHello,
+};
 use crate::protocol::{
-    self, read_auth, read_hello, Ack, ControlChannelCmd, DataChannelCmd, Hello, UdpTraffic,
-    HASH_WIDTH_IN_BYTES,
+    self, read_auth, read_client_control_cmd, read_clock, read_data_reply, read_hello,
+    read_version, read_weight, Ack, Capabilities, ClientControlChannelCmd, Clock,
+    ControlChannelCmd, DataChannelCmd, Framing, Hello, InstanceId, TokenHash, TokenSalts,
+    UdpTraffic, Version, CAP_CLOCK, CAP_FORWARD_CONFIRM, CAP_FORWARD_PORT, CAP_FORWARD_REPORT,
+    CAP_HEARTBEAT, CAP_PING, CAP_PUBLIC_ADDR, CAP_REPLACED_CMD, CAP_REUSE, CAP_TOKEN_HASH,
+    CAP_VISITOR_ADDR, CAP_WEIGHT, HASH_WIDTH_IN_BYTES,
+};
+use crate::reuse::ReusableStream;
+use crate::sampling::{self, Flow, SampledStream, Sampler};
+use crate::shaping::{ShapedStream, Shaper};
+use crate::sniff::Incoming;
+use crate::splice;
+use crate::state_dump::{self, ClientGuard};
+use crate::status_page::{run_status_page, ServiceStatus, StatusSource};
+use crate::supervisor::catch_panic;
+use crate::tarpit;
+use crate::task_group::TaskGroup;
+use crate::transport::{
+    self, Diverted, ExternalTransport, MemoryTransport, TcpTransport, Transport,
 };
-use crate::transport::{TcpTransport, Transport};
+use crate::upstream::run_upstream_relay;
+use crate::visitor::{self, VisitorAuth, VisitorStream};
 use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
+use bytes::BytesMut;
 
 use rand::RngCore;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{self, copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    self, copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream,
+};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex, RwLock};
 use tokio::time;
+use tokio_util::sync::DropGuard;
 use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
 
+#[cfg(feature = "http2")]
+use crate::transport::Http2Transport;
+#[cfg(feature = "kcp")]
+use crate::transport::KcpTransport;
+#[cfg(feature = "mux")]
+use crate::transport::MuxTransport;
 #[cfg(feature = "noise")]
 use crate::transport::NoiseTransport;
 #[cfg(feature = "tls")]
 use crate::transport::TlsTransport;
+#[cfg(feature = "websocket")]
+use crate::transport::WebsocketTransport;
 
 type ServiceDigest = protocol::Digest; // SHA256 of a service name
 type Nonce = protocol::Digest; // Also called `session_key`
 
+// Running control channels of a service, indexed by their session keys
+type Members = Arc<StdMutex<HashMap<Nonce, Member>>>;
+
+struct Member {
+    instance_id: Option<InstanceId>, // `None` if the client is older than PROTO_V1
+    _shutdown_tx: oneshot::Sender<()>, // Shutdown the control channel by dropping it
+    data_ch_req_tx: mpsc::UnboundedSender<bool>, // Requests a data channel from this control channel only
+    capabilities: Capabilities,                  // Capabilities of the client
+    weight: u32, // The share of visitors if the service is load balanced
+    joined_at: Instant,
+    outlier: Arc<Outlier>,
+    _client: ClientGuard, // Lists the client in the admin API
+}
+
+fn fmt_instance_id(id: &Option<InstanceId>) -> String {
+    match id {
+        Some(id) => hex::encode(id),
+        None => "unknown".to_string(),
+    }
+}
+
+fn fmt_version(v: &Option<String>) -> &str {
+    v.as_deref().unwrap_or("unknown")
+}
+
 const TCP_POOL_SIZE: usize = 8; // The number of cached connections for TCP servies
 const UDP_POOL_SIZE: usize = 2; // The number of cached connections for UDP services
 const CHAN_SIZE: usize = 2048; // The capacity of various chans
-const HANDSHAKE_TIMEOUT: u64 = 5; // Timeout for transport handshake
+
+fn pool_size(service_type: ServiceType) -> usize {
+    match service_type {
+        ServiceType::Tcp => TCP_POOL_SIZE,
+        ServiceType::Udp => UDP_POOL_SIZE,
+        ServiceType::Honeypot => unreachable!("Honeypots have no control channel"),
+    }
+}
+pub(crate) const HANDSHAKE_TIMEOUT: u64 = 5; // Timeout for transport handshake
 
 // The entrypoint of running a server
 pub async fn run_server(
@@ -42,22 +137,28 @@ pub async fn run_server(
     service_rx: mpsc::Receiver<ServiceChange>,
 ) -> Result<()> {
     let config = match &config.server {
-            Some(config) => config,
-            None => {
-                return Err(anyhow!("Try to run as a server, but the configuration is missing. Please add the `[server]` block"))
-            }
-        };
+        Some(config) => config,
+        None => {
+            return Err(anyhow!(
+                "Try to run as a server, but the configuration is missing"
+            ))
+            .hint(Hint::MissingBlock("server"))
+            .context(Failure::Config)
+        }
+    };
+
+    if let Some(upstream) = &config.upstream {
+        return run_upstream_relay(config, upstream, shutdown_rx, service_rx).await;
+    }
 
     match config.transport.transport_type {
         TransportType::Tcp => {
-            let mut server = Server::<TcpTransport>::from(config).await?;
-            server.run(shutdown_rx, service_rx).await?;
+            run_with_transport::<TcpTransport>(config, shutdown_rx, service_rx).await?;
         }
         TransportType::Tls => {
             #[cfg(feature = "tls")]
             {
-                let mut server = Server::<TlsTransport>::from(config).await?;
-                server.run(shutdown_rx, service_rx).await?;
+                run_with_transport::<TlsTransport>(config, shutdown_rx, service_rx).await?;
             }
             #[cfg(not(feature = "tls"))]
             crate::helper::feature_not_compile("tls")
@@ -65,17 +166,80 @@ pub async fn run_server(
         TransportType::Noise => {
             #[cfg(feature = "noise")]
             {
-                let mut server = Server::<NoiseTransport>::from(config).await?;
-                server.run(shutdown_rx, service_rx).await?;
+                run_with_transport::<NoiseTransport>(config, shutdown_rx, service_rx).await?;
             }
             #[cfg(not(feature = "noise"))]
             crate::helper::feature_not_compile("noise")
         }
+        TransportType::Websocket => {
+            #[cfg(feature = "websocket")]
+            {
+                run_with_transport::<WebsocketTransport>(config, shutdown_rx, service_rx).await?;
+            }
+            #[cfg(not(feature = "websocket"))]
+            crate::helper::feature_not_compile("websocket")
+        }
+        TransportType::Http2 => {
+            #[cfg(feature = "http2")]
+            {
+                run_with_transport::<Http2Transport>(config, shutdown_rx, service_rx).await?;
+            }
+            #[cfg(not(feature = "http2"))]
+            crate::helper::feature_not_compile("http2")
+        }
+        TransportType::Kcp => {
+            #[cfg(feature = "kcp")]
+            {
+                run_with_transport::<KcpTransport>(config, shutdown_rx, service_rx).await?;
+            }
+            #[cfg(not(feature = "kcp"))]
+            crate::helper::feature_not_compile("kcp")
+        }
+        TransportType::Memory => {
+            run_with_transport::<MemoryTransport>(config, shutdown_rx, service_rx).await?;
+        }
+        TransportType::External => {
+            run_with_transport::<ExternalTransport>(config, shutdown_rx, service_rx).await?;
+        }
+        TransportType::Custom => {
+            let run = transport::registered(&config.transport)
+                .context(Failure::Config)?
+                .server;
+            run(config, shutdown_rx, service_rx).await?;
+        }
     }
 
     Ok(())
 }
 
+// `run_with_transport`, boxed to be registered for custom transports
+pub(crate) fn run_boxed<T: 'static + Transport>(
+    config: &ServerConfig,
+    shutdown_rx: broadcast::Receiver<bool>,
+    service_rx: mpsc::Receiver<ServiceChange>,
+) -> transport::RunFuture<'_> {
+    Box::pin(run_with_transport::<T>(config, shutdown_rx, service_rx))
+}
+
+// Channels are multiplexed into one connection of `T` with `transport.mux`
+async fn run_with_transport<T: 'static + Transport>(
+    config: &ServerConfig,
+    shutdown_rx: broadcast::Receiver<bool>,
+    service_rx: mpsc::Receiver<ServiceChange>,
+) -> Result<()> {
+    if config.transport.mux {
+        #[cfg(feature = "mux")]
+        {
+            let mut server = Server::<MuxTransport<T>>::from(config).await?;
+            return server.run(shutdown_rx, service_rx).await;
+        }
+        #[cfg(not(feature = "mux"))]
+        crate::helper::feature_not_compile("mux")
+    }
+    let mut server = Server::<T>::from(config).await?;
+    server.run(shutdown_rx, service_rx).await
+}
+
 // A hash map of ControlChannelHandles, indexed by ServiceDigest or Nonce
 // See also MultiMap
 type ControlChannelMap<T> = MultiMap<ServiceDigest, Nonce, ControlChannelHandle<T>>;
@@ -87,10 +251,75 @@ struct Server<'a, T: Transport> {
 
     // `[server.services]` config, indexed by ServiceDigest
     services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
+    // Counts the services for the readiness as long as they're configured
+    configured: HashMap<ServiceDigest, ConfiguredGuard>,
+    // Stops the listeners of honeypot services when dropped
+    honeypots: HashMap<ServiceDigest, DropGuard>,
     // Collection of contorl channels
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
     // Wrapper around the transport layer
     transport: Arc<T>,
+    ctx: ServerContext,
+}
+
+// Server-wide states used by the tasks of services
+#[derive(Clone)]
+struct ServerContext {
+    // Alerts on new visitor IPs, if `[server.visitor_alert]` is configured
+    visitor_alert: Option<Arc<VisitorAlert>>,
+    // Owns the tasks of the server. Tasks of a service that must stop with its
+    // control channels are in a child group of it
+    tasks: TaskGroup,
+    // Listeners of the maintenance pages of services that have no control channel yet.
+    // Once there is one, its own listener serves the page while the client is offline
+    maintenance: Arc<StdMutex<HashMap<ServiceDigest, DropGuard>>>,
+    // Whether visitors rejected by the ACL are held in the tarpit
+    tarpit: bool,
+}
+
+impl ServerContext {
+    fn start_maintenance(&self, digest: ServiceDigest, service: &ServerServiceConfig) {
+        if service.maintenance_page.is_none() {
+            return;
+        }
+        let tasks = self.tasks.child();
+        run_maintenance_listener(service, self.tarpit, tasks.clone());
+        self.maintenance
+            .lock()
+            .unwrap()
+            .insert(digest, tasks.cancel_on_drop());
+    }
+
+    fn stop_maintenance(&self, digest: &ServiceDigest) {
+        let _ = self.maintenance.lock().unwrap().remove(digest);
+    }
+}
+
+// What the status page shows. A service is online if any client is connected for it
+struct ServiceStatuses<T: Transport> {
+    services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
+    control_channels: Arc<RwLock<ControlChannelMap<T>>>,
+}
+
+#[async_trait]
+impl<T: 'static + Transport> StatusSource for ServiceStatuses<T> {
+    async fn statuses(&self) -> Vec<ServiceStatus> {
+        let services = self.services.read().await;
+        let control_channels = self.control_channels.read().await;
+        let mut v: Vec<ServiceStatus> = services
+            .iter()
+            .filter(|(_, s)| !s.hide_from_status_page && s.service_type != ServiceType::Honeypot)
+            .map(|(digest, s)| ServiceStatus {
+                name: s.name.clone(),
+                online: control_channels
+                    .get1(digest)
+                    .map(|h| h.is_alive())
+                    .unwrap_or(false),
+            })
+            .collect();
+        v.sort_by(|a, b| a.name.cmp(&b.name));
+        v
+    }
 }
 
 // Generate a hash map of services which is indexed by ServiceDigest
@@ -107,11 +336,41 @@ fn generate_service_hashmap(
 impl<'a, T: 'static + Transport> Server<'a, T> {
     // Create a server from `[server]`
     pub async fn from(config: &'a ServerConfig) -> Result<Server<'a, T>> {
+        ACL.load(config.acl_file.as_deref()).await?;
+        GROUPS.load(config);
+        PAIRING.load(config.pairing.as_ref());
+
         Ok(Server {
             config,
             services: Arc::new(RwLock::new(generate_service_hashmap(config))),
+            configured: config
+                .services
+                .iter()
+                .filter(|(_, s)| s.service_type != ServiceType::Honeypot)
+                .map(|(name, _)| {
+                    (
+                        protocol::digest(name.as_bytes()),
+                        ConfiguredGuard::new(name),
+                    )
+                })
+                .collect(),
+            honeypots: HashMap::new(),
             control_channels: Arc::new(RwLock::new(ControlChannelMap::new())),
-            transport: Arc::new(T::new(&config.transport).await?),
+            transport: Arc::new(
+                T::new(&config.transport)
+                    .await
+                    .with_context(|| "Failed to create the transport")
+                    .context(Failure::Config)?,
+            ),
+            ctx: ServerContext {
+                visitor_alert: config
+                    .visitor_alert
+                    .as_ref()
+                    .map(|c| Arc::new(VisitorAlert::new(c))),
+                tasks: TaskGroup::new(),
+                maintenance: Default::default(),
+                tarpit: config.tarpit,
+            },
         })
     }
 
@@ -126,8 +385,49 @@ impl<'a, T: 'static + Transport> Server<'a, T> {
             .transport
             .bind(&self.config.bind_addr)
             .await
-            .with_context(|| "Failed to listen at `server.bind_addr`")?;
+            .with_context(|| "Failed to listen at `server.bind_addr`")
+            .hint(Hint::Bind("server.bind_addr"))
+            .context(Failure::Bind)?;
         info!("Listening at {}", self.config.bind_addr);
+        let _listening = ListeningGuard::new("server", None, &self.config.bind_addr);
+
+        // Stop all tasks if this future is dropped rather than shutdown
+        let _tasks = self.ctx.tasks.cancel_on_drop();
+
+        if let Some(config) = &self.config.status_page {
+            let l = TcpListener::bind(&config.bind_addr)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to listen for the status page at {}",
+                        config.bind_addr
+                    )
+                })
+                .hint(Hint::Bind("server.status_page.bind_addr"))
+                .context(Failure::Bind)?;
+            let listening =
+                ListeningGuard::new("status_page", l.local_addr().ok(), &config.bind_addr);
+            let source = Arc::new(ServiceStatuses {
+                services: self.services.clone(),
+                control_channels: self.control_channels.clone(),
+            });
+            let config = config.clone();
+            self.ctx.tasks.spawn(async move {
+                let _listening = listening;
+                if let Err(e) = run_status_page(l, config, source).await {
+                    error!("{:#}", e);
+                }
+            });
+        }
+
+        for (digest, s) in self.services.read().await.iter() {
+            self.ctx.start_maintenance(*digest, s);
+            if s.service_type == ServiceType::Honeypot {
+                let tasks = self.ctx.tasks.child();
+                run_honeypot(s, tasks.clone());
+                self.honeypots.insert(*digest, tasks.cancel_on_drop());
+            }
+        }
 
         // Retry at least every 100ms
         let mut backoff = ExponentialBackoff {
@@ -170,11 +470,14 @@ impl<'a, T: 'static + Transport> Server<'a, T> {
                                         Ok(conn) => {
                                             let services = self.services.clone();
                                             let control_channels = self.control_channels.clone();
-                                            tokio::spawn(async move {
-                                                if let Err(err) = handle_connection(conn, services, control_channels).await {
+                                            let ctx = self.ctx.clone();
+                                            self.ctx.tasks.spawn(async move {
+                                                if let Err(err) = handle_connection(conn, services, control_channels, ctx).await {
                                                     error!("{:?}", err);
                                                 }
                                             }.instrument(info_span!("handle_connection", %addr)));
+                                        }, Err(e) if e.downcast_ref::<Diverted>().is_some() => {
+                                            debug!("Connection from {} is diverted", addr);
                                         }, Err(e) => {
                                             error!("{:?}", e);
                                         }
@@ -188,7 +491,7 @@ impl<'a, T: 'static + Transport> Server<'a, T> {
                     }
                 },
                 // Wait for the shutdown signal
-                _ = shutdown_rx.recv() => {
+                _ = recv_shutdown(&mut shutdown_rx) => {
                     info!("Shuting down gracefully...");
                     break;
                 },
@@ -200,6 +503,13 @@ impl<'a, T: 'static + Transport> Server<'a, T> {
             }
         }
 
+        // Stop all tasks before returning, so that the next instance won't
+        // race with the remaining ones, like for the ports of services
+        self.ctx
+            .tasks
+            .shutdown(Duration::from_secs(SHUTDOWN_TIMEOUT))
+            .await;
+
         info!("Shutdown");
 
         Ok(())
@@ -207,20 +517,44 @@ impl<'a, T: 'static + Transport> Server<'a, T> {
 
     async fn handle_hot_reload(&mut self, e: ServiceChange) {
         match e {
-            ServiceChange::ServerAdd(s) => {
+            ServiceChange::ServerAdd(mut s) => {
+                // Changes from library users are not validated yet
+                let default_token = self.config.default_token_of(&s).clone();
+                if let Err(e) = Config::validate_server_service(&mut s, &default_token)
+                    .and_then(|_| GROUPS.join(&s.name, s.group.as_deref()))
+                {
+                    error!("Failed to add the service: {:#}", e);
+                    return;
+                }
                 let hash = protocol::digest(s.name.as_bytes());
+                self.ctx.stop_maintenance(&hash);
+                self.ctx.start_maintenance(hash, &s);
+                self.honeypots.remove(&hash);
+                self.configured.remove(&hash);
+                if s.service_type == ServiceType::Honeypot {
+                    let tasks = self.ctx.tasks.child();
+                    run_honeypot(&s, tasks.clone());
+                    self.honeypots.insert(hash, tasks.cancel_on_drop());
+                } else {
+                    self.configured.insert(hash, ConfiguredGuard::new(&s.name));
+                }
+
                 let mut wg = self.services.write().await;
-                let _ = wg.insert(hash, s);
+                let _ = wg.insert(hash, *s);
 
                 let mut wg = self.control_channels.write().await;
                 let _ = wg.remove1(&hash);
             }
             ServiceChange::ServerDelete(s) => {
+                let _ = GROUPS.join(&s, None);
                 let hash = protocol::digest(s.as_bytes());
                 let _ = self.services.write().await.remove(&hash);
 
                 let mut wg = self.control_channels.write().await;
                 let _ = wg.remove1(&hash);
+                self.ctx.stop_maintenance(&hash);
+                self.configured.remove(&hash);
+                self.honeypots.remove(&hash);
             }
             _ => (),
         }
@@ -232,15 +566,46 @@ async fn handle_connection<T: 'static + Transport>(
     mut conn: T::Stream,
     services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
+    ctx: ServerContext,
 ) -> Result<()> {
     // Read hello
     let hello = read_hello(&mut conn).await?;
     match hello {
         ControlChannelHello(_, service_digest) => {
-            do_control_channel_handshake(conn, services, control_channels, service_digest).await?;
+            do_control_channel_handshake(
+                conn,
+                services,
+                control_channels,
+                service_digest,
+                None,
+                0,
+                ctx,
+            )
+            .await?;
+        }
+        ClientControlChannelHello(_, service_digest, instance_id, capabilities) => {
+            do_control_channel_handshake(
+                conn,
+                services,
+                control_channels,
+                service_digest,
+                Some(instance_id),
+                capabilities,
+                ctx,
+            )
+            .await?;
         }
         DataChannelHello(_, nonce) => {
-            do_data_channel_handshake(conn, control_channels, nonce).await?;
+            do_data_channel_handshake(conn, control_channels, nonce, false).await?;
+        }
+        ReverseDataChannelHello(_, nonce) => {
+            do_data_channel_handshake(conn, control_channels, nonce, true).await?;
+        }
+        PairingHello(_, id) => {
+            pairing::serve(&mut conn, &id).await?;
+        }
+        ServerControlChannelHello(..) => {
+            bail!("Unexpected type of hello");
         }
     }
     Ok(())
@@ -251,27 +616,64 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
     services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
     service_digest: ServiceDigest,
+    instance_id: Option<InstanceId>,
+    capabilities: Capabilities,
+    ctx: ServerContext,
 ) -> Result<()> {
-    info!("Try to handshake a control channel");
+    info!(
+        instance = %fmt_instance_id(&instance_id),
+        capabilities = %protocol::fmt_capabilities(capabilities),
+        "Try to handshake a control channel"
+    );
 
     // Generate a nonce
     let mut nonce = vec![0u8; HASH_WIDTH_IN_BYTES];
     rand::thread_rng().fill_bytes(&mut nonce);
 
-    // Send hello
-    let hello_send = Hello::ControlChannelHello(
-        protocol::CURRENT_PROTO_VERSION,
-        nonce.clone().try_into().unwrap(),
-    );
+    // Send hello. Clients older than PROTO_V1 only understand `ControlChannelHello`
+    let hello_send = match instance_id {
+        Some(_) => Hello::ServerControlChannelHello(
+            protocol::CURRENT_PROTO_VERSION,
+            nonce.clone().try_into().unwrap(),
+            protocol::local_capabilities(),
+        ),
+        None => Hello::ControlChannelHello(
+            protocol::CURRENT_PROTO_VERSION,
+            nonce.clone().try_into().unwrap(),
+        ),
+    };
     conn.write_all(&bincode::serialize(&hello_send).unwrap())
         .await?;
+    let framing = Framing::new(capabilities);
+    // Clients with `CAP_CLOCK` answer with theirs before sending the auth
+    let exchange_clocks = capabilities & CAP_CLOCK != 0;
+    let clock_sent = Clock::now();
+    if exchange_clocks {
+        conn.write_all(&framing.encode(&clock_sent)?).await?;
+    }
+    // The client tells its version before the auth, but the server only after it, so that
+    // scanners can't tell vulnerable builds
+    let exchange_versions = protocol::versions_negotiated(capabilities);
     conn.flush().await?;
 
+    // Clients with `CAP_TOKEN_HASH` read the salts before sending the auth
+    let exchange_salts = capabilities & CAP_TOKEN_HASH != 0;
+
     // Lookup the service
-    let service_config = match services.read().await.get(&service_digest) {
+    // Honeypots have no client
+    let service_config = match services
+        .read()
+        .await
+        .get(&service_digest)
+        .filter(|s| s.service_type != ServiceType::Honeypot)
+    {
         Some(v) => v,
         None => {
-            conn.write_all(&bincode::serialize(&Ack::ServiceNotExist).unwrap())
+            if exchange_salts {
+                conn.write_all(&framing.encode(&TokenSalts(Vec::new()))?)
+                    .await?;
+            }
+            conn.write_all(&framing.encode(&Ack::ServiceNotFound)?)
                 .await?;
             bail!("No such a service {}", hex::encode(&service_digest));
         }
@@ -280,45 +682,205 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
 
     let service_name = &service_config.name;
 
-    // Calculate the checksum
-    let mut concat = Vec::from(service_config.token.as_ref().unwrap().as_bytes());
-    concat.append(&mut nonce);
+    // The config has been validated
+    let token_hashes: Vec<TokenHash> = service_config
+        .token_hashes
+        .iter()
+        .filter_map(|h| TokenHash::parse(h).ok())
+        .collect();
+    if exchange_salts {
+        let salts = TokenSalts(token_hashes.iter().map(|h| h.salt).collect());
+        conn.write_all(&framing.encode(&salts)?).await?;
+        conn.flush().await?;
+    }
 
-    // Read auth
-    let protocol::Auth(d) = read_auth(&mut conn).await?;
+    // The clock of the client was read halfway between sending ours and receiving it
+    let skew = if exchange_clocks {
+        let clock = read_clock(&mut conn, framing).await?;
+        let now = Clock::now();
+        Some(clock.skew(&Clock((clock_sent.0 + now.0) / 2)))
+    } else {
+        None
+    };
+    // `None` if the client is too old to tell
+    let version = if exchange_versions {
+        Some(read_version(&mut conn).await?.0)
+    } else {
+        None
+    };
+    let tolerance = service_config.totp_tolerance();
 
-    // Validate
-    let session_key = protocol::digest(&concat);
-    if session_key != d {
-        conn.write_all(&bincode::serialize(&Ack::AuthFailed).unwrap())
-            .await?;
-        debug!(
-            "Expect {}, but got {}",
-            hex::encode(session_key),
-            hex::encode(d)
+    // Read auth. Clients that got the salts send a proof for each of them
+    let n = if exchange_salts {
+        token_hashes.len().max(1)
+    } else {
+        1
+    };
+    let mut proofs = Vec::with_capacity(n);
+    for _ in 0..n {
+        proofs.push(read_auth(&mut conn, framing).await?.0);
+    }
+    let weight = if capabilities & CAP_WEIGHT != 0 {
+        read_weight(&mut conn, framing).await?.0
+    } else {
+        1
+    };
+
+    // Validate. All the candidates are checked in constant time, so the time taken
+    // doesn't tell which one is closer
+    let (session_key, valid) = if token_hashes.is_empty() {
+        let expected = expected_auth_digests(&service_config, &nonce);
+        let d = proofs[0];
+        let valid = expected
+            .iter()
+            .fold(false, |valid, e| protocol::ct_eq(e, &d) | valid);
+        if !valid {
+            debug!(
+                "Expect {}, but got {}",
+                expected
+                    .iter()
+                    .map(hex::encode)
+                    .collect::<Vec<_>>()
+                    .join(" or "),
+                hex::encode(d)
+            );
+        }
+        (d, valid)
+    } else if !exchange_salts {
+        warn!(
+            "The client of service {} is too old for hashed tokens",
+            service_name
         );
-        bail!("Service {} failed the authentication", service_name);
+        (proofs[0], false)
     } else {
+        let valid = token_hashes
+            .iter()
+            .zip(&proofs)
+            .fold(false, |valid, (h, p)| h.verify(&nonce, p) | valid);
+        (protocol::digest(&proofs.concat()), valid)
+    };
+
+    if !valid {
+        let ack = match (skew, tolerance) {
+            _ if !token_hashes.is_empty() && !exchange_salts => Ack::VersionMismatch,
+            // The token is likely right, but the time isn't
+            (Some(skew), Some(tolerance)) if skew.abs() > tolerance as f64 => Ack::Rejected {
+                reason: format!(
+                    "The clock of the client is {:+.1}s off, beyond the tolerance of {}s of time-based tokens",
+                    skew, tolerance
+                ),
+            },
+            _ => Ack::AuthFailed,
+        };
+        conn.write_all(&framing.encode(&ack.clone().for_peer(capabilities))?)
+            .await?;
+        conn.flush().await?;
+        match ack {
+            Ack::Rejected { reason } => bail!(
+                "Service {} failed the authentication. {}",
+                service_name,
+                reason
+            ),
+            _ => bail!("Service {} failed the authentication", service_name),
+        }
+    } else {
+        if let (Some(skew), Some(tolerance)) = (skew, tolerance) {
+            if skew.abs() > tolerance as f64 / 2.0 {
+                warn!(
+                    "The clock of the client of service {} is {:+.1}s off, close to the tolerance of {}s of time-based tokens",
+                    service_name, skew, tolerance
+                );
+            }
+        }
         let mut h = control_channels.write().await;
 
-        // If there's already a control channel for the service, then drop the old one.
-        // Because a control channel doesn't report back when it's dead,
-        // the handle in the map could be stall, dropping the old handle enables
-        // the client to reconnect.
-        if h.remove1(&service_digest).is_some() {
-            warn!(
-                "Dropping previous control channel for service {}",
-                service_name
-            );
+        if let Some(handle) = h.get1(&service_digest) {
+            // A client reconnecting means its previous control channel is dead,
+            // even if the server hasn't found it out
+            if let Some(id) = &instance_id {
+                if handle.remove_instance(id) {
+                    info!(
+                        "Client reconnected. Dropping its previous control channel for service {}",
+                        service_name
+                    );
+                }
+            }
+
+            if !handle.is_alive() && handle.can_resume(&service_config) {
+                ack_ok(&mut conn, framing, exchange_versions).await?;
+
+                info!(service = %service_config.name, instance = %fmt_instance_id(&instance_id), version = %fmt_version(&version), "Control channel re-established");
+                handle.add_control_channel(
+                    conn,
+                    session_key,
+                    instance_id,
+                    capabilities,
+                    weight,
+                    version,
+                );
+                return Ok(());
+            } else if !handle.is_alive() {
+                // The previous client has gone. There's no duplicate at all
+                let _ = h.remove1(&service_digest);
+                info!(
+                    "Dropping previous control channel for service {}",
+                    service_name
+                );
+            } else {
+                match service_config.on_duplicate {
+                    DuplicatePolicy::Replace => {
+                        // Note that a control channel only finds out it's dead when
+                        // reading or writing fails, so the handle in the map could be
+                        // stale. Replacing it enables the client to reconnect.
+                        warn!(
+                            "Replacing previous control channel for service {}",
+                            service_name
+                        );
+                        if let Some(handle) = h.remove1(&service_digest) {
+                            handle.replace();
+                        }
+                    }
+                    DuplicatePolicy::Reject => {
+                        conn.write_all(&framing.encode(&Ack::ServiceBusy)?).await?;
+                        conn.flush().await?;
+                        bail!(
+                            "Service {} is already registered by another client",
+                            service_name
+                        );
+                    }
+                    DuplicatePolicy::LoadBalance => {
+                        ack_ok(&mut conn, framing, exchange_versions).await?;
+
+                        info!(service = %service_config.name, instance = %fmt_instance_id(&instance_id), version = %fmt_version(&version), "Control channel joined the load balancing");
+                        handle.add_control_channel(
+                            conn,
+                            session_key,
+                            instance_id,
+                            capabilities,
+                            weight,
+                            version,
+                        );
+                        return Ok(());
+                    }
+                }
+            }
         }
 
         // Send ack
-        conn.write_all(&bincode::serialize(&Ack::Ok).unwrap())
-            .await?;
-        conn.flush().await?;
+        ack_ok(&mut conn, framing, exchange_versions).await?;
 
-        info!(service = %service_config.name, "Control channel established");
-        let handle = ControlChannelHandle::new(conn, service_config);
+        info!(service = %service_config.name, instance = %fmt_instance_id(&instance_id), version = %fmt_version(&version), "Control channel established");
+        ctx.stop_maintenance(&service_digest);
+        let handle = ControlChannelHandle::new(
+            conn,
+            service_config,
+            session_key,
+            instance_id,
+            capabilities,
+            weight,
+            version,
+            ctx,
+        );
 
         // Insert the new handle
         let _ = h.insert(service_digest, session_key, handle);
@@ -327,21 +889,62 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
     Ok(())
 }
 
+// Accept a control channel, followed by the version of the server if it's exchanged
+async fn ack_ok<S: AsyncWrite + Unpin>(
+    conn: &mut S,
+    framing: Framing,
+    exchange_versions: bool,
+) -> Result<()> {
+    conn.write_all(&framing.encode(&Ack::Ok)?).await?;
+    if exchange_versions {
+        conn.write_all(&framing.encode(&Version::local())?).await?;
+    }
+    conn.flush().await?;
+    Ok(())
+}
+
+// The auth digests that the client may send for a plain token. For time-based tokens, the
+// windows within the tolerance are accepted as well, to tolerate the clock skew and the
+// handshake crossing a window boundary
+fn expected_auth_digests(service: &ServerServiceConfig, nonce: &[u8]) -> Vec<protocol::Digest> {
+    let token = service.token.as_ref().unwrap();
+    match (service.totp_step, service.totp_tolerance()) {
+        (Some(step), Some(tolerance)) => protocol::totp_windows(step, tolerance)
+            .map(|w| protocol::auth_digest(&protocol::totp_token(token, w), nonce))
+            .collect(),
+        _ => vec![protocol::auth_digest(token, nonce)],
+    }
+}
+
 async fn do_data_channel_handshake<T: 'static + Transport>(
     conn: T::Stream,
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
     nonce: Nonce,
+    reverse: bool,
 ) -> Result<()> {
     debug!("Try to handshake a data channel");
 
     // Validate
     let control_channels_guard = control_channels.read().await;
-    match control_channels_guard.get2(&nonce) {
+    let handle = control_channels_guard.get2(&nonce).or_else(|| {
+        // Control channels that joined a load balanced service are not indexed by their session keys
+        control_channels_guard
+            .values()
+            .find(|h| h.has_session_key(&nonce))
+    });
+    match handle {
+        // The client and the server disagree on whether the service is reverse
+        Some(handle) if handle.service.connect_addr.is_some() != reverse => {
+            warn!(
+                "Data channel of the wrong direction for service {}. Set both `listen_addr` of the client and `connect_addr` of the server, or neither",
+                handle.service.name
+            );
+        }
         Some(handle) => {
             // Send the data channel to the corresponding control channel
             handle
                 .data_ch_tx
-                .send(conn)
+                .send((conn, nonce))
                 .await
                 .with_context(|| "Data channel for a stale control channel")?;
         }
@@ -353,9 +956,22 @@ async fn do_data_channel_handshake<T: 'static + Transport>(
 }
 
 pub struct ControlChannelHandle<T: Transport> {
-    // Shutdown the control channel by dropping it
-    _shutdown_tx: broadcast::Sender<bool>,
-    data_ch_tx: mpsc::Sender<T::Stream>,
+    // Shutdown the control channels by dropping it.
+    // Sending `true` tells the clients that they're replaced before shutting down
+    shutdown_tx: broadcast::Sender<bool>,
+    // Data channels, along with the session keys of the control channels they belong to
+    data_ch_tx: mpsc::Sender<(T::Stream, Nonce)>,
+    // Shared by the control channels of the service, so requests are taken in turns
+    data_ch_req_rx: Arc<Mutex<mpsc::UnboundedReceiver<bool>>>,
+    members: Members,
+    service: ServerServiceConfig,
+    // Control channels are run in the server-wide task group
+    tasks: TaskGroup,
+    // The connection pool and the listener of the service
+    service_tasks: TaskGroup,
+    // Stops the service tasks when the handle is dropped
+    _service_tasks: DropGuard,
+    _log_level: Option<LogLevelGuard>,
 }
 
 impl<T> ControlChannelHandle<T>
@@ -364,10 +980,20 @@ where
 {
     // Create a control channel handle, where the control channel handling task
     // and the connection pool task are created.
+    #[allow(clippy::too_many_arguments)]
     #[instrument(skip_all, fields(service = %service.name))]
-    fn new(conn: T::Stream, service: ServerServiceConfig) -> ControlChannelHandle<T> {
+    fn new(
+        conn: T::Stream,
+        service: ServerServiceConfig,
+        session_key: Nonce,
+        instance_id: Option<InstanceId>,
+        capabilities: Capabilities,
+        weight: u32,
+        version: Option<String>,
+        ctx: ServerContext,
+    ) -> ControlChannelHandle<T> {
         // Create a shutdown channel
-        let (shutdown_tx, shutdown_rx) = broadcast::channel::<bool>(1);
+        let (shutdown_tx, _) = broadcast::channel::<bool>(1);
 
         // Store data channels
         let (data_ch_tx, data_ch_rx) = mpsc::channel(CHAN_SIZE * 2);
@@ -375,10 +1001,12 @@ where
         // Store data channel creation requests
         let (data_ch_req_tx, data_ch_req_rx) = mpsc::unbounded_channel();
 
-        // Cache some data channels for later use
-        let pool_size = match service.service_type {
-            ServiceType::Tcp => TCP_POOL_SIZE,
-            ServiceType::Udp => UDP_POOL_SIZE,
+        // Cache some data channels for later use. With `warmup`, each control channel requests
+        // them itself. Reverse services are sent data channels unrequested
+        let pool_size = if service.warmup.is_none() && service.connect_addr.is_none() {
+            pool_size(service.service_type)
+        } else {
+            0
         };
 
         for _i in 0..pool_size {
@@ -387,104 +1015,393 @@ where
             };
         }
 
-        let shutdown_rx_clone = shutdown_tx.subscribe();
+        if service.compression.is_some() {
+            match compression::negotiate(service.compression.as_ref(), capabilities) {
+                Some(v) => debug!("Compressing data channels with {:?}", v),
+                None => warn!(
+                    "The client has none of the codecs of `compression`. Data channels are not compressed"
+                ),
+            }
+        }
+
+        let members = Members::default();
+
+        let service_tasks = ctx.tasks.child();
         let bind_addr = service.bind_addr.clone();
+        let service_name = service.name.clone();
         match service.service_type {
-            ServiceType::Tcp => tokio::spawn(
-                async move {
-                    if let Err(e) = run_tcp_connection_pool::<T>(
-                        bind_addr,
-                        data_ch_rx,
-                        data_ch_req_tx,
-                        shutdown_rx_clone,
-                    )
-                    .await
-                    .with_context(|| "Failed to run TCP connection pool")
-                    {
-                        error!("{:?}", e);
+            ServiceType::Tcp if service.connect_addr.is_some() => {
+                let service = service.clone();
+                let members = members.clone();
+                let ctx = ctx.clone();
+                service_tasks.spawn(
+                    async move {
+                        // Nothing is requested, but the control channels stop once it's closed
+                        let _data_ch_req_tx = data_ch_req_tx;
+                        let reverse =
+                            run_tcp_reverse::<T>(service.clone(), members.clone(), data_ch_rx, ctx)
+                                .instrument(Span::current());
+                        if catch_panic(&service.name, reverse).await.is_none() {
+                            members.lock().unwrap().clear();
+                        }
                     }
-                }
-                .instrument(Span::current()),
-            ),
-            ServiceType::Udp => tokio::spawn(
-                async move {
-                    if let Err(e) = run_udp_connection_pool::<T>(
-                        bind_addr,
-                        data_ch_rx,
-                        data_ch_req_tx,
-                        shutdown_rx_clone,
-                    )
-                    .await
-                    .with_context(|| "Failed to run TCP connection pool")
-                    {
-                        error!("{:?}", e);
+                    .instrument(Span::current()),
+                )
+            }
+            ServiceType::Tcp => {
+                let service = service.clone();
+                let members = members.clone();
+                let ctx = ctx.clone();
+                let tasks = service_tasks.clone();
+                let data_ch_tx = data_ch_tx.downgrade();
+                let pool_tasks = service_tasks.clone();
+                service_tasks.spawn(
+                    async move {
+                        let pool = run_tcp_connection_pool::<T>(
+                            service.clone(),
+                            members.clone(),
+                            data_ch_rx,
+                            data_ch_tx,
+                            data_ch_req_tx,
+                            ctx,
+                            tasks,
+                        )
+                        .instrument(Span::current());
+                        match catch_panic(&service.name, pool).await {
+                            Some(Err(e)) => {
+                                let e = e.context("Failed to run TCP connection pool");
+                                events::emit_error(&service.name, &e);
+                                error!("{:?}", e);
+                            }
+                            Some(Ok(_)) => {}
+                            // Shutdown the control channels, so the client reconnects
+                            // and the service is started over
+                            None => members.lock().unwrap().clear(),
+                        }
+                        // So that a client coming back doesn't resume the service
+                        pool_tasks.cancel();
                     }
-                }
-                .instrument(Span::current()),
-            ),
+                    .instrument(Span::current()),
+                )
+            }
+            ServiceType::Udp => {
+                let members = members.clone();
+                let visitor_alert = ctx.visitor_alert.clone();
+                let sampler = Sampler::from_config(&service);
+                let dns = DnsGuard::from_config(&service);
+                let limit =
+                    DatagramLimit::new(service.max_datagram_size, service.oversized_datagram);
+                let group = service.group.clone();
+                let pool_tasks = service_tasks.clone();
+                service_tasks.spawn(
+                    async move {
+                        let pool = run_udp_connection_pool::<T>(
+                            service_name.clone(),
+                            group,
+                            bind_addr,
+                            members.clone(),
+                            data_ch_rx,
+                            data_ch_req_tx,
+                            visitor_alert,
+                            sampler,
+                            dns,
+                            limit,
+                        )
+                        .instrument(Span::current());
+                        match catch_panic(&service_name, pool).await {
+                            Some(Err(e)) => {
+                                let e = e.context("Failed to run UDP connection pool");
+                                events::emit_error(&service_name, &e);
+                                error!("{:?}", e);
+                            }
+                            Some(Ok(_)) => {}
+                            None => members.lock().unwrap().clear(),
+                        }
+                        pool_tasks.cancel();
+                    }
+                    .instrument(Span::current()),
+                )
+            }
+            ServiceType::Honeypot => unreachable!(),
+        };
+
+        if let Some(config) = service.keep_warm.clone() {
+            let members = members.clone();
+            service_tasks.spawn(
+                keep_warm::run(config, move || !members.lock().unwrap().is_empty())
+                    .instrument(Span::current()),
+            );
+        }
+
+        let handle = ControlChannelHandle {
+            shutdown_tx,
+            data_ch_tx,
+            data_ch_req_rx: Arc::new(Mutex::new(data_ch_req_rx)),
+            members,
+            _log_level: LogLevelGuard::new(&service.name, service.log_level.as_deref()),
+            service,
+            tasks: ctx.tasks,
+            _service_tasks: service_tasks.cancel_on_drop(),
+            service_tasks,
         };
+        handle.add_control_channel(
+            conn,
+            session_key,
+            instance_id,
+            capabilities,
+            weight,
+            version,
+        );
+        handle
+    }
+
+    // Run a control channel for the service.
+    // There could be more than one of them if the service is load balanced
+    fn add_control_channel(
+        &self,
+        conn: T::Stream,
+        session_key: Nonce,
+        instance_id: Option<InstanceId>,
+        capabilities: Capabilities,
+        weight: u32,
+        version: Option<String>,
+    ) {
+        let (member_shutdown_tx, member_shutdown_rx) = oneshot::channel();
+        let (member_data_ch_req_tx, member_data_ch_req_rx) = mpsc::unbounded_channel();
+        let outlier = Arc::new(Outlier::default());
+        self.members.lock().unwrap().insert(
+            session_key,
+            Member {
+                instance_id,
+                _shutdown_tx: member_shutdown_tx,
+                data_ch_req_tx: member_data_ch_req_tx,
+                capabilities,
+                weight,
+                joined_at: Instant::now(),
+                outlier: outlier.clone(),
+                _client: ClientGuard::new(
+                    &self.service.name,
+                    &fmt_instance_id(&instance_id),
+                    version,
+                    capabilities,
+                ),
+            },
+        );
 
-        // Create the control channel
         let ch = ControlChannel::<T> {
             conn,
-            shutdown_rx,
-            service,
-            data_ch_req_rx,
+            shutdown_rx: self.shutdown_tx.subscribe(),
+            member_shutdown_rx,
+            service: self.service.clone(),
+            data_ch_req_rx: self.data_ch_req_rx.clone(),
+            member_data_ch_req_rx,
+            capabilities,
+            outlier,
         };
 
-        // Run the control channel
-        tokio::spawn(
+        let members = self.members.clone();
+        let service_name = self.service.name.clone();
+        let close_listener_after = self.service.close_listener_after.map(Duration::from_secs);
+        let service_tasks = self.service_tasks.clone();
+        self.tasks.spawn(
             async move {
-                if let Err(err) = ch.run().await {
+                // The connection is dropped if it panics, so the client will reconnect
+                if let Some(Err(err)) =
+                    catch_panic(&service_name, ch.run().instrument(Span::current())).await
+                {
                     error!("{:?}", err);
                 }
+                let gone = {
+                    let mut members = members.lock().unwrap();
+                    members.remove(&session_key);
+                    members.is_empty()
+                };
+
+                // Stop accepting visitors if no client comes back in time. A client that
+                // comes back later starts the service over
+                if let (true, Some(d)) = (gone, close_listener_after) {
+                    let tasks = service_tasks.clone();
+                    service_tasks.spawn(
+                        async move {
+                            time::sleep(d).await;
+                            if members.lock().unwrap().is_empty() {
+                                info!("No client for {:?}. Stop accepting visitors", d);
+                                tasks.cancel();
+                            }
+                        }
+                        .instrument(Span::current()),
+                    );
+                }
             }
             .instrument(Span::current()),
         );
+    }
 
-        ControlChannelHandle {
-            _shutdown_tx: shutdown_tx,
-            data_ch_tx,
-        }
+    // Whether any control channel of the service is still running
+    fn is_alive(&self) -> bool {
+        !self.members.lock().unwrap().is_empty()
+    }
+
+    // Whether a client coming back can take over the service as it is, along with the visitors
+    // waiting for it. Not if the service has changed, or stopped accepting visitors
+    fn can_resume(&self, service: &ServerServiceConfig) -> bool {
+        self.service == *service && !self.service_tasks.is_cancelled()
+    }
+
+    fn has_session_key(&self, session_key: &Nonce) -> bool {
+        self.members.lock().unwrap().contains_key(session_key)
+    }
+
+    // Shutdown the control channels from a client instance.
+    // Returns whether there's any
+    fn remove_instance(&self, instance_id: &InstanceId) -> bool {
+        let mut members = self.members.lock().unwrap();
+        let n = members.len();
+        members.retain(|_, m| m.instance_id.as_ref() != Some(instance_id));
+        members.len() != n
+    }
+
+    // Shutdown the control channels, and tell the clients to stop
+    fn replace(self) {
+        let _ = self.shutdown_tx.send(true);
     }
 }
 
 // Control channel, using T as the transport layer. P is TcpStream or UdpTraffic
 struct ControlChannel<T: Transport> {
-    conn: T::Stream,                               // The connection of control channel
-    service: ServerServiceConfig,                  // A copy of the corresponding service config
-    shutdown_rx: broadcast::Receiver<bool>,        // Receives the shutdown signal
-    data_ch_req_rx: mpsc::UnboundedReceiver<bool>, // Receives visitor connections
+    conn: T::Stream,                           // The connection of control channel
+    service: ServerServiceConfig,              // A copy of the corresponding service config
+    shutdown_rx: broadcast::Receiver<bool>,    // Receives the shutdown signal
+    member_shutdown_rx: oneshot::Receiver<()>, // Receives the shutdown signal of this control channel only
+    data_ch_req_rx: Arc<Mutex<mpsc::UnboundedReceiver<bool>>>, // Receives visitor connections
+    member_data_ch_req_rx: mpsc::UnboundedReceiver<bool>, // Receives visitors picked for this control channel
+    capabilities: Capabilities,                           // Capabilities of the client
+    outlier: Arc<Outlier>, // Failures of forwarding, reported by the client
 }
 
 impl<T: Transport> ControlChannel<T> {
     // Run a control channel
     #[instrument(skip(self), fields(service = %self.service.name))]
     async fn run(mut self) -> Result<()> {
-        let cmd = bincode::serialize(&ControlChannelCmd::CreateDataChannel).unwrap();
+        let framing = Framing::new(self.capabilities);
+        let cmd = framing.encode(&ControlChannelCmd::CreateDataChannel)?;
+        let _events = ServiceUpGuard::new(&self.service.name);
+
+        // So that automation on the client can publish where visitors reach the service
+        if let Some(addr) = self.service.public_addr() {
+            if self.capabilities & CAP_PUBLIC_ADDR != 0 {
+                let addr = framing.encode(&ControlChannelCmd::PublicAddr(addr))?;
+                if !self.send_cmd(&addr).await {
+                    return Ok(());
+                }
+            }
+        }
+
+        // Heartbeats are only sent to clients that answer them
+        let interval = self
+            .service
+            .heartbeat_interval
+            .unwrap_or(HEARTBEAT_INTERVAL);
+        let heartbeat = interval != 0 && self.capabilities & CAP_HEARTBEAT != 0;
+        let interval = Duration::from_secs(interval.max(1));
+        let timeout =
+            Duration::from_secs(self.service.heartbeat_timeout.unwrap_or(HEARTBEAT_TIMEOUT));
+        let heartbeat_cmd = framing.encode(&ControlChannelCmd::Heartbeat)?;
+        let pong_cmd = framing.encode(&ControlChannelCmd::Pong)?;
+        let mut ticker = time::interval_at(time::Instant::now() + interval, interval);
+        let mut last_seen = Instant::now();
+        // When the heartbeat waiting for its answer was sent, for the round-trip time
+        let mut heartbeat_sent = None;
+
+        // Data channels are requested at once, and then paced while the visitors that waited
+        // for the client are served, so it isn't hit by all of them at once
+        let mut gap = None;
+        if let Some(warmup) = self.service.warmup.clone() {
+            let n = warmup
+                .channels
+                .unwrap_or_else(|| pool_size(self.service.service_type));
+            for _ in 0..n {
+                if !self.send_cmd(&cmd).await {
+                    return Ok(());
+                }
+            }
+            gap = Some(Duration::from_secs(1) / warmup.rate);
+        }
+        // When the next request may be sent, while paced
+        let mut next = None;
 
         // Wait for data channel requests and the shutdown signal
         loop {
             tokio::select! {
-                val = self.data_ch_req_rx.recv() => {
+                val = async { self.data_ch_req_rx.lock().await.recv().await }, if next.is_none() => {
+                    if val.is_none() || !self.send_cmd(&cmd).await {
+                        break;
+                    }
+                    next = self.pace(&mut gap).await;
+                },
+                // Never closed, since the sender lives in `members` as long as the control channel
+                Some(_) = self.member_data_ch_req_rx.recv(), if next.is_none() => {
+                    if !self.send_cmd(&cmd).await {
+                        break;
+                    }
+                    next = self.pace(&mut gap).await;
+                },
+                _ = async { time::sleep_until(next.unwrap()).await }, if next.is_some() => {
+                    next = None;
+                },
+                _ = ticker.tick(), if heartbeat => {
+                    if last_seen.elapsed() >= timeout {
+                        warn!("Nothing from the client for {:?}. Consider it dead", last_seen.elapsed());
+                        break;
+                    }
+                    if !self.send_cmd(&heartbeat_cmd).await {
+                        break;
+                    }
+                    heartbeat_sent = Some(Instant::now());
+                },
+                // Older clients send nothing after the handshake,
+                // so this only returns when the connection is closed
+                val = self.conn.read_u8() => {
                     match val {
-                        Some(_) => {
-                            if let Err(e) = self.conn.write_all(&cmd).await.with_context(||"Failed to write control cmds") {
-                                error!("{:?}", e);
-                                break;
-                            }
-                            if let Err(e) = self.conn.flush().await.with_context(|| "Failed to flush control cmds") {
-                                error!("{:?}", e);
-                                break;
+                        Ok(b) if self.capabilities & (CAP_FORWARD_REPORT | CAP_HEARTBEAT | CAP_PING) != 0 => {
+                            last_seen = Instant::now();
+                            match read_client_control_cmd(b, &mut self.conn, framing).await {
+                                Ok(ClientControlChannelCmd::ForwardFailed) => {
+                                    debug!("The client failed to connect to its local service");
+                                    self.outlier.failed(&self.service.name);
+                                }
+                                Ok(ClientControlChannelCmd::Heartbeat) => {
+                                    if let Some(t) = heartbeat_sent.take() {
+                                        let rtt = t.elapsed();
+                                        debug!(?rtt, "Heartbeat answered");
+                                        state_dump::control_rtt(&self.service.name, rtt);
+                                    }
+                                }
+                                Ok(ClientControlChannelCmd::Ping) => {
+                                    if !self.send_cmd(&pong_cmd).await {
+                                        break;
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("{:?}", e);
+                                    break;
+                                }
                             }
                         }
-                        None => {
-                            break;
-                        }
+                        _ => break,
                     }
                 },
+                _ = &mut self.member_shutdown_rx => {
+                    break;
+                },
                 // Wait for the shutdown signal
-                _ = self.shutdown_rx.recv() => {
+                val = self.shutdown_rx.recv() => {
+                    // Older clients don't understand `Replaced`. Just close the connection for them
+                    if matches!(val, Ok(true)) && self.capabilities & CAP_REPLACED_CMD != 0 {
+                        let cmd = framing.encode(&ControlChannelCmd::Replaced)?;
+                        let _ = self.conn.write_all(&cmd).await;
+                        let _ = self.conn.flush().await;
+                    }
                     break;
                 }
             }
@@ -494,34 +1411,91 @@ impl<T: Transport> ControlChannel<T> {
 
         Ok(())
     }
+
+    // When the next data channel may be requested. Pacing stops once no request is left waiting
+    async fn pace(&mut self, gap: &mut Option<Duration>) -> Option<time::Instant> {
+        let d = (*gap)?;
+        if self.data_ch_req_rx.lock().await.is_empty() && self.member_data_ch_req_rx.is_empty() {
+            debug!("Warmed up");
+            *gap = None;
+            return None;
+        }
+        Some(time::Instant::now() + d)
+    }
+
+    // Send a command to the client. Returns false if the control channel is broken
+    async fn send_cmd(&mut self, cmd: &[u8]) -> bool {
+        if let Err(e) = self
+            .conn
+            .write_all(cmd)
+            .await
+            .with_context(|| "Failed to write control cmds")
+        {
+            error!("{:?}", e);
+            return false;
+        }
+        if let Err(e) = self
+            .conn
+            .flush()
+            .await
+            .with_context(|| "Failed to flush control cmds")
+        {
+            error!("{:?}", e);
+            return false;
+        }
+        true
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn tcp_listen_and_send(
+    service_name: String,
+    group: Option<String>,
     addr: String,
+    protocol: Option<SharedProtocol>,
+    listen: ListenOptions,
+    members: Members,
+    maintenance_page: Option<Arc<MaintenancePage>>,
+    visitor_auth: Arc<VisitorAuth>,
+    balance: Option<Arc<Balance>>,
+    visitor_alert: Option<Arc<VisitorAlert>>,
     data_ch_req_tx: mpsc::UnboundedSender<bool>,
-    mut shutdown_rx: broadcast::Receiver<bool>,
-) -> mpsc::Receiver<TcpStream> {
+    reuse: bool,
+    tarpit: bool,
+    tasks: TaskGroup,
+    server_tasks: TaskGroup,
+) -> mpsc::Receiver<(VisitorStream, Option<Vec<u8>>)> {
     let (tx, rx) = mpsc::channel(CHAN_SIZE);
-
-    tokio::spawn(async move {
-        // FIXME: Respect shutdown signal
-        let l = backoff::future::retry_notify(listen_backoff(), || async {
-            Ok(TcpListener::bind(&addr).await?)
-        }, |e, duration| {
-            error!("{:?}. Retry in {:?}", e, duration);
-        })
-        .await
-        .with_context(|| "Failed to listen for the service");
-
-        let l: TcpListener = match l {
-            Ok(v) => v,
-            Err(e) => {
-                error!("{:?}", e);
-                return;
+    // Data channels of load balanced services, and of services reusing them, are requested by
+    // the pool instead of for every visitor
+    let by_pool = balance.is_some() || reuse;
+
+    // Cancelling `tasks` stops the listener, even if it's still retrying to bind
+    let listener_tasks = tasks.clone();
+    listener_tasks.spawn(async move {
+        let mut l = match protocol {
+            // Visitors are handed over by the listener of the port
+            Some(protocol) => Incoming::shared(&addr, protocol, &server_tasks),
+            None => {
+                let l = backoff::future::retry_notify(listen_backoff(), || async {
+                    Ok(tcp_listen(&addr, listen).await?)
+                }, |e, duration| {
+                    error!("{:?}. Retry in {:?}", e, duration);
+                })
+                .await
+                .with_context(|| "Failed to listen for the service");
+                match l {
+                    Ok(v) => Incoming::Listener(v),
+                    Err(e) => {
+                        error!("{:?}", e);
+                        return;
+                    }
+                }
             }
         };
 
         info!("Listening at {}", &addr);
+        let _listening = ListeningGuard::new(&service_name, l.local_addr(), &addr);
 
         // Retry at least every 1s
         let mut backoff = ExponentialBackoff {
@@ -530,42 +1504,108 @@ fn tcp_listen_and_send(
             ..Default::default()
         };
 
-        // Wait for visitors and the shutdown signal
+        // Wait for visitors. The listener is dropped when the task group is cancelled
         loop {
-            tokio::select! {
-                val = l.accept() => {
-                    match val {
-                        Err(e) => {
-                            // `l` is a TCP listener so this must be a IO error
-                            // Possibly a EMFILE. So sleep for a while
-                            error!("{}. Sleep for a while", e);
-                            if let Some(d) = backoff.next_backoff() {
-                                time::sleep(d).await;
-                            } else {
-                                // This branch will never be reached for current backoff policy
-                                error!("Too many retries. Aborting...");
-                                break;
-                            }
+            let val = l.accept().await;
+            match val {
+                Err(e) => {
+                    // `l` is a TCP listener so this must be a IO error
+                    // Possibly a EMFILE. So sleep for a while
+                    error!("{}. Sleep for a while", e);
+                    if let Some(d) = backoff.next_backoff() {
+                        time::sleep(d).await;
+                    } else {
+                        // This branch will never be reached for current backoff policy
+                        error!("Too many retries. Aborting...");
+                        break;
+                    }
+                }
+                Ok((incoming, addr)) => {
+                    if !ACL.admit(&service_name, group.as_deref(), addr.ip()) {
+                        debug!("Visitor from {} is rejected by the ACL", privacy::addr(addr));
+                        if tarpit {
+                            tarpit::trap(incoming, addr, &tasks);
                         }
-                        Ok((incoming, addr)) => {
-                            // For every visitor, request to create a data channel
-                            if data_ch_req_tx.send(true).with_context(|| "Failed to send data chan create request").is_err() {
-                                // An error indicates the control channel is broken
-                                // So break the loop
-                                break;
-                            }
+                        continue;
+                    }
+                    if !GROUPS.admits(group.as_deref()) {
+                        debug!("Visitor from {} is refused, as the group is not enabled", privacy::addr(addr));
+                        continue;
+                    }
 
-                            backoff.reset();
+                    if let Some(alert) = &visitor_alert {
+                        alert.visit(&service_name, addr.ip());
+                    }
+                    privacy::audit(&service_name, addr);
+
+                    // Nobody is there to forward to while the client is offline
+                    if let Some(page) = &maintenance_page {
+                        if members.lock().unwrap().is_empty() {
+                            let page = page.clone();
+                            tasks.spawn(async move {
+                                if let Err(e) = page.serve(incoming).await {
+                                    debug!("Failed to serve the maintenance page to {}: {:#}", privacy::addr(addr), e);
+                                }
+                            });
+                            continue;
+                        }
+                    }
 
-                            debug!("New visitor from {}", addr);
+                    let peek_cookie = balance.as_ref().map(|b| b.peeks_cookie()).unwrap_or(false);
+                    if !visitor_auth.is_open() || peek_cookie {
+                        backoff.reset();
 
-                            // Send the visitor to the connection pool
-                            let _ = tx.send(incoming).await;
-                        }
+                        let permit = match visitor::pending_slot() {
+                            Some(v) => v,
+                            None => {
+                                debug!("Too many visitors waiting for the authentication. Dropped the one from {}", privacy::addr(addr));
+                                continue;
+                            }
+                        };
+                        debug!("New visitor from {}, waiting for the authentication", privacy::addr(addr));
+
+                        // Authenticate in a separate task so that a slow visitor won't block the listener
+                        let visitor_auth = visitor_auth.clone();
+                        let balance = balance.clone();
+                        let data_ch_req_tx = data_ch_req_tx.clone();
+                        let tx = tx.clone();
+                        tasks.spawn(async move {
+                            match visitor_auth.prepare(incoming, addr).await {
+                                Ok(incoming) => {
+                                    let key = match &balance {
+                                        Some(b) => b.sticky_key(&incoming, addr).await,
+                                        None => None,
+                                    };
+                                    drop(permit);
+                                    if by_pool || data_ch_req_tx.send(true).is_ok() {
+                                        let _ = tx.send((incoming, key)).await;
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!(visitor = %privacy::addr(addr), "Visitor failed the authentication: {:#}", e);
+                                }
+                            }
+                        }.instrument(Span::current()));
+                        continue;
                     }
-                },
-                _ = shutdown_rx.recv() => {
-                    break;
+
+                    // For every visitor, request to create a data channel
+                    if !by_pool && data_ch_req_tx.send(true).with_context(|| "Failed to send data chan create request").is_err() {
+                        // An error indicates the control channel is broken
+                        // So break the loop
+                        break;
+                    }
+
+                    backoff.reset();
+
+                    debug!("New visitor from {}", privacy::addr(addr));
+
+                    // Send the visitor to the connection pool
+                    let key = match &balance {
+                        Some(b) if b.is_sticky() => Some(addr.ip().to_string().into_bytes()),
+                        _ => None,
+                    };
+                    let _ = tx.send((VisitorStream::Tcp(incoming), key)).await;
                 }
             }
         }
@@ -578,18 +1618,263 @@ fn tcp_listen_and_send(
 
 #[instrument(skip_all)]
 async fn run_tcp_connection_pool<T: Transport>(
-    bind_addr: String,
-    mut data_ch_rx: mpsc::Receiver<T::Stream>,
+    service: ServerServiceConfig,
+    members: Members,
+    mut data_ch_rx: mpsc::Receiver<(T::Stream, Nonce)>,
+    // Where data channels are put back for the next visitor, with `reuse_data_channels`
+    data_ch_tx: mpsc::WeakSender<(T::Stream, Nonce)>,
     data_ch_req_tx: mpsc::UnboundedSender<bool>,
-    shutdown_rx: broadcast::Receiver<bool>,
+    ctx: ServerContext,
+    tasks: TaskGroup,
 ) -> Result<()> {
-    let mut visitor_rx = tcp_listen_and_send(bind_addr, data_ch_req_tx, shutdown_rx);
-    while let Some(mut visitor) = visitor_rx.recv().await {
-        if let Some(mut ch) = data_ch_rx.recv().await {
-            tokio::spawn(async move {
-                let cmd = bincode::serialize(&DataChannelCmd::StartForwardTcp).unwrap();
-                if ch.write_all(&cmd).await.is_ok() {
-                    let _ = copy_bidirectional(&mut ch, &mut visitor).await;
+    let visitor_auth = Arc::new(VisitorAuth::from_config(&service).await?);
+    let maintenance_page = match &service.maintenance_page {
+        Some(path) => Some(Arc::new(MaintenancePage::load(&service.name, path).await?)),
+        None => None,
+    };
+    let balance = Balance::from_config(&service).map(Arc::new);
+    let sampler = Sampler::from_config(&service);
+    let shaper = Shaper::from_config(&service);
+    let dns = DnsGuard::from_config(&service);
+    let http_proxy = HttpProxy::from_config(&service);
+    let reuse = service.reuse_data_channels;
+    let service_compression = service.compression.clone();
+    let max_age = service.max_connection_age.map(Duration::from_secs);
+    // Reused data channels are kept idle up to the number opened in advance
+    let max_idle = service
+        .warmup
+        .as_ref()
+        .and_then(|w| w.channels)
+        .unwrap_or_else(|| pool_size(ServiceType::Tcp));
+    let listen = ListenOptions::from_config(&service);
+    let service_name = Arc::new(service.name);
+    let ftp = service.helper == Some(ProtocolHelper::Ftp);
+    let passive_ports = service
+        .passive_port_min
+        .zip(service.passive_port_max)
+        .map(|(min, max)| min..=max);
+    // Passive data connections of FTP visitors, opened by `ftp::Passive`
+    let (passive_tx, mut passive_rx) = mpsc::unbounded_channel();
+    let passive_tasks = tasks.clone();
+    let mut visitor_rx = tcp_listen_and_send(
+        service_name.to_string(),
+        service.group.clone(),
+        service.bind_addr,
+        service.protocol,
+        listen,
+        members.clone(),
+        maintenance_page,
+        visitor_auth,
+        balance.clone(),
+        ctx.visitor_alert,
+        data_ch_req_tx.clone(),
+        reuse,
+        ctx.tarpit,
+        tasks,
+        ctx.tasks.clone(),
+    );
+    // Data channels cached for load balanced services, indexed by the session keys of their control channels
+    let mut cached: HashMap<Nonce, Vec<T::Stream>> = HashMap::new();
+    // Visitors of load balanced services that failed to be forwarded, along with the clients tried
+    let (retry_tx, mut retry_rx) = mpsc::unbounded_channel();
+    loop {
+        // `port` is set for passive data connections, which go to that port of the client's host
+        let (visitor, sticky_key, mut tried, port) = tokio::select! {
+            Some(v) = retry_rx.recv() => v,
+            Some((conn, port)) = passive_rx.recv() => {
+                let _ = data_ch_req_tx.send(true);
+                (VisitorStream::Tcp(conn), None, Vec::new(), Some(port))
+            }
+            v = visitor_rx.recv() => match v {
+                Some((visitor, sticky_key)) => (visitor, sticky_key, Vec::new(), None),
+                None => break,
+            },
+        };
+        let ch = match &balance {
+            Some(balance) => {
+                match member_data_channel::<T>(
+                    balance,
+                    sticky_key.as_deref(),
+                    &tried,
+                    &members,
+                    &mut data_ch_rx,
+                    &mut cached,
+                )
+                .await
+                {
+                    Some(v) => Some(v),
+                    // No client is connected, or all of them have been tried. Drop the visitor
+                    None => continue,
+                }
+            }
+            // Skip the cached data channels of the control channels that have gone
+            None => {
+                // Data channels in use may come back, or not. Don't wait for them
+                if reuse && data_ch_rx.is_empty() {
+                    let _ = data_ch_req_tx.send(true);
+                }
+                loop {
+                    match data_ch_rx.recv().await {
+                        Some((_, session_key))
+                            if !members.lock().unwrap().contains_key(&session_key) =>
+                        {
+                            let _ = data_ch_req_tx.send(true);
+                        }
+                        v => break v,
+                    }
+                }
+            }
+        };
+        if let Some((mut ch, session_key)) = ch {
+            let capabilities = members
+                .lock()
+                .unwrap()
+                .get(&session_key)
+                .map(|m| m.capabilities);
+            let retry_tx = balance.is_some().then(|| retry_tx.clone());
+            // Forwarded connections outlive the control channels
+            let service_name = service_name.clone();
+            let sampler = sampler.clone();
+            let shaper = shaper.clone();
+            let dns = dns.clone();
+            let http_proxy = http_proxy.clone();
+            let data_ch_tx = data_ch_tx.clone();
+            let data_ch_req_tx = data_ch_req_tx.clone();
+            let members = members.clone();
+            let cut = GROUPS
+                .cut_token(service.group.as_deref())
+                .unwrap_or_default();
+            let passive = match (ftp && port.is_none(), capabilities) {
+                (true, Some(c)) if c & CAP_FORWARD_PORT != 0 => visitor
+                    .peer_addr()
+                    .and_then(|v| Ok((v.ip(), visitor.local_addr()?.ip())))
+                    .ok()
+                    .map(|(visitor, local_ip)| Passive {
+                        visitor,
+                        local_ip,
+                        ports: passive_ports.clone(),
+                        tx: passive_tx.clone(),
+                        tasks: passive_tasks.clone(),
+                    }),
+                (true, Some(_)) => {
+                    warn!(
+                        "The client can't forward passive FTP data connections. Please update it"
+                    );
+                    None
+                }
+                _ => None,
+            };
+            // The compression for the client the data channel is from
+            let compression =
+                capabilities.and_then(|c| compression::negotiate(service_compression.as_ref(), c));
+            ctx.tasks.spawn(async move {
+                let visitor_addr = visitor.peer_addr().ok();
+                let started = async {
+                    // The client may or may not confirm the data channel, and there's no telling
+                    let capabilities =
+                        capabilities.with_context(|| "The control channel has gone")?;
+                    let reusable = reuse && capabilities & CAP_REUSE != 0;
+                    let cmd = match port {
+                        Some(_) => DataChannelCmd::StartForwardTcpPort,
+                        None if reusable => DataChannelCmd::StartForwardTcpReusable,
+                        None => DataChannelCmd::StartForwardTcp,
+                    };
+                    let framing = Framing::new(capabilities);
+                    ch.write_all(&framing.encode(&cmd)?).await?;
+                    if let Some(port) = port {
+                        ch.write_all(&port.to_be_bytes()).await?;
+                    }
+                    let codec = match protocol::compression_negotiated(capabilities) {
+                        true => {
+                            ch.write_u8(compression::to_byte(compression)).await?;
+                            compression.map(Codec::new).transpose()?
+                        }
+                        false => None,
+                    };
+                    if capabilities & CAP_VISITOR_ADDR != 0 {
+                        protocol::write_visitor_addr(&mut ch, visitor_addr).await?;
+                    }
+                    ch.flush().await?;
+                    if capabilities & CAP_FORWARD_CONFIRM != 0 {
+                        time::timeout(
+                            Duration::from_secs(FORWARD_CONFIRM_TIMEOUT),
+                            read_data_reply(&mut ch, framing),
+                        )
+                        .await
+                        .with_context(|| "Timeout")??;
+                    }
+                    Ok::<_, anyhow::Error>((reusable && port.is_none(), codec))
+                };
+                // Whether the data channel is put back for the next visitor
+                let mut reused = false;
+                match started.await {
+                    Ok((reusable, codec)) => {
+                        // Nothing is done with what's forwarded, so it's copied in the kernel
+                        let plain = !reusable
+                            && codec.is_none()
+                            && dns.is_none()
+                            && shaper.is_none()
+                            && sampler.is_none()
+                            && passive.is_none()
+                            && http_proxy.is_none();
+                        let mut framed = ReusableStream::new(&mut ch, reusable);
+                        let mut ch = CompressedStream::new(&mut framed, codec);
+                        let mut stats = DataChannelGuard::new(&service_name);
+                        let ip = visitor_addr.map(|v| v.ip());
+                        let ip = ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+                        let visitor = DnsStream::new(visitor, dns.map(|v| (v, ip)));
+                        let mut visitor = ShapedStream::new(visitor, shaper);
+                        let http_proxy = http_proxy.as_deref();
+                        let copy = async {
+                            let raw = T::as_tcp(ch.get_mut().get_mut()).filter(|_| plain);
+                            let visitor_raw = visitor.get_mut().get_mut().as_tcp();
+                            if let (Some(raw), Some(visitor)) = (raw, visitor_raw) {
+                                return splice::copy_bidirectional(raw, visitor).await;
+                            }
+                            match sampler {
+                                Some(sampler) => {
+                                    let mut visitor = SampledStream::new(&mut visitor, sampler);
+                                    forward_tcp(&mut ch, &mut visitor, passive, http_proxy).await
+                                }
+                                None => {
+                                    forward_tcp(&mut ch, &mut visitor, passive, http_proxy).await
+                                }
+                            }
+                        };
+                        // Disabling the group cuts the visitor
+                        match cut.run_until_cancelled(within_age(copy, max_age)).await {
+                            Some(Some(Ok((outbound, inbound)))) => {
+                                (stats.inbound, stats.outbound) = (inbound, outbound);
+                            }
+                            Some(Some(Err(_))) => {}
+                            Some(None) => {
+                                debug!("The visitor is closed, as it reached `max_connection_age`");
+                                close_gracefully(&mut visitor, &mut ch).await;
+                            }
+                            None => debug!("The visitor is cut, as the group is disabled"),
+                        }
+                        reused = ch.get_ref().is_reusable();
+                    }
+                    // Nothing has been sent to the visitor yet, so another client can take it
+                    Err(e) => match retry_tx {
+                        Some(retry_tx) => {
+                            debug!("Failed to forward a visitor: {:#}. Try another client", e);
+                            tried.push(session_key);
+                            let _ = retry_tx.send((visitor, sticky_key, tried, port));
+                        }
+                        None => debug!("Failed to forward a visitor: {:#}", e),
+                    },
+                }
+                // Keep up to `max_idle` data channels waiting, the finished ones first. The
+                // others are closed, which the client takes as the end
+                if let Some(tx) = data_ch_tx.upgrade().filter(|_| reuse) {
+                    if tx.max_capacity() - tx.capacity() < max_idle {
+                        if reused && members.lock().unwrap().contains_key(&session_key) {
+                            let _ = tx.try_send((ch, session_key));
+                        } else {
+                            let _ = data_ch_req_tx.send(true);
+                        }
+                    }
                 }
             });
         } else {
@@ -601,20 +1886,190 @@ async fn run_tcp_connection_pool<T: Transport>(
     Ok(())
 }
 
+// Forward the visitors of a reverse service, which come to the client, to `connect_addr`. Each
+// data channel the client opens carries one of them
+async fn run_tcp_reverse<T: Transport>(
+    service: ServerServiceConfig,
+    members: Members,
+    mut data_ch_rx: mpsc::Receiver<(T::Stream, Nonce)>,
+    ctx: ServerContext,
+) {
+    let shaper = Shaper::from_config(&service);
+    let max_age = service.max_connection_age.map(Duration::from_secs);
+    let service_name = Arc::new(service.name);
+    let connect_addr = Arc::new(service.connect_addr.unwrap_or_default());
+    while let Some((mut ch, session_key)) = data_ch_rx.recv().await {
+        if !members.lock().unwrap().contains_key(&session_key) {
+            continue;
+        }
+        let service_name = service_name.clone();
+        let connect_addr = connect_addr.clone();
+        let shaper = shaper.clone();
+        let cut = GROUPS
+            .cut_token(service.group.as_deref())
+            .unwrap_or_default();
+        ctx.tasks.spawn(async move {
+            let plain = shaper.is_none();
+            // Closing the data channel tells the client
+            let mut conn = match TcpStream::connect(connect_addr.as_str()).await {
+                Ok(v) => ShapedStream::new(v, shaper),
+                Err(e) => {
+                    warn!("Failed to connect to {}: {}", connect_addr, e);
+                    return;
+                }
+            };
+            let mut stats = DataChannelGuard::new(&service_name);
+            let copy = async {
+                match T::as_tcp(&mut ch).filter(|_| plain) {
+                    Some(raw) => splice::copy_bidirectional(conn.get_mut(), raw).await,
+                    None => copy_bidirectional(&mut conn, &mut ch).await,
+                }
+            };
+            let copy = within_age(copy, max_age);
+            match cut.run_until_cancelled(copy).await {
+                Some(Some(Ok((outbound, inbound)))) => {
+                    (stats.inbound, stats.outbound) = (inbound, outbound)
+                }
+                Some(Some(Err(_))) => {}
+                Some(None) => {
+                    debug!("The visitor is closed, as it reached `max_connection_age`");
+                    close_gracefully(&mut ch, &mut conn).await;
+                }
+                None => debug!("The visitor is cut, as the group is disabled"),
+            }
+        });
+    }
+    info!("Shutdown");
+}
+
+// Forward a visitor for at most `max_age`. `None` if it's reached
+async fn within_age<F: std::future::Future>(
+    copy: F,
+    max_age: Option<Duration>,
+) -> Option<F::Output> {
+    match max_age {
+        Some(age) => time::timeout(age, copy).await.ok(),
+        None => Some(copy.await),
+    }
+}
+
+// Shut down the writes to both ends of a forwarded connection, so they see it end rather than
+// being reset
+async fn close_gracefully<A, B>(a: &mut A, b: &mut B)
+where
+    A: AsyncWrite + Unpin,
+    B: AsyncWrite + Unpin,
+{
+    let _ = time::timeout(Duration::from_secs(SHUTDOWN_TIMEOUT), async {
+        let _ = a.shutdown().await;
+        let _ = b.shutdown().await;
+    })
+    .await;
+}
+
+// Copy between the data channel and the visitor, through the FTP helper if set
+async fn forward_tcp<C, V>(
+    ch: &mut C,
+    visitor: &mut V,
+    passive: Option<Passive>,
+    http_proxy: Option<&HttpProxy>,
+) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    V: AsyncRead + AsyncWrite + Unpin,
+{
+    match (passive, http_proxy) {
+        (Some(passive), _) => ftp::forward(ch, visitor, passive).await,
+        (None, Some(proxy)) => http_proxy::forward(ch, visitor, proxy).await,
+        (None, None) => copy_bidirectional(ch, visitor).await,
+    }
+}
+
+// Get a data channel from the control channel picked for a visitor of a load balanced service, and
+// request another one from it to replace. Data channels from other control channels are cached for later
+async fn member_data_channel<T: Transport>(
+    balance: &Balance,
+    sticky_key: Option<&[u8]>,
+    tried: &[Nonce],
+    members: &Members,
+    data_ch_rx: &mut mpsc::Receiver<(T::Stream, Nonce)>,
+    cached: &mut HashMap<Nonce, Vec<T::Stream>>,
+) -> Option<(T::Stream, Nonce)> {
+    loop {
+        let (session_key, req_tx) = {
+            let members = members.lock().unwrap();
+            cached.retain(|k, _| members.contains_key(k));
+            // Clients are identified by their instance IDs, which survive reconnections
+            let candidates = |skip_ejected: bool| {
+                members
+                    .iter()
+                    .filter(|(k, _)| !tried.contains(k))
+                    .filter(|(_, m)| !(skip_ejected && m.outlier.is_ejected()))
+                    .map(|(k, m)| Candidate {
+                        value: *k,
+                        id: m.instance_id.map(|v| v.to_vec()).unwrap_or(k.to_vec()),
+                        weight: m.weight,
+                        // Clients put back after an ejection start over the slow start
+                        age: m
+                            .outlier
+                            .returned_at()
+                            .unwrap_or(m.joined_at)
+                            .max(m.joined_at)
+                            .elapsed(),
+                    })
+                    .collect::<Vec<_>>()
+            };
+            // If all the clients are ejected, trying them is better than nothing
+            let mut c = candidates(balance.outlier_detection);
+            if c.is_empty() {
+                c = candidates(false);
+            }
+            let session_key = balance.pick(sticky_key, c)?;
+            let m = &members[&session_key];
+            m.outlier.forwarded();
+            (session_key, m.data_ch_req_tx.clone())
+        };
+
+        let _ = req_tx.send(true);
+        if let Some(ch) = cached.get_mut(&session_key).and_then(|v| v.pop()) {
+            return Some((ch, session_key));
+        }
+        let _ = req_tx.send(true);
+
+        // Wait for the data channel. Check every second whether the control channel has gone
+        loop {
+            match time::timeout(Duration::from_secs(1), data_ch_rx.recv()).await {
+                Ok(Some((ch, k))) if k == session_key => return Some((ch, k)),
+                Ok(Some((ch, k))) => cached.entry(k).or_default().push(ch),
+                Ok(None) => return None,
+                Err(_) if !members.lock().unwrap().contains_key(&session_key) => break,
+                Err(_) => (),
+            }
+        }
+    }
+}
+
 #[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
 async fn run_udp_connection_pool<T: Transport>(
+    service_name: String,
+    group: Option<String>,
     bind_addr: String,
-    mut data_ch_rx: mpsc::Receiver<T::Stream>,
+    members: Members,
+    mut data_ch_rx: mpsc::Receiver<(T::Stream, Nonce)>,
     _data_ch_req_tx: mpsc::UnboundedSender<bool>,
-    mut shutdown_rx: broadcast::Receiver<bool>,
+    visitor_alert: Option<Arc<VisitorAlert>>,
+    sampler: Option<Arc<Sampler>>,
+    dns: Option<Arc<DnsGuard>>,
+    mut limit: DatagramLimit,
 ) -> Result<()> {
     // TODO: Load balance
 
-    // FIXME: Respect shutdown signal
+    // The pool is stopped by cancelling its task group
     let l: UdpSocket = backoff::future::retry_notify(
         listen_backoff(),
         || async {
-            Ok(UdpSocket::bind(&bind_addr)
+            Ok(udp_bind(&bind_addr)
                 .await
                 .with_context(|| "Failed to listen for the service")?)
         },
@@ -626,38 +2081,84 @@ async fn run_udp_connection_pool<T: Transport>(
     .with_context(|| "Failed to listen for the service")?;
 
     info!("Listening at {}", &bind_addr);
-
-    let cmd = bincode::serialize(&DataChannelCmd::StartForwardUdp).unwrap();
+    let _listening = ListeningGuard::new(&service_name, l.local_addr().ok(), &bind_addr);
 
     // Receive one data channel
-    let mut conn = data_ch_rx
+    let (mut conn, session_key) = data_ch_rx
         .recv()
         .await
         .ok_or(anyhow!("No available data channels"))?;
-    conn.write_all(&cmd).await?;
+    let capabilities = members
+        .lock()
+        .unwrap()
+        .get(&session_key)
+        .map(|m| m.capabilities)
+        .unwrap_or_default();
+    conn.write_all(&Framing::new(capabilities).encode(&DataChannelCmd::StartForwardUdp)?)
+        .await?;
+    let _events = DataChannelGuard::new(&service_name);
+
+    // Buffer the data channel, so a packet is not split into several writes, and
+    // several packets can be read at once
+    let mut conn = BufStream::new(conn);
 
-    let mut buf = [0u8; UDP_BUFFER_SIZE];
+    let mut buf = vec![0u8; limit.buffer_size()];
+    let mut arena = BytesMut::new();
+    // For the gaps between the sampled datagrams of the service
+    let (mut last_inbound, mut last_outbound) = (None, None);
     loop {
         tokio::select! {
             // Forward inbound traffic to the client
             val = l.recv_from(&mut buf) => {
-                let (n, from) = val?;
+                let (n, from) = match val {
+                    Ok(v) => v,
+                    Err(e) if is_transient_udp_error(&e) => {
+                        debug!("Failed to receive from visitors: {}", e);
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let n = match limit.apply(n, from) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                // Visitors over UDP are not connections, so a draining group drops them as well
+                if !ACL.allows(&service_name, group.as_deref(), from.ip())
+                    || !GROUPS.admits(group.as_deref())
+                {
+                    continue;
+                }
+                if matches!(&dns, Some(dns) if !dns.admit(from.ip(), &buf[..n])) {
+                    continue;
+                }
+                if let Some(alert) = &visitor_alert {
+                    alert.visit(&service_name, from.ip());
+                }
+                if let Some(sampler) = &sampler {
+                    sampler.record(Flow::Inbound, n, sampling::gap(&mut last_inbound));
+                }
                 UdpTraffic::write_slice(&mut conn, from, &buf[..n]).await?;
+                conn.flush().await?;
             },
 
             // Forward outbound traffic from the client to the visitor
             hdr_len = conn.read_u8() => {
-                let t = UdpTraffic::read(&mut conn, hdr_len?).await?;
-                l.send_to(&t.data, t.from).await?;
-            }
-
-            _ = shutdown_rx.recv() => {
-                break;
+                let t = UdpTraffic::read(&mut conn, hdr_len?, &mut arena).await?;
+                if let Some(sampler) = &sampler {
+                    let gap = sampling::gap(&mut last_outbound);
+                    sampler.record(Flow::Outbound, t.data.len(), gap);
+                }
+                let data = match &dns {
+                    Some(dns) => dns.udp_response(&t.data),
+                    None => Cow::Borrowed(&t.data[..]),
+                };
+                if let Err(e) = l.send_to(&data, t.from).await {
+                    if !is_transient_udp_error(&e) {
+                        return Err(e.into());
+                    }
+                    debug!("Failed to send to the visitor {}: {}", privacy::addr(t.from), e);
+                }
             }
         }
     }
-
-    debug!("UDP pool dropped");
-
-    Ok(())
 }
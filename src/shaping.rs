@@ -0,0 +1,263 @@
+// Bandwidth shaping of the visitors of services with `bandwidth_limit` or `per_connection_limit`.
+// A visitor takes from its own token bucket and from the one shared by the service, so that one
+// visitor can't use up the allowance of the whole service. Each direction is shaped on its own
+use crate::config::ServerServiceConfig;
+use crate::sampling::Flow;
+use crate::state_dump;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+// Holds up to a second of traffic. Taking more than there is leaves a debt, which is waited out
+#[derive(Debug)]
+struct Bucket {
+    rate: f64, // Bytes per second
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    // The time until the debt is paid off
+    fn wait(&mut self) -> Duration {
+        self.refill();
+        if self.tokens > 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate).max(Duration::from_millis(1))
+        }
+    }
+
+    fn take(&mut self, n: usize) {
+        self.refill();
+        self.tokens -= n as f64;
+    }
+}
+
+// The buckets of a direction
+#[derive(Default)]
+struct Buckets {
+    service: Option<Arc<Mutex<Bucket>>>,
+    connection: Option<Bucket>,
+}
+
+impl Buckets {
+    fn wait(&mut self) -> Duration {
+        let service = self.service.as_ref().map(|b| b.lock().unwrap().wait());
+        let connection = self.connection.as_mut().map(|b| b.wait());
+        service.max(connection).unwrap_or_default()
+    }
+
+    fn take(&mut self, n: usize) {
+        if let Some(b) = &self.service {
+            b.lock().unwrap().take(n);
+        }
+        if let Some(b) = &mut self.connection {
+            b.take(n);
+        }
+    }
+}
+
+pub struct Shaper {
+    service: String,
+    inbound: Option<Arc<Mutex<Bucket>>>,
+    outbound: Option<Arc<Mutex<Bucket>>>,
+    per_connection: Option<u64>,
+}
+
+impl Shaper {
+    pub fn from_config(service: &ServerServiceConfig) -> Option<Arc<Shaper>> {
+        if service.bandwidth_limit.is_none() && service.per_connection_limit.is_none() {
+            return None;
+        }
+        let bucket = || {
+            service
+                .bandwidth_limit
+                .map(|v| Arc::new(Mutex::new(Bucket::new(v))))
+        };
+        Some(Arc::new(Shaper {
+            service: service.name.clone(),
+            inbound: bucket(),
+            outbound: bucket(),
+            per_connection: service.per_connection_limit,
+        }))
+    }
+
+    fn buckets(&self, flow: Flow) -> Buckets {
+        Buckets {
+            service: match flow {
+                Flow::Inbound => self.inbound.clone(),
+                Flow::Outbound => self.outbound.clone(),
+            },
+            connection: self.per_connection.map(Bucket::new),
+        }
+    }
+}
+
+// The state of a direction of a visitor
+#[derive(Default)]
+struct Direction {
+    buckets: Buckets,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Direction {
+    // Ready once the buckets allow more traffic. The time held up is counted for the service
+    fn poll_ready(&mut self, cx: &mut Context<'_>, service: &str) -> Poll<()> {
+        loop {
+            if let Some(delay) = &mut self.delay {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+            let wait = self.buckets.wait();
+            if wait.is_zero() {
+                return Poll::Ready(());
+            }
+            state_dump::throttled(service, wait);
+            self.delay = Some(Box::pin(sleep(wait)));
+        }
+    }
+}
+
+// Shapes what is read from and written to a visitor. Passes everything through without a shaper
+pub struct ShapedStream<S> {
+    inner: S,
+    shaper: Option<Arc<Shaper>>,
+    read: Direction,
+    write: Direction,
+}
+
+impl<S> ShapedStream<S> {
+    pub fn new(inner: S, shaper: Option<Arc<Shaper>>) -> ShapedStream<S> {
+        let direction = |flow| Direction {
+            buckets: shaper.as_ref().map(|s| s.buckets(flow)).unwrap_or_default(),
+            delay: None,
+        };
+        ShapedStream {
+            read: direction(Flow::Inbound),
+            write: direction(Flow::Outbound),
+            inner,
+            shaper,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ShapedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(shaper) = &this.shaper {
+            ready!(this.read.poll_ready(cx, &shaper.service));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.read.buckets.take(buf.filled().len() - before);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ShapedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(shaper) = &this.shaper {
+            ready!(this.write.poll_ready(cx, &shaper.service));
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.write.buckets.take(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn shaper(bandwidth_limit: Option<u64>, per_connection_limit: Option<u64>) -> Arc<Shaper> {
+        let mut s = ServerServiceConfig::with_name("test_shaped_stream");
+        s.bandwidth_limit = bandwidth_limit;
+        s.per_connection_limit = per_connection_limit;
+        Shaper::from_config(&s).unwrap()
+    }
+
+    // The time to write `n` bytes to each of `conns` visitors at the same time
+    async fn write_time(shaper: Arc<Shaper>, conns: usize, n: usize) -> Duration {
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..conns)
+            .map(|_| {
+                let shaper = shaper.clone();
+                tokio::spawn(async move {
+                    let (a, mut b) = tokio::io::duplex(64 * 1024);
+                    let mut a = ShapedStream::new(a, Some(shaper));
+                    tokio::spawn(async move {
+                        let mut v = Vec::new();
+                        let _ = b.read_to_end(&mut v).await;
+                    });
+                    for _ in 0..n / 1000 {
+                        a.write_all(&[0u8; 1000]).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn test_shaped_stream() {
+        // Half a second for what is over the burst of a second
+        let t = write_time(shaper(None, Some(200_000)), 1, 300_000).await;
+        assert!(t >= Duration::from_millis(400) && t < Duration::from_millis(1000));
+
+        // Each visitor gets its own allowance
+        let t = write_time(shaper(None, Some(200_000)), 2, 300_000).await;
+        assert!(t < Duration::from_millis(1000));
+
+        // Within the one of the service
+        let t = write_time(shaper(Some(200_000), Some(200_000)), 2, 300_000).await;
+        assert!(t >= Duration::from_millis(1800));
+
+        let v = state_dump::dump(&serde_json::json!({}));
+        assert!(v["throttled_ms"]["test_shaped_stream"].as_u64().unwrap() > 0);
+    }
+}
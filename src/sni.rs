@@ -0,0 +1,188 @@
+// Reads the SNI of a TLS ClientHello without terminating TLS
+use crate::constants::{SNI_PEEK_MAX_LEN, VISITOR_AUTH_TIMEOUT};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+const TLS_SNI_HOST_NAME: u8 = 0x00;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ClientHello {
+    // More data is needed
+    Incomplete,
+    // Not a ClientHello, or it carries no SNI
+    NoSni,
+    Sni(String),
+}
+
+// Peek at the first bytes of `conn` and parse the SNI, leaving the data for the forwarding.
+// Returns `None` if the visitor doesn't start with a ClientHello that carries a SNI
+pub async fn peek_sni(conn: &TcpStream) -> Option<String> {
+    let mut buf = vec![0u8; SNI_PEEK_MAX_LEN];
+    let peek = async {
+        loop {
+            let n = conn.peek(&mut buf).await.ok()?;
+            match parse_client_hello(&buf[..n]) {
+                ClientHello::Sni(v) => return Some(v),
+                ClientHello::Incomplete if n > 0 && n < buf.len() => {
+                    // Peeking returns at once while there's data, so wait for more to arrive
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+                _ => return None,
+            }
+        }
+    };
+    time::timeout(Duration::from_secs(VISITOR_AUTH_TIMEOUT), peek)
+        .await
+        .ok()
+        .flatten()
+}
+
+// A cursor over the ClientHello. `None` means running out of data
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (v, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(v)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|v| v[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|v| u16::from_be_bytes([v[0], v[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|v| (v[0] as usize) << 16 | (v[1] as usize) << 8 | v[2] as usize)
+    }
+
+    // Take a vector prefixed by its length
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()? as usize;
+        self.take(n)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()? as usize;
+        self.take(n)
+    }
+}
+
+// Parse the first TLS record. A ClientHello that spans multiple records is treated as having no SNI
+fn parse_client_hello(buf: &[u8]) -> ClientHello {
+    let mut r = Reader(buf);
+    match r.u8() {
+        Some(TLS_HANDSHAKE) => {}
+        Some(_) => return ClientHello::NoSni,
+        None => return ClientHello::Incomplete,
+    }
+    let record = match (|| {
+        r.take(2)?; // Legacy record version
+        r.vec16()
+    })() {
+        Some(v) => v,
+        None => return ClientHello::Incomplete,
+    };
+
+    let sni = (|| {
+        let mut r = Reader(record);
+        if r.u8()? != TLS_CLIENT_HELLO {
+            return None;
+        }
+        let len = r.u24()?;
+        let mut r = Reader(r.take(len)?);
+        r.take(2 + 32)?; // Legacy version and random
+        r.vec8()?; // Session ID
+        r.vec16()?; // Cipher suites
+        r.vec8()?; // Compression methods
+
+        let mut exts = Reader(r.vec16()?);
+        while let Some(ty) = exts.u16() {
+            let data = exts.vec16()?;
+            if ty != TLS_EXT_SERVER_NAME {
+                continue;
+            }
+            let mut names = Reader(Reader(data).vec16()?);
+            while let Some(name_type) = names.u8() {
+                let name = names.vec16()?;
+                if name_type == TLS_SNI_HOST_NAME {
+                    return String::from_utf8(name.to_vec()).ok();
+                }
+            }
+        }
+        None
+    })();
+
+    match sni {
+        Some(v) => ClientHello::Sni(v),
+        None => ClientHello::NoSni,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A minimal ClientHello, with an extension before the SNI
+    fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut exts = vec![];
+        // Supported versions, to be skipped
+        exts.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(sni) = sni {
+            let n = sni.len() as u16;
+            exts.extend_from_slice(&[0x00, 0x00]);
+            exts.extend_from_slice(&(n + 5).to_be_bytes());
+            exts.extend_from_slice(&(n + 3).to_be_bytes());
+            exts.push(0x00);
+            exts.extend_from_slice(&n.to_be_bytes());
+            exts.extend_from_slice(sni.as_bytes());
+        }
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0xaa; 32]);
+        body.push(0); // Session ID
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // Cipher suites
+        body.extend_from_slice(&[0x01, 0x00]); // Compression methods
+        body.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+        body.extend_from_slice(&exts);
+
+        let mut hs = vec![TLS_CLIENT_HELLO];
+        hs.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hs.extend_from_slice(&body);
+
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(hs.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hs);
+        record
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        let hello = client_hello(Some("example.com"));
+        assert_eq!(
+            parse_client_hello(&hello),
+            ClientHello::Sni("example.com".to_string())
+        );
+        assert_eq!(
+            parse_client_hello(&hello[..hello.len() - 1]),
+            ClientHello::Incomplete
+        );
+        assert_eq!(parse_client_hello(&[]), ClientHello::Incomplete);
+        assert_eq!(parse_client_hello(&client_hello(None)), ClientHello::NoSni);
+        assert_eq!(
+            parse_client_hello(b"SSH-2.0-OpenSSH_9.6\r\n"),
+            ClientHello::NoSni
+        );
+    }
+}
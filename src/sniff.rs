@@ -0,0 +1,305 @@
+// Ports shared by several services, which tell their visitors apart by the protocol of the first
+// bytes they send. A shared port is listened at while any of its services is, and each visitor
+// is handed over to the listener of the service of its protocol, untouched
+use crate::config::SharedProtocol;
+use crate::constants::{listen_backoff, PROTOCOL_SNIFF_MAX_PENDING, PROTOCOL_SNIFF_TIMEOUT};
+use crate::privacy;
+use crate::task_group::TaskGroup;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time;
+use tokio_util::sync::DropGuard;
+use tracing::{debug, error, info};
+
+type Visitor = (TcpStream, SocketAddr);
+
+// Enough for the longest of the prefixes below
+const PEEK_LEN: usize = 8;
+const SSH: &[u8] = b"SSH-";
+// Including the preface of HTTP/2 without TLS, `PRI * HTTP/2.0`
+const HTTP_METHODS: [&[u8]; 10] = [
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+    b"PRI ",
+];
+
+struct SharedPort {
+    addr: String,
+    routes: Mutex<HashMap<SharedProtocol, mpsc::Sender<Visitor>>>,
+    // Stops listening once the last route is gone
+    _listener: DropGuard,
+}
+
+impl Drop for SharedPort {
+    fn drop(&mut self) {
+        let mut ports = PORTS.lock().unwrap();
+        // The port may have been listened at again already
+        if ports.get(&self.addr).is_some_and(|v| v.strong_count() == 0) {
+            ports.remove(&self.addr);
+        }
+    }
+}
+
+lazy_static! {
+    static ref PORTS: Mutex<HashMap<String, Weak<SharedPort>>> = Mutex::new(HashMap::new());
+    // Shared by all ports, so visitors that never speak can't exhaust the server
+    static ref PENDING: Arc<Semaphore> = Arc::new(Semaphore::new(PROTOCOL_SNIFF_MAX_PENDING));
+}
+
+// The route of a service on a shared port, removed when dropped
+pub struct Route {
+    port: Arc<SharedPort>,
+    protocol: SharedProtocol,
+    tx: mpsc::Sender<Visitor>,
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        let mut routes = self.port.routes.lock().unwrap();
+        // The service may have been replaced already by a reloaded one
+        if routes
+            .get(&self.protocol)
+            .is_some_and(|v| v.same_channel(&self.tx))
+        {
+            routes.remove(&self.protocol);
+        }
+    }
+}
+
+// Where the visitors of a service come from
+pub enum Incoming {
+    Listener(TcpListener),
+    // The route is kept for as long as the visitors are taken
+    Shared {
+        _route: Route,
+        rx: mpsc::Receiver<Visitor>,
+    },
+}
+
+impl Incoming {
+    // Take the visitors of `protocol` at the port shared at `addr`, listening at it if nobody is.
+    // The port outlives the service that listens at it first, so its tasks are in a child group
+    // of `server_tasks`, the group of the server
+    pub fn shared(addr: &str, protocol: SharedProtocol, server_tasks: &TaskGroup) -> Incoming {
+        let (tx, rx) = mpsc::channel(32);
+        let mut ports = PORTS.lock().unwrap();
+        let port = match ports.get(addr).and_then(Weak::upgrade) {
+            Some(v) => v,
+            None => {
+                let tasks = server_tasks.child();
+                let port = Arc::new(SharedPort {
+                    addr: addr.to_string(),
+                    routes: Mutex::new(HashMap::new()),
+                    _listener: tasks.cancel_on_drop(),
+                });
+                tasks.spawn(listen(
+                    addr.to_string(),
+                    Arc::downgrade(&port),
+                    tasks.clone(),
+                ));
+                ports.insert(addr.to_string(), Arc::downgrade(&port));
+                port
+            }
+        };
+        port.routes.lock().unwrap().insert(protocol, tx.clone());
+        Incoming::Shared {
+            _route: Route { port, protocol, tx },
+            rx,
+        }
+    }
+
+    pub async fn accept(&mut self) -> io::Result<Visitor> {
+        match self {
+            Incoming::Listener(l) => l.accept().await,
+            Incoming::Shared { rx, .. } => rx
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::other("The shared port is closed")),
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Incoming::Listener(l) => l.local_addr().ok(),
+            Incoming::Shared { .. } => None,
+        }
+    }
+}
+
+// Cancelling `tasks` stops the listener, and the visitors whose protocol is being told
+async fn listen(addr: String, port: Weak<SharedPort>, tasks: TaskGroup) {
+    let l = backoff::future::retry_notify(
+        listen_backoff(),
+        || async { Ok(TcpListener::bind(&addr).await?) },
+        |e: io::Error, duration| {
+            error!("{:?}. Retry in {:?}", e, duration);
+        },
+    )
+    .await;
+    let l = match l {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to listen at the shared port {}: {:?}", addr, e);
+            return;
+        }
+    };
+    info!("Listening at the shared port {}", addr);
+
+    loop {
+        let (conn, visitor) = match l.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}. Sleep for a while", e);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let permit = match PENDING.clone().try_acquire_owned() {
+            Ok(v) => v,
+            Err(_) => {
+                debug!(
+                    "Too many visitors of shared ports to tell the protocols of. Dropped the one from {}",
+                    privacy::addr(visitor)
+                );
+                continue;
+            }
+        };
+        let port = port.clone();
+        let addr = addr.clone();
+        tasks.spawn(async move {
+            let protocol = peek_protocol(&conn).await;
+            drop(permit);
+            // Visitors of the other protocols go to the service of `other`, if any
+            let tx = port.upgrade().and_then(|port| {
+                let routes = port.routes.lock().unwrap();
+                routes
+                    .get(&protocol)
+                    .or_else(|| routes.get(&SharedProtocol::Other))
+                    .cloned()
+            });
+            match tx {
+                Some(tx) => {
+                    let _ = tx.send((conn, visitor)).await;
+                }
+                None => debug!(
+                    "No service at {} for the visitor from {} speaking {:?}",
+                    addr,
+                    privacy::addr(visitor),
+                    protocol
+                ),
+            }
+        });
+    }
+}
+
+// Peek at the first bytes of `conn`, leaving them for the service. Visitors that send nothing
+// for `PROTOCOL_SNIFF_TIMEOUT`, which wait for the server to speak first, are of `other`
+async fn peek_protocol(conn: &TcpStream) -> SharedProtocol {
+    let mut buf = [0u8; PEEK_LEN];
+    let peek = async {
+        loop {
+            let n = match conn.peek(&mut buf).await {
+                Ok(0) | Err(_) => return SharedProtocol::Other,
+                Ok(v) => v,
+            };
+            match detect(&buf[..n]) {
+                Some(v) => return v,
+                // Peeking returns at once while there's data, so wait for more to arrive
+                None => time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    };
+    time::timeout(Duration::from_secs(PROTOCOL_SNIFF_TIMEOUT), peek)
+        .await
+        .unwrap_or(SharedProtocol::Other)
+}
+
+// `None` if more bytes are needed to tell
+fn detect(buf: &[u8]) -> Option<SharedProtocol> {
+    // A TLS record of a handshake, of versions from SSL 3.0 on
+    match buf {
+        [] => return None,
+        [0x16] => return None,
+        [0x16, 0x03, ..] => return Some(SharedProtocol::Tls),
+        _ => (),
+    }
+    let candidates = std::iter::once((SharedProtocol::Ssh, SSH))
+        .chain(HTTP_METHODS.iter().map(|v| (SharedProtocol::Http, *v)));
+    let mut incomplete = false;
+    for (protocol, prefix) in candidates {
+        if buf.starts_with(prefix) {
+            return Some(protocol);
+        }
+        incomplete |= prefix.starts_with(buf);
+    }
+    (!incomplete).then_some(SharedProtocol::Other)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_detect() {
+        let cases: [(&[u8], Option<SharedProtocol>); 9] = [
+            (b"SSH-2.0-OpenSSH_9.6\r\n", Some(SharedProtocol::Ssh)),
+            (b"SS", None),
+            (b"\x16\x03\x01\x02\x00\x01", Some(SharedProtocol::Tls)),
+            (b"\x16", None),
+            (b"GET / HTTP/1.1\r\n", Some(SharedProtocol::Http)),
+            (b"OPTIONS", None),
+            (b"PRI * HTTP/2.0\r\n", Some(SharedProtocol::Http)),
+            (b"GETX", Some(SharedProtocol::Other)),
+            (b"\x00\x01", Some(SharedProtocol::Other)),
+        ];
+        for (buf, expected) in cases {
+            assert_eq!(detect(buf), expected, "{:?}", buf);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared() -> anyhow::Result<()> {
+        // Take a free port
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?
+            .to_string();
+        let tasks = TaskGroup::new();
+        let mut ssh = Incoming::shared(&addr, SharedProtocol::Ssh, &tasks);
+        let mut other = Incoming::shared(&addr, SharedProtocol::Other, &tasks);
+        time::sleep(Duration::from_millis(100)).await;
+
+        let mut conn = TcpStream::connect(&addr).await?;
+        conn.write_all(b"SSH-2.0-test\r\n").await?;
+        let (visitor, _) = ssh.accept().await?;
+        let mut buf = [0u8; 4];
+        let mut visitor = visitor;
+        visitor.read_exact(&mut buf).await?;
+        assert_eq!(&buf, SSH);
+
+        let mut conn = TcpStream::connect(&addr).await?;
+        conn.write_all(b"\x16\x03\x01").await?;
+        other.accept().await?;
+
+        // The port is closed and forgotten once no service is left
+        drop((ssh, other));
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(&addr).await.is_err());
+        assert!(!PORTS.lock().unwrap().contains_key(&addr));
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+// Forwarding between two TCP sockets in the kernel with splice(2) on Linux, so the bytes never
+// reach the userspace buffers of `copy_bidirectional`. Elsewhere, it's `copy_bidirectional`
+use std::io;
+use tokio::net::TcpStream;
+
+// Copy between `a` and `b` until both directions have ended. Returns the bytes copied from `a` to
+// `b` and from `b` to `a`, like `copy_bidirectional`
+#[cfg(target_os = "linux")]
+pub(crate) async fn copy_bidirectional(
+    a: &mut TcpStream,
+    b: &mut TcpStream,
+) -> io::Result<(u64, u64)> {
+    let (to_b, to_a) = match (linux::Pipe::new(), linux::Pipe::new()) {
+        (Ok(to_b), Ok(to_a)) => (to_b, to_a),
+        // Out of file descriptors most likely. Still worth forwarding
+        (Err(e), _) | (_, Err(e)) => {
+            tracing::debug!("Failed to create pipes for splice: {}", e);
+            return tokio::io::copy_bidirectional(a, b).await;
+        }
+    };
+    tokio::try_join!(linux::copy(a, b, &to_b), linux::copy(b, a, &to_a))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) async fn copy_bidirectional(
+    a: &mut TcpStream,
+    b: &mut TcpStream,
+) -> io::Result<(u64, u64)> {
+    tokio::io::copy_bidirectional(a, b).await
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use socket2::SockRef;
+    use std::io;
+    use std::net::Shutdown;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use tokio::io::Interest;
+    use tokio::net::TcpStream;
+
+    // The default capacity of a pipe
+    const PIPE_LEN: usize = 64 * 1024;
+
+    // What's read from one socket waits in the pipe until it's written to the other
+    pub(super) struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        pub(super) fn new() -> io::Result<Pipe> {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Owned from now on, so they're closed once done
+            let (read, write) =
+                unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            Ok(Pipe { read, write })
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        let n = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                flags,
+            )
+        };
+        match n {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
+        }
+    }
+
+    // Copy from `from` to `to` through `pipe` until `from` ends, then shut down the writes to `to`.
+    // The pipe is emptied each time before it's filled again, so it's only ever the sockets that
+    // aren't ready
+    pub(super) async fn copy(from: &TcpStream, to: &TcpStream, pipe: &Pipe) -> io::Result<u64> {
+        let mut copied = 0;
+        loop {
+            let n = loop {
+                from.readable().await?;
+                match from.try_io(Interest::READABLE, || {
+                    splice(from.as_raw_fd(), pipe.write.as_raw_fd(), PIPE_LEN)
+                }) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    v => break v?,
+                }
+            };
+            if n == 0 {
+                break;
+            }
+            let mut left = n;
+            while left > 0 {
+                to.writable().await?;
+                match to.try_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left)
+                }) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    v => left -= v?,
+                }
+            }
+            copied += n as u64;
+        }
+        SockRef::from(to).shutdown(Shutdown::Write)?;
+        Ok(copied)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Both ends of a TCP connection over the loopback
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_copy_bidirectional() {
+        let (mut ch, mut ch_peer) = pair().await;
+        let (mut visitor, mut visitor_peer) = pair().await;
+        let forward =
+            tokio::spawn(async move { copy_bidirectional(&mut ch_peer, &mut visitor_peer).await });
+
+        // Larger than a pipe, so it's copied in several rounds
+        let outbound: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let inbound = b"response".to_vec();
+        let send = {
+            let outbound = outbound.clone();
+            async move {
+                ch.write_all(&outbound).await.unwrap();
+                ch.shutdown().await.unwrap();
+                let mut buf = Vec::new();
+                ch.read_to_end(&mut buf).await.unwrap();
+                buf
+            }
+        };
+        let reply = async {
+            let mut buf = Vec::new();
+            visitor.read_to_end(&mut buf).await.unwrap();
+            visitor.write_all(&inbound).await.unwrap();
+            visitor.shutdown().await.unwrap();
+            buf
+        };
+        let (received_by_ch, received_by_visitor) = tokio::join!(send, reply);
+
+        assert_eq!(received_by_visitor, outbound);
+        assert_eq!(received_by_ch, inbound);
+        assert_eq!(
+            forward.await.unwrap().unwrap(),
+            (outbound.len() as u64, inbound.len() as u64)
+        );
+    }
+}
@@ -0,0 +1,253 @@
+// A read-only page for end users, showing whether the services of the server are online
+use crate::admin::{read_request, reason, Request};
+use crate::config::StatusPageConfig;
+use crate::constants::STATUS_PAGE_REQUEST_TIMEOUT;
+use crate::protocol;
+use crate::task_group::TaskGroup;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ServiceStatus {
+    pub name: String,
+    // Whether a client is connected for the service
+    pub online: bool,
+}
+
+// Where the statuses come from, implemented by the server
+#[async_trait]
+pub trait StatusSource: Send + Sync {
+    async fn statuses(&self) -> Vec<ServiceStatus>;
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn error(status: u16) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: reason(status).to_string(),
+        }
+    }
+}
+
+// `l` is bound by the caller, so that a wrong `bind_addr` fails the server at once
+pub async fn run_status_page(
+    l: TcpListener,
+    config: StatusPageConfig,
+    source: Arc<dyn StatusSource>,
+) -> Result<()> {
+    info!("Status page listening at {}", config.bind_addr);
+
+    // Requests in flight are dropped along with the status page
+    let tasks = TaskGroup::new();
+    let _guard = tasks.cancel_on_drop();
+
+    let config = Arc::new(config);
+    loop {
+        let (conn, addr) = match l.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept a status page connection: {}", e);
+                continue;
+            }
+        };
+        let config = config.clone();
+        let source = source.clone();
+        tasks.spawn(async move {
+            if let Err(e) = time::timeout(
+                Duration::from_secs(STATUS_PAGE_REQUEST_TIMEOUT),
+                handle_connection(conn, addr, &config, &*source),
+            )
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Timeout")))
+            {
+                debug!("Status page request from {} failed: {:#}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut conn: TcpStream,
+    addr: SocketAddr,
+    config: &StatusPageConfig,
+    source: &dyn StatusSource,
+) -> Result<()> {
+    let resp = match read_request(&mut conn).await {
+        Ok(req) => {
+            debug!(
+                "Status page request {} {} from {}",
+                req.method, req.path, addr
+            );
+            if !authorized(&req, config) {
+                Response::error(401)
+            } else {
+                route(&req, config, source).await
+            }
+        }
+        Err(_) => Response::error(400),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        resp.status,
+        reason(resp.status),
+        resp.content_type,
+        resp.body.len()
+    );
+    conn.write_all(head.as_bytes()).await?;
+    conn.write_all(resp.body.as_bytes()).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+// The token is in the query, so the link can be shared with the users
+fn authorized(req: &Request, config: &StatusPageConfig) -> bool {
+    match &config.token {
+        Some(token) => req
+            .query
+            .split('&')
+            .filter_map(|kv| kv.strip_prefix("token="))
+            .any(|v| protocol::ct_eq(v.as_bytes(), token.as_bytes())),
+        None => true,
+    }
+}
+
+async fn route(req: &Request, config: &StatusPageConfig, source: &dyn StatusSource) -> Response {
+    if req.method != "GET" {
+        return Response::error(405);
+    }
+    match req.path.as_str() {
+        "/" => Response {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: render_html(&config.title, &source.statuses().await),
+        },
+        "/status.json" => Response {
+            status: 200,
+            content_type: "application/json",
+            body: serde_json::json!({ "services": source.statuses().await }).to_string(),
+        },
+        _ => Response::error(404),
+    }
+}
+
+fn render_html(title: &str, statuses: &[ServiceStatus]) -> String {
+    let title = escape_html(title);
+    let rows: String = statuses
+        .iter()
+        .map(|s| {
+            let (class, text) = if s.online {
+                ("up", "Online")
+            } else {
+                ("down", "Offline")
+            };
+            format!(
+                "<tr><td>{}</td><td class=\"{}\">{}</td></tr>\n",
+                escape_html(&s.name),
+                class,
+                text
+            )
+        })
+        .collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 40em; margin: 2em auto; padding: 0 1em; }}
+table {{ width: 100%; border-collapse: collapse; }}
+td {{ padding: 0.5em; border-bottom: 1px solid #ddd; }}
+.up {{ color: #1a7f37; }}
+.down {{ color: #cf222e; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<table>
+{rows}</table>
+</body>
+</html>
+"#
+    )
+}
+
+fn escape_html(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => r.push_str("&amp;"),
+            '<' => r.push_str("&lt;"),
+            '>' => r.push_str("&gt;"),
+            '"' => r.push_str("&quot;"),
+            '\'' => r.push_str("&#39;"),
+            c => r.push(c),
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_authorized() {
+        let mut req = Request {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            query: "a=1&token=t".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        let mut config = StatusPageConfig {
+            bind_addr: String::new(),
+            token: None,
+            title: String::new(),
+        };
+        assert!(authorized(&req, &config));
+        config.token = Some("t".to_string());
+        assert!(authorized(&req, &config));
+        config.token = Some("x".to_string());
+        assert!(!authorized(&req, &config));
+        req.query.clear();
+        assert!(!authorized(&req, &config));
+    }
+
+    #[test]
+    fn test_render_html() {
+        let html = render_html(
+            "<Status>",
+            &[
+                ServiceStatus {
+                    name: "nas".to_string(),
+                    online: true,
+                },
+                ServiceStatus {
+                    name: "a&b".to_string(),
+                    online: false,
+                },
+            ],
+        );
+        assert!(html.contains("<title>&lt;Status&gt;</title>"));
+        assert!(html.contains("<td>nas</td><td class=\"up\">Online</td>"));
+        assert!(html.contains("<td>a&amp;b</td><td class=\"down\">Offline</td>"));
+    }
+}
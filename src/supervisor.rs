@@ -0,0 +1,94 @@
+// Isolates panics of service tasks, so one broken service doesn't take down the others
+use lazy_static::lazy_static;
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PanicRecord {
+    pub count: u64,
+    pub last_message: String,
+    pub last_time: u64, // Seconds since the UNIX epoch
+}
+
+lazy_static! {
+    // Panics of service tasks, indexed by the service name
+    static ref PANICS: Mutex<HashMap<String, PanicRecord>> = Mutex::new(HashMap::new());
+}
+
+// Run `fut` of `service` in a separate task, catching panics.
+// Returns `None` if it panicked, and the panic is recorded
+pub async fn catch_panic<F>(service: &str, fut: F) -> Option<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    // Abort the task if this future is dropped, so the task goes away with whoever awaits it
+    match AbortOnDropHandle::new(tokio::spawn(fut)).await {
+        Ok(v) => Some(v),
+        Err(e) if e.is_panic() => {
+            record_panic(service, e.into_panic());
+            None
+        }
+        // Aborted by others
+        Err(_) => None,
+    }
+}
+
+fn record_panic(service: &str, payload: Box<dyn Any + Send>) {
+    let msg = if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Unknown panic".to_string()
+    };
+    error!(service = %service, "Task panicked: {}", msg);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut panics = PANICS.lock().unwrap();
+    let r = panics
+        .entry(service.to_string())
+        .or_insert_with(|| PanicRecord {
+            count: 0,
+            last_message: String::new(),
+            last_time: 0,
+        });
+    r.count += 1;
+    r.last_message = msg;
+    r.last_time = now;
+}
+
+// Panics recorded so far, indexed by the service name
+pub fn panics() -> HashMap<String, PanicRecord> {
+    PANICS.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        assert_eq!(catch_panic("test_ok", async { 1 }).await, Some(1));
+        assert!(!panics().contains_key("test_ok"));
+
+        for _ in 0..2 {
+            let ret = catch_panic("test_panic", async {
+                panic!("boom");
+            })
+            .await;
+            assert_eq!(ret, None);
+        }
+        let r = &panics()["test_panic"];
+        assert_eq!(r.count, 2);
+        assert_eq!(r.last_message, "boom");
+    }
+}
@@ -0,0 +1,49 @@
+// A tarpit for visitors rejected by the ACL, if `server.tarpit` is set. Instead of being
+// closed at once, their connections are held open and fed a byte every few seconds, which
+// wastes the time of scanners. The client is never involved
+#![cfg_attr(not(feature = "server"), allow(dead_code))]
+use crate::constants::{TARPIT_INTERVAL, TARPIT_MAX_CONNECTIONS, TARPIT_MAX_DURATION};
+use crate::privacy;
+use crate::task_group::TaskGroup;
+use lazy_static::lazy_static;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::time::{self, Instant};
+use tracing::debug;
+
+lazy_static! {
+    // Shared by all listeners, so scanners can't exhaust the server through the tarpit
+    static ref SLOTS: Arc<Semaphore> = Arc::new(Semaphore::new(TARPIT_MAX_CONNECTIONS));
+}
+
+// Hold the connection in the tarpit. It's dropped right away if the tarpit is full
+pub fn trap(conn: TcpStream, addr: SocketAddr, tasks: &TaskGroup) {
+    let permit = match SLOTS.clone().try_acquire_owned() {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    debug!("Visitor from {} is held in the tarpit", privacy::addr(addr));
+    tasks.spawn(async move {
+        let _permit = permit;
+        let started = Instant::now();
+        let _ = dribble(conn, started).await;
+        debug!(
+            "Visitor from {} left the tarpit after {:?}",
+            privacy::addr(addr),
+            started.elapsed()
+        );
+    });
+}
+
+async fn dribble(mut conn: TcpStream, started: Instant) -> std::io::Result<()> {
+    conn.set_nodelay(true)?;
+    while started.elapsed() < Duration::from_secs(TARPIT_MAX_DURATION) {
+        time::sleep(Duration::from_secs(TARPIT_INTERVAL)).await;
+        conn.write_all(&[rand::random::<u8>()]).await?;
+    }
+    Ok(())
+}
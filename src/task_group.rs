@@ -0,0 +1,122 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tokio_util::task::TaskTracker;
+use tracing::warn;
+
+// A group of tasks that are cancelled together.
+// A child group is cancelled with its parent, and shares the tracker of the parent,
+// so that the root group of an instance can wait for all tasks on shutdown
+#[derive(Clone, Default)]
+pub struct TaskGroup {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl TaskGroup {
+    pub fn new() -> TaskGroup {
+        Default::default()
+    }
+
+    pub fn child(&self) -> TaskGroup {
+        TaskGroup {
+            token: self.token.child_token(),
+            tracker: self.tracker.clone(),
+        }
+    }
+
+    // Spawn a task, which is dropped once the group is cancelled.
+    // Returns `None` if it's cancelled
+    pub fn spawn<F>(&self, fut: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let token = self.token.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                biased;
+                _ = token.cancelled() => None,
+                v = fut => Some(v),
+            }
+        })
+    }
+
+    // Spawn a task on the runtime of `handle`. Tasks it spawns run there too
+    pub fn spawn_on<F>(&self, fut: F, handle: &Handle) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let token = self.token.clone();
+        self.tracker.spawn_on(
+            async move {
+                tokio::select! {
+                    biased;
+                    _ = token.cancelled() => None,
+                    v = fut => Some(v),
+                }
+            },
+            handle,
+        )
+    }
+
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    // Cancel the group when the guard is dropped
+    pub fn cancel_on_drop(&self) -> DropGuard {
+        self.token.clone().drop_guard()
+    }
+
+    // Cancel the group, and wait for all tasks sharing the tracker to finish, at most for `timeout`.
+    // Should only be called on the root group
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.token.cancel();
+        self.tracker.close();
+        if time::timeout(timeout, self.tracker.wait()).await.is_err() {
+            warn!(
+                "{} tasks are still running {:?} after the shutdown",
+                self.tracker.len(),
+                timeout
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_task_group() {
+        let root = TaskGroup::new();
+        let child = root.child();
+
+        let a = child.spawn(async { 1 });
+        assert_eq!(a.await.unwrap(), Some(1));
+
+        let b = child.spawn(std::future::pending::<()>());
+        let c = root.spawn(std::future::pending::<()>());
+        drop(child.cancel_on_drop());
+        assert_eq!(b.await.unwrap(), None);
+        assert!(!c.is_finished());
+
+        {
+            let _guard = root.child().cancel_on_drop();
+        }
+        assert!(!c.is_finished());
+
+        root.shutdown(Duration::from_secs(1)).await;
+        assert!(c.is_finished());
+        assert_eq!(c.await.unwrap(), None);
+    }
+}
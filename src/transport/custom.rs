@@ -0,0 +1,77 @@
+// Transports implemented by library users, like over ICMP or with their own obfuscation. They're
+// registered by name, and picked with `type = "custom"` and `custom.name`. The entrypoints of the
+// client and the server are generic over the transport, so they're instantiated for it when it's
+// registered
+use super::Transport;
+#[cfg(feature = "client")]
+use crate::config::ClientConfig;
+#[cfg(feature = "server")]
+use crate::config::ServerConfig;
+use crate::config::TransportConfig;
+#[cfg(any(feature = "client", feature = "server"))]
+use crate::config_watcher::ServiceChange;
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+#[cfg(any(feature = "client", feature = "server"))]
+use std::future::Future;
+#[cfg(any(feature = "client", feature = "server"))]
+use std::pin::Pin;
+use std::sync::RwLock;
+#[cfg(any(feature = "client", feature = "server"))]
+use tokio::sync::{broadcast, mpsc};
+
+#[cfg(any(feature = "client", feature = "server"))]
+pub(crate) type RunFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+#[cfg(any(feature = "client", feature = "server"))]
+pub(crate) type Run<C> =
+    for<'a> fn(&'a C, broadcast::Receiver<bool>, mpsc::Receiver<ServiceChange>) -> RunFuture<'a>;
+
+#[derive(Clone, Copy)]
+pub(crate) struct Registered {
+    #[cfg(feature = "client")]
+    pub(crate) client: Run<ClientConfig>,
+    #[cfg(feature = "server")]
+    pub(crate) server: Run<ServerConfig>,
+}
+
+lazy_static! {
+    static ref TRANSPORTS: RwLock<HashMap<String, Registered>> = Default::default();
+}
+
+// Let clients and servers with `custom.name` of `name` run over `T`, which replaces the transport
+// registered before, if any. `T::new` gets the whole `transport` config, with its own options
+// in `custom.options`
+pub fn register_transport<T: Transport + 'static>(name: &str) {
+    let registered = Registered {
+        #[cfg(feature = "client")]
+        client: crate::client::run_boxed::<T>,
+        #[cfg(feature = "server")]
+        server: crate::server::run_boxed::<T>,
+    };
+    TRANSPORTS
+        .write()
+        .unwrap()
+        .insert(name.to_string(), registered);
+}
+
+// The transport registered for `custom.name`
+pub(crate) fn registered(config: &TransportConfig) -> Result<Registered> {
+    let name = config
+        .custom
+        .as_ref()
+        .map(|v| v.name.as_str())
+        .unwrap_or_default();
+    TRANSPORTS
+        .read()
+        .unwrap()
+        .get(name)
+        .copied()
+        .ok_or_else(|| {
+            anyhow!(
+                "No transport is registered as {:?}. Register it with `register_transport` first",
+                name
+            )
+        })
+}
@@ -0,0 +1,221 @@
+// A transport over streams that library users bring themselves, like those of their own dialer,
+// a VPN, or a test harness. A client with the `external` transport gets its connections from
+// the dialer registered for its `remote_addr`, and a server takes the streams sent for the address
+// its `bind_addr` resolves to, instead of dialing and accepting by itself
+use crate::config::TransportConfig;
+
+use super::Transport;
+use anyhow::{anyhow, Context as _, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, ToSocketAddrs};
+use tokio::sync::mpsc;
+
+// Anything that can carry a channel
+pub trait ExternalStream: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static> ExternalStream for T {}
+
+pub struct BoxedStream(Box<dyn ExternalStream>);
+
+impl Debug for BoxedStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BoxedStream")
+    }
+}
+
+impl AsyncRead for BoxedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for BoxedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+type Accepted = (BoxedStream, SocketAddr);
+type DialFuture = Pin<Box<dyn Future<Output = io::Result<BoxedStream>> + Send>>;
+type Dialer = Arc<dyn Fn() -> DialFuture + Send + Sync>;
+
+// The streams of a server. The sender is kept, so that the receiver never closes
+struct Listener {
+    tx: mpsc::Sender<Accepted>,
+    rx: Arc<tokio::sync::Mutex<mpsc::Receiver<Accepted>>>,
+}
+
+lazy_static! {
+    // Indexed by the resolved `bind_addr` of servers
+    static ref LISTENERS: Mutex<HashMap<SocketAddr, Listener>> = Default::default();
+    // Indexed by `remote_addr` of clients
+    static ref DIALERS: Mutex<HashMap<String, Dialer>> = Default::default();
+}
+
+// Hands streams to the server whose `bind_addr` resolves to the address it's registered for
+#[derive(Clone, Debug)]
+pub struct StreamSender {
+    tx: mpsc::Sender<Accepted>,
+}
+
+impl StreamSender {
+    // Waits while the server is busy, or not running. `peer` is taken as the address of the
+    // client
+    pub async fn send<S: ExternalStream>(&self, conn: S, peer: SocketAddr) -> Result<()> {
+        self.tx
+            .send((BoxedStream(Box::new(conn)), peer))
+            .await
+            .map_err(|_| anyhow!("The listener is gone"))
+    }
+}
+
+fn listener<T>(addr: SocketAddr, f: impl FnOnce(&Listener) -> T) -> T {
+    let mut listeners = LISTENERS.lock().unwrap();
+    let l = listeners.entry(addr).or_insert_with(|| {
+        let (tx, rx) = mpsc::channel(1);
+        Listener {
+            tx,
+            rx: Arc::new(tokio::sync::Mutex::new(rx)),
+        }
+    });
+    f(l)
+}
+
+// Get the sender of streams for servers whose `bind_addr` resolves to `addr`. It's the same one
+// for the same address, so it keeps working across restarts of the server
+pub fn register_listener(addr: SocketAddr) -> StreamSender {
+    listener(addr, |l| StreamSender { tx: l.tx.clone() })
+}
+
+// Let clients with `remote_addr` of `addr` connect with `dialer`, which replaces the one
+// registered before, if any
+pub fn register_dialer<F, Fut, S>(addr: &str, dialer: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<S>> + Send + 'static,
+    S: ExternalStream,
+{
+    let dialer: Dialer = Arc::new(move || {
+        let conn = dialer();
+        Box::pin(async move { Ok(BoxedStream(Box::new(conn.await?))) })
+    });
+    DIALERS.lock().unwrap().insert(addr.to_string(), dialer);
+}
+
+#[derive(Debug)]
+pub struct ExternalTransport {}
+
+pub struct ExternalListener {
+    rx: Arc<tokio::sync::Mutex<mpsc::Receiver<Accepted>>>,
+}
+
+#[async_trait]
+impl Transport for ExternalTransport {
+    type Acceptor = ExternalListener;
+    type RawStream = BoxedStream;
+    type Stream = BoxedStream;
+
+    async fn new(_config: &TransportConfig) -> Result<Self> {
+        Ok(ExternalTransport {})
+    }
+
+    // The address is only a key, and never bound
+    async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
+        let addr = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("Failed to lookup the address"))?;
+        let rx = listener(addr, |l| l.rx.clone());
+        Ok(ExternalListener { rx })
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        // The sender is held by `LISTENERS`, so this never returns `None`
+        Ok(a.rx.lock().await.recv().await.unwrap())
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        Ok(conn)
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let dialer = DIALERS
+            .lock()
+            .unwrap()
+            .get(addr)
+            .cloned()
+            .ok_or_else(|| anyhow!("No dialer is registered for {}", addr))?;
+        dialer()
+            .await
+            .with_context(|| format!("Failed to connect to {}", addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_external_transport() {
+        let t = ExternalTransport::new(&TransportConfig::default())
+            .await
+            .unwrap();
+        assert!(t.connect("test_external_transport").await.is_err());
+
+        let l = t.bind("127.0.0.1:40021").await.unwrap();
+        let tx = register_listener("127.0.0.1:40021".parse().unwrap());
+        register_dialer("test_external_transport", move || {
+            let tx = tx.clone();
+            async move {
+                let (local, remote) = tokio::io::duplex(1024);
+                let peer = "10.0.0.1:1234".parse().unwrap();
+                tx.send(remote, peer).await.map_err(io::Error::other)?;
+                Ok(local)
+            }
+        });
+
+        let server = tokio::spawn(async move {
+            let (conn, peer) = t.accept(&l).await.unwrap();
+            assert_eq!(peer, "10.0.0.1:1234".parse().unwrap());
+            let mut conn = t.handshake(conn).await.unwrap();
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+        });
+
+        let t = ExternalTransport {};
+        let mut conn = t.connect("test_external_transport").await.unwrap();
+        conn.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,329 @@
+// Channels as streams of HTTP/2, multiplexed into one connection to the server, which runs over
+// TLS with `http2.tls`. New data channels then skip the TCP and TLS handshakes, which pays off
+// for services with many short-lived visitors. Each stream is opened by a POST of the client
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use super::sub::SubTransport;
+use super::Transport;
+use crate::config::TransportConfig;
+use crate::constants::{
+    HTTP2_ACCEPT_BACKLOG, HTTP2_CONNECTION_WINDOW, HTTP2_HANDSHAKE_TIMEOUT, HTTP2_STREAM_WINDOW,
+};
+use anyhow::{anyhow, bail, Context as _, Result};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use h2::client::SendRequest;
+use h2::{RecvStream, SendStream};
+use http::{Method, Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, warn};
+
+// A byte stream over a stream of HTTP/2
+#[derive(Debug)]
+pub struct Http2Stream {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    // The unread part of the last frame
+    read_buf: Bytes,
+}
+
+impl Http2Stream {
+    fn new(send: SendStream<Bytes>, recv: RecvStream) -> Http2Stream {
+        Http2Stream {
+            send,
+            recv,
+            read_buf: Bytes::new(),
+        }
+    }
+}
+
+fn to_io_error(e: h2::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl AsyncRead for Http2Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.read_buf.is_empty() {
+                let n = buf.remaining().min(this.read_buf.len());
+                buf.put_slice(&this.read_buf[..n]);
+                this.read_buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    // Let the peer send more
+                    let _ = this.recv.flow_control().release_capacity(data.len());
+                    this.read_buf = data;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Http2Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.send.reserve_capacity(buf.len());
+        loop {
+            let n = self.send.capacity().min(buf.len());
+            if n != 0 {
+                self.send
+                    .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+                    .map_err(to_io_error)?;
+                return Poll::Ready(Ok(n));
+            }
+            // Wait for the window of the peer
+            match ready!(self.send.poll_capacity(cx)) {
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+                None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            }
+        }
+    }
+
+    // Frames are written by the task of the connection
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.send.send_data(Bytes::new(), true).map_err(to_io_error))
+    }
+}
+
+type Accepted = (Http2Stream, SocketAddr);
+
+pub struct Http2Listener {
+    rx: tokio::sync::Mutex<mpsc::Receiver<Accepted>>,
+    // Accepts connections, whose streams are sent to `rx`
+    task: JoinHandle<()>,
+}
+
+impl Drop for Http2Listener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Pass the streams of a connection to `tx`, until the connection or the listener is closed
+async fn serve<S>(conn: S, addr: SocketAddr, tx: mpsc::Sender<Accepted>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut conn = h2::server::Builder::new()
+        .initial_window_size(HTTP2_STREAM_WINDOW)
+        .initial_connection_window_size(HTTP2_CONNECTION_WINDOW)
+        .handshake::<_, Bytes>(conn)
+        .await?;
+    // The connection is only driven while waiting for streams here
+    while let Some(req) = conn.accept().await {
+        let (req, mut respond) = req?;
+        if req.method() != Method::POST {
+            let resp = Response::builder().status(StatusCode::NOT_FOUND).body(())?;
+            respond.send_response(resp, true)?;
+            continue;
+        }
+        let send = respond.send_response(Response::new(()), false)?;
+        match tx.try_send((Http2Stream::new(send, req.into_body()), addr)) {
+            Ok(()) => (),
+            // The stream is reset once dropped
+            Err(TrySendError::Full(_)) => warn!("Too many streams of {} to accept", addr),
+            Err(TrySendError::Closed(_)) => break,
+        }
+    }
+    Ok(())
+}
+
+// Open a connection to the server over `conn`, driven by a task of its own
+async fn open<S>(conn: S, addr: &str) -> Result<SendRequest<Bytes>>
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let (send, connection) = h2::client::Builder::new()
+        .initial_window_size(HTTP2_STREAM_WINDOW)
+        .initial_connection_window_size(HTTP2_CONNECTION_WINDOW)
+        .handshake(conn)
+        .await
+        .with_context(|| "Failed to do the HTTP/2 handshake")?;
+    let addr = addr.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("HTTP/2 connection to {} closed: {}", addr, e);
+        }
+    });
+    Ok(send)
+}
+
+async fn request(send: SendRequest<Bytes>, uri: &str) -> Result<Http2Stream> {
+    let mut send = send.ready().await?;
+    let req = Request::builder().method(Method::POST).uri(uri).body(())?;
+    let (resp, stream) = send.send_request(req, false)?;
+    let resp = resp.await?;
+    if resp.status() != StatusCode::OK {
+        bail!("Unexpected response {}", resp.status());
+    }
+    Ok(Http2Stream::new(stream, resp.into_body()))
+}
+
+#[derive(Debug)]
+pub struct Http2Transport {
+    sub: Arc<SubTransport>,
+    // The connections of the client, indexed by the address of the server. One that silently
+    // died is noticed by TCP keepalive
+    conns: tokio::sync::Mutex<HashMap<String, SendRequest<Bytes>>>,
+}
+
+#[async_trait]
+impl Transport for Http2Transport {
+    type Acceptor = Http2Listener;
+    type RawStream = Http2Stream;
+    type Stream = Http2Stream;
+
+    async fn new(config: &TransportConfig) -> Result<Self> {
+        let http2 = config.http2.clone().unwrap_or_default();
+        Ok(Http2Transport {
+            sub: Arc::new(SubTransport::new(config, http2.tls).await?),
+            conns: Default::default(),
+        })
+    }
+
+    async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
+        let l = TcpListener::bind(addr)
+            .await
+            .with_context(|| "Failed to create tcp listener")?;
+        let (tx, rx) = mpsc::channel(HTTP2_ACCEPT_BACKLOG);
+        let sub = self.sub.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let (conn, addr) = match sub.accept(&l).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("Failed to accept a connection: {:#}", e);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let (sub, tx) = (sub.clone(), tx.clone());
+                tokio::spawn(async move {
+                    let conn = time::timeout(
+                        Duration::from_secs(HTTP2_HANDSHAKE_TIMEOUT),
+                        sub.handshake(conn),
+                    )
+                    .await
+                    .map_err(|_| anyhow!("Transport handshake timeout"))
+                    .and_then(|v| v);
+                    let ret = match conn {
+                        Ok(conn) => serve(conn, addr, tx).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = ret {
+                        debug!("HTTP/2 connection from {} closed: {:#}", addr, e);
+                    }
+                });
+            }
+        });
+        Ok(Http2Listener {
+            rx: tokio::sync::Mutex::new(rx),
+            task,
+        })
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        a.rx.lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("The listener is closed"))
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        Ok(conn)
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let send = {
+            let mut conns = self.conns.lock().await;
+            match conns.get(addr) {
+                Some(send) if send.clone().ready().await.is_ok() => send.clone(),
+                _ => {
+                    let send = open(self.sub.connect(addr).await?, addr).await?;
+                    conns.insert(addr.to_string(), send.clone());
+                    send
+                }
+            }
+        };
+        let scheme = if self.sub.is_tls() { "https" } else { "http" };
+        request(send, &format!("{}://{}/", scheme, addr))
+            .await
+            .with_context(|| "Failed to open a HTTP/2 stream")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_http2_stream() {
+        let (a, b) = tokio::io::duplex(1024);
+        let addr: SocketAddr = "127.0.0.1:2333".parse().unwrap();
+        let (tx, mut rx) = mpsc::channel(4);
+        tokio::spawn(serve(a, addr, tx));
+        let send = open(b, "example.com").await.unwrap();
+
+        // Streams share the connection
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let client = request(send.clone(), "http://example.com/").await.unwrap();
+            let (server, from) = rx.recv().await.unwrap();
+            assert_eq!(from, addr);
+            streams.push((client, server));
+        }
+
+        for (i, (client, server)) in streams.iter_mut().enumerate() {
+            client.write_all(&[i as u8; 4]).await.unwrap();
+            let mut buf = [0u8; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [i as u8; 4]);
+        }
+
+        // More than the windows. A stream dropped before both sides end is reset
+        let (mut client, mut server) = streams.pop().unwrap();
+        client.shutdown().await.unwrap();
+        let data = vec![7u8; 4 * HTTP2_CONNECTION_WINDOW as usize];
+        let writer = tokio::spawn(async move {
+            server.write_all(&data).await.unwrap();
+            server.shutdown().await.unwrap();
+            assert_eq!(server.read(&mut [0u8; 1]).await.unwrap(), 0);
+        });
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 4 * HTTP2_CONNECTION_WINDOW as usize);
+        writer.await.unwrap();
+    }
+}
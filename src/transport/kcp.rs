@@ -0,0 +1,506 @@
+// Channels over KCP, a reliable protocol on top of UDP that resends lost segments aggressively
+// instead of backing off like TCP. It keeps links with heavy loss, like mobile or satellite ones,
+// usable, at the cost of more traffic. KCP has no handshakes, so a conversation starts with its
+// first segment, and ends with an empty message from each side, after which it's forgotten
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use super::Transport;
+use crate::config::{KcpConfig, TransportConfig};
+use crate::constants::{
+    KCP_ACCEPT_BACKLOG, KCP_IDLE_TIMEOUT, KCP_INPUT_QUEUE, KCP_KEEPALIVE_INTERVAL,
+};
+use crate::helper::udp_bind;
+use anyhow::{anyhow, Context as _, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, Notify};
+use tokio::time;
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, warn};
+
+mod ikcp;
+use ikcp::Kcp;
+
+// The largest UDP payload
+const MAX_PACKET: usize = 65536;
+
+struct State {
+    kcp: Kcp,
+    // The unread part of the last message
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    // The peer has sent its last message
+    eof: bool,
+    // This side has queued its last message
+    closed: bool,
+    // The stream is gone, and the conversation is only kept to deliver what's left
+    dropped: bool,
+    error: Option<io::ErrorKind>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl State {
+    // Writes wait while this many segments aren't acked
+    fn is_full(&self) -> bool {
+        self.kcp.wait_snd() >= 2 * self.kcp.snd_wnd()
+    }
+
+    fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            self.kcp.send(&[]);
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Wakes the task of the conversation
+    notify: Notify,
+}
+
+pub struct KcpStream {
+    shared: Arc<Shared>,
+}
+
+impl Debug for KcpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KcpStream")
+    }
+}
+
+impl Drop for KcpStream {
+    fn drop(&mut self) {
+        let mut s = self.shared.state.lock().unwrap();
+        s.dropped = true;
+        s.close();
+        self.shared.notify.notify_one();
+    }
+}
+
+impl AsyncRead for KcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut s = self.shared.state.lock().unwrap();
+        loop {
+            if s.read_pos < s.read_buf.len() {
+                let n = buf.remaining().min(s.read_buf.len() - s.read_pos);
+                buf.put_slice(&s.read_buf[s.read_pos..s.read_pos + n]);
+                s.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if s.eof {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(kind) = s.error {
+                return Poll::Ready(Err(kind.into()));
+            }
+            match s.kcp.recv() {
+                Some(msg) => {
+                    s.eof = msg.is_empty();
+                    s.read_buf = msg;
+                    s.read_pos = 0;
+                    // The window may have opened
+                    self.shared.notify.notify_one();
+                }
+                None => {
+                    s.read_waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for KcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut s = self.shared.state.lock().unwrap();
+        if let Some(kind) = s.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if s.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if s.is_full() {
+            s.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(s.kcp.mss() * ikcp::MAX_FRAGMENTS);
+        s.kcp.send(&buf[..n]);
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    // Segments are sent by the task of the conversation
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.state.lock().unwrap().close();
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Start a conversation, driven by a task of its own, which holds `guard` until it ends. `peer` is
+// `None` if `socket` is connected to it
+fn open<G: Send + 'static>(
+    conv: u32,
+    config: &KcpConfig,
+    input: mpsc::Receiver<Vec<u8>>,
+    socket: Arc<UdpSocket>,
+    peer: Option<SocketAddr>,
+    guard: G,
+) -> KcpStream {
+    let mut kcp = Kcp::new(conv);
+    kcp.set_nodelay(
+        config.nodelay,
+        config.interval,
+        config.resend,
+        config.nodelay,
+    );
+    kcp.set_window(config.window, config.window);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            kcp,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            eof: false,
+            closed: false,
+            dropped: false,
+            error: None,
+            read_waker: None,
+            write_waker: None,
+        }),
+        notify: Notify::new(),
+    });
+    tokio::spawn(drive(shared.clone(), input, socket, peer, guard));
+    KcpStream { shared }
+}
+
+async fn drive<G>(
+    shared: Arc<Shared>,
+    mut input: mpsc::Receiver<Vec<u8>>,
+    socket: Arc<UdpSocket>,
+    peer: Option<SocketAddr>,
+    _guard: G,
+) {
+    let start = Instant::now();
+    let now = || start.elapsed().as_millis() as u32;
+    let (mut last_recv, mut last_send) = (Instant::now(), Instant::now());
+    loop {
+        let delay = {
+            let s = shared.state.lock().unwrap();
+            let t = now();
+            s.kcp.check(t).wrapping_sub(t)
+        };
+        let mut received = false;
+        tokio::select! {
+            pkt = input.recv() => match pkt {
+                Some(pkt) => {
+                    if let Err(e) = shared.state.lock().unwrap().kcp.input(&pkt) {
+                        debug!("Dropped a bad KCP packet: {:?}", e);
+                    } else {
+                        last_recv = Instant::now();
+                        received = true;
+                    }
+                }
+                None => {
+                    shared.state.lock().unwrap().error = Some(io::ErrorKind::ConnectionAborted);
+                }
+            },
+            _ = shared.notify.notified() => (),
+            _ = time::sleep(Duration::from_millis(delay as u64)) => (),
+        }
+
+        let (output, done) = {
+            let mut s = shared.state.lock().unwrap();
+            if last_recv.elapsed() > Duration::from_secs(KCP_IDLE_TIMEOUT) {
+                s.error = Some(io::ErrorKind::TimedOut);
+            }
+            if last_send.elapsed() > Duration::from_secs(KCP_KEEPALIVE_INTERVAL) {
+                s.kcp.keepalive();
+            }
+            s.kcp.update(now());
+            if s.kcp.is_dead() {
+                s.error = Some(io::ErrorKind::ConnectionReset);
+            }
+            // Nobody reads anymore, but the peer is let finish
+            if s.dropped {
+                while let Some(msg) = s.kcp.recv() {
+                    s.eof |= msg.is_empty();
+                }
+            }
+            if received || s.error.is_some() {
+                if let Some(w) = s.read_waker.take() {
+                    w.wake();
+                }
+            }
+            if !s.is_full() || s.error.is_some() {
+                if let Some(w) = s.write_waker.take() {
+                    w.wake();
+                }
+            }
+            let done = s.error.is_some() || (s.dropped && s.eof && s.kcp.wait_snd() == 0);
+            (s.kcp.take_output(), done)
+        };
+
+        for pkt in output {
+            let ret = match peer {
+                Some(peer) => socket.send_to(&pkt, peer).await,
+                None => socket.send(&pkt).await,
+            };
+            if let Err(e) = ret {
+                debug!("Failed to send a KCP packet: {}", e);
+            }
+            last_send = Instant::now();
+        }
+        if done {
+            break;
+        }
+    }
+}
+
+type Accepted = (KcpStream, SocketAddr);
+// The inputs of the conversations of a listener, indexed by the peer and the conversation
+type Sessions = Arc<Mutex<HashMap<(SocketAddr, u32), mpsc::Sender<Vec<u8>>>>>;
+
+// Forgets a conversation of a listener once it ends
+struct SessionGuard {
+    sessions: Sessions,
+    key: (SocketAddr, u32),
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.key);
+    }
+}
+
+// Pass the packets received by `socket` to their conversations, and new conversations to `tx`.
+// Once the listener is dropped, the conversations accepted are still served until they end, like
+// TCP connections outliving their listener
+async fn listen(socket: Arc<UdpSocket>, config: KcpConfig, tx: mpsc::Sender<Accepted>) {
+    let sessions: Sessions = Default::default();
+    let mut buf = vec![0u8; MAX_PACKET];
+    let mut closed = false;
+    loop {
+        let (n, addr) = tokio::select! {
+            ret = socket.recv_from(&mut buf) => match ret {
+                Ok(v) => v,
+                Err(e) => {
+                    debug!("Failed to receive a KCP packet: {}", e);
+                    continue;
+                }
+            },
+            _ = tx.closed(), if !closed => {
+                closed = true;
+                continue;
+            }
+            _ = time::sleep(Duration::from_secs(1)), if closed => {
+                if sessions.lock().unwrap().is_empty() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let pkt = &buf[..n];
+        let (conv, cmd) = match ikcp::peek(pkt) {
+            Some(v) => v,
+            None => continue,
+        };
+        let key = (addr, conv);
+        let input = sessions.lock().unwrap().get(&key).cloned();
+        let input = match input {
+            Some(v) => v,
+            // Only data starts a conversation, so stray acks of one that ended don't
+            None if cmd == ikcp::CMD_PUSH => {
+                let permit = match tx.try_reserve() {
+                    Ok(v) => v,
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        warn!("Too many KCP conversations of {} to accept", addr);
+                        continue;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => continue,
+                };
+                let (input, rx) = mpsc::channel(KCP_INPUT_QUEUE);
+                sessions.lock().unwrap().insert(key, input.clone());
+                let guard = SessionGuard {
+                    sessions: sessions.clone(),
+                    key,
+                };
+                let stream = open(conv, &config, rx, socket.clone(), Some(addr), guard);
+                permit.send((stream, addr));
+                input
+            }
+            None => continue,
+        };
+        // Dropped like any other UDP packet if the conversation falls behind
+        let _ = input.try_send(pkt.to_vec());
+    }
+}
+
+pub struct KcpListener {
+    rx: tokio::sync::Mutex<mpsc::Receiver<Accepted>>,
+}
+
+#[derive(Debug)]
+pub struct KcpTransport {
+    config: KcpConfig,
+}
+
+#[async_trait]
+impl Transport for KcpTransport {
+    type Acceptor = KcpListener;
+    type RawStream = KcpStream;
+    type Stream = KcpStream;
+
+    async fn new(config: &TransportConfig) -> Result<Self> {
+        Ok(KcpTransport {
+            config: config.kcp.clone().unwrap_or_default(),
+        })
+    }
+
+    async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
+        let socket = udp_bind(addr)
+            .await
+            .with_context(|| "Failed to create udp socket")?;
+        let (tx, rx) = mpsc::channel(KCP_ACCEPT_BACKLOG);
+        tokio::spawn(listen(Arc::new(socket), self.config.clone(), tx));
+        Ok(KcpListener {
+            rx: tokio::sync::Mutex::new(rx),
+        })
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        a.rx.lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("The listener is closed"))
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        Ok(conn)
+    }
+
+    // Nothing is sent yet, so a server that's down is only noticed by the first read or write
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let peer = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("Failed to lookup the address"))?;
+        let local = if peer.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)
+            .await
+            .with_context(|| "Failed to create udp socket")?;
+        socket
+            .connect(peer)
+            .await
+            .with_context(|| format!("Failed to connect to {}", addr))?;
+        let socket = Arc::new(socket);
+
+        let (tx, rx) = mpsc::channel(KCP_INPUT_QUEUE);
+        let reader = {
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; MAX_PACKET];
+                loop {
+                    match socket.recv(&mut buf).await {
+                        Ok(n) => {
+                            if tx.send(buf[..n].to_vec()).await.is_err() {
+                                break;
+                            }
+                        }
+                        // Like ICMP errors of a server that isn't up yet
+                        Err(e) => debug!("Failed to receive a KCP packet: {}", e),
+                    }
+                }
+            })
+        };
+        Ok(open(
+            rand::random(),
+            &self.config,
+            rx,
+            socket,
+            None,
+            AbortOnDropHandle::new(reader),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_kcp_transport() {
+        let t = KcpTransport::new(&TransportConfig::default())
+            .await
+            .unwrap();
+        let l = t.bind("127.0.0.1:40031").await.unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut streams = Vec::new();
+            for _ in 0..2 {
+                let (conn, _) = t.accept(&l).await.unwrap();
+                let mut conn = t.handshake(conn).await.unwrap();
+                streams.push(tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    conn.read_to_end(&mut buf).await.unwrap();
+                    conn.write_all(&buf).await.unwrap();
+                    conn.shutdown().await.unwrap();
+                }));
+            }
+            for s in streams {
+                s.await.unwrap();
+            }
+        });
+
+        let t = KcpTransport::new(&TransportConfig::default())
+            .await
+            .unwrap();
+        let mut clients = Vec::new();
+        for i in 0..2u8 {
+            let mut conn = t.connect("127.0.0.1:40031").await.unwrap();
+            clients.push(tokio::spawn(async move {
+                // Many times the window
+                let data = vec![i; 4 * 1024 * 1024];
+                conn.write_all(&data).await.unwrap();
+                conn.shutdown().await.unwrap();
+                let mut buf = Vec::new();
+                conn.read_to_end(&mut buf).await.unwrap();
+                assert!(buf == data);
+            }));
+        }
+        for c in clients {
+            c.await.unwrap();
+        }
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,680 @@
+// A port of the ARQ of ikcp, the reference implementation of KCP, in its message mode. Segments
+// are sent again on their own timers, and on acks of later segments, without the backoff of TCP,
+// which keeps the latency low on lossy links at the cost of more traffic
+use std::collections::VecDeque;
+
+const RTO_NDL: u32 = 30; // The minimal RTO in the nodelay mode
+const RTO_MIN: u32 = 100;
+const RTO_DEF: u32 = 200;
+const RTO_MAX: u32 = 60000;
+pub const CMD_PUSH: u8 = 81;
+const CMD_ACK: u8 = 82;
+const CMD_WASK: u8 = 83; // Asks for the window of the peer
+const CMD_WINS: u8 = 84; // Tells the window
+const ASK_SEND: u32 = 1;
+const ASK_TELL: u32 = 2;
+const WND_SND: u16 = 32;
+const WND_RCV: u16 = 128; // At least the maximum number of fragments of a message
+const MTU_DEF: usize = 1400;
+const INTERVAL: u32 = 100;
+pub const OVERHEAD: usize = 24;
+const DEADLINK: u32 = 20;
+const THRESH_INIT: u32 = 2;
+const THRESH_MIN: u32 = 2;
+const PROBE_INIT: u32 = 7000;
+const PROBE_LIMIT: u32 = 120000;
+const FASTACK_LIMIT: u32 = 5;
+// The maximum number of fragments of a message
+pub const MAX_FRAGMENTS: usize = WND_RCV as usize - 1;
+
+fn timediff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+#[derive(Default)]
+struct Segment {
+    conv: u32,
+    cmd: u8,
+    frg: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    resendts: u32,
+    rto: u32,
+    fastack: u32,
+    xmit: u32,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.conv.to_le_bytes());
+        buf.push(self.cmd);
+        buf.push(self.frg);
+        buf.extend_from_slice(&self.wnd.to_le_bytes());
+        buf.extend_from_slice(&self.ts.to_le_bytes());
+        buf.extend_from_slice(&self.sn.to_le_bytes());
+        buf.extend_from_slice(&self.una.to_le_bytes());
+        buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.data);
+    }
+}
+
+fn read_u32(buf: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(buf[i..i + 4].try_into().unwrap())
+}
+
+// The conversation and the command of a packet
+pub fn peek(packet: &[u8]) -> Option<(u32, u8)> {
+    if packet.len() < OVERHEAD {
+        return None;
+    }
+    Some((read_u32(packet, 0), packet[4]))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InputError {
+    Malformed,
+    WrongConv,
+}
+
+pub struct Kcp {
+    conv: u32,
+    mtu: usize,
+    mss: usize,
+    dead: bool,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    ssthresh: u32,
+    rx_rttval: u32,
+    rx_srtt: u32,
+    rx_rto: u32,
+    rx_minrto: u32,
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    probe: u32,
+    current: u32,
+    interval: u32,
+    ts_flush: u32,
+    nodelay: u32,
+    updated: bool,
+    ts_probe: u32,
+    probe_wait: u32,
+    dead_link: u32,
+    incr: u32,
+    fastresend: u32,
+    nocwnd: bool,
+    snd_queue: VecDeque<Segment>,
+    rcv_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+    acklist: Vec<(u32, u32)>,
+    // Packets to be sent
+    output: Vec<Vec<u8>>,
+}
+
+impl Kcp {
+    pub fn new(conv: u32) -> Kcp {
+        Kcp {
+            conv,
+            mtu: MTU_DEF,
+            mss: MTU_DEF - OVERHEAD,
+            dead: false,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ssthresh: THRESH_INIT,
+            rx_rttval: 0,
+            rx_srtt: 0,
+            rx_rto: RTO_DEF,
+            rx_minrto: RTO_MIN,
+            snd_wnd: WND_SND as u32,
+            rcv_wnd: WND_RCV as u32,
+            rmt_wnd: WND_RCV as u32,
+            cwnd: 1,
+            probe: 0,
+            current: 0,
+            interval: INTERVAL,
+            ts_flush: INTERVAL,
+            nodelay: 0,
+            updated: false,
+            ts_probe: 0,
+            probe_wait: 0,
+            dead_link: DEADLINK,
+            incr: MTU_DEF as u32 - OVERHEAD as u32,
+            fastresend: 0,
+            nocwnd: false,
+            snd_queue: VecDeque::new(),
+            rcv_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            acklist: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    // `nodelay` lowers the minimal RTO and its growth on timeouts, `resend` is the number of
+    // acks skipping a segment that resend it at once, or 0 for never, and `nocwnd` turns off
+    // the congestion window
+    pub fn set_nodelay(&mut self, nodelay: bool, interval: u32, resend: u32, nocwnd: bool) {
+        self.nodelay = nodelay as u32;
+        self.rx_minrto = if nodelay { RTO_NDL } else { RTO_MIN };
+        self.interval = interval.clamp(10, 5000);
+        self.fastresend = resend;
+        self.nocwnd = nocwnd;
+    }
+
+    pub fn set_window(&mut self, snd_wnd: u16, rcv_wnd: u16) {
+        self.snd_wnd = snd_wnd.max(1) as u32;
+        self.rcv_wnd = rcv_wnd.max(WND_RCV) as u32;
+    }
+
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
+
+    pub fn snd_wnd(&self) -> usize {
+        self.snd_wnd as usize
+    }
+
+    // The peer stopped acking a segment
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    // The number of segments not acked yet
+    pub fn wait_snd(&self) -> usize {
+        self.snd_buf.len() + self.snd_queue.len()
+    }
+
+    pub fn take_output(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.output)
+    }
+
+    // Tell the peer the window in the next flush, which keeps an idle conversation alive
+    pub fn keepalive(&mut self) {
+        self.probe |= ASK_TELL;
+    }
+
+    // The next message, if it has arrived in whole
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        let front = self.rcv_queue.front()?;
+        if self.rcv_queue.len() < front.frg as usize + 1 {
+            return None;
+        }
+        let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
+
+        let mut msg = Vec::new();
+        while let Some(seg) = self.rcv_queue.pop_front() {
+            msg.extend_from_slice(&seg.data);
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        self.move_rcv_buf();
+
+        // Tell the peer that the window is open again
+        if recover && self.rcv_queue.len() < self.rcv_wnd as usize {
+            self.probe |= ASK_TELL;
+        }
+        Some(msg)
+    }
+
+    // Queue a message of at most `MAX_FRAGMENTS` segments. Empty messages are sent as well
+    pub fn send(&mut self, data: &[u8]) {
+        let count = data.len().div_ceil(self.mss).max(1);
+        assert!(count <= MAX_FRAGMENTS, "The message is too large");
+        let mut chunks = data.chunks(self.mss);
+        for i in 0..count {
+            self.snd_queue.push_back(Segment {
+                frg: (count - i - 1) as u8,
+                data: chunks.next().unwrap_or_default().to_vec(),
+                ..Default::default()
+            });
+        }
+    }
+
+    fn move_rcv_buf(&mut self) {
+        while let Some(seg) = self.rcv_buf.front() {
+            if seg.sn != self.rcv_nxt || self.rcv_queue.len() >= self.rcv_wnd as usize {
+                break;
+            }
+            let seg = self.rcv_buf.pop_front().unwrap();
+            self.rcv_queue.push_back(seg);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        }
+    }
+
+    fn update_ack(&mut self, rtt: u32) {
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt;
+            self.rx_rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = ((7 * self.rx_srtt + rtt) / 8).max(1);
+        }
+        let rto = self.rx_srtt + self.interval.max(4 * self.rx_rttval);
+        self.rx_rto = rto.clamp(self.rx_minrto, RTO_MAX);
+    }
+
+    fn shrink_buf(&mut self) {
+        self.snd_una = self.snd_buf.front().map_or(self.snd_nxt, |seg| seg.sn);
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        if let Some(i) = self.snd_buf.iter().position(|seg| seg.sn == sn) {
+            self.snd_buf.remove(i);
+        }
+    }
+
+    fn parse_una(&mut self, una: u32) {
+        while matches!(self.snd_buf.front(), Some(seg) if timediff(una, seg.sn) > 0) {
+            self.snd_buf.pop_front();
+        }
+    }
+
+    fn parse_fastack(&mut self, sn: u32) {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for seg in self.snd_buf.iter_mut() {
+            if timediff(sn, seg.sn) < 0 {
+                break;
+            } else if sn != seg.sn {
+                seg.fastack += 1;
+            }
+        }
+    }
+
+    fn parse_data(&mut self, seg: Segment) {
+        let sn = seg.sn;
+        if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0
+            || timediff(sn, self.rcv_nxt) < 0
+        {
+            return;
+        }
+        // Ordered by sn, and mostly appended
+        let mut i = self.rcv_buf.len();
+        while i > 0 {
+            let prev = self.rcv_buf[i - 1].sn;
+            if prev == sn {
+                return;
+            }
+            if timediff(sn, prev) > 0 {
+                break;
+            }
+            i -= 1;
+        }
+        self.rcv_buf.insert(i, seg);
+        self.move_rcv_buf();
+    }
+
+    pub fn input(&mut self, mut data: &[u8]) -> Result<(), InputError> {
+        if data.len() < OVERHEAD {
+            return Err(InputError::Malformed);
+        }
+        let prev_una = self.snd_una;
+        let mut maxack = None;
+
+        while data.len() >= OVERHEAD {
+            let conv = read_u32(data, 0);
+            if conv != self.conv {
+                return Err(InputError::WrongConv);
+            }
+            let cmd = data[4];
+            let frg = data[5];
+            let wnd = u16::from_le_bytes([data[6], data[7]]);
+            let ts = read_u32(data, 8);
+            let sn = read_u32(data, 12);
+            let una = read_u32(data, 16);
+            let len = read_u32(data, 20) as usize;
+            data = &data[OVERHEAD..];
+            if data.len() < len {
+                return Err(InputError::Malformed);
+            }
+            if !matches!(cmd, CMD_PUSH | CMD_ACK | CMD_WASK | CMD_WINS) {
+                return Err(InputError::Malformed);
+            }
+
+            self.rmt_wnd = wnd as u32;
+            self.parse_una(una);
+            self.shrink_buf();
+
+            match cmd {
+                CMD_ACK => {
+                    if timediff(self.current, ts) >= 0 {
+                        self.update_ack(timediff(self.current, ts) as u32);
+                    }
+                    self.parse_ack(sn);
+                    self.shrink_buf();
+                    if maxack.is_none_or(|v| timediff(sn, v) > 0) {
+                        maxack = Some(sn);
+                    }
+                }
+                CMD_PUSH if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 => {
+                    self.acklist.push((sn, ts));
+                    if timediff(sn, self.rcv_nxt) >= 0 {
+                        self.parse_data(Segment {
+                            conv,
+                            cmd,
+                            frg,
+                            wnd,
+                            ts,
+                            sn,
+                            una,
+                            data: data[..len].to_vec(),
+                            ..Default::default()
+                        });
+                    }
+                }
+                CMD_WASK => self.probe |= ASK_TELL,
+                _ => (),
+            }
+            data = &data[len..];
+        }
+
+        if let Some(sn) = maxack {
+            self.parse_fastack(sn);
+        }
+
+        // Grow the congestion window with acks
+        if timediff(self.snd_una, prev_una) > 0 && self.cwnd < self.rmt_wnd {
+            let mss = self.mss as u32;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr += mss;
+            } else {
+                self.incr = self.incr.max(mss);
+                self.incr += (mss * mss) / self.incr + mss / 16;
+                if (self.cwnd + 1) * mss <= self.incr {
+                    self.cwnd = self.incr.div_ceil(mss);
+                }
+            }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd * mss;
+            }
+        }
+        Ok(())
+    }
+
+    fn wnd_unused(&self) -> u16 {
+        self.rcv_wnd.saturating_sub(self.rcv_queue.len() as u32) as u16
+    }
+
+    // Append `seg` to the packet being built, sending it first if it would be too large
+    fn push_packet(output: &mut Vec<Vec<u8>>, buf: &mut Vec<u8>, mtu: usize, seg: &Segment) {
+        if buf.len() + OVERHEAD + seg.data.len() > mtu && !buf.is_empty() {
+            output.push(std::mem::take(buf));
+        }
+        seg.encode(buf);
+    }
+
+    fn flush(&mut self) {
+        if !self.updated {
+            return;
+        }
+        let current = self.current;
+        let wnd = self.wnd_unused();
+        let mut buf = Vec::with_capacity(self.mtu);
+        let mut seg = Segment {
+            conv: self.conv,
+            cmd: CMD_ACK,
+            wnd,
+            una: self.rcv_nxt,
+            ..Default::default()
+        };
+
+        for (sn, ts) in std::mem::take(&mut self.acklist) {
+            seg.sn = sn;
+            seg.ts = ts;
+            Kcp::push_packet(&mut self.output, &mut buf, self.mtu, &seg);
+        }
+
+        // Probe the window of the peer if it's closed
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = PROBE_INIT;
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+            } else if timediff(current, self.ts_probe) >= 0 {
+                self.probe_wait = self.probe_wait.max(PROBE_INIT);
+                self.probe_wait = (self.probe_wait + self.probe_wait / 2).min(PROBE_LIMIT);
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+                self.probe |= ASK_SEND;
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+        seg.sn = 0;
+        seg.ts = 0;
+        if self.probe & ASK_SEND != 0 {
+            seg.cmd = CMD_WASK;
+            Kcp::push_packet(&mut self.output, &mut buf, self.mtu, &seg);
+        }
+        if self.probe & ASK_TELL != 0 {
+            seg.cmd = CMD_WINS;
+            Kcp::push_packet(&mut self.output, &mut buf, self.mtu, &seg);
+        }
+        self.probe = 0;
+
+        let mut cwnd = self.snd_wnd.min(self.rmt_wnd);
+        if !self.nocwnd {
+            cwnd = cwnd.min(self.cwnd);
+        }
+        while timediff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0 {
+            let mut seg = match self.snd_queue.pop_front() {
+                Some(v) => v,
+                None => break,
+            };
+            seg.conv = self.conv;
+            seg.cmd = CMD_PUSH;
+            seg.ts = current;
+            seg.sn = self.snd_nxt;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            seg.resendts = current;
+            seg.rto = self.rx_rto;
+            self.snd_buf.push_back(seg);
+        }
+
+        let resent = if self.fastresend > 0 {
+            self.fastresend
+        } else {
+            u32::MAX
+        };
+        let rtomin = if self.nodelay == 0 {
+            self.rx_rto >> 3
+        } else {
+            0
+        };
+        let (mut change, mut lost) = (false, false);
+        for seg in self.snd_buf.iter_mut() {
+            let mut needsend = false;
+            if seg.xmit == 0 {
+                needsend = true;
+                seg.xmit += 1;
+                seg.rto = self.rx_rto;
+                seg.resendts = current.wrapping_add(seg.rto + rtomin);
+            } else if timediff(current, seg.resendts) >= 0 {
+                needsend = true;
+                seg.xmit += 1;
+                if self.nodelay == 0 {
+                    seg.rto += seg.rto.max(self.rx_rto);
+                } else {
+                    seg.rto += seg.rto / 2;
+                }
+                // Unlike ikcp, so that dead links are noticed in minutes
+                seg.rto = seg.rto.min(RTO_MAX);
+                seg.resendts = current.wrapping_add(seg.rto);
+                lost = true;
+            } else if seg.fastack >= resent && seg.xmit <= FASTACK_LIMIT {
+                needsend = true;
+                seg.xmit += 1;
+                seg.fastack = 0;
+                seg.resendts = current.wrapping_add(seg.rto);
+                change = true;
+            }
+
+            if needsend {
+                seg.ts = current;
+                seg.wnd = wnd;
+                seg.una = self.rcv_nxt;
+                Kcp::push_packet(&mut self.output, &mut buf, self.mtu, seg);
+                if seg.xmit >= self.dead_link {
+                    self.dead = true;
+                }
+            }
+        }
+        if !buf.is_empty() {
+            self.output.push(buf);
+        }
+
+        let mss = self.mss as u32;
+        if change {
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.ssthresh = (inflight / 2).max(THRESH_MIN);
+            self.cwnd = self.ssthresh + resent.min(u32::MAX - self.ssthresh);
+            self.incr = self.cwnd.saturating_mul(mss);
+        }
+        if lost {
+            self.ssthresh = (cwnd / 2).max(THRESH_MIN);
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+    }
+
+    // Flush if it's time to. `current` is a timestamp in milliseconds
+    pub fn update(&mut self, current: u32) {
+        self.current = current;
+        if !self.updated {
+            self.updated = true;
+            self.ts_flush = current;
+        }
+        let mut slap = timediff(current, self.ts_flush);
+        if !(-10000..10000).contains(&slap) {
+            self.ts_flush = current;
+            slap = 0;
+        }
+        if slap >= 0 {
+            self.ts_flush = self.ts_flush.wrapping_add(self.interval);
+            if timediff(current, self.ts_flush) >= 0 {
+                self.ts_flush = current.wrapping_add(self.interval);
+            }
+            self.flush();
+        }
+    }
+
+    // When `update` should be called next
+    pub fn check(&self, current: u32) -> u32 {
+        if !self.updated {
+            return current;
+        }
+        let mut ts_flush = self.ts_flush;
+        if !(-10000..10000).contains(&timediff(current, ts_flush)) {
+            ts_flush = current;
+        }
+        if timediff(current, ts_flush) >= 0 {
+            return current;
+        }
+        let mut minimal = timediff(ts_flush, current) as u32;
+        for seg in &self.snd_buf {
+            let diff = timediff(seg.resendts, current);
+            if diff <= 0 {
+                return current;
+            }
+            minimal = minimal.min(diff as u32);
+        }
+        current.wrapping_add(minimal.min(self.interval))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Run both ends for `ms`, dropping every `loss`th packet
+    fn run(a: &mut Kcp, b: &mut Kcp, from: u32, ms: u32, loss: usize) {
+        let mut n = 0;
+        for t in (from..from + ms).step_by(10) {
+            a.update(t);
+            b.update(t);
+            let packets = [(a.take_output(), false), (b.take_output(), true)];
+            for (output, to_a) in packets {
+                for p in output {
+                    n += 1;
+                    if loss == 0 || n % loss != 0 {
+                        let dst = if to_a { &mut *a } else { &mut *b };
+                        dst.input(&p).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_kcp() {
+        let mut a = Kcp::new(7);
+        let mut b = Kcp::new(7);
+        for k in [&mut a, &mut b] {
+            k.set_nodelay(true, 10, 2, true);
+            k.set_window(128, 128);
+        }
+
+        let msgs: Vec<Vec<u8>> = (0..200u32)
+            .map(|i| vec![i as u8; (i as usize * 97) % 5000])
+            .collect();
+        for m in &msgs {
+            a.send(m);
+        }
+        // Empty messages are delivered too
+        a.send(&[]);
+
+        let mut got = Vec::new();
+        let mut t = 0;
+        while got.len() < msgs.len() + 1 && t < 60000 {
+            run(&mut a, &mut b, t, 100, 3);
+            t += 100;
+            while let Some(m) = b.recv() {
+                got.push(m);
+            }
+        }
+        assert_eq!(&got[..msgs.len()], &msgs[..]);
+        assert!(got[msgs.len()].is_empty());
+        run(&mut a, &mut b, t, 2000, 0);
+        assert_eq!(a.wait_snd(), 0);
+        assert!(!a.is_dead());
+
+        assert_eq!(b.input(&[0u8; 10]), Err(InputError::Malformed));
+        let mut other = Kcp::new(8);
+        other.send(b"hi");
+        other.update(0);
+        assert_eq!(b.input(&other.take_output()[0]), Err(InputError::WrongConv));
+    }
+
+    #[test]
+    fn test_dead_link() {
+        let mut a = Kcp::new(1);
+        a.set_nodelay(true, 10, 2, true);
+        a.send(b"hello");
+        for t in (0..1000000).step_by(100) {
+            a.update(t);
+            a.take_output();
+            if a.is_dead() {
+                return;
+            }
+        }
+        panic!("Not dead");
+    }
+}
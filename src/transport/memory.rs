@@ -0,0 +1,136 @@
+// A transport over in-process pipes, for tests that run the client and the server
+// in one process without opening ports. Only the port of an address counts, so a server
+// bound to `0.0.0.0:2333` is reached by `localhost:2333`
+use crate::config::TransportConfig;
+use crate::constants::MEMORY_TRANSPORT_BUFFER_SIZE;
+
+use super::Transport;
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use tokio::io::{self, DuplexStream};
+use tokio::net::{lookup_host, ToSocketAddrs};
+use tokio::sync::mpsc;
+
+lazy_static! {
+    // The listeners of the process, indexed by port
+    static ref LISTENERS: Mutex<HashMap<u16, mpsc::Sender<DuplexStream>>> = Default::default();
+}
+
+#[derive(Debug)]
+pub struct MemoryTransport {}
+
+pub struct MemoryListener {
+    port: u16,
+    tx: mpsc::Sender<DuplexStream>,
+    rx: tokio::sync::Mutex<mpsc::Receiver<DuplexStream>>,
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        let mut listeners = LISTENERS.lock().unwrap();
+        if matches!(listeners.get(&self.port), Some(tx) if tx.same_channel(&self.tx)) {
+            listeners.remove(&self.port);
+        }
+    }
+}
+
+async fn lookup_port<T: ToSocketAddrs>(addr: T) -> Result<u16> {
+    lookup_host(addr)
+        .await?
+        .next()
+        .map(|a| a.port())
+        .ok_or_else(|| anyhow!("Failed to lookup the address"))
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    type Acceptor = MemoryListener;
+    type RawStream = DuplexStream;
+    type Stream = DuplexStream;
+
+    async fn new(_config: &TransportConfig) -> Result<Self> {
+        Ok(MemoryTransport {})
+    }
+
+    async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
+        let port = lookup_port(addr).await?;
+        if port == 0 {
+            bail!("The memory transport can't pick a port. Specify one");
+        }
+
+        let mut listeners = LISTENERS.lock().unwrap();
+        if matches!(listeners.get(&port), Some(tx) if !tx.is_closed()) {
+            bail!("Port {} is already in use", port);
+        }
+        let (tx, rx) = mpsc::channel(1);
+        listeners.insert(port, tx.clone());
+        Ok(MemoryListener {
+            port,
+            tx,
+            rx: tokio::sync::Mutex::new(rx),
+        })
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        // The sender is held by the listener itself, so this never returns `None`
+        let conn = a.rx.lock().await.recv().await.unwrap();
+        Ok((conn, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))))
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        Ok(conn)
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let port = lookup_port(addr).await?;
+        let tx = LISTENERS.lock().unwrap().get(&port).cloned();
+        let (local, remote) = io::duplex(MEMORY_TRANSPORT_BUFFER_SIZE);
+        match tx {
+            Some(tx) if tx.send(remote).await.is_ok() => Ok(local),
+            _ => Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+                .with_context(|| format!("Failed to connect to {}", addr)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let t = MemoryTransport::new(&TransportConfig::default())
+            .await
+            .unwrap();
+        assert!(t.connect("127.0.0.1:40001").await.is_err());
+
+        let l = t.bind("0.0.0.0:40001").await.unwrap();
+        assert!(t.bind("127.0.0.1:40001").await.is_err());
+
+        let server = tokio::spawn(async move {
+            let (conn, _) = t.accept(&l).await.unwrap();
+            let mut conn = t.handshake(conn).await.unwrap();
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+            l
+        });
+
+        let t = MemoryTransport {};
+        let mut conn = t.connect("localhost:40001").await.unwrap();
+        conn.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // The port is released with the listener
+        drop(server.await.unwrap());
+        assert!(t.connect("127.0.0.1:40001").await.is_err());
+        assert!(t.bind("127.0.0.1:40001").await.is_ok());
+    }
+}
@@ -4,7 +4,20 @@ use async_trait::async_trait;
 use std::fmt::Debug;
 use std::net::SocketAddr;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::ToSocketAddrs;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+// The error of `Transport::handshake` for connections that aren't channels and were handed
+// over elsewhere, like requests to a web server sharing the port. Not worth an error log
+#[derive(Debug)]
+pub struct Diverted;
+
+impl std::fmt::Display for Diverted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Not a channel. Diverted")
+    }
+}
+
+impl std::error::Error for Diverted {}
 
 // Specify a transport layer, like TCP, TLS
 #[async_trait]
@@ -20,9 +33,25 @@ pub trait Transport: Debug + Send + Sync {
     async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)>;
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream>;
     async fn connect(&self, addr: &str) -> Result<Self::Stream>;
+
+    // The TCP socket of a stream that carries what's forwarded as it is, so it can be copied in
+    // the kernel
+    fn as_tcp(_stream: &mut Self::Stream) -> Option<&mut TcpStream> {
+        None
+    }
 }
 
+mod custom;
+mod external;
+mod memory;
 mod tcp;
+pub use custom::register_transport;
+#[cfg(any(feature = "client", feature = "server"))]
+pub(crate) use custom::{registered, RunFuture};
+pub use external::{
+    register_dialer, register_listener, ExternalStream, ExternalTransport, StreamSender,
+};
+pub use memory::MemoryTransport;
 pub use tcp::TcpTransport;
 #[cfg(feature = "tls")]
 mod tls;
@@ -33,3 +62,98 @@ pub use tls::TlsTransport;
 mod noise;
 #[cfg(feature = "noise")]
 pub use noise::NoiseTransport;
+
+#[cfg(any(feature = "websocket", feature = "http2"))]
+mod sub;
+
+#[cfg(feature = "websocket")]
+mod websocket;
+#[cfg(feature = "websocket")]
+pub use websocket::WebsocketTransport;
+
+#[cfg(feature = "http2")]
+mod http2;
+#[cfg(feature = "http2")]
+pub use http2::Http2Transport;
+
+#[cfg(feature = "kcp")]
+mod kcp;
+#[cfg(feature = "kcp")]
+pub use kcp::KcpTransport;
+
+#[cfg(feature = "mux")]
+mod mux;
+#[cfg(feature = "mux")]
+pub use mux::MuxTransport;
+
+// Evaluate `$run` with `$t` standing for the transport of `$type`, for what connects or listens
+// outside of the client and the server
+macro_rules! with_transport {
+    ($type:expr, $t:ident => $run:expr) => {
+        match $type {
+            $crate::config::TransportType::Tcp => {
+                type $t = $crate::transport::TcpTransport;
+                $run
+            }
+            $crate::config::TransportType::Tls => {
+                #[cfg(feature = "tls")]
+                {
+                    type $t = $crate::transport::TlsTransport;
+                    $run
+                }
+                #[cfg(not(feature = "tls"))]
+                $crate::helper::feature_not_compile("tls")
+            }
+            $crate::config::TransportType::Noise => {
+                #[cfg(feature = "noise")]
+                {
+                    type $t = $crate::transport::NoiseTransport;
+                    $run
+                }
+                #[cfg(not(feature = "noise"))]
+                $crate::helper::feature_not_compile("noise")
+            }
+            $crate::config::TransportType::Websocket => {
+                #[cfg(feature = "websocket")]
+                {
+                    type $t = $crate::transport::WebsocketTransport;
+                    $run
+                }
+                #[cfg(not(feature = "websocket"))]
+                $crate::helper::feature_not_compile("websocket")
+            }
+            $crate::config::TransportType::Http2 => {
+                #[cfg(feature = "http2")]
+                {
+                    type $t = $crate::transport::Http2Transport;
+                    $run
+                }
+                #[cfg(not(feature = "http2"))]
+                $crate::helper::feature_not_compile("http2")
+            }
+            $crate::config::TransportType::Kcp => {
+                #[cfg(feature = "kcp")]
+                {
+                    type $t = $crate::transport::KcpTransport;
+                    $run
+                }
+                #[cfg(not(feature = "kcp"))]
+                $crate::helper::feature_not_compile("kcp")
+            }
+            $crate::config::TransportType::Memory => {
+                type $t = $crate::transport::MemoryTransport;
+                $run
+            }
+            $crate::config::TransportType::External => {
+                type $t = $crate::transport::ExternalTransport;
+                $run
+            }
+            // Only the client and the server are instantiated for custom transports
+            $crate::config::TransportType::Custom => Err(anyhow::anyhow!(
+                "Custom transports can only be used by the client and the server"
+            ))
+            .context($crate::error::Failure::Config),
+        }
+    };
+}
+pub(crate) use with_transport;
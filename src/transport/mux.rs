@@ -0,0 +1,226 @@
+// Channels as streams of yamux, multiplexed into one connection of the underlying transport per
+// server. A client behind a NAT gateway then holds a single entry in its connection tracking
+// table, and new data channels skip the handshakes of TLS or Noise
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::Transport;
+use crate::config::TransportConfig;
+use crate::constants::{MUX_ACCEPT_BACKLOG, MUX_HANDSHAKE_TIMEOUT, MUX_STREAM_WINDOW};
+use anyhow::{anyhow, Context as _, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::{debug, warn};
+use yamux::{Connection, Control, Mode, WindowUpdateMode};
+
+pub type MuxStream = Compat<yamux::Stream>;
+
+type Accepted = (MuxStream, SocketAddr);
+
+fn yamux_config() -> yamux::Config {
+    let mut config = yamux::Config::default();
+    config
+        .set_receive_window(MUX_STREAM_WINDOW)
+        .set_max_buffer_size(MUX_STREAM_WINDOW as usize)
+        // Stalls the sender until the data is read, instead of buffering it
+        .set_window_update_mode(WindowUpdateMode::OnRead);
+    config
+}
+
+pub struct MuxListener {
+    rx: tokio::sync::Mutex<mpsc::Receiver<Accepted>>,
+    // Accepts connections, whose streams are sent to `rx`
+    task: JoinHandle<()>,
+}
+
+impl Drop for MuxListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Pass the streams of a connection to `tx`, until the connection or the listener is closed
+async fn serve<S>(conn: S, addr: SocketAddr, tx: mpsc::Sender<Accepted>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut conn = Connection::new(conn.compat(), yamux_config(), Mode::Server);
+    // The connection is only driven while waiting for streams here
+    while let Some(stream) = conn.next_stream().await? {
+        match tx.try_send((stream.compat(), addr)) {
+            Ok(()) => (),
+            // The stream is reset once dropped
+            Err(TrySendError::Full(_)) => warn!("Too many streams of {} to accept", addr),
+            Err(TrySendError::Closed(_)) => break,
+        }
+    }
+    Ok(())
+}
+
+// Open a connection to the server over `conn`, driven by a task of its own
+fn open<S>(conn: S, addr: &str) -> Control
+where
+    S: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut conn = Connection::new(conn.compat(), yamux_config(), Mode::Client);
+    let control = conn.control();
+    let addr = addr.to_string();
+    tokio::spawn(async move {
+        // The server opens no streams
+        loop {
+            match conn.next_stream().await {
+                Ok(Some(_)) => (),
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("Multiplexed connection to {} closed: {}", addr, e);
+                    break;
+                }
+            }
+        }
+    });
+    control
+}
+
+// Wraps another transport, whose connections carry the streams
+#[derive(Debug)]
+pub struct MuxTransport<T: Transport> {
+    inner: Arc<T>,
+    // The connections of the client, indexed by the address of the server. One that silently
+    // died is noticed by the keepalive of the underlying transport
+    conns: tokio::sync::Mutex<HashMap<String, Control>>,
+}
+
+#[async_trait]
+impl<T: 'static + Transport> Transport for MuxTransport<T> {
+    type Acceptor = MuxListener;
+    type RawStream = MuxStream;
+    type Stream = MuxStream;
+
+    async fn new(config: &TransportConfig) -> Result<Self> {
+        Ok(MuxTransport {
+            inner: Arc::new(T::new(config).await?),
+            conns: Default::default(),
+        })
+    }
+
+    async fn bind<A: ToSocketAddrs + Send + Sync>(&self, addr: A) -> Result<Self::Acceptor> {
+        let l = self.inner.bind(addr).await?;
+        let (tx, rx) = mpsc::channel(MUX_ACCEPT_BACKLOG);
+        let inner = self.inner.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let (conn, addr) = match inner.accept(&l).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("Failed to accept a connection: {:#}", e);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let (inner, tx) = (inner.clone(), tx.clone());
+                tokio::spawn(async move {
+                    let conn = time::timeout(
+                        Duration::from_secs(MUX_HANDSHAKE_TIMEOUT),
+                        inner.handshake(conn),
+                    )
+                    .await
+                    .map_err(|_| anyhow!("Transport handshake timeout"))
+                    .and_then(|v| v);
+                    let ret = match conn {
+                        Ok(conn) => serve(conn, addr, tx).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = ret {
+                        debug!("Multiplexed connection from {} closed: {:#}", addr, e);
+                    }
+                });
+            }
+        });
+        Ok(MuxListener {
+            rx: tokio::sync::Mutex::new(rx),
+            task,
+        })
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        a.rx.lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("The listener is closed"))
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        Ok(conn)
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let mut conns = self.conns.lock().await;
+        if let Some(control) = conns.get_mut(addr) {
+            match control.open_stream().await {
+                Ok(stream) => return Ok(stream.compat()),
+                Err(e) => debug!("Reconnect to {}: {}", addr, e),
+            }
+        }
+        let mut control = open(self.inner.connect(addr).await?, addr);
+        let stream = control
+            .open_stream()
+            .await
+            .with_context(|| "Failed to open a multiplexed stream")?;
+        conns.insert(addr.to_string(), control);
+        Ok(stream.compat())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_mux_stream() {
+        let (a, b) = tokio::io::duplex(1024);
+        let addr: SocketAddr = "127.0.0.1:2333".parse().unwrap();
+        let (tx, mut rx) = mpsc::channel(4);
+        tokio::spawn(serve(a, addr, tx));
+        let mut control = open(b, "example.com");
+
+        // Streams share the connection
+        let mut streams = Vec::new();
+        for i in 0..2 {
+            let mut client = control.open_stream().await.unwrap().compat();
+            // A stream is announced to the server with its first frame
+            client.write_all(&[i as u8; 4]).await.unwrap();
+            let (server, from) = rx.recv().await.unwrap();
+            assert_eq!(from, addr);
+            streams.push((client, server));
+        }
+
+        for (i, (_, server)) in streams.iter_mut().enumerate() {
+            let mut buf = [0u8; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [i as u8; 4]);
+        }
+
+        // More than the windows, after half closing
+        let (mut client, mut server) = streams.pop().unwrap();
+        client.shutdown().await.unwrap();
+        let data = vec![7u8; 4 * MUX_STREAM_WINDOW as usize];
+        let writer = tokio::spawn(async move {
+            assert_eq!(server.read(&mut [0u8; 1]).await.unwrap(), 0);
+            server.write_all(&data).await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 4 * MUX_STREAM_WINDOW as usize);
+        writer.await.unwrap();
+    }
+}
@@ -3,18 +3,41 @@ use std::net::SocketAddr;
 use super::Transport;
 use crate::{
     config::{NoiseConfig, TransportConfig},
-    helper::set_tcp_keepalive,
+    helper::{feature_not_compile, set_tcp_keepalive, tcp_connect},
+    proxy::Proxy,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
+use snowstorm::snow::params::HandshakeModifier;
 use snowstorm::{Builder, NoiseParams, NoiseStream};
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tracing::{debug, info};
+
+// The size of a Kyber1024 public key, which only the first message of a hybrid handshake carries
+const KYBER1024_PUBLIC_KEY_LEN: usize = 1568;
+
+// A configured key, and how it's called in the logs
+struct Key {
+    key: Vec<u8>,
+    name: String,
+    // Not the first one configured. Worth knowing about while keys are rotated
+    old: bool,
+}
 
 pub struct NoiseTransport {
     config: NoiseConfig,
     params: NoiseParams,
-    local_private_key: Vec<u8>,
-    remote_public_key: Option<Vec<u8>>,
+    // At least one
+    local_keys: Vec<Key>,
+    remote_keys: Vec<Key>,
+    // The candidate the client succeeded with last, tried first
+    preferred: AtomicUsize,
+    // The key, and where the pattern mixes it in
+    psk: Option<(Vec<u8>, Vec<u8>)>,
+    proxy: Option<Proxy>,
 }
 
 impl std::fmt::Debug for NoiseTransport {
@@ -24,15 +47,195 @@ impl std::fmt::Debug for NoiseTransport {
 }
 
 impl NoiseTransport {
-    fn builder(&self) -> Builder {
-        let builder = Builder::new(self.params.clone()).local_private_key(&self.local_private_key);
-        match &self.remote_public_key {
-            Some(x) => builder.remote_public_key(x),
-            None => builder,
+    fn builder<'a>(&'a self, local: &'a Key, remote: Option<&'a Key>) -> Builder<'a> {
+        let mut builder = Builder::new(self.params.clone()).local_private_key(&local.key);
+        if let Some(x) = remote {
+            builder = builder.remote_public_key(&x.key);
+        }
+        if let Some((key, locations)) = &self.psk {
+            for location in locations {
+                builder = builder.psk(*location, key);
+            }
+        }
+        builder
+    }
+
+    // The pairs of keys a handshake may be done with. The remote keys are only set beforehand if
+    // the pattern needs them, like the client with NK. Only the server has several local keys
+    fn candidates(&self, initiator: bool) -> Vec<(&Key, Option<&Key>)> {
+        let remotes: Vec<Option<&Key>> = if self
+            .params
+            .handshake
+            .pattern
+            .need_known_remote_pubkey(initiator)
+        {
+            self.remote_keys.iter().map(Some).collect()
+        } else {
+            vec![None]
+        };
+        let locals = if initiator {
+            &self.local_keys[..1]
+        } else {
+            &self.local_keys[..]
+        };
+        locals
+            .iter()
+            .flat_map(|l| remotes.iter().map(move |r| (l, *r)))
+            .collect()
+    }
+
+    // The candidate of the server that can read the first message of the client. With patterns
+    // like XX, whose first message doesn't involve the static keys, that's the first one
+    fn select_candidate<'a>(
+        &self,
+        candidates: &[(&'a Key, Option<&'a Key>)],
+        first: &[u8],
+    ) -> Result<(&'a Key, Option<&'a Key>)> {
+        if let [c] = candidates {
+            return Ok(*c);
+        }
+        let mut payload = vec![0u8; first.len()];
+        candidates
+            .iter()
+            .find(|(l, r)| {
+                self.builder(l, *r)
+                    .build_responder()
+                    .and_then(|mut s| s.read_message(first, &mut payload))
+                    .is_ok()
+            })
+            .copied()
+            .ok_or_else(|| {
+                anyhow!("Noise handshake failed to authenticate the peer with any of the keys. Check that the keys and `psk` match")
+            })
+    }
+
+    // With patterns like XX and IK, the peer sends its static key in the handshake, which is only
+    // authenticated by looking it up in the remote keys
+    fn check_remote_static(&self, conn: &NoiseStream<TcpStream>) -> Result<Option<&Key>> {
+        match conn.state().get_remote_static() {
+            Some(v) if !self.remote_keys.is_empty() => self
+                .remote_keys
+                .iter()
+                .find(|k| k.key == v)
+                .map(Some)
+                .ok_or_else(|| {
+                    anyhow!("The static key of the peer doesn't match `remote_public_key` or any of `remote_public_keys`")
+                }),
+            _ => Ok(None),
+        }
+    }
+
+    fn log_keys(&self, local: &Key, remote: Option<&Key>) {
+        let names = match remote {
+            Some(k) => format!("{} and the remote key {}", local.name, k.name),
+            None => local.name.clone(),
+        };
+        if local.old || remote.is_some_and(|k| k.old) {
+            info!("Noise handshake with an old key: {}", names);
+        } else {
+            debug!("Noise handshake with {}", names);
         }
     }
 }
 
+// The keys of `key` followed by the ones of `keys`
+fn decode_keys(key: &Option<String>, keys: &[String], name: &str) -> Result<Vec<Key>> {
+    key.iter()
+        .map(|v| (v, format!("`{}`", name)))
+        .chain(
+            keys.iter()
+                .enumerate()
+                .map(|(i, v)| (v, format!("`{}s[{}]`", name, i))),
+        )
+        .enumerate()
+        .map(|(i, (v, label))| {
+            let key = base64::decode(v).with_context(|| format!("Failed to decode {}", label))?;
+            Ok(Key {
+                key,
+                name: label,
+                old: i > 0,
+            })
+        })
+        .collect()
+}
+
+// The pattern with Kyber1024 mixed into the X25519 exchange by the `hfs` modifier, like
+// `Noise_NK_25519_ChaChaPoly_BLAKE2s` to `Noise_NKhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s`
+fn hybrid_pattern(pattern: &str) -> Result<String> {
+    let mut parts: Vec<String> = pattern.split('_').map(|v| v.to_string()).collect();
+    if parts.len() != 5 || parts[2] != "25519" {
+        bail!(
+            "`post_quantum` needs a pattern with the 25519 DH function, like Noise_NK_25519_ChaChaPoly_BLAKE2s, not {}",
+            pattern
+        );
+    }
+    // Modifiers are joined with `+`, like `NKpsk0+hfs`
+    let handshake = &mut parts[1];
+    if handshake.ends_with(|c: char| c.is_ascii_digit()) {
+        handshake.push('+');
+    }
+    handshake.push_str("hfs");
+    parts[2] = "25519+Kyber1024".to_string();
+    Ok(parts.join("_"))
+}
+
+// The first message of the handshake, left in the socket for the handshake to read
+async fn peek_first_message(conn: &TcpStream) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; 2];
+    let mut len = None;
+    loop {
+        let n = conn
+            .peek(&mut buf)
+            .await
+            .with_context(|| "Failed to read the noise handshake")?;
+        if n == 0 {
+            bail!("The peer closed the connection before the noise handshake");
+        }
+        if n < buf.len() {
+            // Wait for the rest to arrive
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            continue;
+        }
+        match len {
+            Some(_) => break,
+            None => {
+                let v = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+                len = Some(v);
+                buf.resize(2 + v, 0);
+            }
+        }
+    }
+    Ok(buf.split_off(2))
+}
+
+// The responder tells a hybrid handshake by the size of the first message, so that a mismatch of
+// `post_quantum` fails with an error that says so, instead of a failure to decrypt
+fn check_hybrid(first: &[u8], post_quantum: bool) -> Result<()> {
+    let hybrid = first.len() >= KYBER1024_PUBLIC_KEY_LEN;
+    match (hybrid, post_quantum) {
+        (true, false) => bail!(
+            "The peer started a post-quantum hybrid noise handshake, but `post_quantum` is not set here. It must be identical on both ends"
+        ),
+        (false, true) => bail!(
+            "The peer started a noise handshake without post-quantum hybrid key exchange, but `post_quantum` is set here. It must be identical on both ends"
+        ),
+        _ => Ok(()),
+    }
+}
+
+// Tell the peers that fail to authenticate apart from the network errors
+fn handshake_error(e: snowstorm::Error) -> anyhow::Error {
+    match e {
+        snowstorm::Error::SnowError(snowstorm::snow::Error::Decrypt) => anyhow!(
+            "Noise handshake failed to authenticate the peer. Check that the keys and `psk` match"
+        ),
+        snowstorm::Error::IoError(e) if e.kind() == ErrorKind::UnexpectedEof => anyhow!(
+            "The peer closed the connection during the noise handshake. It may have failed to authenticate this end. Check that the keys, `psk` and `post_quantum` match"
+        ),
+        e => anyhow::Error::new(e).context("Failed to do noise handshake"),
+    }
+}
+
 #[async_trait]
 impl Transport for NoiseTransport {
     type Acceptor = TcpListener;
@@ -40,31 +243,76 @@ impl Transport for NoiseTransport {
     type Stream = snowstorm::stream::NoiseStream<TcpStream>;
 
     async fn new(config: &TransportConfig) -> Result<Self> {
+        let proxy = config.proxy.clone();
         let config = match &config.noise {
             Some(v) => v.clone(),
             None => return Err(anyhow!("Missing noise config")),
         };
-        let builder = Builder::new(config.pattern.parse()?);
-
-        let remote_public_key = match &config.remote_public_key {
-            Some(x) => {
-                Some(base64::decode(x).with_context(|| "Failed to decode remote_public_key")?)
+        let pattern = if config.post_quantum {
+            if !cfg!(feature = "noise-pq") {
+                feature_not_compile("noise-pq")
             }
-            None => None,
+            hybrid_pattern(&config.pattern)?
+        } else {
+            config.pattern.clone()
         };
+        let builder = Builder::new(config.pattern.parse()?);
 
-        let local_private_key = match &config.local_private_key {
-            Some(x) => base64::decode(x).with_context(|| "Failed to decode local_private_key")?,
-            None => builder.generate_keypair()?.private,
-        };
+        let remote_keys = decode_keys(
+            &config.remote_public_key,
+            &config.remote_public_keys,
+            "remote_public_key",
+        )?;
 
-        let params: NoiseParams = config.pattern.parse()?;
+        let mut local_keys = decode_keys(
+            &config.local_private_key,
+            &config.local_private_keys,
+            "local_private_key",
+        )?;
+        if local_keys.is_empty() {
+            local_keys.push(Key {
+                key: builder.generate_keypair()?.private,
+                name: "a key generated at startup".to_string(),
+                old: false,
+            });
+        }
+        let params: NoiseParams = pattern
+            .parse()
+            .with_context(|| format!("Invalid noise pattern {}", pattern))?;
+
+        let locations: Vec<u8> = params
+            .handshake
+            .modifiers
+            .list
+            .iter()
+            .filter_map(|m| match m {
+                HandshakeModifier::Psk(v) => Some(*v),
+                _ => None,
+            })
+            .collect();
+        let psk = match (&config.psk, locations.is_empty()) {
+            (Some(x), false) => {
+                let key = base64::decode(x).with_context(|| "Failed to decode psk")?;
+                if key.len() != 32 {
+                    bail!("`psk` must be 32 bytes");
+                }
+                Some((key, locations))
+            }
+            (None, false) => bail!("The pattern {} needs `psk`", config.pattern),
+            (Some(_), true) => bail!(
+                "`psk` needs a pattern with a psk modifier, like Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s"
+            ),
+            (None, true) => None,
+        };
 
         Ok(NoiseTransport {
             config,
             params,
-            local_private_key,
-            remote_public_key,
+            local_keys,
+            remote_keys,
+            preferred: AtomicUsize::new(0),
+            psk,
+            proxy,
         })
     }
 
@@ -82,21 +330,283 @@ impl Transport for NoiseTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
-        let conn = NoiseStream::handshake(conn, self.builder().build_responder()?)
+        let first = peek_first_message(&conn).await?;
+        check_hybrid(&first, self.config.post_quantum)?;
+        let (local, remote) = self.select_candidate(&self.candidates(false), &first)?;
+        let conn = NoiseStream::handshake(conn, self.builder(local, remote).build_responder()?)
             .await
-            .with_context(|| "Failed to do noise handshake")?;
+            .map_err(handshake_error)?;
+        let transmitted = self.check_remote_static(&conn)?;
+        self.log_keys(local, remote.or(transmitted));
         Ok(conn)
     }
 
     async fn connect(&self, addr: &str) -> Result<Self::Stream> {
-        let conn = TcpStream::connect(addr)
-            .await
-            .with_context(|| "Failed to connect TCP socket")?;
-        set_tcp_keepalive(&conn);
+        // Without knowing which of the remote keys the server has, try them in turn
+        let candidates = self.candidates(true);
+        let start = self.preferred.load(Ordering::Relaxed);
+        let mut last_err = None;
+        for i in (0..candidates.len()).map(|i| (start + i) % candidates.len()) {
+            let (local, remote) = candidates[i];
+            let conn = tcp_connect(addr, self.proxy.as_ref())
+                .await
+                .with_context(|| "Failed to connect TCP socket")?;
+
+            let ret = NoiseStream::handshake(conn, self.builder(local, remote).build_initiator()?)
+                .await
+                .map_err(handshake_error)
+                .and_then(|conn| {
+                    let transmitted = self.check_remote_static(&conn)?;
+                    Ok((conn, transmitted))
+                });
+            match ret {
+                Ok((conn, transmitted)) => {
+                    self.preferred.store(i, Ordering::Relaxed);
+                    self.log_keys(local, remote.or(transmitted));
+                    return Ok(conn);
+                }
+                Err(e) => {
+                    if let Some(k) = remote.filter(|_| candidates.len() > 1) {
+                        debug!(
+                            "Noise handshake with the remote key {} failed: {:#}",
+                            k.name, e
+                        );
+                    }
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PSK: &str = "c3VwZXJzZWNyZXRzdXBlcnNlY3JldHN1cGVyc2VjcmU=";
+    const PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+    async fn transport_with(noise: NoiseConfig) -> Result<NoiseTransport> {
+        NoiseTransport::new(&TransportConfig {
+            noise: Some(noise),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn transport(pattern: &str, psk: Option<&str>) -> Result<NoiseTransport> {
+        transport_with(NoiseConfig {
+            pattern: pattern.to_string(),
+            local_private_key: None,
+            remote_public_key: None,
+            local_private_keys: vec![],
+            remote_public_keys: vec![],
+            psk: psk.map(|v| v.to_string()),
+            post_quantum: false,
+        })
+        .await
+    }
+
+    // The results of the handshakes of the server and the client
+    async fn handshake(server: NoiseTransport, client: NoiseTransport) -> (Result<()>, Result<()>) {
+        let l = server.bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (conn, _) = server.accept(&l).await?;
+            server.handshake(conn).await.map(|_| ())
+        });
+        let client = client.connect(&addr).await.map(|_| ());
+        (server.await.unwrap(), client)
+    }
+
+    #[tokio::test]
+    async fn test_psk() {
+        let (server, client) = handshake(
+            transport(PATTERN, Some(PSK)).await.unwrap(),
+            transport(PATTERN, Some(PSK)).await.unwrap(),
+        )
+        .await;
+        server.unwrap();
+        client.unwrap();
+
+        let wrong = base64::encode([0u8; 32]);
+        let (server, _) = handshake(
+            transport(PATTERN, Some(PSK)).await.unwrap(),
+            transport(PATTERN, Some(&wrong)).await.unwrap(),
+        )
+        .await;
+        assert!(server
+            .unwrap_err()
+            .to_string()
+            .contains("failed to authenticate"));
 
-        let conn = NoiseStream::handshake(conn, self.builder().build_initiator()?)
+        assert!(transport(PATTERN, None).await.is_err());
+        assert!(transport("Noise_NN_25519_ChaChaPoly_BLAKE2s", Some(PSK))
             .await
-            .with_context(|| "Failed to do noise handshake")?;
-        return Ok(conn);
+            .is_err());
+        assert!(transport(PATTERN, Some("c2hvcnQ=")).await.is_err());
+    }
+
+    #[test]
+    fn test_hybrid_pattern() {
+        assert_eq!(
+            hybrid_pattern("Noise_NK_25519_ChaChaPoly_BLAKE2s").unwrap(),
+            "Noise_NKhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s"
+        );
+        assert_eq!(
+            hybrid_pattern(PATTERN).unwrap(),
+            "Noise_NNpsk0+hfs_25519+Kyber1024_ChaChaPoly_BLAKE2s"
+        );
+        assert!(hybrid_pattern("Noise_NK_448_ChaChaPoly_BLAKE2s").is_err());
+    }
+
+    #[cfg(feature = "noise-pq")]
+    #[tokio::test]
+    async fn test_post_quantum() {
+        let transport = |post_quantum| {
+            transport_with(NoiseConfig {
+                pattern: PATTERN.to_string(),
+                local_private_key: None,
+                remote_public_key: None,
+                local_private_keys: vec![],
+                remote_public_keys: vec![],
+                psk: Some(PSK.to_string()),
+                post_quantum,
+            })
+        };
+
+        let (server, client) = handshake(
+            transport(true).await.unwrap(),
+            transport(true).await.unwrap(),
+        )
+        .await;
+        server.unwrap();
+        client.unwrap();
+
+        let (server, client) = handshake(
+            transport(false).await.unwrap(),
+            transport(true).await.unwrap(),
+        )
+        .await;
+        assert!(server
+            .unwrap_err()
+            .to_string()
+            .contains("`post_quantum` is not set here"));
+        assert!(client.is_err());
+
+        let (server, client) = handshake(
+            transport(true).await.unwrap(),
+            transport(false).await.unwrap(),
+        )
+        .await;
+        assert!(server
+            .unwrap_err()
+            .to_string()
+            .contains("`post_quantum` is set here"));
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let pattern = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+        let builder = Builder::new(pattern.parse().unwrap());
+        let old = builder.generate_keypair().unwrap();
+        let new = builder.generate_keypair().unwrap();
+        let unknown = builder.generate_keypair().unwrap();
+        let server = || {
+            transport_with(NoiseConfig {
+                pattern: pattern.to_string(),
+                local_private_key: Some(base64::encode(&new.private)),
+                remote_public_key: None,
+                local_private_keys: vec![base64::encode(&old.private)],
+                remote_public_keys: vec![],
+                psk: None,
+                post_quantum: false,
+            })
+        };
+        let client = |keys: &[&[u8]]| {
+            transport_with(NoiseConfig {
+                pattern: pattern.to_string(),
+                local_private_key: None,
+                remote_public_key: None,
+                local_private_keys: vec![],
+                remote_public_keys: keys.iter().map(base64::encode).collect(),
+                psk: None,
+                post_quantum: false,
+            })
+        };
+
+        // The server accepts either of its keys
+        for key in [&old.public, &new.public] {
+            let (server, client) =
+                handshake(server().await.unwrap(), client(&[key]).await.unwrap()).await;
+            server.unwrap();
+            client.unwrap();
+        }
+
+        // The client tries the next key after a failed handshake
+        let rotated = client(&[&unknown.public, &new.public]).await.unwrap();
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap().to_string();
+        let s = server().await.unwrap();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = l.accept().await {
+                let _ = s.handshake(conn).await;
+            }
+        });
+        rotated.connect(&addr).await.unwrap();
+        assert_eq!(rotated.preferred.load(Ordering::Relaxed), 1);
+
+        let (server, client) = handshake(
+            server().await.unwrap(),
+            client(&[&unknown.public]).await.unwrap(),
+        )
+        .await;
+        assert!(server.is_err());
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remote_static() {
+        // XX sends the static keys in the handshake, which are checked if `remote_public_key` is set
+        let pattern = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+        let builder = Builder::new(pattern.parse().unwrap());
+        let server_key = builder.generate_keypair().unwrap();
+        let client_key = builder.generate_keypair().unwrap();
+        let transport = |local: &[u8], remote: &[u8]| {
+            transport_with(NoiseConfig {
+                pattern: pattern.to_string(),
+                local_private_key: Some(base64::encode(local)),
+                remote_public_key: Some(base64::encode(remote)),
+                local_private_keys: vec![],
+                remote_public_keys: vec![],
+                psk: None,
+                post_quantum: false,
+            })
+        };
+
+        let (server, client) = handshake(
+            transport(&server_key.private, &client_key.public)
+                .await
+                .unwrap(),
+            transport(&client_key.private, &server_key.public)
+                .await
+                .unwrap(),
+        )
+        .await;
+        server.unwrap();
+        client.unwrap();
+
+        let (server, _) = handshake(
+            transport(&server_key.private, &server_key.public)
+                .await
+                .unwrap(),
+            transport(&client_key.private, &server_key.public)
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert!(server.unwrap_err().to_string().contains("doesn't match"));
     }
 }
@@ -0,0 +1,117 @@
+// The transport that carries transports built on top of HTTP, which is TCP, or TLS with the `tls`
+// config of the transport
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::{TcpTransport, Transport};
+use crate::config::TransportConfig;
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(feature = "tls")]
+use super::tls::TlsStream;
+#[cfg(feature = "tls")]
+use super::TlsTransport;
+
+#[derive(Debug)]
+pub enum SubTransport {
+    Tcp(TcpTransport),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsTransport>),
+}
+
+#[derive(Debug)]
+pub enum SubStream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(TlsStream),
+}
+
+impl SubTransport {
+    pub async fn new(config: &TransportConfig, tls: bool) -> Result<SubTransport> {
+        if !tls {
+            return Ok(SubTransport::Tcp(TcpTransport::new(config).await?));
+        }
+        #[cfg(feature = "tls")]
+        return Ok(SubTransport::Tls(Box::new(
+            TlsTransport::new(config).await?,
+        )));
+        #[cfg(not(feature = "tls"))]
+        anyhow::bail!("TLS over the transport needs the `tls` feature");
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, SubTransport::Tcp(_))
+    }
+
+    pub async fn accept(&self, a: &TcpListener) -> Result<(TcpStream, SocketAddr)> {
+        match self {
+            SubTransport::Tcp(t) => t.accept(a).await,
+            #[cfg(feature = "tls")]
+            SubTransport::Tls(t) => t.accept(a).await,
+        }
+    }
+
+    pub async fn handshake(&self, conn: TcpStream) -> Result<SubStream> {
+        Ok(match self {
+            SubTransport::Tcp(t) => SubStream::Tcp(t.handshake(conn).await?),
+            #[cfg(feature = "tls")]
+            SubTransport::Tls(t) => SubStream::Tls(t.handshake(conn).await?),
+        })
+    }
+
+    pub async fn connect(&self, addr: &str) -> Result<SubStream> {
+        Ok(match self {
+            SubTransport::Tcp(t) => SubStream::Tcp(t.connect(addr).await?),
+            #[cfg(feature = "tls")]
+            SubTransport::Tls(t) => SubStream::Tls(t.connect(addr).await?),
+        })
+    }
+}
+
+impl AsyncRead for SubStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SubStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            SubStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SubStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SubStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            SubStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SubStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            SubStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SubStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            SubStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
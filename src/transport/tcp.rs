@@ -1,5 +1,6 @@
 use crate::config::TransportConfig;
-use crate::helper::set_tcp_keepalive;
+use crate::helper::{set_tcp_keepalive, tcp_connect};
+use crate::proxy::Proxy;
 
 use super::Transport;
 use anyhow::Result;
@@ -8,7 +9,9 @@ use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 
 #[derive(Debug)]
-pub struct TcpTransport {}
+pub struct TcpTransport {
+    proxy: Option<Proxy>,
+}
 
 #[async_trait]
 impl Transport for TcpTransport {
@@ -16,8 +19,10 @@ impl Transport for TcpTransport {
     type Stream = TcpStream;
     type RawStream = TcpStream;
 
-    async fn new(_config: &TransportConfig) -> Result<Self> {
-        Ok(TcpTransport {})
+    async fn new(config: &TransportConfig) -> Result<Self> {
+        Ok(TcpTransport {
+            proxy: config.proxy.clone(),
+        })
     }
 
     async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
@@ -35,8 +40,10 @@ impl Transport for TcpTransport {
     }
 
     async fn connect(&self, addr: &str) -> Result<Self::Stream> {
-        let s = TcpStream::connect(addr).await?;
-        set_tcp_keepalive(&s);
-        Ok(s)
+        tcp_connect(addr, self.proxy.as_ref()).await
+    }
+
+    fn as_tcp(stream: &mut Self::Stream) -> Option<&mut TcpStream> {
+        Some(stream)
     }
 }
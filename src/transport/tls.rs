@@ -1,29 +1,89 @@
+use std::fmt;
+use std::io;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock, Weak};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, SystemTime};
 
 use super::Transport;
-use crate::config::{TlsConfig, TransportConfig};
-use crate::helper::set_tcp_keepalive;
+use crate::config::{TlsConfig, TlsVersion, TransportConfig};
+use crate::constants::TLS_RELOAD_INTERVAL;
+use crate::helper::{set_tcp_keepalive, tcp_connect};
+use crate::proxy::Proxy;
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio_native_tls::native_tls::{self, Certificate, Identity};
-use tokio_native_tls::{TlsAcceptor, TlsConnector, TlsStream};
+use tokio_native_tls::{TlsAcceptor, TlsConnector};
+use tracing::{error, info};
+
+// rustls is used for what native-tls can't do. See `TlsConfig::uses_rustls`
+#[derive(Clone)]
+enum Connector {
+    Native(TlsConnector),
+    #[cfg(feature = "tls-rustls")]
+    Rustls(tokio_rustls::TlsConnector),
+}
+
+#[derive(Clone)]
+enum Acceptor {
+    Native(TlsAcceptor),
+    #[cfg(feature = "tls-rustls")]
+    Rustls(tokio_rustls::TlsAcceptor),
+}
+
+impl fmt::Debug for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Connector::Native(v) => v.fmt(f),
+            #[cfg(feature = "tls-rustls")]
+            Connector::Rustls(_) => f.write_str("RustlsConnector"),
+        }
+    }
+}
+
+impl fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Acceptor::Native(v) => v.fmt(f),
+            #[cfg(feature = "tls-rustls")]
+            Acceptor::Rustls(_) => f.write_str("RustlsAcceptor"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TlsStream {
+    Native(tokio_native_tls::TlsStream<TcpStream>),
+    #[cfg(feature = "tls-rustls")]
+    Rustls(Box<tokio_rustls::TlsStream<TcpStream>>),
+}
+
+// Rebuilt when the files it's made of change, without affecting the established connections
+#[derive(Debug, Default)]
+struct Tls {
+    connector: Option<Connector>,
+    acceptor: Option<Acceptor>,
+}
 
 #[derive(Debug)]
 pub struct TlsTransport {
     config: TlsConfig,
-    connector: Option<TlsConnector>,
-    tls_acceptor: Option<TlsAcceptor>,
+    tls: Arc<RwLock<Tls>>,
+    proxy: Option<Proxy>,
 }
 
 #[async_trait]
 impl Transport for TlsTransport {
     type Acceptor = TcpListener;
     type RawStream = TcpStream;
-    type Stream = TlsStream<TcpStream>;
+    type Stream = TlsStream;
 
     async fn new(config: &TransportConfig) -> Result<Self> {
+        let proxy = config.proxy.clone();
         let config = match &config.tls {
             Some(v) => v,
             None => {
@@ -31,6 +91,117 @@ impl Transport for TlsTransport {
             }
         };
 
+        #[cfg(not(feature = "tls-rustls"))]
+        if config.uses_rustls() {
+            crate::helper::feature_not_compile("tls-rustls")
+        }
+
+        // `cert` and `key` point to the cache of ACME, filled before loading
+        #[cfg(not(feature = "acme"))]
+        if config.acme.is_some() {
+            crate::helper::feature_not_compile("acme")
+        }
+        #[cfg(feature = "acme")]
+        if let Some(acme) = &config.acme {
+            crate::acme::ensure(acme).await?;
+        }
+
+        let tls = Arc::new(RwLock::new(Tls::load(config).await?));
+        tokio::spawn(reload_on_change(config.clone(), Arc::downgrade(&tls)));
+        #[cfg(feature = "acme")]
+        if let Some(acme) = &config.acme {
+            tokio::spawn(crate::acme::renew_on_expiry(
+                acme.clone(),
+                Arc::downgrade(&tls),
+            ));
+        }
+
+        Ok(TlsTransport {
+            config: config.clone(),
+            tls,
+            proxy,
+        })
+    }
+
+    async fn bind<A: ToSocketAddrs + Send + Sync>(&self, addr: A) -> Result<Self::Acceptor> {
+        let l = TcpListener::bind(addr)
+            .await
+            .with_context(|| "Failed to create tcp listener")?;
+        Ok(l)
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        let (conn, addr) = a.accept().await?;
+        set_tcp_keepalive(&conn);
+
+        Ok((conn, addr))
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let acceptor = self.tls.read().unwrap().acceptor.clone();
+        Ok(match acceptor.unwrap() {
+            Acceptor::Native(acceptor) => TlsStream::Native(acceptor.accept(conn).await?),
+            #[cfg(feature = "tls-rustls")]
+            Acceptor::Rustls(acceptor) => {
+                let conn = acceptor.accept(conn).await?;
+                with_rustls::record_handshake(conn.get_ref().1.handshake_kind());
+                TlsStream::Rustls(Box::new(conn.into()))
+            }
+        })
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let conn = tcp_connect(addr, self.proxy.as_ref()).await?;
+
+        let sni = self
+            .config
+            .sni
+            .clone()
+            .or_else(|| self.config.hostname.clone())
+            .unwrap_or_else(|| String::from(addr.split(':').next().unwrap()));
+        let connector = self.tls.read().unwrap().connector.clone();
+        Ok(match connector.unwrap() {
+            Connector::Native(connector) => TlsStream::Native(connector.connect(&sni, conn).await?),
+            #[cfg(feature = "tls-rustls")]
+            Connector::Rustls(connector) => {
+                let name = tokio_rustls::rustls::pki_types::ServerName::try_from(sni)
+                    .with_context(|| "Invalid SNI of the server")?;
+                let conn = connector.connect(name, conn).await?;
+                with_rustls::record_handshake(conn.get_ref().1.handshake_kind());
+                TlsStream::Rustls(Box::new(conn.into()))
+            }
+        })
+    }
+}
+
+impl Tls {
+    async fn load(config: &TlsConfig) -> Result<Tls> {
+        #[cfg(feature = "tls-rustls")]
+        if config.uses_rustls() {
+            return Ok(Tls {
+                connector: if config.trusted_root.is_some() || !config.pinned_spki_sha256.is_empty()
+                {
+                    Some(Connector::Rustls(
+                        with_rustls::build_connector(config).await?,
+                    ))
+                } else {
+                    None
+                },
+                acceptor: match config.cert {
+                    Some(_) => Some(Acceptor::Rustls(with_rustls::build_acceptor(config).await?)),
+                    None => None,
+                },
+            });
+        }
+
+        // TLS 1.3 is the highest one supported by native-tls, so it's left unbounded
+        let protocol = |v: TlsVersion| match v {
+            TlsVersion::Tls10 => Some(native_tls::Protocol::Tlsv10),
+            TlsVersion::Tls11 => Some(native_tls::Protocol::Tlsv11),
+            TlsVersion::Tls12 => Some(native_tls::Protocol::Tlsv12),
+            TlsVersion::Tls13 => None,
+        };
+
         let connector = match config.trusted_root.as_ref() {
             Some(path) => {
                 let s = fs::read_to_string(path)
@@ -38,67 +209,743 @@ impl Transport for TlsTransport {
                     .with_context(|| "Failed to read the `tls.trusted_root`")?;
                 let cert = Certificate::from_pem(s.as_bytes())
                     .with_context(|| "Failed to read certificate from `tls.trusted_root`")?;
-                let connector = native_tls::TlsConnector::builder()
+                let mut builder = native_tls::TlsConnector::builder();
+                builder
                     .add_root_certificate(cert)
-                    .build()?;
-                Some(TlsConnector::from(connector))
+                    .danger_accept_invalid_hostnames(!config.verify_hostname)
+                    .max_protocol_version(config.max_version.and_then(protocol));
+                if let Some(v) = config.min_version {
+                    builder.min_protocol_version(protocol(v));
+                }
+                Some(Connector::Native(TlsConnector::from(builder.build()?)))
             }
             None => None,
         };
 
-        let tls_acceptor = match config.pkcs12.as_ref() {
+        let acceptor = match config.pkcs12.as_ref() {
             Some(path) => {
                 let ident = Identity::from_pkcs12(
                     &fs::read(path).await?,
                     config.pkcs12_password.as_ref().unwrap(),
                 )
                 .with_context(|| "Failed to create identitiy")?;
-                Some(TlsAcceptor::from(
-                    native_tls::TlsAcceptor::new(ident).unwrap(),
-                ))
+                let mut builder = native_tls::TlsAcceptor::builder(ident);
+                builder.max_protocol_version(config.max_version.and_then(protocol));
+                if let Some(v) = config.min_version {
+                    builder.min_protocol_version(protocol(v));
+                }
+                Some(Acceptor::Native(TlsAcceptor::from(
+                    builder
+                        .build()
+                        .with_context(|| "Failed to create the TLS acceptor")?,
+                )))
             }
             None => None,
         };
 
-        Ok(TlsTransport {
-            config: config.clone(),
+        Ok(Tls {
             connector,
-            tls_acceptor,
+            acceptor,
         })
     }
+}
+
+async fn modified_times(paths: &[String]) -> Vec<Option<SystemTime>> {
+    let mut v = Vec::with_capacity(paths.len());
+    for path in paths {
+        // Symlinks are followed, so renewals that swap them, like certbot's, are noticed
+        v.push(fs::metadata(path).await.and_then(|m| m.modified()).ok());
+    }
+    v
+}
 
-    async fn bind<A: ToSocketAddrs + Send + Sync>(&self, addr: A) -> Result<Self::Acceptor> {
-        let l = TcpListener::bind(addr)
+#[cfg(unix)]
+async fn hangup(signal: &mut Option<tokio::signal::unix::Signal>) {
+    match signal {
+        Some(s) => {
+            s.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn hangup(_: &mut Option<()>) {
+    std::future::pending().await
+}
+
+// Reload the certificates when their files change, or on SIGHUP, so that renewed ones are used
+// without a restart. Stops once the transport is dropped
+async fn reload_on_change(config: TlsConfig, tls: Weak<RwLock<Tls>>) {
+    let paths: Vec<String> = [
+        &config.pkcs12,
+        &config.cert,
+        &config.key,
+        &config.trusted_root,
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect();
+    let mut mtimes = modified_times(&paths).await;
+    #[cfg(unix)]
+    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+    #[cfg(not(unix))]
+    let mut signal = None;
+
+    loop {
+        let forced = tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(TLS_RELOAD_INTERVAL)) => false,
+            _ = hangup(&mut signal) => true,
+        };
+        let tls = match tls.upgrade() {
+            Some(v) => v,
+            None => break,
+        };
+        let new_mtimes = modified_times(&paths).await;
+        if !forced && new_mtimes == mtimes {
+            continue;
+        }
+        // Files being written may fail to load. They are tried again until they succeed
+        match Tls::load(&config).await {
+            Ok(v) => {
+                *tls.write().unwrap() = v;
+                mtimes = new_mtimes;
+                info!("Reloaded the TLS certificates");
+            }
+            Err(e) => error!("{:#}. Keep using the current TLS certificates", e),
+        }
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsStream::Native(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "tls-rustls")]
+            TlsStream::Rustls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TlsStream::Native(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "tls-rustls")]
+            TlsStream::Rustls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsStream::Native(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "tls-rustls")]
+            TlsStream::Rustls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsStream::Native(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "tls-rustls")]
+            TlsStream::Rustls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(feature = "tls-rustls")]
+mod with_rustls {
+    use super::*;
+    use crate::constants::TLS_SESSION_CACHE_SIZE;
+    use anyhow::bail;
+    use sha2::{Digest, Sha256};
+    use tokio_rustls::rustls::client::danger::{
+        HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    };
+    use tokio_rustls::rustls::client::Resumption;
+    use tokio_rustls::rustls::client::WebPkiServerVerifier;
+    use tokio_rustls::rustls::crypto::{
+        ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+        WebPkiSupportedAlgorithms,
+    };
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+    use tokio_rustls::rustls::server::{
+        NoServerSessionStorage, ServerSessionMemoryCache, WebPkiClientVerifier,
+    };
+    use tokio_rustls::rustls::{
+        self, version, CertificateError, ClientConfig, DigitallySignedStruct, HandshakeKind,
+        RootCertStore, ServerConfig, SignatureScheme, SupportedProtocolVersion,
+    };
+
+    // Counted for the resumption rate in the state dump
+    pub(super) fn record_handshake(kind: Option<HandshakeKind>) {
+        crate::state_dump::tls_handshake(kind == Some(HandshakeKind::Resumed));
+    }
+
+    pub(super) async fn read_certs(
+        path: &str,
+        field: &str,
+    ) -> Result<Vec<CertificateDer<'static>>> {
+        let v = fs::read(path)
             .await
-            .with_context(|| "Failed to create tcp listener")?;
-        Ok(l)
+            .with_context(|| format!("Failed to read `tls.{}`", field))?;
+        rustls_pemfile::certs(&mut v.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to parse `tls.{}`", field))
     }
 
-    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
-        let (conn, addr) = a.accept().await?;
-        set_tcp_keepalive(&conn);
+    async fn read_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+        let v = fs::read(path)
+            .await
+            .with_context(|| "Failed to read `tls.key`")?;
+        rustls_pemfile::private_key(&mut v.as_slice())
+            .with_context(|| "Failed to parse `tls.key`")?
+            .ok_or_else(|| anyhow!("No private key found in `tls.key`"))
+    }
 
-        Ok((conn, addr))
+    async fn read_roots(path: &str) -> Result<Arc<RootCertStore>> {
+        let mut roots = RootCertStore::empty();
+        for c in read_certs(path, "trusted_root").await? {
+            roots
+                .add(c)
+                .with_context(|| "Invalid certificate in `tls.trusted_root`")?;
+        }
+        Ok(Arc::new(roots))
     }
 
-    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
-        let conn = self.tls_acceptor.as_ref().unwrap().accept(conn).await?;
-        Ok(conn)
+    // The versions in the range of `min_version` and `max_version` that rustls supports
+    fn versions(config: &TlsConfig) -> Result<Vec<&'static SupportedProtocolVersion>> {
+        let min = config.min_version.unwrap_or(TlsVersion::Tls12);
+        let max = config.max_version.unwrap_or(TlsVersion::Tls13);
+        let v: Vec<_> = [
+            (TlsVersion::Tls12, &version::TLS12),
+            (TlsVersion::Tls13, &version::TLS13),
+        ]
+        .into_iter()
+        .filter(|(v, _)| (min..=max).contains(v))
+        .map(|(_, v)| v)
+        .collect();
+        if v.is_empty() {
+            bail!("Only TLS 1.2 and 1.3 are supported with `cert`, `cipher_suites` or `min_version = \"1.3\"`");
+        }
+        Ok(v)
     }
 
-    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
-        let conn = TcpStream::connect(&addr).await?;
-        set_tcp_keepalive(&conn);
+    // The ring provider, with the cipher suites narrowed down to `cipher_suites`
+    fn provider(config: &TlsConfig) -> Result<Arc<CryptoProvider>> {
+        let mut provider = ring::default_provider();
+        if !config.cipher_suites.is_empty() {
+            provider.cipher_suites = config
+                .cipher_suites
+                .iter()
+                .map(|name| {
+                    provider
+                        .cipher_suites
+                        .iter()
+                        .find(|s| s.suite().as_str() == Some(name.as_str()))
+                        .copied()
+                        .ok_or_else(|| {
+                            let names: Vec<_> = provider
+                                .cipher_suites
+                                .iter()
+                                .filter_map(|s| s.suite().as_str())
+                                .collect();
+                            anyhow!(
+                                "Unknown cipher suite {} in `tls.cipher_suites`. Supported: {}",
+                                name,
+                                names.join(", ")
+                            )
+                        })
+                })
+                .collect::<Result<_>>()?;
+        }
+        Ok(Arc::new(provider))
+    }
+
+    // Checks the certificate of the server against `hostname` rather than the SNI, or against no
+    // name at all. The chain is verified either way
+    #[derive(Debug)]
+    struct NameVerifier {
+        inner: Arc<WebPkiServerVerifier>,
+        hostname: Option<ServerName<'static>>,
+        verify_hostname: bool,
+    }
+
+    impl ServerCertVerifier for NameVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName<'_>,
+            ocsp_response: &[u8],
+            now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            let name = self.hostname.as_ref().unwrap_or(server_name);
+            match self
+                .inner
+                .verify_server_cert(end_entity, intermediates, name, ocsp_response, now)
+            {
+                Err(rustls::Error::InvalidCertificate(
+                    CertificateError::NotValidForName
+                    | CertificateError::NotValidForNameContext { .. },
+                )) if !self.verify_hostname => Ok(ServerCertVerified::assertion()),
+                v => v,
+            }
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls12_signature(message, cert, dss)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls13_signature(message, cert, dss)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.inner.supported_verify_schemes()
+        }
+    }
+
+    // The SHA-256 hash of the SubjectPublicKeyInfo of `cert`, which `pinned_spki_sha256` lists
+    pub(super) fn spki_sha256(cert: &CertificateDer<'_>) -> Result<[u8; 32], rustls::Error> {
+        let cert = webpki::EndEntityCert::try_from(cert)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        Ok(Sha256::digest(cert.subject_public_key_info()).into())
+    }
+
+    // Only accepts certificates of the server whose key is pinned. The chain and the names are
+    // verified by `inner`, if `trusted_root` is set
+    #[derive(Debug)]
+    struct PinVerifier {
+        pins: Vec<[u8; 32]>,
+        inner: Option<Arc<dyn ServerCertVerifier>>,
+        algorithms: WebPkiSupportedAlgorithms,
+    }
+
+    impl ServerCertVerifier for PinVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName<'_>,
+            ocsp_response: &[u8],
+            now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            if !self.pins.contains(&spki_sha256(end_entity)?) {
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+            match &self.inner {
+                Some(inner) => inner.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    now,
+                ),
+                None => Ok(ServerCertVerified::assertion()),
+            }
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls12_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls13_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.algorithms.supported_schemes()
+        }
+    }
+
+    // Verify the server with `trusted_root` and `pinned_spki_sha256`, and present `cert` to it if
+    // set
+    pub(super) async fn build_connector(config: &TlsConfig) -> Result<tokio_rustls::TlsConnector> {
+        let provider = provider(config)?;
+        let chain = match config.trusted_root.as_deref() {
+            Some(path) => {
+                let inner = WebPkiServerVerifier::builder_with_provider(
+                    read_roots(path).await?,
+                    provider.clone(),
+                )
+                .build()
+                .with_context(|| "Failed to create the server certificate verifier")?;
+                let v: Arc<dyn ServerCertVerifier> = if !config.verify_hostname
+                    || (config.sni.is_some() && config.hostname.is_some())
+                {
+                    let hostname = match &config.hostname {
+                        Some(v) => Some(
+                            ServerName::try_from(v.clone())
+                                .with_context(|| "Invalid `tls.hostname`")?,
+                        ),
+                        None => None,
+                    };
+                    Arc::new(NameVerifier {
+                        inner,
+                        hostname,
+                        verify_hostname: config.verify_hostname,
+                    })
+                } else {
+                    inner
+                };
+                Some(v)
+            }
+            None => None,
+        };
+        let verifier: Arc<dyn ServerCertVerifier> = if config.pinned_spki_sha256.is_empty() {
+            chain.ok_or_else(|| anyhow!("Missing `tls.trusted_root`"))?
+        } else {
+            let pins = config
+                .pinned_spki_sha256
+                .iter()
+                .map(|v| {
+                    base64::decode(v)
+                        .ok()
+                        .and_then(|v| v.try_into().ok())
+                        .ok_or_else(|| anyhow!("Invalid `tls.pinned_spki_sha256` {}", v))
+                })
+                .collect::<Result<_>>()?;
+            Arc::new(PinVerifier {
+                pins,
+                inner: chain,
+                algorithms: provider.signature_verification_algorithms,
+            })
+        };
 
-        let connector = self.connector.as_ref().unwrap();
-        Ok(connector
-            .connect(
-                self.config
-                    .hostname
-                    .as_ref()
-                    .unwrap_or(&String::from(addr.split(':').next().unwrap())),
-                conn,
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&versions(config)?)
+            .with_context(|| "Invalid `tls.cipher_suites` for the TLS versions")?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+        let mut tls_config = match config.cert.as_deref().zip(config.key.as_deref()) {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(read_certs(cert, "cert").await?, read_key(key).await?)
+                .with_context(|| "Failed to create the TLS config")?,
+            None => builder.with_no_client_auth(),
+        };
+        // The sessions are kept in the connector, so they are lost when the certificates reload
+        tls_config.resumption = if config.session_resumption {
+            Resumption::in_memory_sessions(TLS_SESSION_CACHE_SIZE)
+        } else {
+            Resumption::disabled()
+        };
+        Ok(tokio_rustls::TlsConnector::from(Arc::new(tls_config)))
+    }
+
+    // Present `cert` to clients. With `required_client_auth`, they must present one signed by
+    // `trusted_root`
+    pub(super) async fn build_acceptor(config: &TlsConfig) -> Result<tokio_rustls::TlsAcceptor> {
+        let (cert, key) = config
+            .cert
+            .as_deref()
+            .zip(config.key.as_deref())
+            .ok_or_else(|| anyhow!("Missing `tls.cert` or `tls.key`"))?;
+        let provider = provider(config)?;
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&versions(config)?)
+            .with_context(|| "Invalid `tls.cipher_suites` for the TLS versions")?;
+        let builder = if config.required_client_auth {
+            let roots = read_roots(
+                config
+                    .trusted_root
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("Missing `tls.trusted_root`"))?,
             )
-            .await?)
+            .await?;
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider)
+                .build()
+                .with_context(|| "Failed to create the client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+        let mut tls_config = builder
+            .with_single_cert(read_certs(cert, "cert").await?, read_key(key).await?)
+            .with_context(|| "Failed to create the TLS config")?;
+        // Stateless tickets for TLS 1.3 and session IDs for TLS 1.2
+        if config.session_resumption {
+            tls_config.session_storage = ServerSessionMemoryCache::new(TLS_SESSION_CACHE_SIZE);
+            tls_config.ticketer =
+                ring::Ticketer::new().with_context(|| "Failed to create the TLS ticketer")?;
+        } else {
+            tls_config.session_storage = Arc::new(NoServerSessionStorage {});
+            tls_config.send_tls13_tickets = 0;
+        }
+        Ok(tokio_rustls::TlsAcceptor::from(Arc::new(tls_config)))
+    }
+}
+
+#[cfg(all(test, feature = "tls-rustls"))]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn fixture(name: &str) -> Option<String> {
+        Some(format!("tests/tls_client_auth/{}", name))
+    }
+
+    fn transport_config(tls: TlsConfig) -> TransportConfig {
+        TransportConfig {
+            tls: Some(TlsConfig {
+                hostname: tls.hostname.clone().or(Some("localhost".to_string())),
+                trusted_root: fixture("ca.pem"),
+                ..tls
+            }),
+            ..Default::default()
+        }
+    }
+
+    // Accept connections, and greet them with "hello"
+    async fn serve(server: TlsTransport) -> String {
+        let l = server.bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = server.accept(&l).await.unwrap();
+                if let Ok(mut conn) = server.handshake(conn).await {
+                    let _ = conn.write_all(b"hello").await;
+                    let _ = conn.shutdown().await;
+                }
+            }
+        });
+        addr
+    }
+
+    async fn client(tls: TlsConfig) -> TlsTransport {
+        TlsTransport::new(&transport_config(tls)).await.unwrap()
+    }
+
+    async fn hello(client: &TlsTransport, addr: &str) -> Result<()> {
+        let mut conn = client.connect(addr).await?;
+        let mut buf = [0u8; 5];
+        conn.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_auth() {
+        let server = TlsTransport::new(&transport_config(TlsConfig {
+            cert: fixture("server.pem"),
+            key: fixture("server.key"),
+            required_client_auth: true,
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        let addr = serve(server).await;
+
+        let client = TlsTransport::new(&transport_config(TlsConfig {
+            cert: fixture("client.pem"),
+            key: fixture("client.key"),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        hello(&client, &addr).await.unwrap();
+
+        // Without a certificate
+        let client = TlsTransport::new(&transport_config(Default::default()))
+            .await
+            .unwrap();
+        assert!(hello(&client, &addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_names() {
+        let server = TlsTransport::new(&transport_config(TlsConfig {
+            cert: fixture("server.pem"),
+            key: fixture("server.key"),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        let addr = serve(server).await;
+
+        // The certificate is for `localhost`
+        let wrong_name = || TlsConfig {
+            hostname: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        let c = client(TlsConfig {
+            sni: Some("cdn.example.com".to_string()),
+            ..Default::default()
+        })
+        .await;
+        hello(&c, &addr).await.unwrap();
+        let c = client(wrong_name()).await;
+        assert!(hello(&c, &addr).await.is_err());
+        let c = client(TlsConfig {
+            verify_hostname: false,
+            ..wrong_name()
+        })
+        .await;
+        hello(&c, &addr).await.unwrap();
+        let c = client(TlsConfig {
+            verify_hostname: false,
+            cipher_suites: vec!["TLS13_AES_256_GCM_SHA384".to_string()],
+            ..wrong_name()
+        })
+        .await;
+        hello(&c, &addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pins() {
+        let server = TlsTransport::new(&transport_config(TlsConfig {
+            cert: fixture("server.pem"),
+            key: fixture("server.key"),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        let addr = serve(server).await;
+
+        let cert = with_rustls::read_certs(&fixture("server.pem").unwrap(), "cert")
+            .await
+            .unwrap();
+        let pin = base64::encode(with_rustls::spki_sha256(&cert[0]).unwrap());
+        let wrong_pin = base64::encode([0u8; 32]);
+
+        let c = client(TlsConfig {
+            pinned_spki_sha256: vec![wrong_pin.clone(), pin.clone()],
+            ..Default::default()
+        })
+        .await;
+        hello(&c, &addr).await.unwrap();
+        let c = client(TlsConfig {
+            pinned_spki_sha256: vec![wrong_pin],
+            ..Default::default()
+        })
+        .await;
+        assert!(hello(&c, &addr).await.is_err());
+
+        // Without `trusted_root`, only the pin is checked
+        let c = TlsTransport::new(&TransportConfig {
+            tls: Some(TlsConfig {
+                hostname: Some("example.com".to_string()),
+                pinned_spki_sha256: vec![pin],
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        hello(&c, &addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_versions() {
+        let server = TlsTransport::new(&transport_config(TlsConfig {
+            cert: fixture("server.pem"),
+            key: fixture("server.key"),
+            min_version: Some(TlsVersion::Tls13),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        let addr = serve(server).await;
+
+        let client = TlsTransport::new(&transport_config(TlsConfig {
+            cipher_suites: vec!["TLS13_AES_256_GCM_SHA384".to_string()],
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        hello(&client, &addr).await.unwrap();
+
+        let client = TlsTransport::new(&transport_config(TlsConfig {
+            max_version: Some(TlsVersion::Tls12),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        assert!(hello(&client, &addr).await.is_err());
+
+        assert!(TlsTransport::new(&transport_config(TlsConfig {
+            cipher_suites: vec!["TLS_NULL_WITH_NULL_NULL".to_string()],
+            ..Default::default()
+        }))
+        .await
+        .is_err());
+        // No cipher suites of TLS 1.2 are left
+        assert!(TlsTransport::new(&transport_config(TlsConfig {
+            max_version: Some(TlsVersion::Tls12),
+            cipher_suites: vec!["TLS13_AES_256_GCM_SHA384".to_string()],
+            ..Default::default()
+        }))
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_resumption() {
+        let resumed = |conn: &TlsStream| match conn {
+            TlsStream::Rustls(s) => {
+                s.get_ref().1.handshake_kind() == Some(tokio_rustls::rustls::HandshakeKind::Resumed)
+            }
+            _ => unreachable!(),
+        };
+        for (max_version, session_resumption) in [
+            (TlsVersion::Tls12, true),
+            (TlsVersion::Tls13, true),
+            (TlsVersion::Tls13, false),
+        ] {
+            let server = TlsTransport::new(&transport_config(TlsConfig {
+                cert: fixture("server.pem"),
+                key: fixture("server.key"),
+                session_resumption,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+            let addr = serve(server).await;
+
+            let c = client(TlsConfig {
+                session_resumption: true,
+                max_version: Some(max_version),
+                ..Default::default()
+            })
+            .await;
+            let mut conn = c.connect(&addr).await.unwrap();
+            assert!(!resumed(&conn));
+            // The tickets of TLS 1.3 come after the handshake
+            let mut buf = [0u8; 5];
+            conn.read_exact(&mut buf).await.unwrap();
+            let conn = c.connect(&addr).await.unwrap();
+            assert_eq!(resumed(&conn), session_resumption);
+        }
     }
 }
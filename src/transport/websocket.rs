@@ -0,0 +1,307 @@
+// Channels framed as WebSocket messages, for networks and CDNs that only pass HTTP(S).
+// Everything is sent in binary messages. With `websocket.tls`, it runs over TLS like `wss://`.
+// The server only takes requests for `websocket.path`, and may hand others over to a web server
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::sub::{SubStream, SubTransport};
+use super::{Diverted, Transport};
+use crate::admin::parse_request_head;
+use crate::config::{TransportConfig, WebsocketConfig};
+use crate::constants::WEBSOCKET_MAX_REQUEST_HEAD;
+use anyhow::{anyhow, bail, Context as _, Result};
+use async_trait::async_trait;
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::http::header::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::debug;
+
+// A byte stream over the messages of a WebSocket
+#[derive(Debug)]
+pub struct WebsocketStream<S> {
+    inner: WebSocketStream<S>,
+    // The unread part of the last message
+    read_buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> WebsocketStream<S> {
+    fn new(inner: WebSocketStream<S>) -> WebsocketStream<S> {
+        WebsocketStream {
+            inner,
+            read_buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+fn to_io_error(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebsocketStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos < this.read_buf.len() {
+                let n = buf.remaining().min(this.read_buf.len() - this.pos);
+                buf.put_slice(&this.read_buf[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(v))) => {
+                    this.read_buf = v;
+                    this.pos = 0;
+                }
+                // Pings are answered by tungstenite
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Text(_))) => (),
+                Some(Ok(Message::Frame(_))) => (),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebsocketStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = Pin::new(&mut self.inner);
+        ready!(inner.as_mut().poll_ready(cx)).map_err(to_io_error)?;
+        inner
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(to_io_error)
+    }
+}
+
+enum Accepted<S> {
+    Websocket(Box<WebSocketStream<S>>),
+    // Any other request, with what has been read of it
+    Other(S, Vec<u8>, String),
+}
+
+// Read the request head, and open the websocket if it asks for `path`
+async fn accept<S: AsyncRead + AsyncWrite + Unpin>(mut conn: S, path: &str) -> Result<Accepted<S>> {
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if buf.len() >= WEBSOCKET_MAX_REQUEST_HEAD {
+            bail!("The request head is too large");
+        }
+        let mut chunk = [0u8; 4096];
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("Connection closed before the request head ends");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let req = parse_request_head(&String::from_utf8_lossy(&buf[..head_end]))?;
+    let upgrade = req
+        .header("Upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let key = match req.header("Sec-WebSocket-Key") {
+        Some(key) if upgrade && req.method == "GET" && req.path == path => key,
+        _ => return Ok(Accepted::Other(conn, buf, req.path)),
+    };
+    let resp = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    conn.write_all(resp.as_bytes()).await?;
+    conn.flush().await?;
+    let rest = buf.split_off(head_end);
+    Ok(Accepted::Websocket(Box::new(
+        WebSocketStream::from_partially_read(conn, rest, Role::Server, None).await,
+    )))
+}
+
+// Pass the request on to the web server, which takes it from there
+async fn divert(mut conn: SubStream, head: Vec<u8>, fallback: String) -> Result<()> {
+    let mut web = TcpStream::connect(&fallback)
+        .await
+        .with_context(|| format!("Failed to connect to the fallback {}", fallback))?;
+    web.write_all(&head).await?;
+    tokio::io::copy_bidirectional(&mut conn, &mut web).await?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct WebsocketTransport {
+    config: WebsocketConfig,
+    // Extra headers of the client
+    headers: Vec<(HeaderName, HeaderValue)>,
+    sub: SubTransport,
+}
+
+#[async_trait]
+impl Transport for WebsocketTransport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = WebsocketStream<SubStream>;
+
+    async fn new(config: &TransportConfig) -> Result<Self> {
+        let websocket = config.websocket.clone().unwrap_or_default();
+        let sub = SubTransport::new(config, websocket.tls).await?;
+        let headers = websocket
+            .headers
+            .iter()
+            .map(|(k, v)| {
+                Ok((
+                    HeaderName::from_bytes(k.as_bytes())?,
+                    HeaderValue::from_str(v)?,
+                ))
+            })
+            .collect::<Result<_>>()
+            .with_context(|| "Invalid `websocket.headers`")?;
+        Ok(WebsocketTransport {
+            config: websocket,
+            headers,
+            sub,
+        })
+    }
+
+    async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
+        TcpListener::bind(addr)
+            .await
+            .with_context(|| "Failed to create tcp listener")
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        self.sub.accept(a).await
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let conn = self.sub.handshake(conn).await?;
+        let (mut conn, head, path) = match accept(conn, &self.config.path)
+            .await
+            .with_context(|| "Failed to accept the websocket")?
+        {
+            Accepted::Websocket(ws) => return Ok(WebsocketStream::new(*ws)),
+            Accepted::Other(conn, head, path) => (conn, head, path),
+        };
+        match &self.config.fallback {
+            Some(fallback) => {
+                let fallback = fallback.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = divert(conn, head, fallback).await {
+                        debug!("Failed to divert a request for {}: {:#}", path, e);
+                    }
+                });
+                Err(Diverted.into())
+            }
+            // Anything else on the port, like probes of the proxy in front, is turned away
+            None => {
+                let _ = conn
+                    .write_all(
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                    .await;
+                bail!("Unexpected request for {}", path)
+            }
+        }
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let conn = self.sub.connect(addr).await?;
+        let scheme = if self.sub.is_tls() { "wss" } else { "ws" };
+        // The host in the URL is the `Host` header that proxies route by
+        let host = self.config.host.as_deref().unwrap_or(addr);
+        let url = format!("{}://{}{}", scheme, host, self.config.path);
+        let mut req = url
+            .as_str()
+            .into_client_request()
+            .with_context(|| format!("Invalid websocket URL {}", url))?;
+        for (k, v) in &self.headers {
+            req.headers_mut().insert(k, v.clone());
+        }
+        let (ws, _) = tokio_tungstenite::client_async(req, conn)
+            .await
+            .map_err(|e| anyhow!(e))
+            .with_context(|| format!("Failed to open the websocket {}", url))?;
+        Ok(WebsocketStream::new(ws))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_websocket_stream() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (server, client) = tokio::join!(
+            accept(a, "/tunnel"),
+            tokio_tungstenite::client_async("ws://example.com/tunnel", b)
+        );
+        let mut server = match server.unwrap() {
+            Accepted::Websocket(ws) => WebsocketStream::new(*ws),
+            Accepted::Other(..) => panic!("Not accepted"),
+        };
+        let mut client = WebsocketStream::new(client.unwrap().0);
+
+        client.write_all(b"hello").await.unwrap();
+        client.write_all(b" world").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0u8; 11];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+
+        // Messages longer than the buffer are read in parts
+        server.write_all(&[7u8; 100]).await.unwrap();
+        server.flush().await.unwrap();
+        let mut buf = [0u8; 60];
+        client.read_exact(&mut buf).await.unwrap();
+        client.read_exact(&mut buf[..40]).await.unwrap();
+        assert_eq!(buf, [7u8; 60]);
+
+        server.shutdown().await.unwrap();
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_accept_other() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let req = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        b.write_all(req).await.unwrap();
+        match accept(a, "/tunnel").await.unwrap() {
+            Accepted::Other(_, head, path) => {
+                assert_eq!(head, req);
+                assert_eq!(path, "/index.html");
+            }
+            Accepted::Websocket(_) => panic!("Accepted"),
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::cli::SelfUpdateArgs;
+use crate::protocol::digest;
+use anyhow::{anyhow, bail, Context, Result};
+use minisign_verify::{PublicKey, Signature};
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+const DOWNLOAD_TIMEOUT: u64 = 600; // Timeout in seconds for downloading a release
+
+// The entrypoint of `rathole self-update`
+pub async fn self_update(args: &SelfUpdateArgs) -> Result<()> {
+    let public_key = PublicKey::from_base64(&args.public_key)
+        .map_err(|e| anyhow!("Invalid public key: {}", e))?;
+    let target = env!("VERGEN_CARGO_TARGET_TRIPLE");
+    let url = args.url.replace("{target}", target);
+    let timeout = Duration::from_secs(DOWNLOAD_TIMEOUT);
+
+    info!("Downloading {}", url);
+    let sig = crate::http::get(&format!("{}.minisig", url), timeout).await?;
+    let sig = String::from_utf8(sig).with_context(|| "The signature is not valid UTF-8")?;
+    let sig = Signature::decode(&sig).map_err(|e| anyhow!("Invalid signature: {}", e))?;
+    let bin = crate::http::get(&url, timeout).await?;
+
+    public_key
+        .verify(&bin, &sig, false)
+        .map_err(|e| anyhow!("Failed to verify the release: {}", e))?;
+    info!("Release verified: {}", sig.trusted_comment());
+
+    let exe = std::env::current_exe().with_context(|| "Failed to locate the running binary")?;
+    let current = fs::read(&exe)
+        .await
+        .with_context(|| format!("Failed to read {}", exe.display()))?;
+    if digest(&current) == digest(&bin) {
+        info!("Already up to date");
+        return Ok(());
+    }
+    check_release(
+        sig.trusted_comment(),
+        target,
+        &crate::cli::VERSION,
+        args.allow_downgrade,
+    )?;
+
+    if args.check {
+        info!("An update is available");
+        return Ok(());
+    }
+
+    replace_exe(&exe, &bin)
+        .await
+        .with_context(|| format!("Failed to replace {}", exe.display()))?;
+    info!(
+        "Updated {}. Restart rathole to run the new binary",
+        exe.display()
+    );
+
+    Ok(())
+}
+
+// The trusted comment of a release names its version and target, like
+// `rathole 0.4.0 x86_64-unknown-linux-gnu`. It's signed along with the binary, so an older release,
+// or one for another target, can't be passed off as an update
+fn check_release(comment: &str, target: &str, current: &str, allow_downgrade: bool) -> Result<()> {
+    if !comment.split_whitespace().any(|w| w == target) {
+        bail!(
+            "The release is not for {}. Its trusted comment is `{}`",
+            target,
+            comment
+        );
+    }
+    let version = comment
+        .split_whitespace()
+        .find_map(parse_version)
+        .ok_or_else(|| anyhow!("The trusted comment `{}` names no version", comment))?;
+    if allow_downgrade {
+        return Ok(());
+    }
+    match parse_version(current) {
+        Some(v) if version > v => Ok(()),
+        _ => bail!(
+            "The release is not newer than the running {}. Its trusted comment is `{}`. Pass `--allow-downgrade` to install it anyway",
+            current,
+            comment
+        ),
+    }
+}
+
+// Like `0.4.0`, or `v0.4.0-3-g1234abc` of `git describe`
+fn parse_version(s: &str) -> Option<(u64, u64, u64)> {
+    let s = s.strip_prefix('v').unwrap_or(s);
+    let s = s.split(['-', '+']).next()?;
+    let mut parts = s.split('.').map(|v| v.parse().ok());
+    let v = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(v)
+}
+
+// Replace `exe` with `bin` atomically, by writing a new file next to it and renaming
+async fn replace_exe(exe: &Path, bin: &[u8]) -> Result<()> {
+    let tmp = exe.with_extension("new");
+    let mut f = fs::File::create(&tmp).await?;
+    f.write_all(bin).await?;
+    f.sync_all().await?;
+    drop(f);
+    fs::set_permissions(&tmp, fs::metadata(exe).await?.permissions()).await?;
+
+    // A running binary can't be overwritten on Windows, but can be renamed
+    #[cfg(windows)]
+    fs::rename(exe, exe.with_extension("old")).await?;
+
+    fs::rename(&tmp, exe).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_release() {
+        let target = "x86_64-unknown-linux-gnu";
+        let comment = "rathole 0.4.0 x86_64-unknown-linux-gnu";
+        assert!(check_release(comment, target, "0.3.4", false).is_ok());
+        assert!(check_release(comment, target, "v0.3.4-12-g1234abc", false).is_ok());
+
+        // Not newer
+        assert!(check_release(comment, target, "0.4.0", false).is_err());
+        assert!(check_release(comment, target, "0.10.0", false).is_err());
+        assert!(check_release(comment, target, "0.10.0", true).is_ok());
+
+        // Another target, or no version, even if downgrades are allowed
+        assert!(check_release(comment, "aarch64-apple-darwin", "0.3.4", true).is_err());
+        assert!(check_release("timestamp:1650000000", target, "0.3.4", true).is_err());
+        assert!(check_release("rathole x86_64-unknown-linux-gnu", target, "0.3.4", true).is_err());
+    }
+}
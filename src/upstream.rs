@@ -0,0 +1,195 @@
+// Relay-only servers, with `server.upstream`. Connections of clients are passed on to the upstream
+// server, each hop with its own transport, and no services are exposed. Clients authenticate with
+// the upstream through the relay, so the relay never holds a token
+use crate::config::{ServerConfig, UpstreamConfig};
+use crate::config_watcher::ServiceChange;
+use crate::constants::SHUTDOWN_TIMEOUT;
+use crate::error::{Failure, Hint, WithHint};
+use crate::health::ListeningGuard;
+use crate::helper::recv_shutdown;
+use crate::protocol::{read_hello, Hello};
+use crate::server::HANDSHAKE_TIMEOUT;
+use crate::task_group::TaskGroup;
+use crate::transport::{with_transport, Diverted, Transport};
+use anyhow::{anyhow, bail, Context, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{copy_bidirectional, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+pub async fn run_upstream_relay(
+    config: &ServerConfig,
+    upstream: &UpstreamConfig,
+    shutdown_rx: broadcast::Receiver<bool>,
+    service_rx: mpsc::Receiver<ServiceChange>,
+) -> Result<()> {
+    with_transport!(config.transport.transport_type, T => {
+        with_transport!(upstream.transport.transport_type, U => {
+            run::<T, U>(config, upstream, shutdown_rx, service_rx).await
+        })
+    })
+}
+
+async fn run<T: 'static + Transport, U: 'static + Transport>(
+    config: &ServerConfig,
+    upstream: &UpstreamConfig,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+    mut service_rx: mpsc::Receiver<ServiceChange>,
+) -> Result<()> {
+    let transport = Arc::new(
+        T::new(&config.transport)
+            .await
+            .with_context(|| "Failed to create the transport")?,
+    );
+    let upstream_transport = Arc::new(
+        U::new(&upstream.transport)
+            .await
+            .with_context(|| "Failed to create the transport of `upstream`")?,
+    );
+    let l = transport
+        .bind(&config.bind_addr)
+        .await
+        .with_context(|| "Failed to listen at `server.bind_addr`")
+        .hint(Hint::Bind("server.bind_addr"))
+        .context(Failure::Bind)?;
+    info!(
+        "Listening at {}, relaying to {}",
+        config.bind_addr, upstream.remote_addr
+    );
+    let _listening = ListeningGuard::new("server", None, &config.bind_addr);
+
+    let tasks = TaskGroup::new();
+    let _tasks = tasks.cancel_on_drop();
+    loop {
+        tokio::select! {
+            ret = transport.accept(&l) => {
+                let (conn, addr) = match ret {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("Failed to accept: {:#}", e);
+                        time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let transport = transport.clone();
+                let upstream_transport = upstream_transport.clone();
+                let remote_addr = upstream.remote_addr.clone();
+                tasks.spawn(async move {
+                    let conn = match time::timeout(
+                        Duration::from_secs(HANDSHAKE_TIMEOUT),
+                        transport.handshake(conn),
+                    )
+                    .await
+                    {
+                        Ok(Ok(conn)) => conn,
+                        Ok(Err(e)) if e.downcast_ref::<Diverted>().is_some() => {
+                            debug!("Connection is diverted");
+                            return;
+                        }
+                        Ok(Err(e)) => {
+                            error!("Failed to do transport handshake: {:#}", e);
+                            return;
+                        }
+                        Err(_) => {
+                            error!("Transport handshake timeout");
+                            return;
+                        }
+                    };
+                    if let Err(e) = relay(conn, &*upstream_transport, &remote_addr).await {
+                        warn!("{:#}", e);
+                    }
+                }.instrument(info_span!("relay", %addr)));
+            },
+            _ = recv_shutdown(&mut shutdown_rx) => {
+                info!("Shuting down gracefully...");
+                break;
+            },
+            Some(e) = service_rx.recv() => {
+                if let ServiceChange::ServerAdd(s) = e {
+                    warn!("Ignored service {}. A server with `upstream` has no services", s.name);
+                }
+            }
+        }
+    }
+
+    tasks.shutdown(Duration::from_secs(SHUTDOWN_TIMEOUT)).await;
+    info!("Shutdown");
+    Ok(())
+}
+
+// Pass a connection from a client on to the upstream. Only the hellos of clients are accepted,
+// so that the upstream isn't reached by anything that isn't rathole
+async fn relay<S, U>(mut conn: S, upstream: &U, remote_addr: &str) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    U: Transport,
+{
+    let hello = time::timeout(
+        Duration::from_secs(HANDSHAKE_TIMEOUT),
+        read_hello(&mut conn),
+    )
+    .await
+    .map_err(|_| anyhow!("Timeout reading the hello"))??;
+    if matches!(hello, Hello::ServerControlChannelHello(..)) {
+        bail!("Unexpected type of hello");
+    }
+
+    let mut upstream = upstream
+        .connect(remote_addr)
+        .await
+        .with_context(|| format!("Failed to connect to the upstream {}", remote_addr))?;
+    upstream.write_all(&bincode::serialize(&hello)?).await?;
+    upstream.flush().await?;
+    debug!("Relaying to {}", remote_addr);
+    copy_bidirectional(&mut conn, &mut upstream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::{digest, CURRENT_PROTO_VERSION};
+    use crate::transport::MemoryTransport;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_relay() {
+        let t = MemoryTransport::new(&Default::default()).await.unwrap();
+        let l = t.bind("127.0.0.1:40011").await.unwrap();
+        let upstream = tokio::spawn(async move {
+            let t = MemoryTransport::new(&Default::default()).await.unwrap();
+            let (mut conn, _) = t.accept(&l).await.unwrap();
+            let hello = read_hello(&mut conn).await.unwrap();
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+            hello
+        });
+
+        let hello = Hello::DataChannelHello(CURRENT_PROTO_VERSION, digest(b"nonce"));
+        let (mut client, conn) = tokio::io::duplex(1024);
+        let relayed = tokio::spawn(async move { relay(conn, &t, "127.0.0.1:40011").await });
+        client
+            .write_all(&bincode::serialize(&hello).unwrap())
+            .await
+            .unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(
+            bincode::serialize(&upstream.await.unwrap()).unwrap(),
+            bincode::serialize(&hello).unwrap()
+        );
+        drop(client);
+        relayed.await.unwrap().unwrap();
+
+        // Anything else doesn't reach the upstream
+        let t = MemoryTransport::new(&Default::default()).await.unwrap();
+        let (mut client, conn) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(relay(conn, &t, "127.0.0.1:40011").await.is_err());
+    }
+}
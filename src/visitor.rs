@@ -0,0 +1,441 @@
+use crate::config::ServerServiceConfig;
+use crate::constants::{VISITOR_AUTH_MAX_PENDING, VISITOR_AUTH_TIMEOUT, VISITOR_KEY_MAX_LEN};
+use crate::privacy;
+use crate::protocol::{ct_eq, digest, Digest};
+use crate::sni::peek_sni;
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tracing::{debug, info};
+
+#[cfg(feature = "visitor-tls")]
+use crate::config::VisitorTlsConfig;
+
+// Digests of the visitor keys of a service, with the names of the key holders. Digests are
+// compared rather than keys, as they're of the same length
+type VisitorKeys = Arc<Vec<(String, Digest)>>;
+
+lazy_static! {
+    // Shared by all services, so visitors that never authenticate can't exhaust the server
+    static ref PENDING: Arc<Semaphore> = Arc::new(Semaphore::new(VISITOR_AUTH_MAX_PENDING));
+}
+
+// A slot for a visitor to wait for the authentication in. None if too many are waiting already
+pub fn pending_slot() -> Option<OwnedSemaphorePermit> {
+    PENDING.clone().try_acquire_owned().ok()
+}
+
+// A connection from a visitor, possibly wrapped by the visitor-facing TLS
+#[derive(Debug)]
+pub enum VisitorStream {
+    Tcp(TcpStream),
+    #[cfg(feature = "visitor-tls")]
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+impl VisitorStream {
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            VisitorStream::Tcp(s) => s.peer_addr(),
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(s) => s.get_ref().0.peer_addr(),
+        }
+    }
+
+    // The address of the server that the visitor connected to
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            VisitorStream::Tcp(s) => s.local_addr(),
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(s) => s.get_ref().0.local_addr(),
+        }
+    }
+
+    // The socket of a visitor that isn't over TLS
+    pub fn as_tcp(&mut self) -> Option<&mut TcpStream> {
+        match self {
+            VisitorStream::Tcp(s) => Some(s),
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(_) => None,
+        }
+    }
+
+    // The SNI the visitor sent, without consuming any data
+    async fn sni(&self) -> Option<String> {
+        match self {
+            VisitorStream::Tcp(s) => peek_sni(s).await,
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(s) => s.get_ref().1.server_name().map(|v| v.to_string()),
+        }
+    }
+}
+
+impl AsyncRead for VisitorStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            VisitorStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for VisitorStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            VisitorStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            VisitorStream::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(s) => Pin::new(s.as_mut()).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            VisitorStream::Tcp(s) => s.is_write_vectored(),
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(s) => s.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            VisitorStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            VisitorStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "visitor-tls")]
+            VisitorStream::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+// What a visitor goes through before its traffic is forwarded
+pub struct VisitorAuth {
+    keys: VisitorKeys,
+    #[cfg(feature = "visitor-tls")]
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    record_sni: bool,
+}
+
+impl VisitorAuth {
+    pub async fn from_config(service: &ServerServiceConfig) -> Result<VisitorAuth> {
+        // Rejected by the validation of the config, which a service added at runtime may bypass
+        #[cfg(not(feature = "visitor-tls"))]
+        if service.visitor_tls.is_some() {
+            bail!("`visitor_tls` needs the feature 'visitor-tls', which is not compiled");
+        }
+
+        Ok(VisitorAuth {
+            keys: Arc::new(
+                service
+                    .visitor_keys
+                    .iter()
+                    .map(|(holder, key)| (holder.clone(), digest(key.as_bytes())))
+                    .collect(),
+            ),
+            #[cfg(feature = "visitor-tls")]
+            tls_acceptor: match &service.visitor_tls {
+                Some(v) => Some(build_visitor_tls_acceptor(v).await?),
+                None => None,
+            },
+            record_sni: service.record_sni,
+        })
+    }
+
+    // Whether visitors can be forwarded right after they are accepted
+    pub fn is_open(&self) -> bool {
+        #[cfg(feature = "visitor-tls")]
+        if self.tls_acceptor.is_some() {
+            return false;
+        }
+        self.keys.is_empty() && !self.record_sni
+    }
+
+    // Authenticate a visitor and wrap the connection as configured.
+    // If both are configured, the key is read inside the TLS session
+    pub async fn prepare(&self, conn: TcpStream, addr: SocketAddr) -> Result<VisitorStream> {
+        #[cfg(feature = "visitor-tls")]
+        let mut conn = match &self.tls_acceptor {
+            Some(acceptor) => {
+                let (conn, fingerprint) = accept_visitor_tls(acceptor, conn).await?;
+                info!(visitor = %privacy::addr(addr), cert = %fingerprint, "Visitor certificate verified");
+                conn
+            }
+            None => VisitorStream::Tcp(conn),
+        };
+        #[cfg(not(feature = "visitor-tls"))]
+        let mut conn = VisitorStream::Tcp(conn);
+
+        if !self.keys.is_empty() {
+            let holder = authenticate_visitor(&mut conn, &self.keys).await?;
+            info!(visitor = %privacy::addr(addr), key = %holder, "Visitor authenticated");
+        }
+
+        if self.record_sni {
+            match conn.sni().await {
+                Some(sni) => info!(visitor = %privacy::addr(addr), sni = %sni, "Visitor connected"),
+                None => debug!(visitor = %privacy::addr(addr), "Visitor connected without a SNI"),
+            }
+        }
+
+        Ok(conn)
+    }
+}
+
+// Read a visitor key, which is a single line sent by the visitor before any other data.
+// Bytes are read one at a time so that nothing after the newline is consumed.
+async fn read_visitor_key<T: AsyncRead + Unpin>(conn: &mut T) -> Result<String> {
+    let mut buf = Vec::new();
+    loop {
+        let b = conn
+            .read_u8()
+            .await
+            .with_context(|| "Failed to read the visitor key")?;
+        if b == b'\n' {
+            break;
+        }
+        // Room for a '\r' after the longest key
+        if buf.len() > VISITOR_KEY_MAX_LEN {
+            bail!("The visitor key is too long");
+        }
+        buf.push(b);
+    }
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    if buf.len() > VISITOR_KEY_MAX_LEN {
+        bail!("The visitor key is too long");
+    }
+    String::from_utf8(buf).with_context(|| "The visitor key is not valid UTF-8")
+}
+
+// Authenticate a visitor against `visitor_keys`. Returns the name of the key holder
+async fn authenticate_visitor<T: AsyncRead + Unpin>(
+    conn: &mut T,
+    visitor_keys: &VisitorKeys,
+) -> Result<String> {
+    let key = time::timeout(
+        Duration::from_secs(VISITOR_AUTH_TIMEOUT),
+        read_visitor_key(conn),
+    )
+    .await
+    .with_context(|| "Timeout waiting for the visitor key")??;
+
+    // Every key is compared, so the time taken tells neither which one matched nor their lengths
+    let key = digest(key.as_bytes());
+    visitor_keys
+        .iter()
+        .fold(None, |found, (holder, v)| {
+            let matched = ct_eq(v, &key);
+            found.or(matched.then_some(holder))
+        })
+        .cloned()
+        .ok_or_else(|| anyhow!("Unknown visitor key"))
+}
+
+#[cfg(feature = "visitor-tls")]
+use tls::{accept_visitor_tls, build_visitor_tls_acceptor};
+
+#[cfg(feature = "visitor-tls")]
+mod tls {
+    use super::*;
+    use tokio::fs;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    // Create a TLS acceptor that requires visitors to present a certificate signed by `client_ca`
+    pub async fn build_visitor_tls_acceptor(config: &VisitorTlsConfig) -> Result<TlsAcceptor> {
+        let cert = fs::read(&config.cert)
+            .await
+            .with_context(|| "Failed to read `visitor_tls.cert`")?;
+        let certs = rustls_pemfile::certs(&mut cert.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| "Failed to parse `visitor_tls.cert`")?;
+
+        let key = fs::read(&config.key)
+            .await
+            .with_context(|| "Failed to read `visitor_tls.key`")?;
+        let key = rustls_pemfile::private_key(&mut key.as_slice())
+            .with_context(|| "Failed to parse `visitor_tls.key`")?
+            .ok_or_else(|| anyhow!("No private key found in `visitor_tls.key`"))?;
+
+        let ca = fs::read(&config.client_ca)
+            .await
+            .with_context(|| "Failed to read `visitor_tls.client_ca`")?;
+        let mut roots = RootCertStore::empty();
+        for c in rustls_pemfile::certs(&mut ca.as_slice()) {
+            roots
+                .add(c.with_context(|| "Failed to parse `visitor_tls.client_ca`")?)
+                .with_context(|| "Invalid certificate in `visitor_tls.client_ca`")?;
+        }
+
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .with_context(|| "Failed to create the client certificate verifier")?;
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .with_context(|| "Failed to create the visitor-facing TLS config")?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    // Do the TLS handshake with a visitor. Returns the wrapped stream and the SHA256
+    // fingerprint of the certificate the visitor presented
+    pub async fn accept_visitor_tls(
+        acceptor: &TlsAcceptor,
+        conn: TcpStream,
+    ) -> Result<(VisitorStream, String)> {
+        let conn = time::timeout(
+            Duration::from_secs(VISITOR_AUTH_TIMEOUT),
+            acceptor.accept(conn),
+        )
+        .await
+        .with_context(|| "Timeout doing the visitor TLS handshake")?
+        .with_context(|| "Failed to do the visitor TLS handshake")?;
+
+        let fingerprint = conn
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|c| c.first())
+            .map(|c| hex::encode(crate::protocol::digest(c.as_ref())))
+            .ok_or_else(|| anyhow!("The visitor presented no certificate"))?;
+
+        Ok((VisitorStream::Tls(Box::new(conn)), fingerprint))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn visitor_auth(keys: &[(&str, &str)]) -> VisitorAuth {
+        let service = ServerServiceConfig {
+            visitor_keys: keys
+                .iter()
+                .map(|(holder, key)| (holder.to_string(), key.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        VisitorAuth::from_config(&service).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_visitor() {
+        let auth = visitor_auth(&[("alice", "key_of_alice"), ("bob", "key_of_bob")]).await;
+        assert!(!auth.is_open());
+
+        // What follows the key is left for the service
+        let mut conn: &[u8] = b"key_of_bob\r\nSSH-2.0";
+        let holder = authenticate_visitor(&mut conn, &auth.keys).await.unwrap();
+        assert_eq!(holder, "bob");
+        assert_eq!(conn, b"SSH-2.0");
+
+        let mut conn: &[u8] = b"key_of_eve\n";
+        assert!(authenticate_visitor(&mut conn, &auth.keys).await.is_err());
+        // Prefixes of a key aren't keys
+        let mut conn: &[u8] = b"key_of\n";
+        assert!(authenticate_visitor(&mut conn, &auth.keys).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_visitor_key_len() {
+        let longest = "k".repeat(VISITOR_KEY_MAX_LEN);
+        let too_long = "k".repeat(VISITOR_KEY_MAX_LEN + 1);
+        // Bypassing the validation of the config
+        let auth = visitor_auth(&[("alice", &longest), ("bob", &too_long)]).await;
+
+        for line in [format!("{}\n", longest), format!("{}\r\n", longest)] {
+            let mut conn = line.as_bytes();
+            let holder = authenticate_visitor(&mut conn, &auth.keys).await.unwrap();
+            assert_eq!(holder, "alice");
+        }
+        let line = format!("{}\n", too_long);
+        let mut conn = line.as_bytes();
+        assert!(authenticate_visitor(&mut conn, &auth.keys).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_visitor_without_key() {
+        let auth = visitor_auth(&[("alice", "key_of_alice")]).await;
+
+        // The visitor closes before sending a line
+        let mut conn: &[u8] = b"";
+        assert!(authenticate_visitor(&mut conn, &auth.keys).await.is_err());
+        let mut conn: &[u8] = b"\n";
+        assert!(authenticate_visitor(&mut conn, &auth.keys).await.is_err());
+    }
+
+    #[cfg(not(feature = "visitor-tls"))]
+    #[tokio::test]
+    async fn test_visitor_tls_not_compiled() {
+        use crate::config::VisitorTlsConfig;
+        let service = ServerServiceConfig {
+            visitor_tls: Some(VisitorTlsConfig {
+                cert: "cert.pem".into(),
+                key: "key.pem".into(),
+                client_ca: "ca.pem".into(),
+            }),
+            ..Default::default()
+        };
+        // An error for the service only, rather than an exit of the whole process
+        assert!(VisitorAuth::from_config(&service).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_revoked_visitor() {
+        let auth = visitor_auth(&[("alice", "key_of_alice"), ("bob", "key_of_bob")]).await;
+        let mut conn: &[u8] = b"key_of_alice\n";
+        assert!(authenticate_visitor(&mut conn, &auth.keys).await.is_ok());
+
+        // Revoking the key of alice with a reload leaves bob alone
+        let auth = visitor_auth(&[("bob", "key_of_bob")]).await;
+        let mut conn: &[u8] = b"key_of_alice\n";
+        assert!(authenticate_visitor(&mut conn, &auth.keys).await.is_err());
+        let mut conn: &[u8] = b"key_of_bob\n";
+        assert_eq!(
+            authenticate_visitor(&mut conn, &auth.keys).await.unwrap(),
+            "bob"
+        );
+    }
+}
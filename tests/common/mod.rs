@@ -110,3 +110,100 @@ pub mod udp {
         }
     }
 }
+
+// A custom transport, TCP with every byte XORed with a key
+pub mod xor {
+    use async_trait::async_trait;
+    use rathole::{Transport, TransportConfig};
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct XorStream {
+        inner: TcpStream,
+        key: u8,
+    }
+
+    impl AsyncRead for XorStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let before = buf.filled().len();
+            let key = self.key;
+            let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+            buf.filled_mut()[before..]
+                .iter_mut()
+                .for_each(|b| *b ^= key);
+            ret
+        }
+    }
+
+    impl AsyncWrite for XorStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let buf: Vec<u8> = buf.iter().map(|b| b ^ self.key).collect();
+            Pin::new(&mut self.inner).poll_write(cx, &buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct XorTransport {
+        key: u8,
+    }
+
+    #[async_trait]
+    impl Transport for XorTransport {
+        type Acceptor = TcpListener;
+        type RawStream = TcpStream;
+        type Stream = XorStream;
+
+        async fn new(config: &TransportConfig) -> Result<Self> {
+            let key = config
+                .custom
+                .as_ref()
+                .and_then(|v| v.options.get("key"))
+                .and_then(|v| v.as_integer())
+                .ok_or_else(|| anyhow::anyhow!("Missing `custom.key`"))?;
+            Ok(XorTransport { key: key as u8 })
+        }
+
+        async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
+            Ok(TcpListener::bind(addr).await?)
+        }
+
+        async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+            Ok(a.accept().await?)
+        }
+
+        async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+            Ok(XorStream {
+                inner: conn,
+                key: self.key,
+            })
+        }
+
+        async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+            Ok(XorStream {
+                inner: TcpStream::connect(addr).await?,
+                key: self.key,
+            })
+        }
+    }
+}
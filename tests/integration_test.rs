@@ -1,11 +1,17 @@
 use anyhow::Result;
 use common::{run_rathole_client, PING, PONG};
 use rand::Rng;
+use rathole::{
+    ClientConfig, ClientServiceConfig, Compression, CompressionPreference, Config,
+    CustomTransportConfig, Event, ProxyProtocol, ServerConfig, ServerServiceConfig, ServiceChange,
+    TransportConfig, TransportType, UpstreamConfig, WarmupConfig,
+};
 use std::time::Duration;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpStream, UdpSocket},
-    sync::broadcast,
+    sync::{broadcast, mpsc},
+    task::JoinSet,
     time,
 };
 use tracing::{debug, info, instrument};
@@ -59,6 +65,8 @@ async fn tcp() -> Result<()> {
     #[cfg(not(target_os = "macos"))]
     test("tests/for_tcp/tls_transport.toml", Type::Tcp).await?;
     test("tests/for_tcp/noise_transport.toml", Type::Tcp).await?;
+    #[cfg(feature = "websocket")]
+    test("tests/for_tcp/websocket_transport.toml", Type::Tcp).await?;
 
     Ok(())
 }
@@ -86,11 +94,1175 @@ async fn udp() -> Result<()> {
     #[cfg(not(target_os = "macos"))]
     test("tests/for_udp/tls_transport.toml", Type::Udp).await?;
     test("tests/for_udp/noise_transport.toml", Type::Udp).await?;
+    #[cfg(feature = "websocket")]
+    test("tests/for_udp/websocket_transport.toml", Type::Udp).await?;
+
+    Ok(())
+}
+
+// The control and data channels go through in-process pipes
+fn memory_transport() -> TransportConfig {
+    TransportConfig {
+        transport_type: TransportType::Memory,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn in_memory_config() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8082";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2337";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2336".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2336".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let mut events = rathole::subscribe();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let (server_service_tx, server_service_rx) = mpsc::channel(1);
+    let (client_service_tx, client_service_rx) = mpsc::channel(1);
+    let server = tokio::spawn(rathole::run_with_config(
+        server_config,
+        shutdown_tx.subscribe(),
+        server_service_rx,
+    ));
+    let client = tokio::spawn(rathole::run_with_config(
+        client_config,
+        shutdown_tx.subscribe(),
+        client_service_rx,
+    ));
+
+    // Add a service to both ends
+    server_service_tx
+        .send(ServiceChange::ServerAdd(Box::new(ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            ..ServerServiceConfig::with_name("echo")
+        })))
+        .await?;
+    client_service_tx
+        .send(ServiceChange::ClientAdd(Box::new(ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        })))
+        .await?;
+    time::sleep(Duration::from_secs(1)).await;
+    tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await?;
+
+    // Remove it, and the port is closed
+    server_service_tx
+        .send(ServiceChange::ServerDelete("echo".to_string()))
+        .await?;
+    client_service_tx
+        .send(ServiceChange::ClientDelete("echo".to_string()))
+        .await?;
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(TcpStream::connect(ECHO_SERVER_ADDR_EXPOSED).await.is_err());
+
+    // The service went up and carried traffic
+    let mut events_seen = vec![];
+    while let Ok(e) = events.try_recv() {
+        events_seen.push(e);
+    }
+    assert!(events_seen.contains(&Event::ServiceUp {
+        service: "echo".to_string()
+    }));
+    assert!(events_seen.iter().any(|e| matches!(
+        e,
+        Event::DataChannelClosed { service, inbound, outbound }
+            if service == "echo" && *inbound > 0 && inbound == outbound
+    )));
+
+    shutdown_tx.send(true)?;
+    server.await??;
+    client.await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn cancel_on_drop() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8083";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2339";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2338".to_string(),
+            default_token: Some("123".to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2338".to_string(),
+            default_token: Some("123".to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    // No shutdown signal is needed. The senders are dropped at once
+    let mut tasks = JoinSet::new();
+    tasks.spawn(rathole::run_with_config(
+        server_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    tasks.spawn(rathole::run_with_config(
+        client_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    time::sleep(Duration::from_secs(1)).await;
+    tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await?;
+
+    // Dropping the futures stops everything, including the forwarded connections
+    let mut conn = TcpStream::connect(ECHO_SERVER_ADDR_EXPOSED).await?;
+    drop(tasks);
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(TcpStream::connect(ECHO_SERVER_ADDR_EXPOSED).await.is_err());
+    assert!(TcpStream::connect("127.0.0.1:2338").await.is_err());
+    let mut buf = [0u8; 1];
+    assert!(matches!(conn.read(&mut buf).await, Ok(0) | Err(_)));
+
+    Ok(())
+}
+
+#[instrument]
+#[tokio::test]
+async fn hashed_token() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8084";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2341";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    // The hashes of "old" and "123", by `rathole hash-token`
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2340".to_string(),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            token_hashes: vec![
+                "8fdb5aa9d5729e0d77ed5d1d863a9486$14fb407a6504b465fb53af7afddf68818170251b90df853d33dacb2cf39f2ace".to_string(),
+                "a32c1c336c59bba1d43a8d48d237c93f$a08fea5f5058beb8edad2859f17dd4ec1c0bc7a81c8b73975a8d12c00ed4c134".to_string(),
+            ],
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2340".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    let mut tasks = JoinSet::new();
+    tasks.spawn(rathole::run_with_config(
+        server_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    tasks.spawn(rathole::run_with_config(
+        client_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    // Stretching the token takes a while in debug builds, the more so under load
+    time::timeout(Duration::from_secs(30), async {
+        while tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await.is_err() {
+            time::sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+// The client and the server run over pipes of the test, as if they came from a dialer of its own
+#[instrument]
+#[tokio::test]
+async fn external_transport() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8086";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2346";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let external = TransportConfig {
+        transport_type: TransportType::External,
+        ..Default::default()
+    };
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2345".to_string(),
+            default_token: Some("123".to_string()),
+            transport: external.clone(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "the server".to_string(),
+            default_token: Some("123".to_string()),
+            transport: external,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    let tx = rathole::register_listener("127.0.0.1:2345".parse()?);
+    rathole::register_dialer("the server", move || {
+        let tx = tx.clone();
+        async move {
+            let (local, remote) = tokio::io::duplex(64 * 1024);
+            tx.send(remote, "10.0.0.1:1234".parse().unwrap())
+                .await
+                .map_err(std::io::Error::other)?;
+            Ok(local)
+        }
+    });
+
+    let mut tasks = JoinSet::new();
+    for config in [server_config, client_config] {
+        tasks.spawn(rathole::run_with_config(
+            config,
+            broadcast::channel(1).1,
+            mpsc::channel(1).1,
+        ));
+    }
+    time::sleep(Duration::from_secs(1)).await;
+    tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await?;
+
+    Ok(())
+}
+
+// All channels of the client share one connection to the server
+#[cfg(feature = "mux")]
+#[instrument]
+#[tokio::test]
+async fn mux() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8088";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2350";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let external = TransportConfig {
+        transport_type: TransportType::External,
+        mux: true,
+        ..Default::default()
+    };
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2349".to_string(),
+            default_token: Some("123".to_string()),
+            transport: external.clone(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "the muxed server".to_string(),
+            default_token: Some("123".to_string()),
+            transport: external,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    let dials = Arc::new(AtomicUsize::new(0));
+    let tx = rathole::register_listener("127.0.0.1:2349".parse()?);
+    let counter = dials.clone();
+    rathole::register_dialer("the muxed server", move || {
+        let tx = tx.clone();
+        counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+            let (local, remote) = tokio::io::duplex(64 * 1024);
+            tx.send(remote, "10.0.0.1:1234".parse().unwrap())
+                .await
+                .map_err(std::io::Error::other)?;
+            Ok(local)
+        }
+    });
+
+    let mut tasks = JoinSet::new();
+    for config in [server_config, client_config] {
+        tasks.spawn(rathole::run_with_config(
+            config,
+            broadcast::channel(1).1,
+            mpsc::channel(1).1,
+        ));
+    }
+    time::sleep(Duration::from_secs(1)).await;
+    let mut visitors = JoinSet::new();
+    for _ in 0..10 {
+        visitors.spawn(tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED));
+    }
+    while let Some(v) = visitors.join_next().await {
+        v??;
+    }
+    assert_eq!(dials.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+// The client reaches the upstream through a relay-only server, which holds no token
+#[instrument]
+#[tokio::test]
+async fn upstream_relay() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8085";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2344";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2342".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let relay_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2343".to_string(),
+            transport: memory_transport(),
+            upstream: Some(UpstreamConfig {
+                remote_addr: "127.0.0.1:2342".to_string(),
+                transport: memory_transport(),
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2343".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    let mut tasks = JoinSet::new();
+    for config in [server_config, relay_config, client_config] {
+        tasks.spawn(rathole::run_with_config(
+            config,
+            broadcast::channel(1).1,
+            mpsc::channel(1).1,
+        ));
+    }
+    time::sleep(Duration::from_secs(1)).await;
+    tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await?;
+
+    Ok(())
+}
+
+#[instrument]
+#[tokio::test]
+async fn warmup_after_reconnect() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8087";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2348";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2347".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            warmup: Some(WarmupConfig {
+                channels: Some(2),
+                rate: 20,
+            }),
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2347".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    let mut server = JoinSet::new();
+    server.spawn(rathole::run_with_config(
+        server_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    let mut client = JoinSet::new();
+    client.spawn(rathole::run_with_config(
+        client_config.clone(),
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    time::sleep(Duration::from_secs(1)).await;
+    tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await?;
+
+    // Visitors wait while the client is away, and are served once it's back
+    drop(client);
+    time::sleep(Duration::from_millis(500)).await;
+    let mut visitors = JoinSet::new();
+    for _ in 0..10 {
+        visitors.spawn(tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED));
+    }
+    time::sleep(Duration::from_millis(500)).await;
+    let mut client = JoinSet::new();
+    client.spawn(rathole::run_with_config(
+        client_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    time::timeout(Duration::from_secs(5), async {
+        while let Some(v) = visitors.join_next().await {
+            v??;
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
 
     Ok(())
 }
 
+// Visitors are forwarded one after another through the data channels of those who have left,
+// and more are opened for visitors at once. What's forwarded is compressed inside the frames,
+// where compression is compiled
 #[instrument]
+#[tokio::test]
+async fn reused_data_channels() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8092";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2358";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2357".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            reuse_data_channels: true,
+            compression: cfg!(feature = "compression")
+                .then_some(CompressionPreference::One(Compression::Zstd)),
+            warmup: Some(WarmupConfig {
+                channels: Some(1),
+                rate: 20,
+            }),
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2357".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    let mut tasks = JoinSet::new();
+    tasks.spawn(rathole::run_with_config(
+        server_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    tasks.spawn(rathole::run_with_config(
+        client_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    time::sleep(Duration::from_secs(1)).await;
+
+    time::timeout(Duration::from_secs(10), async {
+        for _ in 0..5 {
+            tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await?;
+        }
+        let mut visitors = JoinSet::new();
+        for _ in 0..HITTER_NUM {
+            visitors.spawn(tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED));
+        }
+        while let Some(v) = visitors.join_next().await {
+            v??;
+        }
+        tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await
+    })
+    .await??;
+
+    Ok(())
+}
+
+// The local service is told the address of the visitor by a PROXY protocol header
+#[instrument]
+#[tokio::test]
+async fn proxy_protocol() -> Result<()> {
+    init();
+
+    const LOCAL_ADDR: &str = "127.0.0.1:8093";
+    const LOCAL_ADDR_EXPOSED: &str = "127.0.0.1:2360";
+
+    // Answers with the source address in the header
+    let l = tokio::net::TcpListener::bind(LOCAL_ADDR).await?;
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = l.accept().await {
+            let mut header = [0u8; 16 + 12];
+            conn.read_exact(&mut header).await.unwrap();
+            assert_eq!(&header[..16], b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c");
+            let ip = std::net::Ipv4Addr::from(<[u8; 4]>::try_from(&header[16..20]).unwrap());
+            let port = u16::from_be_bytes([header[24], header[25]]);
+            let src = format!("{}:{}", ip, port);
+            conn.write_all(src.as_bytes()).await.unwrap();
+        }
+    });
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2359".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "web".to_string(),
+        ServerServiceConfig {
+            bind_addr: LOCAL_ADDR_EXPOSED.to_string(),
+            ..ServerServiceConfig::with_name("web")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2359".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "web".to_string(),
+        ClientServiceConfig {
+            local_addr: LOCAL_ADDR.to_string(),
+            proxy_protocol: Some(ProxyProtocol::V2),
+            ..ClientServiceConfig::with_name("web")
+        },
+    );
+
+    let mut tasks = JoinSet::new();
+    tasks.spawn(rathole::run_with_config(
+        server_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    tasks.spawn(rathole::run_with_config(
+        client_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    time::sleep(Duration::from_secs(1)).await;
+
+    time::timeout(Duration::from_secs(10), async {
+        let mut conn = TcpStream::connect(LOCAL_ADDR_EXPOSED).await?;
+        let mut src = String::new();
+        conn.read_to_string(&mut src).await?;
+        assert_eq!(src, conn.local_addr()?.to_string());
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+
+    Ok(())
+}
+
+// Visitors of an isolated service with a budget of one connection are forwarded in turn
+#[instrument]
+#[tokio::test]
+async fn isolated_service() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8089";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2352";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2351".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2351".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            max_connections: Some(1),
+            isolated: true,
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    let mut tasks = JoinSet::new();
+    for config in [server_config, client_config] {
+        tasks.spawn(rathole::run_with_config(
+            config,
+            broadcast::channel(1).1,
+            mpsc::channel(1).1,
+        ));
+    }
+    time::sleep(Duration::from_secs(1)).await;
+    let mut visitors = JoinSet::new();
+    for _ in 0..HITTER_NUM {
+        visitors.spawn(tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED));
+    }
+    time::timeout(Duration::from_secs(10), async {
+        while let Some(v) = visitors.join_next().await {
+            v??;
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+
+    Ok(())
+}
+
+// Visitors of a reverse service come to the client, and are forwarded by the server
+#[instrument]
+#[tokio::test]
+async fn reverse_service() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8090";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2354";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2353".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            connect_addr: Some(ECHO_SERVER_ADDR.to_string()),
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2353".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            listen_addr: Some(ECHO_SERVER_ADDR_EXPOSED.to_string()),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    let mut tasks = JoinSet::new();
+    for config in [server_config, client_config] {
+        tasks.spawn(rathole::run_with_config(
+            config,
+            broadcast::channel(1).1,
+            mpsc::channel(1).1,
+        ));
+    }
+    time::sleep(Duration::from_secs(1)).await;
+    let mut visitors = JoinSet::new();
+    for _ in 0..HITTER_NUM {
+        visitors.spawn(tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED));
+    }
+    time::timeout(Duration::from_secs(10), async {
+        while let Some(v) = visitors.join_next().await {
+            v??;
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+
+    Ok(())
+}
+
+// The bytes of a visitor of a reverse service are counted as inbound on both ends, and the
+// replies of the service as outbound
+#[tokio::test]
+async fn reverse_service_bytes() -> Result<()> {
+    init();
+
+    const SERVICE_ADDR: &str = "127.0.0.1:8095";
+    const SERVICE_ADDR_EXPOSED: &str = "127.0.0.1:2365";
+    const REQUEST_LEN: usize = 1000;
+    const REPLY: &[u8] = b"done";
+
+    // Reads a request, and replies with less
+    let l = tokio::net::TcpListener::bind(SERVICE_ADDR).await?;
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = l.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; REQUEST_LEN];
+                conn.read_exact(&mut buf).await?;
+                conn.write_all(REPLY).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2364".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "reverse_bytes".to_string(),
+        ServerServiceConfig {
+            connect_addr: Some(SERVICE_ADDR.to_string()),
+            ..ServerServiceConfig::with_name("reverse_bytes")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2364".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "reverse_bytes".to_string(),
+        ClientServiceConfig {
+            listen_addr: Some(SERVICE_ADDR_EXPOSED.to_string()),
+            ..ClientServiceConfig::with_name("reverse_bytes")
+        },
+    );
+
+    let mut events = rathole::subscribe();
+    let mut tasks = JoinSet::new();
+    for config in [server_config, client_config] {
+        tasks.spawn(rathole::run_with_config(
+            config,
+            broadcast::channel(1).1,
+            mpsc::channel(1).1,
+        ));
+    }
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut conn = TcpStream::connect(SERVICE_ADDR_EXPOSED).await?;
+    conn.write_all(&[0; REQUEST_LEN]).await?;
+    let mut reply = vec![];
+    conn.read_to_end(&mut reply).await?;
+    assert_eq!(reply, REPLY);
+    drop(conn);
+
+    // One from each end
+    let counts = time::timeout(Duration::from_secs(5), async {
+        let mut counts = vec![];
+        while counts.len() < 2 {
+            if let Event::DataChannelClosed {
+                service,
+                inbound,
+                outbound,
+            } = events.recv().await?
+            {
+                if service == "reverse_bytes" {
+                    counts.push((inbound, outbound));
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(counts)
+    })
+    .await??;
+    let expected = (REQUEST_LEN as u64, REPLY.len() as u64);
+    assert_eq!(counts, [expected, expected]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn custom_transport() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8091";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2356";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    rathole::register_transport::<common::xor::XorTransport>("xor");
+    let transport = TransportConfig {
+        transport_type: TransportType::Custom,
+        custom: Some(CustomTransportConfig {
+            name: "xor".to_string(),
+            options: [("key".to_string(), toml::Value::Integer(0x5a))].into(),
+        }),
+        ..Default::default()
+    };
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2355".to_string(),
+            default_token: Some("123".to_string()),
+            transport: transport.clone(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    server_config.server.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ServerServiceConfig {
+            bind_addr: ECHO_SERVER_ADDR_EXPOSED.to_string(),
+            ..ServerServiceConfig::with_name("echo")
+        },
+    );
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2355".to_string(),
+            default_token: Some("123".to_string()),
+            transport,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+
+    let mut tasks = JoinSet::new();
+    for config in [server_config, client_config] {
+        tasks.spawn(rathole::run_with_config(
+            config,
+            broadcast::channel(1).1,
+            mpsc::channel(1).1,
+        ));
+    }
+    time::sleep(Duration::from_secs(1)).await;
+    tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await?;
+
+    Ok(())
+}
+
+// A service with a wrong token stops alone, and the client exits once every service has
+#[tokio::test]
+async fn auth_failure() -> Result<()> {
+    init();
+
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:8094";
+    const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2362";
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    let mut server_config = Config {
+        server: Some(ServerConfig {
+            bind_addr: "127.0.0.1:2361".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    for (name, bind_addr) in [
+        ("echo", ECHO_SERVER_ADDR_EXPOSED),
+        ("wrong", "127.0.0.1:2363"),
+    ] {
+        server_config.server.as_mut().unwrap().services.insert(
+            name.to_string(),
+            ServerServiceConfig {
+                bind_addr: bind_addr.to_string(),
+                ..ServerServiceConfig::with_name(name)
+            },
+        );
+    }
+    let mut client_config = Config {
+        client: Some(ClientConfig {
+            remote_addr: "127.0.0.1:2361".to_string(),
+            default_token: Some("123".to_string()),
+            transport: memory_transport(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    client_config.client.as_mut().unwrap().services.insert(
+        "echo".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            ..ClientServiceConfig::with_name("echo")
+        },
+    );
+    client_config.client.as_mut().unwrap().services.insert(
+        "wrong".to_string(),
+        ClientServiceConfig {
+            local_addr: ECHO_SERVER_ADDR.to_string(),
+            token: Some("456".to_string()),
+            ..ClientServiceConfig::with_name("wrong")
+        },
+    );
+
+    let mut events = rathole::subscribe();
+    let mut tasks = JoinSet::new();
+    tasks.spawn(rathole::run_with_config(
+        server_config,
+        broadcast::channel(1).1,
+        mpsc::channel(1).1,
+    ));
+    let (client_service_tx, client_service_rx) = mpsc::channel(1);
+    let mut client = tokio::spawn(rathole::run_with_config(
+        client_config,
+        broadcast::channel(1).1,
+        client_service_rx,
+    ));
+    time::sleep(Duration::from_secs(1)).await;
+    tcp_echo_hitter(ECHO_SERVER_ADDR_EXPOSED).await?;
+    assert!(!client.is_finished());
+    let mut stopped = vec![];
+    while let Ok(e) = events.try_recv() {
+        if let Event::ServiceStopped { service, .. } = e {
+            stopped.push(service);
+        }
+    }
+    assert_eq!(stopped, ["wrong"]);
+
+    // The last one running goes, and so does the client
+    client_service_tx
+        .send(ServiceChange::ClientDelete("echo".to_string()))
+        .await?;
+    let err = time::timeout(Duration::from_secs(1), &mut client)
+        .await??
+        .unwrap_err();
+    assert_eq!(rathole::exit_code(&err), 77);
+
+    Ok(())
+}
+
 async fn test(config_path: &'static str, t: Type) -> Result<()> {
     let (client_shutdown_tx, client_shutdown_rx) = broadcast::channel(1);
     let (server_shutdown_tx, server_shutdown_rx) = broadcast::channel(1);